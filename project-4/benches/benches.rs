@@ -1,6 +1,7 @@
 use criterion::{Criterion, criterion_group, criterion_main, ParameterizedBenchmark, BatchSize};
 use tempfile::TempDir;
 use kvs::{KvStore, KvsEngine};
+use kvs::engines::sled::SledKvsEngine;
 use std::iter;
 
 fn set_bench(c: &mut Criterion) {
@@ -21,7 +22,21 @@ fn set_bench(c: &mut Criterion) {
             )
         },
         iter::once(()),
-    );
+    )
+    .with_function("sled", |b, _| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (SledKvsEngine::open(temp_dir.path()).unwrap(), temp_dir)
+            },
+            |(db, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    db.set(format!("key{}", i), "value".to_string()).unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
     c.bench("set_bench", bench);
 }
 
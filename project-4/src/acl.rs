@@ -0,0 +1,240 @@
+//! password and ACL authentication of the server connections
+//!
+//! the users are read from a TOML file, e.g.
+//! ```toml
+//! [[user]]
+//! name = "reader"
+//! password = "secret"
+//! categories = ["read"]
+//! keys = ["user:*", "config"]
+//! ```
+//! a key pattern ending with `*` matches the keys with the prefix before it, otherwise only the key itself
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::KvsError;
+use crate::model::Behavior;
+use crate::Result;
+
+/// the name of the user authenticated by `AUTH password`
+pub const DEFAULT_USER: &str = "default";
+
+/// the commands a user may run
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Category {
+    /// `GET`, `MGET`, `DBSIZE`, the subscriptions and `WATCH`
+    Read,
+    /// `SET`, `MSET`, `RM` and `PUBLISH`
+    Write,
+    /// `FLUSHDB`, `INFO`, `CLIENT LIST`, `SLOWLOG`, `PSYNC`, `RAFT`, `CLUSTER`, `SHUTDOWN` and `PROXY` of `KvsProxy`
+    Admin,
+}
+
+impl Category {
+    /// the category of `behavior`, `None` if every authenticated connection may run it, e.g. `SELECT`
+    pub fn of(behavior: &Behavior) -> Option<Category> {
+        match behavior {
+            Behavior::Get { .. }
+            | Behavior::MGet { .. }
+            | Behavior::DbSize
+            | Behavior::Subscribe { .. }
+            | Behavior::Unsubscribe { .. }
+            | Behavior::PSubscribe { .. }
+            | Behavior::PUnsubscribe { .. }
+            | Behavior::Watch { .. }
+            | Behavior::Unwatch => Some(Category::Read),
+            Behavior::Set { .. } | Behavior::MSet { .. } | Behavior::Remove { .. } | Behavior::Publish { .. } => {
+                Some(Category::Write)
+            }
+            Behavior::FlushDb
+            | Behavior::Info { .. }
+            | Behavior::ClientList
+            | Behavior::SlowLogGet { .. }
+            | Behavior::SlowLogLen
+            | Behavior::SlowLogReset
+            | Behavior::PSync { .. }
+            | Behavior::Raft { .. }
+            | Behavior::ClusterAddNode { .. }
+            | Behavior::ClusterRemoveNode { .. }
+            | Behavior::Shutdown => Some(Category::Admin),
+            Behavior::Select { .. }
+            | Behavior::Auth { .. }
+            | Behavior::Hello { .. }
+            | Behavior::ClientTraceParent { .. } => None,
+        }
+    }
+}
+
+/// a user of the ACL file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct User {
+    #[allow(missing_docs)]
+    pub name: String,
+    #[allow(missing_docs)]
+    pub password: String,
+    /// the allowed commands
+    pub categories: Vec<Category>,
+    /// the allowed key patterns, all keys if absent
+    #[serde(default = "all_keys")]
+    pub keys: Vec<String>,
+}
+
+fn all_keys() -> Vec<String> {
+    vec!["*".to_owned()]
+}
+
+impl User {
+    /// a user allowed to run every command on every key
+    pub fn unrestricted(name: &str, password: &str) -> Self {
+        User {
+            name: name.to_owned(),
+            password: password.to_owned(),
+            categories: vec![Category::Read, Category::Write, Category::Admin],
+            keys: all_keys(),
+        }
+    }
+
+    /// return `KvsError::NoPermCommand` or `KvsError::NoPermKey` if the user may not run `behavior`
+    pub fn check(&self, behavior: &Behavior) -> Result<()> {
+        let category = match Category::of(behavior) {
+            Some(category) => category,
+            None => return Ok(()),
+        };
+        if !self.categories.contains(&category) {
+            Err(KvsError::NoPermCommand { user: self.name.clone(), command: command_name(behavior).to_owned() })?
+        }
+        let keys: Vec<&String> = match behavior {
+            Behavior::Set { key, .. } | Behavior::Get { key } | Behavior::Remove { key } => vec![key],
+            Behavior::MGet { keys } => keys.iter().collect(),
+            Behavior::MSet { pairs } => pairs.iter().map(|(key, _)| key).collect(),
+            // every key under the prefix must be allowed
+            Behavior::Watch { prefix } => {
+                let allowed = self.keys.iter()
+                    .any(|pattern| pattern.strip_suffix('*').is_some_and(|p| prefix.starts_with(p)));
+                if !allowed {
+                    Err(KvsError::NoPermKey { user: self.name.clone(), key: format!("{}*", prefix) })?
+                }
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+        for key in keys {
+            if !self.keys.iter().any(|pattern| key_matches(pattern, key)) {
+                Err(KvsError::NoPermKey { user: self.name.clone(), key: key.clone() })?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// the command name shown in the errors, like `client|list`
+pub(crate) fn command_name(behavior: &Behavior) -> &'static str {
+    match behavior {
+        Behavior::Set { .. } => "set",
+        Behavior::Get { .. } => "get",
+        Behavior::Remove { .. } => "rm",
+        Behavior::MGet { .. } => "mget",
+        Behavior::MSet { .. } => "mset",
+        Behavior::Select { .. } => "select",
+        Behavior::DbSize => "dbsize",
+        Behavior::FlushDb => "flushdb",
+        Behavior::Subscribe { .. } => "subscribe",
+        Behavior::Unsubscribe { .. } => "unsubscribe",
+        Behavior::PSubscribe { .. } => "psubscribe",
+        Behavior::PUnsubscribe { .. } => "punsubscribe",
+        Behavior::Publish { .. } => "publish",
+        Behavior::Watch { .. } => "watch",
+        Behavior::Unwatch => "unwatch",
+        Behavior::Auth { .. } => "auth",
+        Behavior::Hello { .. } => "hello",
+        Behavior::Info { .. } => "info",
+        Behavior::ClientList => "client|list",
+        Behavior::ClientTraceParent { .. } => "client|traceparent",
+        Behavior::SlowLogGet { .. } => "slowlog|get",
+        Behavior::SlowLogLen => "slowlog|len",
+        Behavior::SlowLogReset => "slowlog|reset",
+        Behavior::PSync { .. } => "psync",
+        Behavior::Raft { .. } => "raft",
+        Behavior::ClusterAddNode { .. } => "cluster|addnode",
+        Behavior::ClusterRemoveNode { .. } => "cluster|removenode",
+        Behavior::Shutdown => "shutdown",
+    }
+}
+
+/// `prefix*` matches the keys starting with prefix, other patterns match only the same key
+fn key_matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => pattern == key,
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AclFile {
+    #[serde(default)]
+    user: Vec<User>,
+}
+
+/// the users allowed to connect, every connection must authenticate by `AUTH` before other commands
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    users: HashMap<String, Arc<User>>,
+}
+
+impl Acl {
+    /// only the default user with `password`, allowed to run everything
+    pub fn with_password(password: &str) -> Self {
+        let mut acl = Acl::default();
+        acl.add_user(User::unrestricted(DEFAULT_USER, password));
+        acl
+    }
+
+    /// parse the users from TOML text, see the module doc
+    pub fn from_toml(text: &str) -> Result<Self> {
+        let file: AclFile = toml::from_str(text).map_err(|e| KvsError::InvalidAcl(e.to_string()))?;
+        let mut acl = Acl::default();
+        for user in file.user {
+            if acl.users.contains_key(&user.name) {
+                Err(KvsError::InvalidAcl(format!("duplicate user {:?}", user.name)))?
+            }
+            acl.add_user(user);
+        }
+        Ok(acl)
+    }
+
+    /// read the users from a TOML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| KvsError::InvalidAcl(format!("can not read {:?}, {}", path, e)))?;
+        Self::from_toml(&text)
+    }
+
+    /// add or replace the user of the same name
+    pub fn add_user(&mut self, user: User) {
+        self.users.insert(user.name.clone(), Arc::new(user));
+    }
+
+    /// return the user, or `KvsError::WrongPass` if the user is absent or the password is wrong
+    pub fn authenticate(&self, name: &str, password: &str) -> Result<Arc<User>> {
+        match self.users.get(name) {
+            Some(user) if constant_time_eq(user.password.as_bytes(), password.as_bytes()) => Ok(user.clone()),
+            _ => Err(KvsError::WrongPass.into()),
+        }
+    }
+}
+
+/// compare without returning early on the first different byte, not to leak the password by timing
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
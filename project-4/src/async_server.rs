@@ -0,0 +1,451 @@
+//! kvs server on the tokio runtime
+
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, Interest};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+use tokio::sync::watch;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::codec::Framed;
+
+use crate::acl::Acl;
+use crate::client::ConnectOptions;
+use crate::codec::MsgCodec;
+use crate::engines::KvsEngine;
+use crate::logger;
+use crate::metrics::metrics;
+use crate::model::{Msg, MsgLimits};
+use crate::net::{remove_stale_socket, Address};
+use crate::Result;
+use crate::server::{ConnectionTimeouts, ACCEPT_ERROR_BACKOFF};
+use crate::session::{protocol_error_reply, ClientRegistry, PoolInfo, ServerContext, Session};
+use crate::shutdown::ShutdownHandle;
+use crate::raft::RaftHandle;
+use crate::replication;
+use crate::slowlog::SlowLogConfig;
+
+/// serve every connection with a tokio task, so idle connections cost no thread.
+/// the blocking engine calls are offloaded to the blocking pool of tokio
+pub struct AsyncKvsServer<KE: KvsEngine> {
+    addresses: Vec<Address>,
+    engine: KE,
+    limits: MsgLimits,
+    timeouts: ConnectionTimeouts,
+    max_clients: Option<usize>,
+    slowlog: SlowLogConfig,
+    tls: Option<TlsAcceptor>,
+    acl: Option<Acl>,
+    replica_of: Option<String>,
+    primary_connect: ConnectOptions,
+    raft: Option<RaftHandle>,
+    shutdown: ShutdownHandle,
+}
+
+impl<KE: KvsEngine> AsyncKvsServer<KE> {
+    /// create with address and engine, the address is `IP:PORT` or `unix://PATH`
+    pub fn new(binding_address: String, engine: KE) -> Self {
+        AsyncKvsServer {
+            addresses: vec![Address::parse(&binding_address)],
+            engine,
+            limits: MsgLimits::default(),
+            timeouts: ConnectionTimeouts::default(),
+            max_clients: None,
+            slowlog: SlowLogConfig::default(),
+            tls: None,
+            acl: None,
+            replica_of: None,
+            primary_connect: ConnectOptions::default(),
+            raft: None,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// set the limits of the request msg, `MsgLimits::default()` is used if not set
+    pub fn with_limits(mut self, limits: MsgLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// set the connection timeouts, no timeout if not set
+    pub fn with_timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// record the commands slower than `slowlog.threshold` for `SLOWLOG GET`,
+    /// slower than 10 ms if not set
+    pub fn with_slowlog(mut self, slowlog: SlowLogConfig) -> Self {
+        self.slowlog = slowlog;
+        self
+    }
+
+    /// reject the new connections with an error reply when `max_clients` clients are connected,
+    /// no limit if not set
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// serve the TCP connections by TLS, see `ServerTlsConfig::load`, the Unix socket is not affected
+    pub fn with_tls(mut self, tls: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(TlsAcceptor::from(tls));
+        self
+    }
+
+    /// require every connection to authenticate by `AUTH` as a user of `acl` before other commands
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// run as a replica of the primary at `primary`, `IP:PORT` or `unix://PATH`, see `kvs::replication`.
+    /// the data of the engine is replaced by the data of the primary, the writes of the clients are rejected
+    pub fn with_replica_of(mut self, primary: String) -> Self {
+        self.replica_of = Some(primary);
+        self
+    }
+
+    /// connect to the primary by `options`, e.g. by TLS or authenticated by `AUTH`, see `with_replica_of`
+    pub fn with_primary_connect(mut self, options: ConnectOptions) -> Self {
+        self.primary_connect = options;
+        self
+    }
+
+    /// serve as the node of `raft` in a Raft cluster, the engine should be the `RaftEngine` of the node,
+    /// see `kvs::raft`. the messages of the other nodes are taken by the `RAFT` command
+    pub fn with_raft(mut self, raft: RaftHandle) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// listen on the Unix socket at `path` too, the socket file is removed after the server stops
+    pub fn with_unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.addresses.push(Address::Unix(path.into()));
+        self
+    }
+
+    /// the handle to stop the server, `start` and `serve` return after the server is stopped
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// create a multi-thread runtime, then bind the addresses and handle connection on it until shutdown
+    pub fn start(&mut self) -> Result<()> {
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(self.serve())
+    }
+
+    /// bind the addresses and handle connection on the current runtime until shutdown
+    pub async fn serve(&self) -> Result<()> {
+        let mut listeners = Vec::new();
+        for address in &self.addresses {
+            listeners.push(AsyncListener::bind(address).await?);
+        }
+        let pool = PoolInfo {
+            mode: "async",
+            thread_pool: "tokio".to_owned(),
+            threads: tokio::runtime::Handle::current().metrics().num_workers(),
+        };
+        let clients = ClientRegistry::new(self.max_clients);
+        let context = Arc::new(ServerContext::new(
+            clients,
+            self.shutdown.clone(),
+            self.acl.clone(),
+            pool,
+            self.slowlog.clone(),
+            self.replica_of.clone().map(|primary| (primary, self.primary_connect.clone())),
+            self.raft.clone(),
+        ));
+        let follower = replication::follow(self.engine.clone(), context.clone());
+
+        let accept_loops = listeners.iter().map(|listener| self.accept_loop(listener, &context));
+        futures::future::join_all(accept_loops).await;
+        drop(listeners);
+
+        // a connection waiting for the next request, reading a request or writing a reply sees the shutdown
+        // and closes, others close after the in-flight request
+        log::info!("shutting down, closing client connections");
+        tokio::task::spawn_blocking(move || {
+            context.clients.wait_empty();
+            if let Some(follower) = follower {
+                let _ = follower.join();
+            }
+        }).await?;
+        let engine = self.engine.clone();
+        tokio::task::spawn_blocking(move || engine.close()).await??;
+        log::info!("server stopped");
+        Ok(())
+    }
+
+    /// accept connections of `listener` until shutdown
+    async fn accept_loop(&self, listener: &AsyncListener, context: &Arc<ServerContext>) {
+        let mut shutdown_rx = self.shutdown.subscribe();
+        loop {
+            let accepted = tokio::select! {
+                accepted = listener.accept() => accepted,
+                _ = shutdown_rx.wait_for(|shutdown| *shutdown) => break,
+            };
+            match accepted {
+                Ok((AsyncStream::Tcp(stream), peer_addr)) => match &self.tls {
+                    Some(tls) => self.serve_connection(context, tls.accept(stream), peer_addr),
+                    None => self.serve_connection(context, async { Ok(stream) }, peer_addr),
+                },
+                Ok((AsyncStream::Unix(stream), peer_addr)) => {
+                    self.serve_connection(context, async { Ok(stream) }, peer_addr)
+                }
+                Err(e) => {
+                    // e.g. too many open files, the listener is still usable,
+                    // wait a little for the failure to clear instead of spinning on it
+                    log::error!("accept error, {}", e);
+                    tokio::time::sleep(ACCEPT_ERROR_BACKOFF).await;
+                }
+            }
+        }
+    }
+
+    /// register the session of the connection and handle it on a new task,
+    /// `handshake` returns the stream ready for the requests
+    fn serve_connection<S, H>(&self, context: &Arc<ServerContext>, handshake: H, peer_addr: String)
+    where
+        S: Connection,
+        H: Future<Output = io::Result<S>> + Send + 'static,
+    {
+        let session = Session::new(self.engine.clone(), context.clone(), peer_addr.clone(), None);
+        let limits = self.limits.clone();
+        let timeouts = self.timeouts.clone();
+        let mut shutdown_rx = self.shutdown.subscribe();
+        tokio::spawn(async move {
+            let mut stream = match until_shutdown(&mut shutdown_rx, with_timeout(timeouts.read, handshake)).await {
+                Some(Some(Ok(stream))) => stream,
+                Some(Some(Err(e))) => {
+                    metrics().connection_closed("handshake");
+                    return log::warn!("handshake error, peer={}, {}", peer_addr, e);
+                }
+                Some(None) => {
+                    metrics().connection_closed("handshake");
+                    return log::warn!("connection closed, peer={}, handshake timeout", peer_addr);
+                }
+                None => return,
+            };
+            let session = match session {
+                Ok(session) => session,
+                Err(e) => {
+                    log::warn!("connection rejected, peer={}, {}", peer_addr, e);
+                    metrics().connection_closed("rejected");
+                    let _ = stream.write_all(&Msg::Error(e.to_string()).to_bytes()).await;
+                    let _ = stream.flush().await;
+                    return;
+                }
+            };
+            match Self::handle_client(session, limits, timeouts, stream).await {
+                Ok(Some(reason)) => {
+                    metrics().connection_closed(reason);
+                    log::warn!("connection closed, peer={}, {}", peer_addr, reason);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    metrics().connection_failed(&e);
+                    log::error!("connection error, peer={}, {}", peer_addr, e);
+                }
+            }
+        });
+    }
+
+    /// return the reason if the connection is closed by a timeout
+    async fn handle_client<S: Connection>(
+        mut session: Session<KE>,
+        limits: MsgLimits,
+        timeouts: ConnectionTimeouts,
+        stream: S,
+    ) -> Result<Option<&'static str>> {
+        let mut framed = Framed::new(stream, MsgCodec::new(limits));
+        let mut shutdown_rx = session.shutdown_handle().subscribe();
+        loop {
+            if session.is_shutdown() {
+                return Ok(None);
+            }
+            loop {
+                let push = match session.try_push() {
+                    Ok(Some(push)) => push,
+                    Ok(None) => break,
+                    Err(_) => return Ok(Some("slow subscriber")),
+                };
+                match until_shutdown(&mut shutdown_rx, with_timeout(timeouts.write, framed.send(push))).await {
+                    None => return Ok(None),
+                    Some(None) => return Ok(Some("write timeout")),
+                    Some(Some(sent)) => sent?,
+                }
+            }
+            // wait for the next request without consuming it, unless it is already buffered,
+            // a subscribed connection is not idle, it pushes the published messages meanwhile
+            if framed.read_buffer().is_empty() {
+                let idle = if session.is_subscribed() { None } else { timeouts.idle };
+                let peek = with_timeout(idle, framed.get_ref().peek_byte());
+                let woken = tokio::select! {
+                    peeked = peek => Wake::Request(peeked),
+                    pushed = session.recv_push() => Wake::Push(pushed),
+                    _ = shutdown_rx.wait_for(|shutdown| *shutdown) => return Ok(None),
+                };
+                let peeked = match woken {
+                    Wake::Request(peeked) => peeked,
+                    Wake::Push(Ok(push)) => {
+                        match until_shutdown(&mut shutdown_rx, with_timeout(timeouts.write, framed.send(push))).await {
+                            None => return Ok(None),
+                            Some(None) => return Ok(Some("write timeout")),
+                            Some(Some(sent)) => sent?,
+                        }
+                        continue;
+                    }
+                    Wake::Push(Err(_)) => return Ok(Some("slow subscriber")),
+                };
+                match peeked {
+                    None => return Ok(Some("idle timeout")),
+                    Some(peeked) => if peeked? == 0 {
+                        return Ok(None); // closed by peer
+                    },
+                }
+            }
+
+            // a request partially received when the shutdown is requested is dropped
+            let msg = match until_shutdown(&mut shutdown_rx, with_timeout(timeouts.read, framed.next())).await {
+                None => return Ok(None),
+                Some(None) => return Ok(Some("read timeout")),
+                Some(Some(None)) => return Ok(None),
+                Some(Some(Some(Ok(msg)))) => msg,
+                Some(Some(Some(Err(e)))) => {
+                    if let Some(reply) = protocol_error_reply(&e) {
+                        let _ = with_timeout(timeouts.write, framed.send(reply)).await;
+                    }
+                    return Err(e);
+                }
+            };
+            let (s, resp_msg) = tokio::task::spawn_blocking(move || {
+                let peer = session.peer_addr().to_owned();
+                let resp_msg = logger::with_fields(vec![("peer", peer)], || session.handle_msg(msg));
+                (session, resp_msg)
+            }).await?;
+            session = s;
+            match until_shutdown(&mut shutdown_rx, with_timeout(timeouts.write, framed.send(resp_msg))).await {
+                None => return Ok(None),
+                Some(None) => return Ok(Some("write timeout")),
+                Some(Some(sent)) => sent?,
+            }
+        }
+    }
+}
+
+/// what a connection waiting for the next request is woken up by
+enum Wake {
+    /// the first byte of the request is peeked, `None` if the idle timeout passed
+    Request(Option<io::Result<usize>>),
+    /// a msg to push to the client
+    Push(Result<Msg>),
+}
+
+/// `None` if the future is not ready before the timeout
+async fn with_timeout<F: Future>(timeout: Option<Duration>, future: F) -> Option<F::Output> {
+    match timeout {
+        Some(t) => tokio::time::timeout(t, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
+/// `None` if the shutdown is requested before the future is ready. the future is polled first,
+/// so a reply that fits in the socket buffer is still sent after the shutdown
+async fn until_shutdown<F: Future>(shutdown_rx: &mut watch::Receiver<bool>, future: F) -> Option<F::Output> {
+    tokio::select! {
+        biased;
+        output = future => Some(output),
+        _ = shutdown_rx.wait_for(|shutdown| *shutdown) => None,
+    }
+}
+
+/// a connection served by `AsyncKvsServer`
+trait Connection: AsyncRead + AsyncWrite + Unpin + Send + 'static {
+    /// wait for the next byte without consuming it, return 0 if the peer closed the connection
+    fn peek_byte(&self) -> impl Future<Output = io::Result<usize>> + Send + '_;
+}
+
+impl Connection for TcpStream {
+    async fn peek_byte(&self) -> io::Result<usize> {
+        self.peek(&mut [0u8; 1]).await
+    }
+}
+
+impl Connection for UnixStream {
+    // tokio has no `UnixStream::peek`, peek by the socket once it is readable
+    async fn peek_byte(&self) -> io::Result<usize> {
+        let mut probe = [MaybeUninit::<u8>::uninit()];
+        loop {
+            self.readable().await?;
+            match self.try_io(Interest::READABLE, || SockRef::from(self).peek(&mut probe)) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                peeked => return peeked,
+            }
+        }
+    }
+}
+
+impl Connection for TlsStream<TcpStream> {
+    async fn peek_byte(&self) -> io::Result<usize> {
+        let (stream, conn) = self.get_ref();
+        if !conn.wants_read() {
+            // the plaintext or the close notify is buffered
+            return Ok(1);
+        }
+        stream.peek(&mut [0u8; 1]).await
+    }
+}
+
+enum AsyncStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// the socket file of a Unix listener is removed when dropped
+enum AsyncListener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl AsyncListener {
+    async fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(AsyncListener::Tcp(TcpListener::bind(addr).await?)),
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(AsyncListener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    /// return the connection and the peer address shown in `CLIENT LIST`
+    async fn accept(&self) -> io::Result<(AsyncStream, String)> {
+        match self {
+            AsyncListener::Tcp(l) => {
+                let (stream, peer_addr) = l.accept().await?;
+                Ok((AsyncStream::Tcp(stream), peer_addr.to_string()))
+            }
+            AsyncListener::Unix(l, path) => {
+                let (stream, _) = l.accept().await?;
+                Ok((AsyncStream::Unix(stream), format!("{}:0", path.display())))
+            }
+        }
+    }
+}
+
+impl Drop for AsyncListener {
+    fn drop(&mut self) {
+        if let AsyncListener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
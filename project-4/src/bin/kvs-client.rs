@@ -7,6 +7,8 @@ use std::process::exit;
 use kvs::error::KvsError;
 use kvs::client::KvsClient;
 use kvs::model::Msg;
+use kvs::net::UNIX_PREFIX;
+use kvs::tls::ClientTlsConfig;
 
 fn main() -> Result<()> {
     kvs::logger::init_logger();
@@ -19,8 +21,7 @@ fn main() -> Result<()> {
     match m.subcommand() {
         ("get", Some(sub)) => {
             let key = sub.value_of("KEY").unwrap_or("").to_owned();
-            let address = get_address_from_args(sub)?;
-            let mut client = KvsClient::connect(address)?;
+            let mut client = connect_from_args(sub)?;
             let req = Msg::build_bulk_array(&vec!["get".to_owned(), key]);
             let res = client.request_msg(req)?;
             match res {
@@ -41,8 +42,7 @@ fn main() -> Result<()> {
         ("set", Some(sub)) => {
             let key = sub.value_of("KEY").unwrap_or("").to_owned();
             let value = sub.value_of("VALUE").unwrap_or("").to_owned();
-            let address = get_address_from_args(sub)?;
-            let mut client = KvsClient::connect(address)?;
+            let mut client = connect_from_args(sub)?;
             let req = Msg::build_bulk_array(&vec!["set".to_owned(), key, value]);
             let res = client.request_msg(req)?;
             match res {
@@ -58,8 +58,7 @@ fn main() -> Result<()> {
         }
         ("rm", Some(sub)) => {
             let key = sub.value_of("KEY").unwrap_or("").to_owned();
-            let address = get_address_from_args(sub)?;
-            let mut client = KvsClient::connect(address)?;
+            let mut client = connect_from_args(sub)?;
             let req = Msg::build_bulk_array(&vec!["rm".to_owned(), key]);
             let res = client.request_msg(req)?;
             match res {
@@ -73,14 +72,121 @@ fn main() -> Result<()> {
                 _ => unreachable!()
             }
         }
+        ("shutdown", Some(sub)) => {
+            let mut client = connect_from_args(sub)?;
+            let req = Msg::build_bulk_array(&vec!["shutdown".to_owned()]);
+            let res = client.request_msg(req)?;
+            match res {
+                Msg::Line(_) => {
+                    // line means success
+                }
+                Msg::Error(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                _ => unreachable!()
+            }
+        }
+        ("info", Some(sub)) => {
+            let mut client = connect_from_args(sub)?;
+            match client.info(sub.value_of("SECTION"))? {
+                Msg::Bulk(info) => print!("{}", info.unwrap_or_default().replace("\r\n", "\n")),
+                Msg::Error(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                _ => unreachable!()
+            }
+        }
+        ("publish", Some(sub)) => {
+            let channel = sub.value_of("CHANNEL").unwrap_or("");
+            let message = sub.value_of("MESSAGE").unwrap_or("");
+            let mut client = connect_from_args(sub)?;
+            match client.publish(channel, message)? {
+                Msg::Integer(receivers) => println!("{}", receivers),
+                Msg::Error(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+                _ => unreachable!()
+            }
+        }
+        ("subscribe", Some(sub)) => {
+            let channels: Vec<String> = sub.values_of("CHANNEL").unwrap_or_default().map(str::to_owned).collect();
+            let client = connect_from_args(sub)?;
+            let subscribed = if sub.is_present("pattern") {
+                client.psubscribe(&channels)
+            } else {
+                client.subscribe(&channels)
+            };
+            let subscription = match subscribed {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+            for message in subscription {
+                let message = message?;
+                println!("{} {}", message.channel, message.payload);
+            }
+        }
+        ("watch", Some(sub)) => {
+            let prefix = sub.value_of("PREFIX").unwrap_or_default();
+            let events = match connect_from_args(sub)?.watch(prefix) {
+                Ok(events) => events,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    exit(1);
+                }
+            };
+            for event in events {
+                let event = event?;
+                println!("{} {} {}", event.seq, event.kind.name(), event.key);
+            }
+        }
         _ => panic!("need least one argument"),
     }
     Ok(())
 }
 
-/// IP address format is IP:PORT
+/// IP address format is IP:PORT, Unix socket address format is unix://PATH
 fn is_invalid_address(s: &str) -> bool {
-    !s.contains(":")
+    !s.starts_with(UNIX_PREFIX) && !s.contains(":")
+}
+
+/// connect to the server at the address in ArgMatches, by TLS if `--tls-ca` is present,
+/// then authenticate if `--password` is present
+fn connect_from_args(arg: &ArgMatches) -> Result<KvsClient> {
+    let mut client = connect_to_server(arg)?;
+    if let Some(password) = arg.value_of("password") {
+        if let Msg::Error(e) = client.auth(arg.value_of("user"), password)? {
+            eprintln!("{}", e);
+            exit(1);
+        }
+    }
+    Ok(client)
+}
+
+/// connect to the server at the address in ArgMatches, by TLS if `--tls-ca` is present
+fn connect_to_server(arg: &ArgMatches) -> Result<KvsClient> {
+    let address = get_address_from_args(arg)?;
+    match arg.value_of("tls-ca") {
+        Some(ca) => {
+            let tls = ClientTlsConfig {
+                ca: ca.into(),
+                cert: arg.value_of("tls-cert").map(Into::into),
+                key: arg.value_of("tls-key").map(Into::into),
+            };
+            KvsClient::connect_tls(address, tls.load()?)
+        }
+        None => {
+            if arg.is_present("tls-cert") {
+                Err(KvsError::InvalidTls("--tls-cert requires --tls-ca".to_owned()))?
+            }
+            KvsClient::connect(address)
+        }
+    }
 }
 
 /// get ip address from ArgMatches
@@ -0,0 +1,83 @@
+#[macro_use]
+extern crate clap;
+
+use clap::App;
+use kvs::config::ServerConfig;
+use kvs::error::KvsError;
+use kvs::proxy::{KvsProxy, DEFAULT_BACKEND_TIMEOUT, DEFAULT_VIRTUAL_NODES};
+use kvs::Result;
+use std::time::Duration;
+
+/// the options of kvs-server limiting the client connections, taken by kvs-proxy too
+const CONNECTION_KEYS: &[&str] = &[
+    "max-clients",
+    "idle-timeout",
+    "read-timeout",
+    "write-timeout",
+    "max-bulk-len",
+    "max-array-len",
+    "max-nesting-depth",
+    "max-request-size",
+    "requirepass",
+    "acl-file",
+];
+
+fn main() -> Result<()> {
+    // The YAML file is found relative to the current file, similar to how modules are found
+    let yaml = load_yaml!("cli-proxy.yml");
+    let m = App::from(yaml)
+        .version(crate_version!())
+        .get_matches();
+
+    match m.value_of("log-level") {
+        Some(level) => kvs::logger::init_logger_with_level(level),
+        None => kvs::logger::init_logger(),
+    }
+    log::info!("version={}", crate_version!());
+
+    let address = m.value_of("addr").unwrap_or("127.0.0.1:4000").to_owned();
+    let backends: Vec<String> = m.value_of("backends").unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|backend| !backend.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let virtual_nodes = match m.value_of("virtual-nodes") {
+        Some(n) => match n.parse() {
+            Ok(n) if n > 0 => n,
+            _ => Err(KvsError::InvalidConfig(format!("virtual-nodes = {:?}, accept a positive number", n)))?,
+        },
+        None => DEFAULT_VIRTUAL_NODES,
+    };
+    let backend_timeout = match m.value_of("backend-timeout") {
+        Some(seconds) => match seconds.parse() {
+            Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+            _ => Err(KvsError::InvalidConfig(format!("backend-timeout = {:?}, accept a positive number", seconds)))?,
+        },
+        None => DEFAULT_BACKEND_TIMEOUT,
+    };
+    log::info!(
+        "addr={}, backends={:?}, virtual_nodes={}, backend_timeout={:?}",
+        address, backends, virtual_nodes, backend_timeout
+    );
+
+    // the limits of the client connections, parsed like the same options of kvs-server
+    let mut config = ServerConfig::default();
+    for key in CONNECTION_KEYS {
+        if let Some(value) = m.value_of(key) {
+            config.set(key, value)?;
+        }
+    }
+    let mut proxy = KvsProxy::new(address, backends)
+        .with_virtual_nodes(virtual_nodes)
+        .with_backend_timeout(backend_timeout)
+        .with_limits(config.limits())
+        .with_timeouts(config.timeouts()?)
+        .with_max_clients(config.max_clients);
+    if let Some(acl) = config.acl()? {
+        proxy = proxy.with_acl(acl);
+    }
+    let handle = proxy.shutdown_handle();
+    ctrlc::set_handler(move || handle.shutdown())?;
+    proxy.start()
+}
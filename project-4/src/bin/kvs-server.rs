@@ -1,50 +1,208 @@
 #[macro_use]
 extern crate clap;
 
-use std::path::Path;
-use clap::App;
-use kvs::{KvStore, Result};
+use std::path::{Path, PathBuf};
+use clap::{App, ArgMatches};
+use kvs::{KvStore, KvsEngine, Result};
+use kvs::config::{ServerConfig, ThreadPoolKind, CONFIG_KEYS};
+use kvs::engines::kvs_sharded::ShardedKvStore;
+use kvs::engines::lsm::LsmKvStore;
+use kvs::engines::Durability;
+use kvs::engines::sled::SledKvsEngine;
 use kvs::error::KvsError;
+use kvs::net::Address;
+use kvs::raft::{RaftHandle, TcpTransport};
+use kvs::async_server::AsyncKvsServer;
 use kvs::server::KvsServer;
-use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool, ThreadPool};
 
 fn main() -> Result<()> {
-    kvs::logger::init_logger();
     // The YAML file is found relative to the current file, similar to how modules are found
     let yaml = load_yaml!("cli-server.yml");
     let m = App::from(yaml)
         .version(crate_version!())
         .get_matches();
 
-    let address = m.value_of("addr").unwrap_or("127.0.0.1:4000");
-    log::info!("address={}", address);
-    let engine_name = m.value_of("engine").unwrap_or("kvs");
-    log::info!("engine_name={}", engine_name);
+    let config = get_config(&m)?;
+    kvs::logger::init_logger_with_config(&config.log()?)?;
+    if let Some(path) = &config.trace_file {
+        kvs::trace::init_otlp_file(path)?;
+    }
     log::info!("version={}", crate_version!());
+    log::info!("config={:?}", config.redacted());
 
-    let engine_lock_path = Path::new("engine.lock");
-    if Path::exists(engine_lock_path) {
-        let existed_engine = std::fs::read_to_string(engine_lock_path)?;
-        if existed_engine != engine_name {
-            Err(KvsError::WrongEngine { expect: existed_engine, actual: engine_name.to_owned() })?
-        }
+    let data_dir = &config.data_dir;
+    if !data_dir.exists() {
+        std::fs::create_dir_all(data_dir)?;
+    }
+
+    let engine_lock_path = data_dir.join("engine.lock");
+    let existed_engine = if engine_lock_path.exists() {
+        Some(std::fs::read_to_string(&engine_lock_path)?)
     } else {
-        std::fs::write(engine_lock_path, engine_name)?;
+        None
+    };
+    let engine_name = config.engine.clone()
+        .or_else(|| existed_engine.clone())
+        .unwrap_or_else(|| "kvs".to_owned());
+    log::info!("engine_name={}", engine_name);
+    if !["kvs", "lsm", "sharded", "sled"].contains(&engine_name.as_str()) {
+        Err(KvsError::UnsupportedEngine(engine_name.clone()))?
+    }
+    match existed_engine {
+        Some(existed_engine) if existed_engine != engine_name => {
+            Err(KvsError::WrongEngine { expect: existed_engine, actual: engine_name.clone() })?
+        }
+        Some(_) => {}
+        None => std::fs::write(&engine_lock_path, &engine_name)?,
     }
 
-    let open_path = "./db";
-    if !Path::exists(open_path.as_ref()) {
-        std::fs::create_dir_all(open_path)?;
+    if let Some(addr) = &config.metrics_addr {
+        kvs::metrics::serve(addr)?;
     }
 
-    if !["kvs", "sled"].contains(&engine_name){
-        panic!("unsupported engine name")
+    let open_path = data_dir.join("db");
+    if !Path::exists(&open_path) {
+        std::fs::create_dir_all(&open_path)?;
     }
 
-    let engine = KvStore::open(open_path)?;
-    let thread_pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
-    let mut server = KvsServer::new(address.to_owned(), engine, thread_pool);
-    server.start()?;
+    match (engine_name.as_str(), config.durability) {
+        ("sled", Some(durability)) => serve(SledKvsEngine::open_with_durability(open_path, durability)?, &config),
+        ("sled", None) => serve(SledKvsEngine::open(open_path)?, &config),
+        ("lsm", Some(durability)) => serve(LsmKvStore::open_with_durability(open_path, durability)?, &config),
+        ("lsm", None) => serve(LsmKvStore::open(open_path)?, &config),
+        ("sharded", durability) => {
+            let durability = durability.unwrap_or(Durability::Flush);
+            let engine = match config.shards {
+                Some(shards) => ShardedKvStore::open_with_shards(open_path, durability, shards)?,
+                None => ShardedKvStore::open_with_durability(open_path, durability)?,
+            };
+            serve(engine, &config)
+        }
+        (_, Some(durability)) => serve(KvStore::open_with_durability(open_path, durability)?, &config),
+        (_, None) => serve(KvStore::open(open_path)?, &config),
+    }
+}
+
+/// run the server on `engine`, behind a Raft node if the config sets one
+fn serve<KE: KvsEngine>(engine: KE, config: &ServerConfig) -> Result<()> {
+    match config.raft()? {
+        Some(raft) => {
+            log::info!("raft_id={}, raft_members={:?}", raft.id, raft.members);
+            let (engine, handle) = kvs::raft::start(raft, engine, TcpTransport::new(config.peer_connect()?))?;
+            run_server(engine, config, Some(handle))
+        }
+        None => run_server(engine, config, None),
+    }
+}
+
+/// read the config file, then override it by the environment variables and the args
+fn get_config(m: &ArgMatches) -> Result<ServerConfig> {
+    let mut config = match m.value_of("config") {
+        Some(path) => ServerConfig::from_file(path)?,
+        None => ServerConfig::default(),
+    };
+    config.apply_env()?;
+    for key in CONFIG_KEYS {
+        if *key == "async" || *key == "slowlog-log" {
+            if m.is_present(key) {
+                config.set(key, "true")?;
+            }
+        } else if let Some(value) = m.value_of(key) {
+            config.set(key, value)?;
+        }
+    }
+    Ok(config)
+}
+
+/// start the server selected by `config` with `engine`, return after the server is stopped
+fn run_server<KE: KvsEngine>(engine: KE, config: &ServerConfig, raft: Option<RaftHandle>) -> Result<()> {
+    if config.is_async {
+        log::info!("server=async");
+        let (address, unix) = listen_addresses(config);
+        let mut server = AsyncKvsServer::new(address, engine)
+            .with_limits(config.limits())
+            .with_timeouts(config.timeouts()?)
+            .with_max_clients(config.max_clients)
+            .with_slowlog(config.slowlog()?);
+        if let Some(path) = unix {
+            server = server.with_unix_socket(path);
+        }
+        if let Some(tls) = config.tls()? {
+            server = server.with_tls(tls.load()?);
+        }
+        if let Some(acl) = config.acl()? {
+            server = server.with_acl(acl);
+        }
+        if let Some(primary) = &config.replica_of {
+            server = server.with_replica_of(primary.clone()).with_primary_connect(config.peer_connect()?);
+        }
+        if let Some(raft) = raft {
+            server = server.with_raft(raft);
+        }
+        shutdown_on_signal(server.shutdown_handle())?;
+        return server.start();
+    }
+
+    let threads = config.threads.unwrap_or(num_cpus::get() as u32);
+    if threads == 0 {
+        Err(KvsError::InvalidConfig("threads = 0, accept a positive number".to_owned()))?
+    }
+    log::info!("server=sync, thread_pool={:?}, threads={}", config.thread_pool, threads);
+    match config.thread_pool {
+        ThreadPoolKind::Naive => run_sync_server(engine, NaiveThreadPool::new(threads)?, config, raft),
+        ThreadPoolKind::Shared => {
+            let thread_pool = SharedQueueThreadPool::with_queue_capacity(threads, config.queue_size)?;
+            run_sync_server(engine, thread_pool, config, raft)
+        }
+        ThreadPoolKind::Rayon => run_sync_server(engine, RayonThreadPool::new(threads)?, config, raft),
+    }
+}
+
+/// start `KvsServer` over the thread pool chosen by the config
+fn run_sync_server<KE: KvsEngine, TP: ThreadPool>(
+    engine: KE,
+    thread_pool: TP,
+    config: &ServerConfig,
+    raft: Option<RaftHandle>,
+) -> Result<()> {
+    let (address, unix) = listen_addresses(config);
+    let mut server = KvsServer::new(address, engine, thread_pool)
+        .with_limits(config.limits())
+        .with_timeouts(config.timeouts()?)
+        .with_max_clients(config.max_clients)
+        .with_slowlog(config.slowlog()?);
+    if let Some(path) = unix {
+        server = server.with_unix_socket(path);
+    }
+    if let Some(tls) = config.tls()? {
+        server = server.with_tls(tls.load()?);
+    }
+    if let Some(acl) = config.acl()? {
+        server = server.with_acl(acl);
+    }
+    if let Some(primary) = &config.replica_of {
+        server = server.with_replica_of(primary.clone()).with_primary_connect(config.peer_connect()?);
+    }
+    if let Some(raft) = raft {
+        server = server.with_raft(raft);
+    }
+    shutdown_on_signal(server.shutdown_handle())?;
+    server.start()
+}
+
+/// the address to create the server with, and the Unix socket listened alongside it
+fn listen_addresses(config: &ServerConfig) -> (String, Option<PathBuf>) {
+    match (config.tcp_addr(), &config.unix) {
+        (Some(addr), unix) => (addr, unix.clone()),
+        (None, Some(path)) => (Address::Unix(path.clone()).to_string(), None),
+        (None, None) => unreachable!(),
+    }
+}
 
+/// shut down the server on SIGINT or SIGTERM
+fn shutdown_on_signal(handle: ShutdownHandle) -> Result<()> {
+    ctrlc::set_handler(move || handle.shutdown())?;
     Ok(())
 }
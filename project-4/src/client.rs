@@ -1,28 +1,364 @@
 //! kvs client
 
+use std::collections::VecDeque;
 use std::io::Write;
-use std::net::TcpStream;
+use std::sync::Arc;
+use std::time::Duration;
 
-use crate::model::{Msg, MsgExtend};
+use crate::engines::watch::{EventKind, KeyEvent};
+use crate::error::KvsError;
+use crate::model::{Msg, MsgExtend, Protocol};
+use crate::net::{Address, Stream};
+use crate::replication::ReplicationEvent;
+use crate::trace;
 use crate::Result;
 
+/// how a server connects to another kvs server, e.g. a replica to its primary
+#[derive(Clone, Default)]
+pub struct ConnectOptions {
+    /// connect to a TCP address by TLS, see `ClientTlsConfig::load`
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    /// the user to `AUTH` as, the default user if `None`
+    pub user: Option<String>,
+    /// `AUTH` after connecting if it is set
+    pub password: Option<String>,
+    /// the timeout of the TCP connect, and of reading or writing a msg after, no timeout if `None`
+    pub timeout: Option<Duration>,
+}
+
 #[allow(missing_docs)]
 #[allow(dead_code)]
 pub struct KvsClient {
     server_address: String,
-    stream: TcpStream,
+    stream: Stream,
 }
 
 impl KvsClient {
-    /// connect to server with address
+    /// connect to server with address, `IP:PORT` or `unix://PATH`
     pub fn connect(address: String) -> Result<Self> {
-        let stream = TcpStream::connect(&address)?;
+        let stream = Stream::connect(&Address::parse(&address))?;
+        Ok(Self { stream, server_address: address })
+    }
+
+    /// connect to server with TCP address by TLS, see `ClientTlsConfig::load`
+    pub fn connect_tls(address: String, tls: Arc<rustls::ClientConfig>) -> Result<Self> {
+        let stream = Stream::connect_tls(&Address::parse(&address), tls)?;
         Ok(Self { stream, server_address: address })
     }
 
+    /// connect to server with address by `options`, return an error if the authentication fails
+    pub fn connect_with(address: String, options: &ConnectOptions) -> Result<Self> {
+        let stream = Stream::connect_with(&Address::parse(&address), options.tls.clone(), options.timeout)?;
+        stream.set_read_timeout(options.timeout)?;
+        stream.set_write_timeout(options.timeout)?;
+        let mut client = Self { stream, server_address: address };
+        if let Some(password) = &options.password {
+            if let Msg::Error(e) = client.auth(options.user.as_deref(), password)? {
+                Err(anyhow::anyhow!(e))?
+            }
+        }
+        Ok(client)
+    }
+
     /// send message to server, and wait for the response
     pub fn request_msg(&mut self, msg: Msg) -> Result<Msg>{
+        let _span = tracing::info_span!("kvs_client.request", otel.kind = "client", server = %self.server_address)
+            .entered();
+        let traced = self.send_traceparent()?;
         self.stream.write_all(&msg.to_bytes())?;
+        self.stream.flush()?;
+        if traced {
+            self.stream.read_msg()?;
+        }
         self.stream.read_msg()
     }
+
+    /// send all messages before reading the replies, return the replies in the same order
+    pub fn pipeline(&mut self, msgs: &[Msg]) -> Result<Vec<Msg>> {
+        let _span = tracing::info_span!("kvs_client.pipeline", otel.kind = "client", server = %self.server_address)
+            .entered();
+        let mut traced = false;
+        for msg in msgs {
+            traced = self.send_traceparent()?;
+            self.stream.write_all(&msg.to_bytes())?;
+        }
+        self.stream.flush()?;
+        msgs.iter()
+            .map(|_| {
+                if traced {
+                    self.stream.read_msg()?;
+                }
+                self.stream.read_msg()
+            })
+            .collect()
+    }
+
+    /// write `CLIENT TRACEPARENT` before the request if the spans are exported, the server replies to it first,
+    /// return whether it is written
+    fn send_traceparent(&mut self) -> Result<bool> {
+        match trace::traceparent() {
+            Some(traceparent) => {
+                let args = vec!["CLIENT".to_owned(), "TRACEPARENT".to_owned(), traceparent];
+                self.stream.write_all(&Msg::build_bulk_array(&args).to_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// switch the connection to `protocol` by `HELLO`, return the server properties
+    pub fn hello(&mut self, protocol: Protocol) -> Result<Msg> {
+        let req = Msg::build_bulk_array(&vec!["HELLO".to_owned(), protocol.version().to_string()]);
+        self.request_msg(req)
+    }
+
+    /// authenticate the connection by `AUTH`, as the default user if `user` is `None`
+    pub fn auth(&mut self, user: Option<&str>, password: &str) -> Result<Msg> {
+        let mut args = vec!["AUTH".to_owned()];
+        args.extend(user.map(str::to_owned));
+        args.push(password.to_owned());
+        self.request_msg(Msg::build_bulk_array(&args))
+    }
+
+    /// the server statistics by `INFO`, all sections if `section` is `None`
+    pub fn info(&mut self, section: Option<&str>) -> Result<Msg> {
+        let mut args = vec!["INFO".to_owned()];
+        args.extend(section.map(str::to_owned));
+        self.request_msg(Msg::build_bulk_array(&args))
+    }
+
+    /// push `message` to the subscribers of `channel`, return the number of the receivers
+    pub fn publish(&mut self, channel: &str, message: &str) -> Result<Msg> {
+        let req = Msg::build_bulk_array(&vec!["PUBLISH".to_owned(), channel.to_owned(), message.to_owned()]);
+        self.request_msg(req)
+    }
+
+    /// subscribe the channels, return after the server confirms them,
+    /// the connection serves the subscription only from then on
+    pub fn subscribe(self, channels: &[String]) -> Result<Subscription> {
+        let mut subscription = Subscription { client: self, buffered: VecDeque::new() };
+        subscription.subscribe(channels)?;
+        Ok(subscription)
+    }
+
+    /// subscribe the channels matching the glob patterns, see `subscribe`
+    pub fn psubscribe(self, patterns: &[String]) -> Result<Subscription> {
+        let mut subscription = Subscription { client: self, buffered: VecDeque::new() };
+        subscription.psubscribe(patterns)?;
+        Ok(subscription)
+    }
+
+    /// receive the changes of the keys starting with `prefix` in the selected namespace,
+    /// the connection serves the watch only from then on
+    pub fn watch(mut self, prefix: &str) -> Result<KeyEvents> {
+        let req = Msg::build_bulk_array(&vec!["WATCH".to_owned(), prefix.to_owned()]);
+        match self.request_msg(req)? {
+            Msg::Integer(_) => Ok(KeyEvents { client: self }),
+            Msg::Error(e) => Err(anyhow::anyhow!(e)),
+            other => Err(KvsError::InvalidMsg(format!("unexpected reply {:?}", other)).into()),
+        }
+    }
+
+    /// receive the replication stream of the server after the write `offset` of `replid`,
+    /// from a full snapshot if either is `None`, see `kvs::replication`.
+    /// the connection serves the replication only from then on, the reply is the first event
+    pub fn psync(mut self, replid: Option<&str>, offset: Option<u64>) -> Result<ReplicationEvents> {
+        let offset = offset.map_or("-1".to_owned(), |offset| offset.to_string());
+        let req = Msg::build_bulk_array(&vec!["PSYNC".to_owned(), replid.unwrap_or("?").to_owned(), offset]);
+        let reply = ReplicationEvent::parse(self.request_msg(req)?)?;
+        Ok(ReplicationEvents { client: self, reply: Some(reply) })
+    }
+
+    /// another handle of the socket, to shut the connection down from another thread
+    pub(crate) fn try_clone_socket(&self) -> std::io::Result<Stream> {
+        self.stream.try_clone_socket()
+    }
+}
+
+/// a connection receiving the replication stream, iterate it for the events,
+/// the iteration ends when the server closes the connection
+pub struct ReplicationEvents {
+    client: KvsClient,
+    /// the reply to `PSYNC`, not iterated yet
+    reply: Option<ReplicationEvent>,
+}
+
+impl Iterator for ReplicationEvents {
+    type Item = Result<ReplicationEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(reply) = self.reply.take() {
+            return Some(Ok(reply));
+        }
+        match self.client.stream.wait_readable() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        Some(self.client.stream.read_msg().and_then(ReplicationEvent::parse))
+    }
+}
+
+/// a connection watching the keys, iterate it for the changes,
+/// the iteration ends when the server closes the connection
+pub struct KeyEvents {
+    client: KvsClient,
+}
+
+impl Iterator for KeyEvents {
+    type Item = Result<KeyEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.stream.wait_readable() {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(e) => return Some(Err(e.into())),
+        }
+        Some(self.client.stream.read_msg().and_then(parse_key_event))
+    }
+}
+
+/// parse a push like `["keyevent", "set", "user:1", 42]`
+fn parse_key_event(msg: Msg) -> Result<KeyEvent> {
+    let items = match msg {
+        Msg::Array(items) | Msg::Push(items) => items,
+        Msg::Error(e) => Err(anyhow::anyhow!(e))?,
+        other => Err(KvsError::InvalidMsg(format!("unexpected push {:?}", other)))?,
+    };
+    let event = match items.as_slice() {
+        [Msg::Bulk(Some(push)), Msg::Bulk(Some(kind)), Msg::Bulk(Some(key)), Msg::Integer(seq)] if push == "keyevent" => {
+            let kind = match kind.as_str() {
+                "set" => EventKind::Set,
+                "remove" => EventKind::Remove,
+                _ => Err(KvsError::InvalidMsg(format!("unknown key event {:?}", kind)))?,
+            };
+            KeyEvent { kind, key: key.to_owned(), seq: *seq as u64 }
+        }
+        _ => Err(KvsError::InvalidMsg("malformed keyevent push".to_owned()))?,
+    };
+    Ok(event)
+}
+
+/// a message published to a subscribed channel
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Message {
+    /// the subscribed pattern matching the channel, `None` if the channel is subscribed by name
+    pub pattern: Option<String>,
+    #[allow(missing_docs)]
+    pub channel: String,
+    #[allow(missing_docs)]
+    pub payload: String,
+}
+
+/// a connection subscribing channels, iterate it for the published messages,
+/// the iteration ends when the server closes the connection
+pub struct Subscription {
+    client: KvsClient,
+    /// the messages received while waiting for the confirmations
+    buffered: VecDeque<Message>,
+}
+
+impl Subscription {
+    /// subscribe more channels, return after the server confirms them
+    pub fn subscribe(&mut self, channels: &[String]) -> Result<()> {
+        self.request("SUBSCRIBE", channels)?;
+        self.wait_confirmations("subscribe", channels.len())
+    }
+
+    /// subscribe more patterns, return after the server confirms them
+    pub fn psubscribe(&mut self, patterns: &[String]) -> Result<()> {
+        self.request("PSUBSCRIBE", patterns)?;
+        self.wait_confirmations("psubscribe", patterns.len())
+    }
+
+    /// stop receiving the messages of the channels, all channels if empty,
+    /// the messages published before may still be received
+    pub fn unsubscribe(&mut self, channels: &[String]) -> Result<()> {
+        self.request("UNSUBSCRIBE", channels)
+    }
+
+    /// stop receiving by the patterns, all patterns if empty, see `unsubscribe`
+    pub fn punsubscribe(&mut self, patterns: &[String]) -> Result<()> {
+        self.request("PUNSUBSCRIBE", patterns)
+    }
+
+    fn request(&mut self, command: &str, names: &[String]) -> Result<()> {
+        let mut args = vec![command.to_owned()];
+        args.extend_from_slice(names);
+        self.client.stream.write_all(&Msg::build_bulk_array(&args).to_bytes())?;
+        self.client.stream.flush()?;
+        Ok(())
+    }
+
+    fn wait_confirmations(&mut self, kind: &str, mut count: usize) -> Result<()> {
+        while count > 0 {
+            match Push::parse(self.client.stream.read_msg()?)? {
+                Push::Message(message) => self.buffered.push_back(message),
+                Push::Confirmation(confirmed) if confirmed == kind => count -= 1,
+                Push::Confirmation(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for Subscription {
+    type Item = Result<Message>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(message) = self.buffered.pop_front() {
+            return Some(Ok(message));
+        }
+        loop {
+            match self.client.stream.wait_readable() {
+                Ok(true) => {}
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e.into())),
+            }
+            let pushed = self.client.stream.read_msg().and_then(Push::parse);
+            match pushed {
+                Ok(Push::Message(message)) => return Some(Ok(message)),
+                Ok(Push::Confirmation(_)) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// a msg pushed to a subscribing connection
+enum Push {
+    Message(Message),
+    /// the kind like "subscribe" or "punsubscribe"
+    Confirmation(String),
+}
+
+impl Push {
+    /// an error reply is returned as the error
+    fn parse(msg: Msg) -> Result<Push> {
+        let items = match msg {
+            Msg::Array(items) | Msg::Push(items) => items,
+            Msg::Error(e) => Err(anyhow::anyhow!(e))?,
+            other => Err(KvsError::InvalidMsg(format!("unexpected push {:?}", other)))?,
+        };
+        let mut strings = items.into_iter().map(|item| match item {
+            Msg::Bulk(Some(s)) => Some(s),
+            _ => None,
+        });
+        let kind = strings.next().flatten().unwrap_or_default();
+        let push = match kind.as_str() {
+            "message" => match (strings.next().flatten(), strings.next().flatten()) {
+                (Some(channel), Some(payload)) => Push::Message(Message { pattern: None, channel, payload }),
+                _ => Err(KvsError::InvalidMsg("malformed message push".to_owned()))?,
+            },
+            "pmessage" => match (strings.next().flatten(), strings.next().flatten(), strings.next().flatten()) {
+                (Some(pattern), Some(channel), Some(payload)) => {
+                    Push::Message(Message { pattern: Some(pattern), channel, payload })
+                }
+                _ => Err(KvsError::InvalidMsg("malformed pmessage push".to_owned()))?,
+            },
+            "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" => Push::Confirmation(kind),
+            _ => Err(KvsError::InvalidMsg(format!("unknown push {:?}", kind)))?,
+        };
+        Ok(push)
+    }
 }
@@ -0,0 +1,130 @@
+//! RESP codec for tokio
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::model::{parse_msg, Msg, MsgLimits};
+
+/// decode `Msg` from bytes and encode `Msg` to bytes, used with `tokio_util::codec::Framed`
+#[derive(Debug, Clone, Default)]
+pub struct MsgCodec {
+    limits: MsgLimits,
+    /// how far the msg at the front of the buffer is received
+    scan: FrameScan,
+}
+
+impl MsgCodec {
+    /// create with the limits of the msg to decode
+    pub fn new(limits: MsgLimits) -> Self {
+        MsgCodec { limits, scan: FrameScan::default() }
+    }
+}
+
+impl Decoder for MsgCodec {
+    type Item = Msg;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Msg>, Self::Error> {
+        if src.is_empty() || !self.scan.advance(src, &self.limits) {
+            return Ok(None);
+        }
+        // the msg is whole, or breaks the limits or the format which the parser reports
+        match parse_msg(src, &self.limits)? {
+            Some((msg, len)) => {
+                src.advance(len);
+                self.scan = FrameScan::default();
+                Ok(Some(msg))
+            }
+            None => {
+                // the scan missed the end of a malformed msg, parse from the front until it is whole
+                self.scan.broken = true;
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// the progress of the msg at the front of the buffer over the calls of `decode`, so every received byte
+/// is scanned once and the msg is parsed once it is whole, a large bulk arriving in many reads is not
+/// parsed again after every read
+#[derive(Debug, Clone, Default)]
+struct FrameScan {
+    /// the bytes of the whole elements
+    scanned: usize,
+    /// the elements left of the open aggregates, the innermost last
+    remaining: Vec<i64>,
+    /// parse every time, see `MsgCodec::decode`
+    broken: bool,
+}
+
+impl FrameScan {
+    /// scan the elements received after the last call, return whether the msg should be parsed
+    fn advance(&mut self, buf: &[u8], limits: &MsgLimits) -> bool {
+        if self.broken {
+            return true;
+        }
+        loop {
+            let start = self.scanned;
+            if start >= buf.len() {
+                return false;
+            }
+            let line_end = match buf[start..].windows(2).position(|w| w == b"\r\n") {
+                Some(i) => start + i + 2,
+                None => return buf.len() > limits.max_request_size,
+            };
+            let header = std::str::from_utf8(&buf[start + 1..line_end - 2]).ok().and_then(|s| s.parse::<i64>().ok());
+            let mut end = line_end;
+            match buf[start] {
+                b'$' | b'=' => match header {
+                    Some(len) if len < 0 => {}
+                    Some(len) if len as u64 <= limits.max_bulk_len as u64 => end += len as usize + 2,
+                    _ => return true,
+                },
+                kind @ (b'*' | b'%' | b'~' | b'>') => match header {
+                    Some(len) if len >= 0
+                        && len as u64 <= limits.max_array_len as u64
+                        && self.remaining.len() < limits.max_depth =>
+                    {
+                        if len > 0 {
+                            self.scanned = end;
+                            self.remaining.push(if kind == b'%' { len * 2 } else { len });
+                            continue;
+                        }
+                    }
+                    _ => return true,
+                },
+                b'+' | b'-' | b':' | b'_' | b'#' | b',' | b'(' => {}
+                _ => return true,
+            }
+            if end > limits.max_request_size {
+                return true;
+            }
+            if end > buf.len() {
+                return false;
+            }
+            self.scanned = end;
+            // the element is whole, and so is every aggregate it ends
+            loop {
+                match self.remaining.last_mut() {
+                    None => return true,
+                    Some(left) => {
+                        *left -= 1;
+                        if *left > 0 {
+                            break;
+                        }
+                        self.remaining.pop();
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Encoder<Msg> for MsgCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Msg, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item.to_bytes());
+        Ok(())
+    }
+}
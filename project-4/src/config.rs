@@ -0,0 +1,480 @@
+//! kvs-server config, read from a TOML file, overridden by environment variables and then command line flags
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::acl::{Acl, User, DEFAULT_USER};
+use crate::client::ConnectOptions;
+use crate::engines::Durability;
+use crate::error::KvsError;
+use crate::logger::{LogConfig, LogFormat};
+use crate::model::MsgLimits;
+use crate::raft::{parse_members, RaftConfig};
+use crate::server::ConnectionTimeouts;
+use crate::slowlog::SlowLogConfig;
+use crate::tls::{ClientTlsConfig, ServerTlsConfig};
+use crate::Result;
+
+/// the names of all config keys, the same as the command line flags of kvs-server
+pub const CONFIG_KEYS: &[&str] = &[
+    "addr",
+    "unix",
+    "engine",
+    "data-dir",
+    "async",
+    "thread-pool",
+    "threads",
+    "queue-size",
+    "max-clients",
+    "durability",
+    "shards",
+    "log-level",
+    "idle-timeout",
+    "read-timeout",
+    "write-timeout",
+    "max-bulk-len",
+    "max-array-len",
+    "max-nesting-depth",
+    "max-request-size",
+    "tls-cert",
+    "tls-key",
+    "tls-client-ca",
+    "requirepass",
+    "acl-file",
+    "metrics-addr",
+    "slowlog-threshold",
+    "slowlog-max-len",
+    "slowlog-log",
+    "log-format",
+    "log-file",
+    "log-max-size",
+    "log-rotate-interval",
+    "log-max-files",
+    "trace-file",
+    "replica-of",
+    "peer-user",
+    "peer-password",
+    "peer-tls-ca",
+    "peer-tls-cert",
+    "peer-tls-key",
+    "raft-id",
+    "raft-peers",
+];
+
+/// the `ThreadPool` implementation serving the connections of `KvsServer`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThreadPoolKind {
+    /// `NaiveThreadPool`
+    Naive,
+    /// `SharedQueueThreadPool`
+    Shared,
+    /// `RayonThreadPool`
+    Rayon,
+}
+
+impl std::str::FromStr for ThreadPoolKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "naive" => Ok(ThreadPoolKind::Naive),
+            "shared" => Ok(ThreadPoolKind::Shared),
+            "rayon" => Ok(ThreadPoolKind::Rayon),
+            _ => Err(anyhow::anyhow!("unknown thread pool {:?}, accept naive, shared or rayon", s)),
+        }
+    }
+}
+
+/// the effective config of kvs-server
+///
+/// the keys of the TOML file are the names in `CONFIG_KEYS`, e.g.
+/// ```toml
+/// addr = "127.0.0.1:4000"
+/// data-dir = "/var/lib/kvs"
+/// max-clients = 100
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ServerConfig {
+    /// the listening TCP address, IP:PORT, `None` means 127.0.0.1:4000 unless `unix` is set
+    pub addr: Option<String>,
+    /// the path of the listening Unix socket, alone or alongside TCP
+    pub unix: Option<PathBuf>,
+    /// "kvs", "lsm", "sharded" or "sled", `None` means the engine already in use, or "kvs" on the first run
+    pub engine: Option<String>,
+    /// the directory of `engine.lock` and the data
+    pub data_dir: PathBuf,
+    /// serve connections with tokio tasks instead of the thread pool
+    #[serde(rename = "async")]
+    pub is_async: bool,
+    /// the thread pool type of the sync server
+    pub thread_pool: ThreadPoolKind,
+    /// the number of threads of the sync server, `None` means the number of CPUs
+    pub threads: Option<u32>,
+    /// the max number of accepted connections waiting for a free thread
+    pub queue_size: usize,
+    /// the max number of connected clients
+    pub max_clients: usize,
+    /// `None` means the default of the engine
+    pub durability: Option<Durability>,
+    /// the number of shards of the sharded engine when it is created, `None` means the number of CPUs,
+    /// an existing store keeps its shards
+    pub shards: Option<usize>,
+    /// the log filter, e.g. "info" or "kvs=debug", `None` means `RUST_LOG`, or "info" if it is not set
+    pub log_level: Option<String>,
+    /// text lines or JSON objects
+    pub log_format: LogFormat,
+    /// the log file, stderr if `None`
+    pub log_file: Option<PathBuf>,
+    /// bytes, rotate the log file when it reaches the size
+    pub log_max_size: Option<u64>,
+    /// seconds, rotate the log file when it is written for the time
+    pub log_rotate_interval: Option<f64>,
+    /// the number of the rotated log files kept
+    pub log_max_files: usize,
+    /// the file of the spans in the OTLP JSON form, not exported if `None`
+    pub trace_file: Option<PathBuf>,
+    /// seconds, see `ConnectionTimeouts`
+    pub idle_timeout: Option<f64>,
+    /// seconds, see `ConnectionTimeouts`
+    pub read_timeout: Option<f64>,
+    /// seconds, see `ConnectionTimeouts`
+    pub write_timeout: Option<f64>,
+    /// see `MsgLimits`
+    pub max_bulk_len: usize,
+    /// see `MsgLimits`
+    pub max_array_len: usize,
+    /// see `MsgLimits::max_depth`
+    pub max_nesting_depth: usize,
+    /// see `MsgLimits`
+    pub max_request_size: usize,
+    /// the PEM certificate chain, TLS is enabled on TCP with `tls_key`
+    pub tls_cert: Option<PathBuf>,
+    /// the PEM private key
+    pub tls_key: Option<PathBuf>,
+    /// the PEM CA certificates, the clients must present certificates signed by them if it is set
+    pub tls_client_ca: Option<PathBuf>,
+    /// the password of the default user, every connection must `AUTH` first if it or `acl_file` is set
+    pub requirepass: Option<String>,
+    /// the TOML file of the users, see `kvs::acl`
+    pub acl_file: Option<PathBuf>,
+    /// the TCP address serving the Prometheus metrics, IP:PORT, not served if `None`
+    pub metrics_addr: Option<String>,
+    /// seconds, the commands running at least this long are kept for `SLOWLOG GET`, none if negative
+    pub slowlog_threshold: f64,
+    /// the max number of the slow commands kept
+    pub slowlog_max_len: usize,
+    /// write the slow commands to the log too
+    pub slowlog_log: bool,
+    /// the primary followed as a replica, IP:PORT, the server is a primary if `None`
+    pub replica_of: Option<String>,
+    /// the user to `AUTH` as on the other servers, the default user if `None`
+    pub peer_user: Option<String>,
+    /// the password to `AUTH` with on the other servers, no `AUTH` if `None`
+    pub peer_password: Option<String>,
+    /// the PEM CA certificates to verify the other servers, they are connected by TLS if it is set
+    pub peer_tls_ca: Option<PathBuf>,
+    /// the PEM client certificate chain for mutual TLS with the other servers
+    pub peer_tls_cert: Option<PathBuf>,
+    /// the PEM private key of `peer_tls_cert`
+    pub peer_tls_key: Option<PathBuf>,
+    /// the id of the node in a Raft cluster, the server is not in a cluster if `None`
+    pub raft_id: Option<u64>,
+    /// the members to start a new Raft cluster with, like `1=127.0.0.1:4001,2=127.0.0.1:4002`,
+    /// or the members of the running cluster to join, without `raft_id`
+    pub raft_peers: Option<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        let limits = MsgLimits::default();
+        let slowlog = SlowLogConfig::default();
+        let log = LogConfig::default();
+        ServerConfig {
+            addr: None,
+            unix: None,
+            engine: None,
+            data_dir: PathBuf::from("."),
+            is_async: false,
+            thread_pool: ThreadPoolKind::Shared,
+            threads: None,
+            queue_size: 1024,
+            max_clients: 10000,
+            durability: None,
+            shards: None,
+            log_level: log.level,
+            log_format: log.format,
+            log_file: log.file,
+            log_max_size: log.max_size,
+            log_rotate_interval: None,
+            log_max_files: log.max_files,
+            trace_file: None,
+            idle_timeout: None,
+            read_timeout: None,
+            write_timeout: None,
+            max_bulk_len: limits.max_bulk_len,
+            max_array_len: limits.max_array_len,
+            max_nesting_depth: limits.max_depth,
+            max_request_size: limits.max_request_size,
+            tls_cert: None,
+            tls_key: None,
+            tls_client_ca: None,
+            requirepass: None,
+            acl_file: None,
+            metrics_addr: None,
+            slowlog_threshold: slowlog.threshold.map_or(-1.0, |threshold| threshold.as_secs_f64()),
+            slowlog_max_len: slowlog.max_len,
+            slowlog_log: slowlog.log,
+            replica_of: None,
+            peer_user: None,
+            peer_password: None,
+            peer_tls_ca: None,
+            peer_tls_cert: None,
+            peer_tls_key: None,
+            raft_id: None,
+            raft_peers: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// parse the config from TOML text, the absent keys take the default value
+    pub fn from_toml(text: &str) -> Result<Self> {
+        toml::from_str(text).map_err(|e| KvsError::InvalidConfig(e.to_string()).into())
+    }
+
+    /// read the config from a TOML file
+    pub fn from_file(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| KvsError::InvalidConfig(format!("can not read {:?}, {}", path, e)))?;
+        Self::from_toml(&text)
+    }
+
+    /// the environment variable overriding `key`, e.g. `KVS_MAX_CLIENTS` for "max-clients"
+    pub fn env_name(key: &str) -> String {
+        format!("KVS_{}", key.replace('-', "_").to_uppercase())
+    }
+
+    /// override the config with the environment variables named by `env_name`
+    pub fn apply_env(&mut self) -> Result<()> {
+        for key in CONFIG_KEYS {
+            if let Ok(value) = std::env::var(Self::env_name(key)) {
+                self.set(key, &value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// set the value of `key` in `CONFIG_KEYS` from text, an empty text unsets the optional values
+    pub fn set(&mut self, key: &str, value: &str) -> Result<()> {
+        self.try_set(key, value)
+            .map_err(|e| KvsError::InvalidConfig(format!("{} = {:?}, {}", key, value, e)).into())
+    }
+
+    fn try_set(&mut self, key: &str, value: &str) -> Result<()> {
+        fn optional<T: std::str::FromStr>(value: &str) -> Result<Option<T>>
+        where
+            T::Err: std::error::Error + Send + Sync + 'static,
+        {
+            if value.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(value.parse()?))
+        }
+
+        match key {
+            "addr" => self.addr = optional(value)?,
+            "unix" => self.unix = optional(value)?,
+            "engine" => self.engine = optional(value)?,
+            "data-dir" => self.data_dir = PathBuf::from(value),
+            "async" => self.is_async = value.parse()?,
+            "thread-pool" => self.thread_pool = value.parse()?,
+            "threads" => self.threads = optional(value)?,
+            "queue-size" => self.queue_size = value.parse()?,
+            "max-clients" => self.max_clients = value.parse()?,
+            "durability" => {
+                self.durability = if value.is_empty() { None } else { Some(value.parse()?) }
+            }
+            "shards" => self.shards = optional(value)?,
+            "log-level" => self.log_level = optional(value)?,
+            "log-format" => self.log_format = value.parse()?,
+            "log-file" => self.log_file = optional(value)?,
+            "log-max-size" => self.log_max_size = optional(value)?,
+            "log-rotate-interval" => self.log_rotate_interval = optional(value)?,
+            "log-max-files" => self.log_max_files = value.parse()?,
+            "trace-file" => self.trace_file = optional(value)?,
+            "idle-timeout" => self.idle_timeout = optional(value)?,
+            "read-timeout" => self.read_timeout = optional(value)?,
+            "write-timeout" => self.write_timeout = optional(value)?,
+            "max-bulk-len" => self.max_bulk_len = value.parse()?,
+            "max-array-len" => self.max_array_len = value.parse()?,
+            "max-nesting-depth" => self.max_nesting_depth = value.parse()?,
+            "max-request-size" => self.max_request_size = value.parse()?,
+            "tls-cert" => self.tls_cert = optional(value)?,
+            "tls-key" => self.tls_key = optional(value)?,
+            "tls-client-ca" => self.tls_client_ca = optional(value)?,
+            "requirepass" => self.requirepass = optional(value)?,
+            "acl-file" => self.acl_file = optional(value)?,
+            "metrics-addr" => self.metrics_addr = optional(value)?,
+            "slowlog-threshold" => self.slowlog_threshold = value.parse()?,
+            "slowlog-max-len" => self.slowlog_max_len = value.parse()?,
+            "slowlog-log" => self.slowlog_log = value.parse()?,
+            "replica-of" => self.replica_of = optional(value)?,
+            "peer-user" => self.peer_user = optional(value)?,
+            "peer-password" => self.peer_password = optional(value)?,
+            "peer-tls-ca" => self.peer_tls_ca = optional(value)?,
+            "peer-tls-cert" => self.peer_tls_cert = optional(value)?,
+            "peer-tls-key" => self.peer_tls_key = optional(value)?,
+            "raft-id" => self.raft_id = optional(value)?,
+            "raft-peers" => self.raft_peers = optional(value)?,
+            _ => Err(anyhow::anyhow!("unknown key"))?,
+        }
+        Ok(())
+    }
+
+    /// the logger, return an error if the rotation interval is negative or too large
+    pub fn log(&self) -> Result<LogConfig> {
+        let rotate_interval = match self.log_rotate_interval {
+            Some(secs) => Some(Duration::try_from_secs_f64(secs)?),
+            None => None,
+        };
+        Ok(LogConfig {
+            level: self.log_level.clone(),
+            format: self.log_format,
+            file: self.log_file.clone(),
+            max_size: self.log_max_size,
+            rotate_interval,
+            max_files: self.log_max_files,
+        })
+    }
+
+    /// the slow command log, return an error if the threshold is too large
+    pub fn slowlog(&self) -> Result<SlowLogConfig> {
+        let threshold = if self.slowlog_threshold < 0.0 {
+            None
+        } else {
+            Some(Duration::try_from_secs_f64(self.slowlog_threshold)?)
+        };
+        Ok(SlowLogConfig { threshold, max_len: self.slowlog_max_len, log: self.slowlog_log })
+    }
+
+    /// the listening TCP address, `None` if only the Unix socket is listened
+    pub fn tcp_addr(&self) -> Option<String> {
+        match (&self.addr, &self.unix) {
+            (Some(addr), _) => Some(addr.clone()),
+            (None, Some(_)) => None,
+            (None, None) => Some("127.0.0.1:4000".to_owned()),
+        }
+    }
+
+    /// the TLS files of the server, `None` if TLS is disabled,
+    /// return an error if only one of the certificate and the key is set
+    pub fn tls(&self) -> Result<Option<ServerTlsConfig>> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Ok(Some(ServerTlsConfig {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: self.tls_client_ca.clone(),
+            })),
+            (None, None) if self.tls_client_ca.is_none() => Ok(None),
+            _ => Err(KvsError::InvalidConfig("tls-cert and tls-key must be set together".to_owned()).into()),
+        }
+    }
+
+    /// how the server connects to the other servers, the primary of a replica or the peers of a Raft node,
+    /// return an error if a TLS file is missing or invalid
+    pub fn peer_connect(&self) -> Result<ConnectOptions> {
+        let tls = match &self.peer_tls_ca {
+            Some(ca) => {
+                let tls = ClientTlsConfig {
+                    ca: ca.clone(),
+                    cert: self.peer_tls_cert.clone(),
+                    key: self.peer_tls_key.clone(),
+                };
+                Some(tls.load()?)
+            }
+            None if self.peer_tls_cert.is_some() => {
+                Err(KvsError::InvalidConfig("peer-tls-cert requires peer-tls-ca".to_owned()))?
+            }
+            None => None,
+        };
+        // no timeout, the replication stream is quiet while the primary is not written, the Raft transport sets one
+        Ok(ConnectOptions { tls, user: self.peer_user.clone(), password: self.peer_password.clone(), timeout: None })
+    }
+
+    /// the Raft node of the server, `None` if `raft_id` is not set.
+    /// the log of the node is kept in the `raft` directory of `data_dir`
+    pub fn raft(&self) -> Result<Option<RaftConfig>> {
+        let id = match (self.raft_id, &self.raft_peers) {
+            (Some(id), _) => id,
+            (None, None) => return Ok(None),
+            (None, Some(_)) => Err(KvsError::InvalidConfig("raft-peers is set without raft-id".to_owned()))?,
+        };
+        if self.replica_of.is_some() {
+            Err(KvsError::InvalidConfig("replica-of and raft-id can not be set together".to_owned()))?
+        }
+        let members = parse_members(self.raft_peers.as_deref().unwrap_or_default())?;
+        let mut config = RaftConfig::new(id, members);
+        config.dir = Some(self.data_dir.join("raft"));
+        Ok(Some(config))
+    }
+
+    /// the users allowed to connect, `None` if authentication is disabled,
+    /// `requirepass` replaces the default user of `acl_file`
+    pub fn acl(&self) -> Result<Option<Acl>> {
+        let mut acl = match (&self.acl_file, &self.requirepass) {
+            (Some(path), _) => Acl::from_file(path)?,
+            (None, Some(_)) => Acl::default(),
+            (None, None) => return Ok(None),
+        };
+        if let Some(password) = &self.requirepass {
+            acl.add_user(User::unrestricted(DEFAULT_USER, password));
+        }
+        Ok(Some(acl))
+    }
+
+    /// a copy with the password hidden, to be logged
+    pub fn redacted(&self) -> Self {
+        let hidden = |password: &Option<String>| password.as_ref().map(|_| "******".to_owned());
+        ServerConfig {
+            requirepass: hidden(&self.requirepass),
+            peer_password: hidden(&self.peer_password),
+            ..self.clone()
+        }
+    }
+
+    /// the request limits
+    pub fn limits(&self) -> MsgLimits {
+        MsgLimits {
+            max_bulk_len: self.max_bulk_len,
+            max_array_len: self.max_array_len,
+            max_depth: self.max_nesting_depth,
+            max_request_size: self.max_request_size,
+        }
+    }
+
+    /// the connection timeouts, return an error if a timeout is not positive or too large,
+    /// a socket takes no zero timeout
+    pub fn timeouts(&self) -> Result<ConnectionTimeouts> {
+        let duration = |key: &str, secs: Option<f64>| -> Result<Option<Duration>> {
+            match secs {
+                Some(secs) => match Duration::try_from_secs_f64(secs)? {
+                    duration if duration.is_zero() => {
+                        Err(KvsError::InvalidConfig(format!("{} = {:?}, accept a positive number", key, secs)))?
+                    }
+                    duration => Ok(Some(duration)),
+                },
+                None => Ok(None),
+            }
+        };
+        Ok(ConnectionTimeouts {
+            idle: duration("idle-timeout", self.idle_timeout)?,
+            read: duration("read-timeout", self.read_timeout)?,
+            write: duration("write-timeout", self.write_timeout)?,
+        })
+    }
+}
@@ -5,68 +5,124 @@ use std::fs::{File, OpenOptions};
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::thread;
+use std::time::{Instant, SystemTime};
 
 use anyhow::Context;
 
-use crate::engines::KvsEngine;
+use crate::acl::command_name;
+use crate::engines::watch::{EventKind, KeyEvent, Watchers};
+use crate::engines::{
+    check_namespace, dir_size, Durability, EngineStats, KvsEngine, LogStats, SnapshotEntry, DEFAULT_NAMESPACE,
+};
 use crate::error::KvsError;
+use crate::metrics::metrics;
 use crate::model::Behavior;
 use crate::Result;
-use std::sync::{RwLock, Arc};
+use std::sync::{Mutex, RwLock, Arc};
 use crossbeam::{Sender, Receiver};
+use serde::{Deserialize, Serialize};
 use crate::thread_pool::{RayonThreadPool, ThreadPool};
 
-/// store keys and values
+/// store keys and values, the clones share the same store,
+/// a handle accesses the keys of its namespace only
 #[derive(Clone)]
 pub struct KvStore {
-    tx_reader: Sender<ChannelMessage>,
-    tx_writer: Sender<ChannelMessage>,
+    readers: Readers,
+    tx_writer: Sender<WriterMessage>,
+    namespace: String,
+    watchers: Watchers,
+    path: PathBuf,
+    /// updated by the writer after every write
+    stats: Arc<Mutex<EngineStats>>,
+    /// the keys of the core, read by `snapshot`
+    map: Arc<RwLock<Namespaces>>,
 }
 
 impl KvStore {
     /// Open the KvStore at a given path. Return the KvStore.
+    /// The writes are flushed to the OS, and synced to disk when the store is closed.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let path = path.into();
-        let (tx_reader, rx_reader) = crossbeam::unbounded::<ChannelMessage>();
-        let (tx_writer, rx_writer) = crossbeam::unbounded::<ChannelMessage>();
+        Self::open_with_durability(path, Durability::Flush)
+    }
+
+    /// Open the KvStore at a given path, sync every write to disk before return if `durability` is `Sync`
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<KvStore> {
+        let readers = Readers::start(num_cpus::get() as u32)?;
+        Self::start(path.into(), durability, Watchers::default(), readers, false)
+    }
+
+    /// open a shard of `ShardedKvStore`, notifying the `watchers` and read by the `readers` shared by the shards,
+    /// the gauges of the metrics are shared by the shards too
+    pub(crate) fn open_shard(path: PathBuf, durability: Durability, watchers: Watchers, readers: Readers) -> Result<KvStore> {
+        Self::start(path, durability, watchers, readers, true)
+    }
+
+    fn start(
+        path: PathBuf,
+        durability: Durability,
+        watchers: Watchers,
+        readers: Readers,
+        shared_gauges: bool,
+    ) -> Result<KvStore> {
+        let (tx_writer, rx_writer) = crossbeam::unbounded::<WriterMessage>();
+        let core_watchers = watchers.clone();
+        let stats = Arc::new(Mutex::new(EngineStats::default()));
+        let core_stats = stats.clone();
+        let map = Arc::new(RwLock::new(Namespaces::new()));
+        let core_map = map.clone();
+        // the log is loaded before the handle is returned, the readers answer from the map without the core
+        let mut core = KvsCore::open(path.clone(), durability, core_watchers, core_stats, core_map, shared_gauges)?;
         thread::spawn(move || {
-            match KvsCore::open(path) {
-                Ok(mut core) => {
-                    if let Err(e) = core.receive_channel_message(rx_reader, rx_writer) {
-                        log::error!("[KvsCore] receive message error, {}", e);
-                    }
-                }
-                Err(e) => {
-                    log::error!("[KvsCore] open error, {}", e);
-                }
+            if let Err(e) = core.receive_channel_message(rx_writer) {
+                log::error!("[KvsCore] receive message error, {}", e);
             }
             log::warn!("[KvsCore] closed");
         });
 
 
-        Ok(KvStore { tx_reader, tx_writer })
+        Ok(KvStore { readers, tx_writer, namespace: DEFAULT_NAMESPACE.to_owned(), watchers, path, stats, map })
     }
 
 
-    fn request_behavior(&self, cmtx: &Sender<ChannelMessage>, behavior: Behavior) -> Result<Option<String>> {
+    fn request_behavior(&self, reader: bool, behavior: Behavior) -> Result<Option<String>> {
+        let command = command_name(&behavior);
+        let span = tracing::debug_span!("kvs.engine", command, namespace = %self.namespace);
+        let _entered = span.enter();
         let (tx, rx) = crossbeam::unbounded::<Option<String>>();
         let cm = ChannelMessage {
+            namespace: self.namespace.clone(),
             behavior,
             callback: tx,
+            span: span.clone(),
         };
-        cmtx.send(cm).map_err(|se| {
-            log::error!("send channel message error, {:?}, {}", se.0.behavior, se);
-            KvsError::Unknown
-        })?;
+        let sent = if reader {
+            self.readers.tx.send((self.map.clone(), cm)).is_ok()
+        } else {
+            self.tx_writer.send(WriterMessage::Command(cm)).is_ok()
+        };
+        if !sent {
+            log::error!("send channel message error, {}, channel closed", command);
+            Err(KvsError::Unknown)?
+        }
+        Ok(tracing::debug_span!("kvs.channel_wait").in_scope(|| rx.recv())?)
+    }
+
+    /// wait until the writer has applied the writes sent before the call to the map and the stats
+    fn barrier(&self) -> Result<()> {
+        let (tx, rx) = crossbeam::bounded::<()>(1);
+        if self.tx_writer.send(WriterMessage::Barrier(tx)).is_err() {
+            log::error!("send channel message error, barrier, channel closed");
+            Err(KvsError::Unknown)?
+        }
         Ok(rx.recv()?)
     }
 
     fn request_reader_behavior(&self, behavior: Behavior) -> Result<Option<String>> {
-        self.request_behavior(&self.tx_reader, behavior)
+        self.request_behavior(true, behavior)
     }
 
     fn request_writer_behavior(&self, behavior: Behavior) -> Result<Option<String>> {
-        self.request_behavior(&self.tx_writer, behavior)
+        self.request_behavior(false, behavior)
     }
 }
 
@@ -95,15 +151,87 @@ impl KvsEngine for KvStore {
         Ok(())
     }
 
+    #[allow(clippy::needless_return)]
     fn engine_name(&self) -> String {
         return "kvs".to_owned();
     }
+
+    fn select(&self, namespace: &str) -> Result<Self> {
+        check_namespace(namespace)?;
+        Ok(KvStore { namespace: namespace.to_owned(), ..self.clone() })
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        let size = self.request_reader_behavior(Behavior::DbSize)?.unwrap_or_default();
+        Ok(size.parse()?)
+    }
+
+    fn flush_db(&self) -> Result<()> {
+        self.request_writer_behavior(Behavior::FlushDb)?;
+        Ok(())
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>> {
+        Ok(self.watchers.add(&self.namespace, prefix))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        // the writer updates the stats after the writes before it
+        self.barrier()?;
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.disk_bytes = dir_size(&self.path)?;
+        Ok(stats)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        // the writer applies the writes before it to the map
+        self.barrier()?;
+        let map = self.map.read().map_err(|e| {
+            log::error!("[namespaces] hold read lock error, {}", e);
+            KvsError::Unknown
+        })?;
+        Ok(map.iter().filter(|(_, keys)| !keys.is_empty()).map(|(namespace, _)| namespace.clone()).collect())
+    }
+
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        // the writer applies the writes before it to the map
+        self.barrier()?;
+        let map = self.map.read().map_err(|e| {
+            log::error!("[snapshot] hold read lock error, {}", e);
+            KvsError::Unknown
+        })?;
+        let mut entries = Vec::new();
+        for (namespace, keys) in map.iter() {
+            for (key, sv) in keys {
+                entries.push(SnapshotEntry { namespace: namespace.clone(), key: key.clone(), value: sv.to_value()? });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn close(&self) -> Result<()> {
+        let (tx, rx) = crossbeam::unbounded::<Option<String>>();
+        let cm = ChannelMessage {
+            namespace: self.namespace.clone(),
+            behavior: Behavior::Shutdown,
+            callback: tx,
+            span: tracing::Span::current(),
+        };
+        // the channel is closed if the core was closed before
+        if self.tx_writer.send(WriterMessage::Command(cm)).is_ok() {
+            rx.recv()?;
+        }
+        Ok(())
+    }
 }
 
-/// kv存储值
+/// kv存储值, `len` is the length of its log record
 #[derive(Clone, Debug)]
 enum StoreValue {
-    Memory(String),
+    Memory {
+        value: String,
+        len: usize,
+    },
     File {
         offset: u64,
         len: usize,
@@ -114,41 +242,144 @@ enum StoreValue {
 impl StoreValue {
     fn to_value(&self) -> Result<String> {
         let text = match self {
-            StoreValue::Memory(v) => v.to_owned(),
+            StoreValue::Memory { value, .. } => value.to_owned(),
             StoreValue::File { offset, len, path } => {
                 let mut file = OpenOptions::new().read(true).open(path)?;
                 let buffer = KvsCore::read_file_offset(&mut file, *offset, *len)?;
                 let line = String::from_utf8(buffer)?;
-                let behavior = serde_json::from_str::<Behavior>(&line)
+                let record = serde_json::from_str::<LogRecord>(&line)
                     .with_context(|| format!("Failed to deserialize behavior from {}", line))?;
-                if let Behavior::Set { key: _, value } = behavior {
+                if let Behavior::Set { key: _, value } = record.behavior {
                     value
                 } else {
-                    log::error!("[store value] deserialize error, not Behavior::Set, {:?}", &behavior);
+                    log::error!("[store value] deserialize error, not Behavior::Set, {:?}", &record.behavior);
                     Err(KvsError::Unknown)?
                 }
             }
         };
         Ok(text)
     }
+
+    /// the bytes of its log record, with the newline
+    fn record_bytes(&self) -> u64 {
+        match self {
+            StoreValue::Memory { len, .. } | StoreValue::File { len, .. } => *len as u64 + 1,
+        }
+    }
+}
+
+/// 日志记录, the namespace is omitted for `DEFAULT_NAMESPACE`,
+/// so the records written before namespaces existed belong to it
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord {
+    #[serde(default = "default_namespace", skip_serializing_if = "is_default_namespace")]
+    ns: String,
+    #[serde(flatten)]
+    behavior: Behavior,
+}
+
+fn default_namespace() -> String {
+    DEFAULT_NAMESPACE.to_owned()
+}
+
+fn is_default_namespace(namespace: &str) -> bool {
+    namespace == DEFAULT_NAMESPACE
 }
 
 /// 通道消息
 struct ChannelMessage {
+    namespace: String,
     behavior: Behavior,
     callback: Sender<Option<String>>,
+    /// the span of the request, the parent of the spans of the core
+    span: tracing::Span,
+}
+
+/// a message to the writer of the core
+enum WriterMessage {
+    /// run the write, or `Behavior::Shutdown` to close the core
+    Command(ChannelMessage),
+    /// reply on the channel after the messages before it are handled
+    Barrier(Sender<()>),
+}
+
+/// the keys of every namespace, an empty namespace is removed
+type Namespaces = HashMap<String, HashMap<String, StoreValue>>;
+
+/// the threads answering the reads of a `KvStore`, or of all shards of a `ShardedKvStore`,
+/// a read carries the keys of its core
+#[derive(Clone)]
+pub(crate) struct Readers {
+    tx: Sender<(Arc<RwLock<Namespaces>>, ChannelMessage)>,
+}
+
+impl Readers {
+    /// start `threads` readers, they stop when every handle is dropped
+    #[allow(clippy::map_flatten)]
+    pub fn start(threads: u32) -> Result<Self> {
+        let (tx, rx) = crossbeam::unbounded::<(Arc<RwLock<Namespaces>>, ChannelMessage)>();
+        let thread_pool = RayonThreadPool::new(threads)?;
+        for _ in 0..threads {
+            let rx = rx.clone();
+            thread_pool.spawn(move || {
+                while let Ok((map, cm)) = rx.recv() {
+                    let _span = tracing::debug_span!(parent: &cm.span, "kvs_core.read").entered();
+                    match &cm.behavior {
+                        Behavior::Get { key } => {
+                            if let Ok(guard) = map.read() {
+                                let option = guard.get(&cm.namespace)
+                                    .and_then(|keys| keys.get(key))
+                                    .map(|sv| {
+                                        sv.to_value().ok()
+                                    }).flatten();
+                                cm.callback.send(option).unwrap();
+                            }
+                        }
+                        Behavior::DbSize => {
+                            if let Ok(guard) = map.read() {
+                                let size = guard.get(&cm.namespace).map_or(0, |keys| keys.len());
+                                cm.callback.send(Some(size.to_string())).unwrap();
+                            }
+                        }
+                        _ => unreachable!()
+                    }
+                }
+            });
+        }
+        Ok(Readers { tx })
+    }
 }
 
 /// 基于消息的kvs核心实现
 struct KvsCore {
-    map: Arc<RwLock<HashMap<String, StoreValue>>>,
+    map: Arc<RwLock<Namespaces>>,
     path: PathBuf,
+    durability: Durability,
+    /// notified by the writer after the change is logged
+    watchers: Watchers,
+    /// shared with the `KvStore` handles
+    stats: Arc<Mutex<EngineStats>>,
+    compactions: u64,
+    last_compaction: Option<SystemTime>,
     operation_count: u64,
+    /// the size of the log
     offset: u64,
+    /// the bytes of the records of the current values, the others are dropped by the next compaction
+    live_bytes: u64,
+    /// the log bytes, live bytes and keys last added to the gauges shared with the other shards,
+    /// `None` if the core is alone and sets the gauges
+    reported: Option<[i64; 3]>,
 }
 
 impl KvsCore {
-    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn open(
+        path: impl Into<PathBuf>,
+        durability: Durability,
+        watchers: Watchers,
+        stats: Arc<Mutex<EngineStats>>,
+        map: Arc<RwLock<Namespaces>>,
+        shared_gauges: bool,
+    ) -> Result<Self> {
         let mut path = path.into();
         path.push("x.log");
         let file = OpenOptions::new()
@@ -158,64 +389,70 @@ impl KvsCore {
             .open(path.clone())?;
 
         let mut core = KvsCore {
-            map: Arc::new(RwLock::new(HashMap::new())),
+            map,
             path,
+            durability,
+            watchers,
+            stats,
+            compactions: 0,
+            last_compaction: None,
             operation_count: 0,
             offset: 0,
+            live_bytes: 0,
+            reported: if shared_gauges { Some([0; 3]) } else { None },
         };
         core.init_from_buffer_reader(BufReader::new(file))?;
+        core.report_metrics();
         Ok(core)
     }
 
     /// receive and handle message until channel closed
-    fn receive_channel_message(
-        &mut self,
-        rx_reader: Receiver<ChannelMessage>,
-        rx_writer: Receiver<ChannelMessage>,
-    ) -> Result<()> {
-        let threads = num_cpus::get() as u32;
-        let thread_pool = RayonThreadPool::new(threads)?;
-        for _ in 0..threads {
-            let map_clone = self.map.clone();
-            let rx_reader = rx_reader.clone();
-            thread_pool.spawn(move || {
-                let map = map_clone;
-                while let Ok(cm) = rx_reader.recv() {
-                    match &cm.behavior {
-                        Behavior::Get { key } => {
-                            if let Ok(guard) = map.read() {
-                                let option = guard.get(key).map(|sv| {
-                                    sv.to_value().ok()
-                                }).flatten();
-                                cm.callback.send(option).unwrap();
-                            }
-                        }
-                        _ => unreachable!()
-                    }
+    #[allow(clippy::map_flatten)]
+    fn receive_channel_message(&mut self, rx_writer: Receiver<WriterMessage>) -> Result<()> {
+        while let Ok(message) = rx_writer.recv() {
+            let cm = match message {
+                WriterMessage::Command(cm) => cm,
+                WriterMessage::Barrier(done) => {
+                    let _ = done.send(());
+                    continue;
                 }
-            });
-        }
-
-        while let Ok(cm) = rx_writer.recv() {
+            };
             // log::info!("[receive_channel_message] recv: {:?}", cm.behavior);
-            match &cm.behavior {
+            let _span = tracing::debug_span!(parent: &cm.span, "kvs_core.write").entered();
+            let mut events = Vec::new();
+            let line = match &cm.behavior {
+                Behavior::Shutdown => String::new(),
+                behavior => encode_record(&cm.namespace, behavior)?,
+            };
+            let reply = match &cm.behavior {
                 Behavior::Set { key, ref value } => {
                     // TODO 将StoreValue::Memory转换成StoreValue::File
                     match self.map.write() {
                         Ok(mut guard) => {
-                            guard.insert(key.to_owned(), StoreValue::Memory(value.to_owned()));
+                            let new_value = StoreValue::Memory { value: value.to_owned(), len: line.len() };
+                            self.live_bytes += new_value.record_bytes();
+                            let old_value = guard.entry(cm.namespace.clone())
+                                .or_default()
+                                .insert(key.to_owned(), new_value);
+                            self.live_bytes -= old_value.map_or(0, |sv| sv.record_bytes());
                         }
                         Err(e) => {
                             log::error!("behavior set error, {}", e);
                             Err(KvsError::Unknown)?
                         }
                     };
-                    cm.callback.send(None)?;
+                    events.push((EventKind::Set, key.to_owned()));
+                    None
                 }
                 Behavior::Remove { key } => {
                     let option = match self.map.write() {
                         Ok(mut guard) => {
-                            guard.remove(key).map(|sv| {
+                            let removed = remove_key(&mut guard, &cm.namespace, key);
+                            if let Some(sv) = &removed {
+                                self.live_bytes -= sv.record_bytes();
+                                events.push((EventKind::Remove, key.to_owned()));
+                            }
+                            removed.map(|sv| {
                                 sv.to_value().ok()
                             }).flatten()
                         }
@@ -224,22 +461,56 @@ impl KvsCore {
                             Err(KvsError::Unknown)?
                         }
                     };
-                    cm.callback.send(option)?;
+                    option
+                }
+                Behavior::FlushDb => {
+                    match self.map.write() {
+                        Ok(mut guard) => {
+                            if let Some(keys) = guard.remove(&cm.namespace) {
+                                self.live_bytes -= keys.values().map(StoreValue::record_bytes).sum::<u64>();
+                                events.extend(keys.into_keys().map(|key| (EventKind::Remove, key)));
+                            }
+                        }
+                        Err(e) => {
+                            log::error!("behavior flushdb error, {}", e);
+                            Err(KvsError::Unknown)?
+                        }
+                    };
+                    None
+                }
+                Behavior::Shutdown => {
+                    self.sync()?;
+                    cm.callback.send(None)?;
+                    break;
                 }
                 _ => unreachable!()
+            };
+            // the record reaches the log, and the disk with `Durability::Sync`, before the reply
+            self.flush(&line)?;
+            cm.callback.send(reply)?;
+            for (kind, key) in events {
+                self.watchers.notify(&cm.namespace, kind, &key);
             }
-            self.flush(&cm.behavior)?;
         }
         log::info!("[receive_channel_message] rx end");
+        self.watchers.clear();
         Ok(())
     }
 
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn update_operation_count(&mut self) -> Result<()> {
         self.operation_count += 1;
         if self.operation_count % 2048 == 0 {
+            let _span = tracing::info_span!("kvs_core.compact", log_bytes = self.offset).entered();
+            let started = Instant::now();
             self.compact()?;
+            self.compactions += 1;
+            self.last_compaction = Some(SystemTime::now());
+            metrics().compactions.inc();
+            metrics().compaction_duration.observe(started.elapsed().as_secs_f64());
         }
+        self.report_metrics();
         Ok(())
     }
 
@@ -252,14 +523,13 @@ impl KvsCore {
 
         let mut text = String::new();
         // convert StoreValue::Memory to StoreValue::Value
-        for entry in map.iter_mut() {
-            let value = entry.1.to_value()?;
-            *entry.1 = StoreValue::Memory(value.clone());
-            let behavior = Behavior::Set {
-                key: entry.0.to_owned(),
-                value,
-            };
-            text += &format!("{}\n", serde_json::to_string(&behavior)?);
+        for (namespace, keys) in map.iter_mut() {
+            for entry in keys.iter_mut() {
+                let value = entry.1.to_value()?;
+                let line = encode_record(namespace, &Behavior::Set { key: entry.0.to_owned(), value: value.clone() })?;
+                *entry.1 = StoreValue::Memory { value, len: line.len() };
+                text += &format!("{}\n", line);
+            }
         }
         // release lock;
         drop(map);
@@ -270,10 +540,13 @@ impl KvsCore {
             .open(self.path.clone())?;
         truncate_file.write_all(text.as_bytes())?;
         self.init_from_file_text(text)?;
+        // every record of the compacted log is live
+        self.live_bytes = self.offset;
         Ok(())
     }
 
     /// 从偏移值读取文件内容
+    #[allow(clippy::slow_vector_initialization)]
     fn read_file_offset(file: &mut File, offset: u64, size: usize) -> Result<Vec<u8>> {
         file.seek(SeekFrom::Start(offset))?;
         let mut buffer = Vec::with_capacity(size);
@@ -303,7 +576,7 @@ impl KvsCore {
     /// 从文本行应用操作
     fn apply_behavior_from_line(&mut self, line: &str) -> Result<()> {
         let len = line.len();
-        let behavior = serde_json::from_str::<Behavior>(line)
+        let LogRecord { ns, behavior } = serde_json::from_str::<LogRecord>(line)
             .with_context(|| format!("Failed to deserialize behavior from {}", line))?;
 
         let mut map = self.map.write().map_err(|e| {
@@ -311,32 +584,102 @@ impl KvsCore {
             KvsError::Unknown
         })?;
 
-        match behavior {
+        let removed_bytes = match behavior {
             Behavior::Set { key, value: _ } => {
                 // map中保存behavior在文件中的偏移值和它的长度
-                map.insert(key, StoreValue::File {
+                self.live_bytes += len as u64 + 1;
+                map.entry(ns).or_default().insert(key, StoreValue::File {
                     offset: self.offset,
                     len,
                     path: self.path.clone(),
-                });
+                }).map_or(0, |sv| sv.record_bytes())
             }
             Behavior::Remove { key } => {
-                map.remove(&key);
+                remove_key(&mut map, &ns, &key).map_or(0, |sv| sv.record_bytes())
             }
-            _ => {}
-        }
+            Behavior::FlushDb => {
+                map.remove(&ns).map_or(0, |keys| keys.values().map(StoreValue::record_bytes).sum())
+            }
+            _ => 0,
+        };
+        self.live_bytes -= removed_bytes;
         self.offset += len as u64 + 1;
         Ok(())
     }
 
-    fn flush(&mut self, behavior: &Behavior) -> Result<()> {
+    /// sync the log file to disk
+    fn sync(&self) -> Result<()> {
+        OpenOptions::new().append(true).open(&self.path)?.sync_all()?;
+        log::info!("[KvsCore] log synced, path={:?}", self.path);
+        Ok(())
+    }
+
+    /// append the record line encoded by `encode_record`
+    fn flush(&mut self, line: &str) -> Result<()> {
+        let _span = tracing::debug_span!("kvs_core.flush", bytes = line.len() + 1).entered();
         let mut file = OpenOptions::new()
             .append(true)
             .open(&self.path)?;
-        let json = serde_json::to_string(&behavior)?;
-        file.write_all(format!("{}\n", json).as_bytes())?;
+        file.write_all(format!("{}\n", line).as_bytes())?;
         file.flush()?;
+        self.offset += line.len() as u64 + 1;
+        if self.durability == Durability::Sync {
+            file.sync_data()?;
+        }
         self.update_operation_count()?;
         Ok(())
     }
+
+    /// update the metrics and the stats of the log and the keys
+    fn report_metrics(&mut self) {
+        let (keys, namespaces) = match self.map.read() {
+            Ok(map) => (map.values().map(HashMap::len).sum::<usize>(), map.values().filter(|m| !m.is_empty()).count()),
+            Err(_) => return,
+        };
+        let metrics = metrics();
+        let current = [self.offset as i64, self.live_bytes as i64, keys as i64];
+        match &mut self.reported {
+            Some(reported) => {
+                let [log_bytes, live_bytes, keys] = [0, 1, 2].map(|i| current[i] - reported[i]);
+                metrics.log_bytes.add(log_bytes);
+                metrics.live_bytes.add(live_bytes);
+                metrics.stale_bytes.add(log_bytes - live_bytes);
+                metrics.keys.add(keys);
+                *reported = current;
+            }
+            None => {
+                metrics.log_bytes.set(current[0]);
+                metrics.live_bytes.set(current[1]);
+                metrics.stale_bytes.set(current[0] - current[1]);
+                metrics.keys.set(current[2]);
+            }
+        }
+
+        let mut stats = self.stats.lock().unwrap();
+        stats.keys = keys;
+        stats.namespaces = namespaces;
+        stats.log = Some(LogStats {
+            segments: 1,
+            bytes: self.offset,
+            live_bytes: self.live_bytes,
+            compactions: self.compactions,
+            last_compaction: self.last_compaction,
+        });
+    }
+}
+
+/// the log line of `behavior` on `namespace`, without the newline
+fn encode_record(namespace: &str, behavior: &Behavior) -> Result<String> {
+    let record = LogRecord { ns: namespace.to_owned(), behavior: behavior.clone() };
+    Ok(serde_json::to_string(&record)?)
+}
+
+/// remove the key from the namespace, and the namespace if it becomes empty
+fn remove_key(map: &mut Namespaces, namespace: &str, key: &str) -> Option<StoreValue> {
+    let keys = map.get_mut(namespace)?;
+    let removed = keys.remove(key);
+    if keys.is_empty() {
+        map.remove(namespace);
+    }
+    removed
 }
@@ -0,0 +1,178 @@
+//! kvs engine partitioning the keys across independent `KvStore` cores
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crossbeam::Receiver;
+
+use crate::engines::kvs_rw_channel::{KvStore, Readers};
+use crate::engines::watch::{KeyEvent, Watchers};
+use crate::engines::{
+    check_namespace, dir_size, Durability, EngineStats, KvsEngine, LogStats, SnapshotEntry, DEFAULT_NAMESPACE,
+};
+use crate::error::KvsError;
+use crate::hash::hash;
+use crate::Result;
+
+/// the file recording the number of shards, the keys would be lost if it changed
+const SHARDS_FILE: &str = "shards";
+
+/// store keys and values in independent `KvStore` cores, each with its own log directory `shard-{i}`
+/// and writer thread, so the writes to the different shards run in parallel, the reads share one pool of threads.
+/// a key belongs to the shard of its hash, the clones share the same store,
+/// a handle accesses the keys of its namespace only
+///
+/// # Atomicity
+///
+/// a command on a single key runs on the one shard of the key, it is as atomic and durable as on `KvStore`.
+/// the commands on many keys run shard after shard, without a lock across the shards:
+/// - `MSET` writes its keys one by one, a reader may see some of them only,
+///   and a crash may persist the keys of some shards only
+/// - `flush_db` empties the shards one by one, the keys written meanwhile to the emptied shards are kept
+/// - `snapshot` and `stats` read the shards one by one, the writes running meanwhile may be seen on some shards only
+#[derive(Clone)]
+pub struct ShardedKvStore {
+    shards: Vec<KvStore>,
+    namespace: String,
+    watchers: Watchers,
+    path: PathBuf,
+}
+
+impl ShardedKvStore {
+    /// Open the ShardedKvStore at a given path, with the shards it was created with,
+    /// or one shard for every CPU if it is new
+    pub fn open(path: impl Into<PathBuf>) -> Result<ShardedKvStore> {
+        Self::open_with_durability(path, Durability::Flush)
+    }
+
+    /// Open the ShardedKvStore at a given path, sync every write to disk before return if `durability` is `Sync`
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<ShardedKvStore> {
+        let path = path.into();
+        let shards = recorded_shards(&path)?.unwrap_or_else(num_cpus::get);
+        Self::open_with_shards(path, durability, shards)
+    }
+
+    /// Open the ShardedKvStore at a given path with `shards` cores, at least one.
+    /// Return an error if the store was created with another number of shards.
+    pub fn open_with_shards(path: impl Into<PathBuf>, durability: Durability, shards: usize) -> Result<ShardedKvStore> {
+        let path = path.into();
+        let shards = shards.max(1);
+        match recorded_shards(&path)? {
+            Some(expect) if expect != shards => Err(KvsError::WrongShards { expect, actual: shards })?,
+            Some(_) => {}
+            None => {
+                std::fs::create_dir_all(&path)?;
+                std::fs::write(path.join(SHARDS_FILE), shards.to_string())?;
+            }
+        }
+
+        let watchers = Watchers::default();
+        // one pool of readers for all shards, the writers are one thread per shard
+        let readers = Readers::start(num_cpus::get() as u32)?;
+        let mut stores = Vec::with_capacity(shards);
+        for i in 0..shards {
+            let shard_path = path.join(format!("shard-{}", i));
+            std::fs::create_dir_all(&shard_path)?;
+            stores.push(KvStore::open_shard(shard_path, durability, watchers.clone(), readers.clone())?);
+        }
+        log::info!("sharded store opened, path={}, shards={}", path.display(), shards);
+        Ok(ShardedKvStore { shards: stores, namespace: DEFAULT_NAMESPACE.to_owned(), watchers, path })
+    }
+
+    /// the number of the shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    fn shard(&self, key: &str) -> &KvStore {
+        &self.shards[(hash(key) % self.shards.len() as u64) as usize]
+    }
+}
+
+/// the number of shards written by the first open, `None` if the store is new
+fn recorded_shards(path: &std::path::Path) -> Result<Option<usize>> {
+    let file = path.join(SHARDS_FILE);
+    if !file.exists() {
+        return Ok(None);
+    }
+    let text = std::fs::read_to_string(&file)?;
+    let shards = text.trim().parse().map_err(|e| anyhow::anyhow!("invalid {}, {}", file.display(), e))?;
+    Ok(Some(shards))
+}
+
+impl KvsEngine for ShardedKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.shard(&key).set(key, value)
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.shard(&key).get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.shard(&key).remove(key)
+    }
+
+    fn engine_name(&self) -> String {
+        "sharded".to_owned()
+    }
+
+    fn select(&self, namespace: &str) -> Result<Self> {
+        check_namespace(namespace)?;
+        let shards = self.shards.iter().map(|shard| shard.select(namespace)).collect::<Result<_>>()?;
+        Ok(ShardedKvStore { shards, namespace: namespace.to_owned(), ..self.clone() })
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        self.shards.iter().map(KvStore::db_size).sum()
+    }
+
+    fn flush_db(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::flush_db)
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>> {
+        // the shards notify the same watchers, so the events of all shards come in one sequence
+        Ok(self.watchers.add(&self.namespace, prefix))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut log = LogStats::default();
+        let mut keys = 0;
+        let mut namespaces = HashSet::new();
+        for shard in &self.shards {
+            let stats = shard.stats()?;
+            keys += stats.keys;
+            if let Some(shard_log) = stats.log {
+                log.segments += shard_log.segments;
+                log.bytes += shard_log.bytes;
+                log.live_bytes += shard_log.live_bytes;
+                log.compactions += shard_log.compactions;
+                log.last_compaction = log.last_compaction.max(shard_log.last_compaction);
+            }
+            // a namespace may have keys on many shards
+            namespaces.extend(shard.namespaces()?);
+        }
+        Ok(EngineStats { keys, namespaces: namespaces.len(), disk_bytes: dir_size(&self.path)?, log: Some(log) })
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let mut namespaces = HashSet::new();
+        for shard in &self.shards {
+            namespaces.extend(shard.namespaces()?);
+        }
+        Ok(namespaces.into_iter().collect())
+    }
+
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            entries.extend(shard.snapshot()?);
+        }
+        Ok(entries)
+    }
+
+    fn close(&self) -> Result<()> {
+        self.shards.iter().try_for_each(KvStore::close)
+    }
+}
@@ -1,7 +1,7 @@
 //! self implementation kvs engine
 
 use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
+use std::fs::{self, File, OpenOptions};
 use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
 use std::path::PathBuf;
 use std::sync::mpsc::{channel, Receiver, Sender};
@@ -9,7 +9,8 @@ use std::thread;
 
 use anyhow::Context;
 
-use crate::engines::KvsEngine;
+use crate::engines::watch::{EventKind, KeyEvent, Watchers};
+use crate::engines::{check_namespace, dir_size, EngineStats, KvsEngine, SnapshotEntry, DEFAULT_NAMESPACE};
 use crate::error::KvsError;
 use crate::model::Behavior;
 use crate::Result;
@@ -18,6 +19,8 @@ use crate::Result;
 #[derive(Clone)]
 pub struct KvStore {
     tx: Sender<ChannelMessage>,
+    watchers: Watchers,
+    path: PathBuf,
 }
 
 impl KvStore {
@@ -25,8 +28,11 @@ impl KvStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
         let path = path.into();
         let (tx, rx) = channel::<ChannelMessage>();
+        let watchers = Watchers::default();
+        let core_watchers = watchers.clone();
+        let core_path = path.clone();
         thread::spawn(move || {
-            match KvsCore::open(path) {
+            match KvsCore::open(core_path, core_watchers) {
                 Ok(mut core) => {
                     if let Err(e) = core.receive_channel_message(rx) {
                         log::error!("[KvsCore] receive message error, {}", e);
@@ -39,12 +45,12 @@ impl KvStore {
             log::warn!("[KvsCore] closed");
         });
 
-        Ok( KvStore { tx })
+        Ok( KvStore { tx, watchers, path })
     }
 
 
     fn request_behavior(&self, behavior: Behavior) -> Result<Option<String>> {
-        let (tx, rx) = channel::<Option<String>>();
+        let (tx, rx) = channel::<Result<Option<String>>>();
         let cm = ChannelMessage {
             behavior,
             callback: tx,
@@ -53,7 +59,7 @@ impl KvStore {
             log::error!("send channel message error, {:?}, {}", se.0.behavior, se);
             KvsError::Unknown
         })?;
-        Ok(rx.recv()?)
+        rx.recv()?
     }
 }
 
@@ -82,9 +88,78 @@ impl KvsEngine for KvStore {
         Ok(())
     }
 
+    #[allow(clippy::needless_return)]
     fn engine_name(&self) -> String {
         return "kvs".to_owned();
     }
+
+    /// only the default namespace is supported
+    fn select(&self, namespace: &str) -> Result<Self> {
+        check_namespace(namespace)?;
+        if namespace != DEFAULT_NAMESPACE {
+            Err(KvsError::NamespacesUnsupported)?
+        }
+        Ok(self.clone())
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        let size = self.request_behavior(Behavior::DbSize)?.unwrap_or_default();
+        Ok(size.parse()?)
+    }
+
+    fn flush_db(&self) -> Result<()> {
+        self.request_behavior(Behavior::FlushDb)?;
+        Ok(())
+    }
+
+    fn watch(&self, prefix: &str) -> Result<crossbeam::Receiver<KeyEvent>> {
+        Ok(self.watchers.add(DEFAULT_NAMESPACE, prefix))
+    }
+
+    /// the log is not reported
+    fn stats(&self) -> Result<EngineStats> {
+        let keys = self.db_size()?;
+        Ok(EngineStats { keys, namespaces: usize::from(keys > 0), disk_bytes: dir_size(&self.path)?, log: None })
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let keys = self.db_size()?;
+        Ok(if keys > 0 { vec![DEFAULT_NAMESPACE.to_owned()] } else { Vec::new() })
+    }
+
+    /// replay the log, the core writes it before the replies
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        self.request_behavior(Behavior::DbSize)?;
+        let text = fs::read_to_string(self.path.join("x.log"))?;
+        let mut map = HashMap::new();
+        for line in text.lines() {
+            match serde_json::from_str::<Behavior>(line)? {
+                Behavior::Set { key, value } => {
+                    map.insert(key, value);
+                }
+                Behavior::Remove { key } => {
+                    map.remove(&key);
+                }
+                Behavior::FlushDb => map.clear(),
+                _ => {}
+            }
+        }
+        let namespace = DEFAULT_NAMESPACE.to_owned();
+        Ok(map.into_iter().map(|(key, value)| SnapshotEntry { namespace: namespace.clone(), key, value }).collect())
+    }
+
+    fn close(&self) -> Result<()> {
+        let (tx, rx) = channel::<Result<Option<String>>>();
+        let cm = ChannelMessage {
+            behavior: Behavior::Shutdown,
+            callback: tx,
+        };
+        // the channel is closed if the core was closed before
+        if self.tx.send(cm).is_ok() {
+            rx.recv()??;
+        }
+        Ok(())
+    }
 }
 
 /// kv存储值
@@ -123,19 +198,21 @@ impl StoreValue {
 /// 通道消息
 struct ChannelMessage {
     behavior: Behavior,
-    callback: Sender<Option<String>>,
+    /// the reply, or the error if the behavior is not supported by the core
+    callback: Sender<Result<Option<String>>>,
 }
 
 /// 基于消息的kvs核心实现
 struct KvsCore {
     map: HashMap<String, StoreValue>,
     path: PathBuf,
+    watchers: Watchers,
     operation_count: u64,
     offset: u64,
 }
 
 impl KvsCore {
-    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+    pub fn open(path: impl Into<PathBuf>, watchers: Watchers) -> Result<Self> {
         let mut path = path.into();
         path.push("x.log");
         let file = OpenOptions::new()
@@ -147,6 +224,7 @@ impl KvsCore {
         let mut core = KvsCore {
             map: HashMap::new(),
             path,
+            watchers,
             operation_count: 0,
             offset: 0,
         };
@@ -155,6 +233,7 @@ impl KvsCore {
     }
 
     /// receive and handle message until channel closed
+    #[allow(clippy::map_flatten)]
     fn receive_channel_message(&mut self, rx: Receiver<ChannelMessage>) -> Result<()> {
         while let Ok(cm) = rx.recv() {
             log::info!("[receive_channel_message] recv: {:?}", cm.behavior);
@@ -163,28 +242,56 @@ impl KvsCore {
                     // TODO 将StoreValue::Memory转换成StoreValue::File
                     self.map.insert(key.to_owned(), StoreValue::Memory(value.to_owned()));
                     self.flush(&cm.behavior)?;
-                    cm.callback.send(None)?;
+                    cm.callback.send(Ok(None))?;
+                    self.watchers.notify(DEFAULT_NAMESPACE, EventKind::Set, key);
                 }
                 Behavior::Get { key } => {
                     let option = self.map.get(key).map(|sv| {
                         sv.to_value().ok()
                     }).flatten();
-                    cm.callback.send(option)?;
+                    cm.callback.send(Ok(option))?;
                 }
                 Behavior::Remove { key } => {
-                    let option = self.map.remove(key).map(|sv| {
+                    let removed = self.map.remove(key);
+                    let notify = removed.is_some();
+                    let option = removed.map(|sv| {
                         sv.to_value().ok()
                     }).flatten();
                     self.flush(&cm.behavior)?;
-                    cm.callback.send(option)?;
+                    cm.callback.send(Ok(option))?;
+                    if notify {
+                        self.watchers.notify(DEFAULT_NAMESPACE, EventKind::Remove, key);
+                    }
+                }
+                Behavior::DbSize => {
+                    cm.callback.send(Ok(Some(self.map.len().to_string())))?;
+                }
+                Behavior::FlushDb => {
+                    let keys: Vec<String> = self.map.drain().map(|(key, _)| key).collect();
+                    self.flush(&cm.behavior)?;
+                    cm.callback.send(Ok(None))?;
+                    for key in keys {
+                        self.watchers.notify(DEFAULT_NAMESPACE, EventKind::Remove, &key);
+                    }
+                }
+                Behavior::Shutdown => {
+                    self.sync()?;
+                    cm.callback.send(Ok(None))?;
+                    break;
+                }
+                behavior => {
+                    log::error!("[receive_channel_message] unsupported behavior: {:?}", behavior);
+                    cm.callback.send(Err(anyhow::anyhow!("unsupported behavior {:?}", behavior)))?;
                 }
             }
         }
         log::info!("[receive_channel_message] rx end");
+        self.watchers.clear();
         Ok(())
     }
 
 
+    #[allow(clippy::manual_is_multiple_of)]
     fn update_operation_count(&mut self) -> Result<()> {
         self.operation_count += 1;
         if self.operation_count % 2048 == 0 {
@@ -215,6 +322,7 @@ impl KvsCore {
         Ok(())
     }
     /// 从偏移值读取文件内容
+    #[allow(clippy::slow_vector_initialization)]
     fn read_file_offset(file: &mut File, offset: u64, size: usize) -> Result<Vec<u8>> {
         file.seek(SeekFrom::Start(offset))?;
         let mut buffer = Vec::with_capacity(size);
@@ -258,12 +366,22 @@ impl KvsCore {
             Behavior::Remove { key } => {
                 self.map.remove(&key);
             }
+            Behavior::FlushDb => {
+                self.map.clear();
+            }
             _ => {}
         }
         self.offset += len as u64 + 1;
         Ok(())
     }
 
+    /// sync the log file to disk
+    fn sync(&self) -> Result<()> {
+        OpenOptions::new().append(true).open(&self.path)?.sync_all()?;
+        log::info!("[KvsCore] log synced, path={:?}", self.path);
+        Ok(())
+    }
+
     fn flush(&mut self, behavior: &Behavior) -> Result<()> {
         let mut file = OpenOptions::new()
             .append(true)
@@ -0,0 +1,56 @@
+//! the bloom filter of an SSTable, skipping the tables without the key
+
+use std::convert::TryInto;
+
+use crate::hash::hash;
+
+/// a set of keys answering "maybe" or "no", "no" is always right
+pub(crate) struct Bloom {
+    bits: Vec<u8>,
+    /// the bits set for every key
+    hashes: u32,
+}
+
+impl Bloom {
+    /// the filter of `keys`, about 1% false positives with 10 `bits_per_key`
+    pub fn build<'a>(keys: impl ExactSizeIterator<Item = &'a str>, bits_per_key: usize) -> Self {
+        // k = ln 2 * m / n minimizes the false positives
+        let hashes = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let len = (keys.len() * bits_per_key).max(64).div_ceil(8);
+        let mut bloom = Bloom { bits: vec![0; len], hashes };
+        for key in keys {
+            for bit in bloom.positions(key) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    pub fn may_contain(&self, key: &str) -> bool {
+        self.positions(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    /// the bits of `key`, by double hashing the two halves of one 64 bits hash
+    fn positions(&self, key: &str) -> impl Iterator<Item = usize> {
+        let h = hash(key);
+        let (h1, h2) = (h as u32, (h >> 32) as u32);
+        let len = self.bits.len() as u64 * 8;
+        (0..self.hashes).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) as u64 % len) as usize)
+    }
+
+    /// hashes u32, then the bits
+    pub fn encode(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(&self.hashes.to_le_bytes());
+        buf.extend_from_slice(&self.bits);
+    }
+
+    /// `None` if `buf` is not a filter written by `encode`
+    pub fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.len() <= 4 {
+            return None;
+        }
+        let (hashes, bits) = buf.split_at(4);
+        let hashes = u32::from_le_bytes(hashes.try_into().ok()?);
+        Some(Bloom { bits: bits.to_vec(), hashes })
+    }
+}
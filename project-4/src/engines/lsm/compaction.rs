@@ -0,0 +1,157 @@
+//! the levels of the SSTables, merged down by leveled compaction
+//!
+//! level 0 holds the flushed memtables, their keys may overlap. the other levels hold tables sorted by the keys
+//! and not overlapping, every level is `level_multiplier` times larger than the one above. a compaction merges
+//! level 0, or one table of a level too large, with the overlapping tables of the next level
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::engines::lsm::manifest::{LiveCounts, Manifest};
+use crate::engines::lsm::sstable::{entry_len, SsTable, Value};
+use crate::engines::lsm::LsmOptions;
+use crate::Result;
+
+/// the number of the levels, the last one is never compacted
+pub(crate) const MAX_LEVELS: usize = 7;
+
+pub(crate) struct Levels {
+    /// level 0 from the oldest to the newest table, the other levels sorted by the keys
+    pub tables: Vec<Vec<Arc<SsTable>>>,
+    /// the last key compacted from every level, the next compaction of the level starts after it
+    pointers: Vec<Option<String>>,
+}
+
+/// merging the `inputs` of `level` into `level + 1`
+pub(crate) struct Compaction {
+    pub level: usize,
+    /// the tables of `level`, from the oldest to the newest
+    inputs: Vec<Arc<SsTable>>,
+    /// the tables of `level + 1` overlapping the inputs
+    overlapping: Vec<Arc<SsTable>>,
+    /// no deeper level holds keys, so the tombstones hide nothing and are dropped
+    bottom: bool,
+}
+
+impl Levels {
+    /// read the tables listed by the manifest in `dir`
+    pub fn open(dir: &Path, manifest: &Manifest) -> Result<Self> {
+        let mut tables = vec![Vec::new(); MAX_LEVELS];
+        for (level, ids) in manifest.levels.iter().enumerate().take(MAX_LEVELS) {
+            for id in ids {
+                tables[level].push(Arc::new(SsTable::open(dir, *id)?));
+            }
+        }
+        Ok(Levels { tables, pointers: vec![None; MAX_LEVELS] })
+    }
+
+    pub fn manifest(&self, next_id: u64, live: &LiveCounts) -> Manifest {
+        let levels = self.tables.iter().map(|level| level.iter().map(|table| table.id).collect()).collect();
+        Manifest { next_id, levels, live: Some(live.clone()) }
+    }
+
+    /// the newest entry of `key` in the tables, `Some(None)` if it is a tombstone
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        for table in self.tables[0].iter().rev() {
+            if let Some(value) = table.get(key)? {
+                return Ok(Some(value));
+            }
+        }
+        for level in &self.tables[1..] {
+            let i = level.partition_point(|table| table.last_key.as_str() < key);
+            if let Some(value) = level.get(i).map(|table| table.get(key)).transpose()?.flatten() {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// all tables from the oldest to the newest data, the deepest level first
+    pub fn oldest_first(&self) -> impl Iterator<Item = &Arc<SsTable>> {
+        self.tables.iter().rev().flatten()
+    }
+
+    pub fn add_level0(&mut self, table: SsTable) {
+        self.tables[0].push(Arc::new(table));
+    }
+
+    /// the compaction to run next, `None` if every level is within its size
+    pub fn pick(&mut self, options: &LsmOptions) -> Option<Compaction> {
+        if self.tables[0].len() >= options.level0_tables.max(1) {
+            let inputs = self.tables[0].clone();
+            return Some(self.compaction(0, inputs));
+        }
+        let mut max_bytes = options.level1_size;
+        for level in 1..MAX_LEVELS - 1 {
+            let tables = &self.tables[level];
+            if tables.iter().map(|table| table.size).sum::<u64>() > max_bytes {
+                // round robin over the keys, so every key is compacted down in turn
+                let i = match &self.pointers[level] {
+                    Some(pointer) => tables.iter().position(|table| table.first_key > *pointer).unwrap_or(0),
+                    None => 0,
+                };
+                let input = tables[i].clone();
+                self.pointers[level] = Some(input.last_key.clone());
+                return Some(self.compaction(level, vec![input]));
+            }
+            max_bytes = max_bytes.saturating_mul(options.level_multiplier.max(2));
+        }
+        None
+    }
+
+    fn compaction(&self, level: usize, inputs: Vec<Arc<SsTable>>) -> Compaction {
+        let first = inputs.iter().map(|table| table.first_key.as_str()).min().unwrap_or_default();
+        let last = inputs.iter().map(|table| table.last_key.as_str()).max().unwrap_or_default();
+        let overlapping = self.tables[level + 1].iter().filter(|table| table.overlaps(first, last)).cloned().collect();
+        let bottom = self.tables[level + 2..].iter().all(Vec::is_empty);
+        Compaction { level, inputs, overlapping, bottom }
+    }
+
+    /// replace the tables merged by `compaction` with `outputs`, return the replaced tables
+    pub fn apply(&mut self, compaction: &Compaction, outputs: Vec<Arc<SsTable>>) -> Vec<Arc<SsTable>> {
+        let is_input = |table: &Arc<SsTable>, inputs: &[Arc<SsTable>]| inputs.iter().any(|input| input.id == table.id);
+        self.tables[compaction.level].retain(|table| !is_input(table, &compaction.inputs));
+        let next = &mut self.tables[compaction.level + 1];
+        next.retain(|table| !is_input(table, &compaction.overlapping));
+        next.extend(outputs);
+        next.sort_by(|a, b| a.first_key.cmp(&b.first_key));
+        compaction.inputs.iter().chain(&compaction.overlapping).cloned().collect()
+    }
+}
+
+impl Compaction {
+    /// merge the tables into new tables of `options.table_size`, the newer entry of a key wins,
+    /// the ids are taken from `next_id`
+    pub fn run(&self, dir: &Path, next_id: &AtomicU64, options: &LsmOptions) -> Result<Vec<Arc<SsTable>>> {
+        let mut merged = BTreeMap::new();
+        // the next level is older than the inputs
+        for table in self.overlapping.iter().chain(&self.inputs) {
+            merged.extend(table.entries()?);
+        }
+        let entries: Vec<(String, Value)> =
+            merged.into_iter().filter(|(_, value)| !self.bottom || value.is_some()).collect();
+
+        let mut outputs = Vec::new();
+        let (mut start, mut bytes) = (0, 0);
+        for (i, (key, value)) in entries.iter().enumerate() {
+            bytes += entry_len(key, value) as u64;
+            if bytes >= options.table_size || i + 1 == entries.len() {
+                let id = next_id.fetch_add(1, Ordering::Relaxed);
+                let table = SsTable::write(dir, id, &entries[start..=i], options.block_size, options.bloom_bits_per_key)?;
+                outputs.push(Arc::new(table));
+                start = i + 1;
+                bytes = 0;
+            }
+        }
+        log::info!(
+            "compaction finished, level={}, inputs={}, overlapping={}, outputs={}",
+            self.level,
+            self.inputs.len(),
+            self.overlapping.len(),
+            outputs.len()
+        );
+        Ok(outputs)
+    }
+}
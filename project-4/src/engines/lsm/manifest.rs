@@ -0,0 +1,55 @@
+//! the SSTables of every level, rewritten after every flush and compaction
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+const MANIFEST_FILE: &str = "MANIFEST";
+
+/// the ids of the tables of every level, the files not listed are left by an unfinished flush or compaction
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// the id of the next table written
+    pub next_id: u64,
+    /// level 0 from the oldest to the newest table, the other levels sorted by the keys
+    pub levels: Vec<Vec<u64>>,
+    /// the live keys of the tables, `None` in a manifest written before they were counted
+    #[serde(default)]
+    pub live: Option<LiveCounts>,
+}
+
+/// the keys holding a value, counted by the writes so the size of the store is known without reading the tables
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub(crate) struct LiveCounts {
+    /// the live keys of every namespace having keys
+    pub keys: HashMap<String, usize>,
+    /// the bytes of the live entries in an SSTable
+    pub bytes: u64,
+}
+
+impl Manifest {
+    /// the manifest in `dir`, an empty one if the engine is new
+    pub fn load(dir: &Path) -> Result<Self> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::default());
+        }
+        let text = std::fs::read_to_string(&path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// replace the manifest in `dir` by a rename, so a crash leaves the old or the new one whole
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let tmp = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(self)?)?;
+        file.sync_all()?;
+        std::fs::rename(&tmp, dir.join(MANIFEST_FILE))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,498 @@
+//! kvs engine of a log-structured merge tree
+//!
+//! a write is appended to the write-ahead log and put in the sorted memtable. a full memtable is written to an
+//! SSTable of level 0 and the log is emptied, then the levels are merged down by leveled compaction on a background thread.
+//! a read looks in the memtable, then in the tables from the newest to the oldest,
+//! the bloom filter of a table skips it without reading the disk when it does not hold the key
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::thread::{self, JoinHandle};
+use std::time::SystemTime;
+
+use crossbeam::{Receiver, Sender};
+
+use crate::engines::lsm::compaction::Levels;
+use crate::engines::lsm::manifest::{LiveCounts, Manifest};
+use crate::engines::lsm::sstable::{entry_len, SsTable, Value};
+use crate::engines::lsm::wal::{Wal, WalRecord};
+use crate::engines::watch::{EventKind, KeyEvent, Watchers};
+use crate::engines::{
+    check_namespace, dir_size, Durability, EngineStats, KvsEngine, LogStats, SnapshotEntry, DEFAULT_NAMESPACE,
+};
+use crate::error::KvsError;
+use crate::Result;
+
+mod bloom;
+mod compaction;
+mod manifest;
+mod sstable;
+mod wal;
+
+/// the sizes of the memtable, the tables and the levels
+#[derive(Debug, Clone)]
+pub struct LsmOptions {
+    /// bytes, the memtable is written to an SSTable of level 0 when its entries reach the size
+    pub memtable_size: usize,
+    /// bytes, the size of a data block of an SSTable, a lookup reads one block
+    pub block_size: usize,
+    /// the bits of the bloom filter for every key of an SSTable
+    pub bloom_bits_per_key: usize,
+    /// level 0 is compacted into level 1 when it has the number of tables
+    pub level0_tables: usize,
+    /// bytes, level 1 is compacted into level 2 when it is larger than the size
+    pub level1_size: u64,
+    /// every level after level 1 is the times larger than the one above
+    pub level_multiplier: u64,
+    /// bytes, a compaction splits its output into tables of the size
+    pub table_size: u64,
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        LsmOptions {
+            memtable_size: 4 * 1024 * 1024,
+            block_size: 4096,
+            bloom_bits_per_key: 10,
+            level0_tables: 4,
+            level1_size: 16 * 1024 * 1024,
+            level_multiplier: 10,
+            table_size: 2 * 1024 * 1024,
+        }
+    }
+}
+
+/// store keys and values in a log-structured merge tree, the clones share the same store,
+/// a handle accesses the keys of its namespace only
+///
+/// the keys of all namespaces are in the same tables, stored as `namespace \0 key`,
+/// a namespace has no `\0` so its keys are together.
+/// the writes hold a lock for the flushes they trigger, the reads run in parallel.
+/// the compactions run on a background thread signalled after the flushes, without the lock,
+/// and swap the merged tables in when they finish
+#[derive(Clone)]
+pub struct LsmKvStore {
+    state: Arc<RwLock<LsmState>>,
+    namespace: String,
+    watchers: Watchers,
+    path: PathBuf,
+    options: Arc<LsmOptions>,
+    compactor: Arc<CompactorHandle>,
+}
+
+struct LsmState {
+    /// the writes since the last flush, `None` removes the key
+    memtable: BTreeMap<String, Value>,
+    /// the bytes of the memtable entries in an SSTable
+    memtable_bytes: usize,
+    wal: Wal,
+    levels: Levels,
+    next_id: Arc<AtomicU64>,
+    /// the live keys of the tables and the memtable, kept by every write,
+    /// see `put` for what it costs
+    live: LiveCounts,
+    /// the live keys at the last flush, those of the tables, listed by the manifest with them,
+    /// as the writes since are replayed from the log
+    flushed_live: LiveCounts,
+    compactions: u64,
+    last_compaction: Option<SystemTime>,
+}
+
+impl LsmKvStore {
+    /// Open the LsmKvStore at a given path
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_durability(path, Durability::Flush)
+    }
+
+    /// Open the LsmKvStore at a given path, sync every write to disk before return if `durability` is `Sync`
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
+        Self::open_with_options(path, durability, LsmOptions::default())
+    }
+
+    /// Open the LsmKvStore at a given path with the sizes of `options`,
+    /// the tables written with other options are read and compacted as they are
+    pub fn open_with_options(path: impl Into<PathBuf>, durability: Durability, options: LsmOptions) -> Result<Self> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        let manifest = Manifest::load(&path)?;
+        let levels = Levels::open(&path, &manifest)?;
+        remove_unlisted_tables(&path, &manifest)?;
+        let (wal, records) = Wal::open(&path, durability)?;
+
+        let next_id = Arc::new(AtomicU64::new(manifest.next_id));
+        let counted = manifest.live.is_some();
+        let mut state = LsmState {
+            memtable: BTreeMap::new(),
+            memtable_bytes: 0,
+            wal,
+            levels,
+            next_id: next_id.clone(),
+            live: manifest.live.unwrap_or_default(),
+            flushed_live: LiveCounts::default(),
+            compactions: 0,
+            last_compaction: None,
+        };
+        if !counted && state.levels.oldest_first().next().is_some() {
+            log::info!("counting the live keys of the tables, path={}", path.display());
+            for (key, value) in state.live("")? {
+                *state.live.keys.entry(split_key(&key).0.to_owned()).or_default() += 1;
+                state.live.bytes += entry_len(&key, &Some(value)) as u64;
+            }
+        }
+        state.flushed_live = state.live.clone();
+        log::info!("lsm store opened, path={}, replayed_writes={}", path.display(), records.len());
+        // the writes may be in the tables already if the engine stopped before the log was emptied,
+        // writing them again counts nothing twice
+        for record in records {
+            state.put(record.key, record.value)?;
+        }
+        state.maybe_flush(&path, &options)?;
+
+        let state = Arc::new(RwLock::new(state));
+        let options = Arc::new(options);
+        let compactor = Compactor { state: state.clone(), path: path.clone(), options: options.clone(), next_id };
+        let store = LsmKvStore {
+            state,
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            watchers: Watchers::default(),
+            path,
+            options,
+            compactor: Arc::new(CompactorHandle::spawn(compactor)),
+        };
+        // the tables left by the last run may call for compactions too
+        store.compactor.signal();
+        Ok(store)
+    }
+
+    fn read_state(&self) -> Result<RwLockReadGuard<'_, LsmState>> {
+        Ok(self.state.read().map_err(|e| {
+            log::error!("[lsm] hold read lock error, {}", e);
+            KvsError::Unknown
+        })?)
+    }
+
+    fn write_state(&self) -> Result<RwLockWriteGuard<'_, LsmState>> {
+        Ok(self.state.write().map_err(|e| {
+            log::error!("[lsm] hold write lock error, {}", e);
+            KvsError::Unknown
+        })?)
+    }
+
+    /// write `changes` of the keys of the namespace, notify the watchers, then flush the memtable if it is full,
+    /// the compactions the flush calls for run on the background thread
+    fn write(&self, mut state: RwLockWriteGuard<'_, LsmState>, changes: Vec<(String, Value)>) -> Result<()> {
+        let records: Vec<WalRecord> = changes
+            .iter()
+            .map(|(key, value)| WalRecord { key: internal_key(&self.namespace, key), value: value.clone() })
+            .collect();
+        state.wal.append(&records)?;
+        for record in records {
+            state.put(record.key, record.value)?;
+        }
+        for (key, value) in &changes {
+            let kind = if value.is_some() { EventKind::Set } else { EventKind::Remove };
+            self.watchers.notify(&self.namespace, kind, key);
+        }
+        if state.maybe_flush(&self.path, &self.options)? {
+            self.compactor.signal();
+        }
+        Ok(())
+    }
+}
+
+enum CompactorMessage {
+    /// a flush added a table, the levels may call for compactions
+    Compact,
+    /// reply once the compactions called for are done
+    Wait(Sender<()>),
+}
+
+/// the background thread of the compactions, it stops and is joined when the last handle of the store is dropped
+struct CompactorHandle {
+    tx: Option<Sender<CompactorMessage>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl CompactorHandle {
+    fn spawn(compactor: Compactor) -> Self {
+        let (tx, rx) = crossbeam::unbounded();
+        let thread = thread::spawn(move || compactor.receive(rx));
+        CompactorHandle { tx: Some(tx), thread: Some(thread) }
+    }
+
+    fn signal(&self) {
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(CompactorMessage::Compact);
+        }
+    }
+
+    /// wait for the compactions signalled so far
+    fn wait(&self) {
+        let (tx, rx) = crossbeam::bounded(1);
+        if self.tx.as_ref().is_some_and(|compactor| compactor.send(CompactorMessage::Wait(tx)).is_ok()) {
+            let _ = rx.recv();
+        }
+    }
+}
+
+impl Drop for CompactorHandle {
+    fn drop(&mut self) {
+        // the thread ends with the channel, after the compaction it runs
+        self.tx.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// runs the compactions one at a time, the state is locked only to pick a compaction
+/// and to swap its tables in, so the reads and the writes go on while the tables are merged
+struct Compactor {
+    state: Arc<RwLock<LsmState>>,
+    path: PathBuf,
+    options: Arc<LsmOptions>,
+    /// the id of the next table written, shared with the state, so a compaction takes the ids without the lock
+    next_id: Arc<AtomicU64>,
+}
+
+impl Compactor {
+    fn receive(&self, rx: Receiver<CompactorMessage>) {
+        while let Ok(message) = rx.recv() {
+            // the signals queued meanwhile are served by the same run
+            let mut waiting = Vec::new();
+            for message in std::iter::once(message).chain(rx.try_iter()) {
+                if let CompactorMessage::Wait(tx) = message {
+                    waiting.push(tx);
+                }
+            }
+            // the writes are already done, a failed compaction is logged and tried again after the next flush
+            if let Err(e) = self.compact_levels() {
+                log::error!("compaction failed, path={}, {}", self.path.display(), e);
+            }
+            waiting.into_iter().for_each(|tx| {
+                let _ = tx.send(());
+            });
+        }
+    }
+
+    fn compact_levels(&self) -> Result<()> {
+        loop {
+            let compaction = match self.write_state()?.levels.pick(&self.options) {
+                Some(compaction) => compaction,
+                None => return Ok(()),
+            };
+            let outputs = compaction.run(&self.path, &self.next_id, &self.options)?;
+            let replaced = {
+                let mut state = self.write_state()?;
+                let replaced = state.levels.apply(&compaction, outputs);
+                state.levels.manifest(self.next_id.load(Ordering::Relaxed), &state.flushed_live).save(&self.path)?;
+                state.compactions += 1;
+                state.last_compaction = Some(SystemTime::now());
+                replaced
+            };
+            // the reads hold the lock, none of them reads the replaced tables after the swap
+            replaced.iter().for_each(|table| table.delete());
+        }
+    }
+
+    fn write_state(&self) -> Result<RwLockWriteGuard<'_, LsmState>> {
+        Ok(self.state.write().map_err(|e| {
+            log::error!("[lsm] compactor hold write lock error, {}", e);
+            KvsError::Unknown
+        })?)
+    }
+}
+
+impl LsmState {
+    /// write `key` to the memtable, counting the keys it makes live or removes.
+    /// the count needs the old value, so a write of a key not in the memtable looks it up in the tables,
+    /// mostly skipped by the bloom filters but a block read on every level holding the key
+    fn put(&mut self, key: String, value: Value) -> Result<()> {
+        let old = self.get(&key)?;
+        let namespace = split_key(&key).0;
+        match (&old, &value) {
+            (None, Some(_)) => *self.live.keys.entry(namespace.to_owned()).or_default() += 1,
+            (Some(_), None) => {
+                if let Some(keys) = self.live.keys.get_mut(namespace) {
+                    *keys -= 1;
+                    if *keys == 0 {
+                        self.live.keys.remove(namespace);
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.live.bytes -= old.as_ref().map_or(0, |_| entry_len(&key, &old) as u64);
+        self.live.bytes += value.as_ref().map_or(0, |_| entry_len(&key, &value) as u64);
+        self.insert(key, value);
+        Ok(())
+    }
+
+    fn insert(&mut self, key: String, value: Value) {
+        if let Some(old) = self.memtable.get(&key) {
+            self.memtable_bytes -= entry_len(&key, old);
+        }
+        self.memtable_bytes += entry_len(&key, &value);
+        self.memtable.insert(key, value);
+    }
+
+    /// the value of `key` of the tables
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        if let Some(value) = self.memtable.get(key) {
+            return Ok(value.clone());
+        }
+        Ok(self.levels.get(key)?.flatten())
+    }
+
+    /// the current values of the keys starting with `prefix`
+    fn live(&self, prefix: &str) -> Result<BTreeMap<String, String>> {
+        let mut merged = BTreeMap::new();
+        for table in self.levels.oldest_first() {
+            let before = table.last_key.as_str() < prefix;
+            let after = table.first_key.as_str() > prefix && !table.first_key.starts_with(prefix);
+            if !before && !after {
+                merged.extend(table.entries()?.into_iter().filter(|(key, _)| key.starts_with(prefix)));
+            }
+        }
+        let memtable = self.memtable.range(prefix.to_owned()..).take_while(|(key, _)| key.starts_with(prefix));
+        merged.extend(memtable.map(|(key, value)| (key.clone(), value.clone())));
+        Ok(merged.into_iter().filter_map(|(key, value)| value.map(|value| (key, value))).collect())
+    }
+
+    /// write the memtable to an SSTable of level 0 if it is full, return whether it is written
+    fn maybe_flush(&mut self, dir: &Path, options: &LsmOptions) -> Result<bool> {
+        if self.memtable_bytes < options.memtable_size || self.memtable.is_empty() {
+            return Ok(false);
+        }
+        let entries: Vec<(String, Value)> = std::mem::take(&mut self.memtable).into_iter().collect();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let table = SsTable::write(dir, id, &entries, options.block_size, options.bloom_bits_per_key)?;
+        self.levels.add_level0(table);
+        self.flushed_live = self.live.clone();
+        // the log is emptied after the table is listed, a crash between them replays writes already in the table
+        self.levels.manifest(self.next_id.load(Ordering::Relaxed), &self.flushed_live).save(dir)?;
+        self.wal.reset()?;
+        self.memtable_bytes = 0;
+        log::debug!("memtable flushed, entries={}", entries.len());
+        Ok(true)
+    }
+}
+
+impl KvsEngine for LsmKvStore {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        let state = self.write_state()?;
+        self.write(state, vec![(key, Some(value))])
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.read_state()?.get(&internal_key(&self.namespace, &key))
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let state = self.write_state()?;
+        if state.get(&internal_key(&self.namespace, &key))?.is_none() {
+            Err(KvsError::KeyNotFound)?
+        }
+        self.write(state, vec![(key, None)])
+    }
+
+    fn engine_name(&self) -> String {
+        "lsm".to_owned()
+    }
+
+    fn select(&self, namespace: &str) -> Result<Self> {
+        check_namespace(namespace)?;
+        Ok(LsmKvStore { namespace: namespace.to_owned(), ..self.clone() })
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        Ok(self.read_state()?.live.keys.get(&self.namespace).copied().unwrap_or_default())
+    }
+
+    /// write a tombstone for every key of the namespace, the keys are read from the tables
+    fn flush_db(&self) -> Result<()> {
+        let state = self.write_state()?;
+        if !state.live.keys.contains_key(&self.namespace) {
+            return Ok(());
+        }
+        let prefix = namespace_prefix(&self.namespace);
+        let removed: Vec<(String, Value)> =
+            state.live(&prefix)?.into_keys().map(|key| (key[prefix.len()..].to_owned(), None)).collect();
+        self.write(state, removed)
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>> {
+        Ok(self.watchers.add(&self.namespace, prefix))
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let state = self.read_state()?;
+        let tables: Vec<_> = state.levels.oldest_first().collect();
+        let log = LogStats {
+            segments: tables.len() + 1,
+            bytes: state.wal.bytes + tables.iter().map(|table| table.size).sum::<u64>(),
+            live_bytes: state.live.bytes,
+            compactions: state.compactions,
+            last_compaction: state.last_compaction,
+        };
+        let keys = state.live.keys.values().sum();
+        Ok(EngineStats { keys, namespaces: state.live.keys.len(), disk_bytes: dir_size(&self.path)?, log: Some(log) })
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        Ok(self.read_state()?.live.keys.keys().cloned().collect())
+    }
+
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        let live = self.read_state()?.live("")?;
+        Ok(live
+            .into_iter()
+            .map(|(key, value)| {
+                let (namespace, key) = split_key(&key);
+                SnapshotEntry { namespace: namespace.to_owned(), key: key.to_owned(), value }
+            })
+            .collect())
+    }
+
+    /// wait for the running compactions, so the tables are all listed once it returns
+    fn close(&self) -> Result<()> {
+        self.compactor.wait();
+        self.write_state()?.wal.sync()?;
+        self.watchers.clear();
+        Ok(())
+    }
+}
+
+/// the key of the tables
+fn internal_key(namespace: &str, key: &str) -> String {
+    format!("{}\0{}", namespace, key)
+}
+
+/// the start of the keys of `namespace` in the tables
+fn namespace_prefix(namespace: &str) -> String {
+    format!("{}\0", namespace)
+}
+
+/// the namespace and the key of a key of the tables
+fn split_key(key: &str) -> (&str, &str) {
+    key.split_once('\0').unwrap_or((DEFAULT_NAMESPACE, key))
+}
+
+/// remove the tables left by a flush or a compaction unfinished when the engine stopped
+fn remove_unlisted_tables(dir: &Path, manifest: &Manifest) -> Result<()> {
+    let listed: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "sst") {
+            continue;
+        }
+        let id = path.file_stem().and_then(|stem| stem.to_str()).and_then(|stem| stem.parse::<u64>().ok());
+        if id.is_some_and(|id| !listed.contains(&id)) {
+            log::warn!("remove the unlisted table {}", path.display());
+            std::fs::remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+//! the immutable sorted tables on disk
+//!
+//! ```text
+//! | data block | .. | data block | index | bloom filter | footer |
+//! ```
+//!
+//! - an entry of a data block is `key_len u32 | key | value_len u32 | value`, a tombstone has the value_len
+//!   `TOMBSTONE` and no value, the entries of the table are sorted by the key
+//! - the index is `blocks u32 | first_key_len u32 | first_key`, then `last_key_len u32 | last_key | offset u64 | len u64`
+//!   for every block, a lookup reads the one block whose range may hold the key
+//! - the footer is `index_offset u64 | bloom_offset u64 | MAGIC u64`, all numbers are little endian
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::engines::lsm::bloom::Bloom;
+use crate::Result;
+
+/// "kvs_sst1"
+const MAGIC: u64 = 0x6b76_735f_7373_7431;
+const FOOTER_LEN: u64 = 24;
+const TOMBSTONE: u32 = u32::MAX;
+
+/// the value of an entry, `None` is a tombstone hiding the older values of the key
+pub(crate) type Value = Option<String>;
+
+/// the bytes of the entry in a data block
+pub(crate) fn entry_len(key: &str, value: &Value) -> usize {
+    8 + key.len() + value.as_ref().map_or(0, String::len)
+}
+
+/// the file of the table `id` in the directory of the engine
+pub(crate) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{:06}.sst", id))
+}
+
+struct BlockHandle {
+    last_key: String,
+    offset: u64,
+    len: u64,
+}
+
+/// an SSTable, its index and bloom filter are kept in memory, the blocks are read on demand
+pub(crate) struct SsTable {
+    pub id: u64,
+    path: PathBuf,
+    pub first_key: String,
+    pub last_key: String,
+    /// the size of the file
+    pub size: u64,
+    index: Vec<BlockHandle>,
+    bloom: Bloom,
+    /// where the index starts, the end of the data blocks
+    index_offset: u64,
+}
+
+impl SsTable {
+    /// write the sorted non-empty `entries` to the table `id` in `dir`, synced to disk before return
+    pub fn write(dir: &Path, id: u64, entries: &[(String, Value)], block_size: usize, bits_per_key: usize) -> Result<Self> {
+        assert!(!entries.is_empty(), "an SSTable holds one entry at least");
+        let path = table_path(dir, id);
+        let mut writer = BufWriter::new(File::create(&path)?);
+
+        let mut index = Vec::new();
+        let mut block = Vec::with_capacity(block_size);
+        let mut offset = 0;
+        for (i, (key, value)) in entries.iter().enumerate() {
+            put_str(&mut block, key);
+            match value {
+                Some(value) => put_str(&mut block, value),
+                None => block.extend_from_slice(&TOMBSTONE.to_le_bytes()),
+            }
+            if block.len() >= block_size || i + 1 == entries.len() {
+                writer.write_all(&block)?;
+                index.push(BlockHandle { last_key: key.clone(), offset, len: block.len() as u64 });
+                offset += block.len() as u64;
+                block.clear();
+            }
+        }
+
+        let first_key = entries[0].0.clone();
+        let index_offset = offset;
+        let mut buf = Vec::new();
+        buf.extend_from_slice(&(index.len() as u32).to_le_bytes());
+        put_str(&mut buf, &first_key);
+        for handle in &index {
+            put_str(&mut buf, &handle.last_key);
+            buf.extend_from_slice(&handle.offset.to_le_bytes());
+            buf.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let bloom_offset = index_offset + buf.len() as u64;
+        let bloom = Bloom::build(entries.iter().map(|(key, _)| key.as_str()), bits_per_key);
+        bloom.encode(&mut buf);
+        for n in [index_offset, bloom_offset, MAGIC] {
+            buf.extend_from_slice(&n.to_le_bytes());
+        }
+        writer.write_all(&buf)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+
+        let last_key = entries[entries.len() - 1].0.clone();
+        let size = index_offset + buf.len() as u64;
+        Ok(SsTable { id, path, first_key, last_key, size, index, bloom, index_offset })
+    }
+
+    /// read the index and the bloom filter of the table `id` in `dir`
+    pub fn open(dir: &Path, id: u64) -> Result<Self> {
+        let path = table_path(dir, id);
+        let mut file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN {
+            Err(corrupted(&path))?
+        }
+        let footer = read_at(&mut file, size - FOOTER_LEN, FOOTER_LEN)?;
+        let mut decoder = Decoder::new(&footer, &path);
+        let (index_offset, bloom_offset, magic) = (decoder.u64()?, decoder.u64()?, decoder.u64()?);
+        if magic != MAGIC || index_offset > bloom_offset || bloom_offset > size - FOOTER_LEN {
+            Err(corrupted(&path))?
+        }
+
+        let meta = read_at(&mut file, index_offset, size - FOOTER_LEN - index_offset)?;
+        let (index_buf, bloom_buf) = meta.split_at((bloom_offset - index_offset) as usize);
+        let mut decoder = Decoder::new(index_buf, &path);
+        let blocks = decoder.u32()?;
+        let first_key = decoder.string()?;
+        let mut index = Vec::with_capacity(blocks as usize);
+        for _ in 0..blocks {
+            index.push(BlockHandle { last_key: decoder.string()?, offset: decoder.u64()?, len: decoder.u64()? });
+        }
+        let bloom = Bloom::decode(bloom_buf).ok_or_else(|| corrupted(&path))?;
+        let last_key = index.last().ok_or_else(|| corrupted(&path))?.last_key.clone();
+        Ok(SsTable { id, path, first_key, last_key, size, index, bloom, index_offset })
+    }
+
+    /// `None` if the table has no entry of `key`, `Some(None)` if it has a tombstone
+    pub fn get(&self, key: &str) -> Result<Option<Value>> {
+        if key < self.first_key.as_str() || key > self.last_key.as_str() || !self.bloom.may_contain(key) {
+            return Ok(None);
+        }
+        // the first block ending at or after the key
+        let i = self.index.partition_point(|handle| handle.last_key.as_str() < key);
+        let handle = &self.index[i];
+        let mut file = File::open(&self.path)?;
+        let block = read_at(&mut file, handle.offset, handle.len)?;
+        let mut decoder = Decoder::new(&block, &self.path);
+        while !decoder.is_empty() {
+            let (entry_key, value) = decoder.entry()?;
+            if entry_key == key {
+                return Ok(Some(value));
+            }
+        }
+        Ok(None)
+    }
+
+    /// all entries of the table, sorted by the key
+    pub fn entries(&self) -> Result<Vec<(String, Value)>> {
+        let mut file = File::open(&self.path)?;
+        let data = read_at(&mut file, 0, self.index_offset)?;
+        let mut decoder = Decoder::new(&data, &self.path);
+        let mut entries = Vec::new();
+        while !decoder.is_empty() {
+            entries.push(decoder.entry()?);
+        }
+        Ok(entries)
+    }
+
+    /// whether the table may hold keys in `first..=last`
+    pub fn overlaps(&self, first: &str, last: &str) -> bool {
+        self.first_key.as_str() <= last && first <= self.last_key.as_str()
+    }
+
+    /// remove the file, after the table is replaced by a compaction
+    pub fn delete(&self) {
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("remove the compacted table failed, path={}, {}", self.path.display(), e);
+        }
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn read_at(file: &mut File, offset: u64, len: u64) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![0; len as usize];
+    file.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn corrupted(path: &Path) -> anyhow::Error {
+    anyhow::anyhow!("the SSTable {} is corrupted", path.display())
+}
+
+/// read the numbers and the strings of a table in order
+struct Decoder<'a> {
+    buf: &'a [u8],
+    path: &'a Path,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buf: &'a [u8], path: &'a Path) -> Self {
+        Decoder { buf, path }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            Err(corrupted(self.path))?
+        }
+        let (head, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?))
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into()?))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        let len = self.u32()? as usize;
+        Ok(String::from_utf8(self.take(len)?.to_vec())?)
+    }
+
+    fn entry(&mut self) -> Result<(String, Value)> {
+        let key = self.string()?;
+        let value = match self.u32()? {
+            TOMBSTONE => None,
+            len => Some(String::from_utf8(self.take(len as usize)?.to_vec())?),
+        };
+        Ok((key, value))
+    }
+}
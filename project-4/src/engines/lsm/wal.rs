@@ -0,0 +1,90 @@
+//! the write-ahead log of the memtable, replayed when the engine opens
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engines::lsm::sstable::Value;
+use crate::engines::Durability;
+use crate::Result;
+
+/// a write of the memtable, a line of JSON in the log
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct WalRecord {
+    /// the namespace and the key, see `internal_key`
+    pub key: String,
+    /// `None` removes the key
+    pub value: Value,
+}
+
+/// the log of the writes not yet in an SSTable, emptied when the memtable is written to one
+pub(crate) struct Wal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    durability: Durability,
+    /// the size of the log
+    pub bytes: u64,
+}
+
+impl Wal {
+    /// open the log `wal.log` in `dir`, return it with the records written before
+    pub fn open(dir: &Path, durability: Durability) -> Result<(Self, Vec<WalRecord>)> {
+        let path = dir.join("wal.log");
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+        let mut records = Vec::new();
+        let mut bytes = 0;
+        for line in BufReader::new(&mut file).split(b'\n') {
+            let line = line?;
+            match serde_json::from_slice::<WalRecord>(&line) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    // the last line may be cut by a crash, it was never acknowledged
+                    log::warn!("the write-ahead log ends at a broken record, path={}, {}", path.display(), e);
+                    break;
+                }
+            }
+            bytes += line.len() as u64 + 1;
+        }
+        // drop the broken tail, the next records follow the good ones
+        file.set_len(bytes)?;
+        file.seek(SeekFrom::End(0))?;
+        let wal = Wal { path, writer: BufWriter::new(file), durability, bytes };
+        Ok((wal, records))
+    }
+
+    /// write `records` to the log, handed to the OS, or synced to disk with `Durability::Sync`
+    pub fn append(&mut self, records: &[WalRecord]) -> Result<()> {
+        for record in records {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.writer.write_all(&line)?;
+            self.bytes += line.len() as u64;
+        }
+        self.writer.flush()?;
+        if self.durability == Durability::Sync {
+            self.writer.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// empty the log, after its records are written to an SSTable
+    pub fn reset(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        let file = self.writer.get_mut();
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.sync_all()?;
+        self.bytes = 0;
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data().map_err(|e| {
+            log::error!("sync the write-ahead log failed, path={}, {}", self.path.display(), e);
+            e.into()
+        })
+    }
+}
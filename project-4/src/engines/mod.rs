@@ -1,9 +1,21 @@
 //! kvs engine
 
+use std::path::Path;
+use std::time::SystemTime;
+
+use crossbeam::Receiver;
+use serde::{Deserialize, Serialize};
+
+use crate::engines::watch::KeyEvent;
+use crate::error::KvsError;
 use crate::Result;
 pub mod kvs;
 pub mod kvs_single_channel;
 pub mod kvs_rw_channel;
+pub mod kvs_sharded;
+pub mod lsm;
+pub mod sled;
+pub mod watch;
 
 /// defines the storage interface called by KvsServer
 pub trait KvsEngine: Clone + Send + 'static{
@@ -21,4 +33,127 @@ pub trait KvsEngine: Clone + Send + 'static{
 
     /// The engine name
     fn engine_name(&self) -> String;
-}
\ No newline at end of file
+
+    /// A handle of the same engine on `namespace`, every namespace has its own keys.
+    /// The engine is opened on `DEFAULT_NAMESPACE`, return an error if the name is invalid.
+    fn select(&self, namespace: &str) -> Result<Self>;
+
+    /// The number of keys in the namespace of the handle.
+    fn db_size(&self) -> Result<usize>;
+
+    /// Remove all keys in the namespace of the handle, other namespaces are not affected.
+    fn flush_db(&self) -> Result<()>;
+
+    /// Receive the changes of the keys starting with `prefix` in the namespace of the handle,
+    /// from the writes after the call. Drop the receiver to stop watching.
+    /// The receiver is disconnected if it falls `WATCH_QUEUE_CAPACITY` events behind, or the engine closes.
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>>;
+
+    /// The statistics of the whole engine, all namespaces included.
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// The names of the namespaces having keys, in no particular order, without reading the keys.
+    fn namespaces(&self) -> Result<Vec<String>>;
+
+    /// The current keys and values of all namespaces, in no particular order.
+    /// It includes the writes returned before the call, the writes running meanwhile may be missed.
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>>;
+
+    /// Flush the written data to disk and stop the background work of the engine.
+    /// The engine, including its clones, should not be used after it.
+    fn close(&self) -> Result<()>;
+}
+
+/// the statistics of an engine, see `KvsEngine::stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct EngineStats {
+    /// the keys of all namespaces
+    pub keys: usize,
+    /// the namespaces having keys
+    pub namespaces: usize,
+    /// the size of the files of the engine
+    pub disk_bytes: u64,
+    /// `None` if the engine keeps no log of its own, like sled
+    pub log: Option<LogStats>,
+}
+
+/// the log of the writes, compacted now and then
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LogStats {
+    /// the number of the log files
+    pub segments: usize,
+    /// the size of the log files
+    pub bytes: u64,
+    /// the bytes of the records of the current values, the others are dropped by the next compaction
+    pub live_bytes: u64,
+    /// the compactions since the engine is opened
+    pub compactions: u64,
+    /// when the last compaction finished, `None` if there is none since the engine is opened
+    pub last_compaction: Option<SystemTime>,
+}
+
+/// a live key of `KvsEngine::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SnapshotEntry {
+    #[allow(missing_docs)]
+    pub namespace: String,
+    #[allow(missing_docs)]
+    pub key: String,
+    #[allow(missing_docs)]
+    pub value: String,
+}
+
+/// the total size of the files under `path`
+pub(crate) fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = match entry.metadata() {
+            Ok(metadata) => metadata,
+            // removed by a compaction running meanwhile
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// when the written data reaches the disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Durability {
+    /// hand the data to the OS before the reply, sync it to disk in background or when the engine closes
+    Flush,
+    /// sync the data to disk before the reply
+    Sync,
+}
+
+impl std::str::FromStr for Durability {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "flush" => Ok(Durability::Flush),
+            "sync" => Ok(Durability::Sync),
+            _ => Err(anyhow::anyhow!("unknown durability {:?}, accept flush or sync", s)),
+        }
+    }
+}
+
+/// the namespace of a newly opened engine, and of the data written before namespaces existed
+pub const DEFAULT_NAMESPACE: &str = "0";
+
+/// the max length of a namespace name
+pub const MAX_NAMESPACE_LEN: usize = 64;
+
+/// a namespace is a number like "1" or a name like "orders", made of ASCII letters, digits, `_`, `-`, `.` and `:`
+pub fn check_namespace(namespace: &str) -> Result<()> {
+    let valid = !namespace.is_empty()
+        && namespace.len() <= MAX_NAMESPACE_LEN
+        && namespace.chars().all(|c| c.is_ascii_alphanumeric() || "_-.:".contains(c));
+    if !valid {
+        Err(KvsError::InvalidNamespace(namespace.to_owned()))?
+    }
+    Ok(())
+}
@@ -0,0 +1,176 @@
+//! wrap sled as kvs engine
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crossbeam::Receiver;
+use sled::{Db, Event, Tree};
+
+use crate::engines::watch::{EventKind, KeyEvent, Watchers};
+use crate::engines::{check_namespace, Durability, EngineStats, KvsEngine, SnapshotEntry, DEFAULT_NAMESPACE};
+use crate::error::KvsError;
+use crate::Result;
+
+/// store keys and values, `sled::Db` is thread-safe so the clones share the same db
+///
+/// every namespace is a `Tree` of the db, `DEFAULT_NAMESPACE` is the default tree
+///
+/// the changes of a watched namespace are read from `Tree::watch_prefix` by a thread,
+/// which numbers them and hands them to the receivers, sled reports no change for a set to the same value
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    tree: Tree,
+    namespace: String,
+    durability: Durability,
+    watchers: Watchers,
+    /// the namespaces having a thread reading the changes
+    watched: Arc<Mutex<HashSet<String>>>,
+}
+
+impl SledKvsEngine {
+    /// Open the SledKvsEngine at a given path, every write is synced to disk before return
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        Self::open_with_durability(path, Durability::Sync)
+    }
+
+    /// Open the SledKvsEngine at a given path, with `Durability::Flush` sled syncs the writes in background
+    pub fn open_with_durability(path: impl Into<PathBuf>, durability: Durability) -> Result<Self> {
+        let db = sled::open(path.into())?;
+        let tree = (*db).clone();
+        Ok(Self {
+            db,
+            tree,
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            durability,
+            watchers: Watchers::default(),
+            watched: Arc::default(),
+        })
+    }
+
+    /// the default tree and the trees of the other namespaces, with the names of the namespaces
+    fn namespace_trees(&self) -> Result<Vec<(String, Tree)>> {
+        let default_tree: &Tree = &self.db;
+        let mut trees = vec![(DEFAULT_NAMESPACE.to_owned(), default_tree.clone())];
+        for name in self.db.tree_names() {
+            if let Some(namespace) = name.strip_prefix(b"kvs:") {
+                trees.push((String::from_utf8(namespace.to_vec())?, self.db.open_tree(&name)?));
+            }
+        }
+        Ok(trees)
+    }
+
+    fn sync_write(&self) -> Result<()> {
+        if self.durability == Durability::Sync {
+            self.db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.tree.insert(key, value.as_bytes())?;
+        self.sync_write()?;
+        Ok(())
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        let rs = self.tree
+            .get(key)?
+            .map(|ivec| ivec.to_vec());
+
+        if let Some(bytes) = rs {
+            return Ok(Some(String::from_utf8(bytes)?));
+        }
+        Ok(None)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        let option = self.tree.remove(key)?;
+        self.sync_write()?;
+        option.map(|_| ()).ok_or_else(|| KvsError::KeyNotFound.into())
+    }
+
+    fn engine_name(&self) -> String {
+        "sled".to_owned()
+    }
+
+    fn select(&self, namespace: &str) -> Result<Self> {
+        check_namespace(namespace)?;
+        let tree = if namespace == DEFAULT_NAMESPACE {
+            (*self.db).clone()
+        } else {
+            // prefixed not to clash with the trees of sled itself
+            self.db.open_tree(format!("kvs:{}", namespace))?
+        };
+        Ok(Self { tree, namespace: namespace.to_owned(), ..self.clone() })
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        Ok(self.tree.len())
+    }
+
+    fn flush_db(&self) -> Result<()> {
+        self.tree.clear()?;
+        self.sync_write()?;
+        Ok(())
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>> {
+        let rx = self.watchers.add(&self.namespace, prefix);
+        if self.watched.lock().unwrap().insert(self.namespace.clone()) {
+            // ends when the db is dropped
+            let subscriber = self.tree.watch_prefix(vec![]);
+            let watchers = self.watchers.clone();
+            let namespace = self.namespace.clone();
+            thread::spawn(move || {
+                for event in subscriber {
+                    let (kind, key) = match &event {
+                        Event::Insert { key, .. } => (EventKind::Set, key),
+                        Event::Remove { key } => (EventKind::Remove, key),
+                    };
+                    watchers.notify(&namespace, kind, &String::from_utf8_lossy(key));
+                }
+            });
+        }
+        Ok(rx)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats { disk_bytes: self.db.size_on_disk()?, ..EngineStats::default() };
+        for (_, tree) in self.namespace_trees()? {
+            let keys = tree.len();
+            stats.keys += keys;
+            stats.namespaces += usize::from(keys > 0);
+        }
+        Ok(stats)
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        let trees = self.namespace_trees()?;
+        Ok(trees.into_iter().filter(|(_, tree)| !tree.is_empty()).map(|(namespace, _)| namespace).collect())
+    }
+
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        let mut entries = Vec::new();
+        for (namespace, tree) in self.namespace_trees()? {
+            for item in tree.iter() {
+                let (key, value) = item?;
+                entries.push(SnapshotEntry {
+                    namespace: namespace.clone(),
+                    key: String::from_utf8(key.to_vec())?,
+                    value: String::from_utf8(value.to_vec())?,
+                });
+            }
+        }
+        Ok(entries)
+    }
+
+    fn close(&self) -> Result<()> {
+        self.db.flush()?;
+        self.watchers.clear();
+        Ok(())
+    }
+}
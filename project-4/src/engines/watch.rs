@@ -0,0 +1,94 @@
+//! the change events of the keys, delivered to the receivers of `KvsEngine::watch`
+
+use std::sync::{Arc, Mutex};
+
+use crossbeam::{Receiver, Sender, TrySendError};
+
+/// the max number of events queued for a receiver,
+/// a receiver falling behind further is removed, it sees the channel disconnected after the queued events
+pub const WATCH_QUEUE_CAPACITY: usize = 4096;
+
+/// how a key is changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    /// the key is set to a value
+    Set,
+    /// the key is removed, by `remove` or `flush_db`
+    Remove,
+}
+
+impl EventKind {
+    /// "set" or "remove"
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Set => "set",
+            EventKind::Remove => "remove",
+        }
+    }
+}
+
+/// a change of a key
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    #[allow(missing_docs)]
+    pub kind: EventKind,
+    #[allow(missing_docs)]
+    pub key: String,
+    /// increases by one with every event of the engine since it is opened,
+    /// the events of other namespaces and prefixes take the numbers between
+    pub seq: u64,
+}
+
+struct Watcher {
+    namespace: String,
+    prefix: String,
+    tx: Sender<KeyEvent>,
+}
+
+#[derive(Default)]
+struct WatcherList {
+    seq: u64,
+    watchers: Vec<Watcher>,
+}
+
+/// the receivers of the events of an engine, shared by its clones
+#[derive(Clone, Default)]
+pub(crate) struct Watchers {
+    inner: Arc<Mutex<WatcherList>>,
+}
+
+impl Watchers {
+    /// receive the events of the keys starting with `prefix` in `namespace`
+    pub fn add(&self, namespace: &str, prefix: &str) -> Receiver<KeyEvent> {
+        let (tx, rx) = crossbeam::bounded(WATCH_QUEUE_CAPACITY);
+        let watcher = Watcher { namespace: namespace.to_owned(), prefix: prefix.to_owned(), tx };
+        self.inner.lock().unwrap().watchers.push(watcher);
+        rx
+    }
+
+    /// remove all receivers, they see the channels disconnected after the queued events
+    pub fn clear(&self) {
+        self.inner.lock().unwrap().watchers.clear();
+    }
+
+    /// number the event and send it to the matching receivers,
+    /// the dropped or full receivers are removed
+    pub fn notify(&self, namespace: &str, kind: EventKind, key: &str) {
+        let mut list = self.inner.lock().unwrap();
+        list.seq += 1;
+        let event = KeyEvent { kind, key: key.to_owned(), seq: list.seq };
+        list.watchers.retain(|watcher| {
+            if watcher.namespace != namespace || !key.starts_with(&watcher.prefix) {
+                return true;
+            }
+            match watcher.tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("watcher removed, prefix={:?}, {} events queued", watcher.prefix, WATCH_QUEUE_CAPACITY);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            }
+        });
+    }
+}
@@ -9,10 +9,68 @@ pub enum KvsError {
     Unknown,
     #[error("Key not found")]
     KeyNotFound,
-    #[error("Invalid IP address format, accept IP:PORT")]
+    #[error("Invalid address format, accept IP:PORT or unix://PATH")]
     InvalidIPAddressFormat,
     #[error("Invalid argument number")]
     InvalidArgumentNumber,
+    #[error("ERR value is not an integer or out of range")]
+    NotInteger,
     #[error("Wrong engine, expect {expect:?}, actual {actual:?}")]
     WrongEngine { expect: String, actual: String },
+    #[error("Wrong number of shards, expect {expect}, actual {actual}")]
+    WrongShards { expect: usize, actual: usize },
+    #[error("Unsupported engine {0:?}, accept kvs, lsm, sharded or sled")]
+    UnsupportedEngine(String),
+    #[error("NOPROTO unsupported protocol version {0}")]
+    UnsupportedProtocol(String),
+    #[error("Invalid message, {0}")]
+    InvalidMsg(String),
+    #[error("Protocol limit exceeded, {0}")]
+    LimitExceeded(String),
+    #[error("ERR max number of clients reached")]
+    MaxClientsReached,
+    #[error("Invalid config, {0}")]
+    InvalidConfig(String),
+    #[error("Invalid TLS config, {0}")]
+    InvalidTls(String),
+    #[error("ERR invalid namespace {0:?}")]
+    InvalidNamespace(String),
+    #[error("ERR the engine supports only the default namespace")]
+    NamespacesUnsupported,
+    #[error("ERR Can't execute '{0}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / WATCH / UNWATCH are allowed in this context")]
+    SubscribedContext(String),
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnly,
+    #[error("NOTLEADER the leader is {0}")]
+    NotLeader(String),
+    #[error("CLUSTERDOWN no leader is elected")]
+    NoLeader,
+    #[error("TRYAGAIN the leader changed or the request timed out, the write may or may not be applied")]
+    TryAgain,
+    #[error("ERR a membership change is not committed yet")]
+    MembershipChanging,
+    #[error("ERR the raft node is stopped")]
+    RaftStopped,
+    #[error("ERR the server is not in cluster mode")]
+    ClusterDisabled,
+    #[error("ERR backend {addr} is unreachable, {reason}")]
+    BackendUnreachable { addr: String, reason: String },
+    #[error("ERR backend {0} is already in the ring")]
+    BackendExists(String),
+    #[error("ERR the proxy does not support '{0}'")]
+    ProxyUnsupported(String),
+    #[error("subscriber removed, the pushed messages are not read fast enough")]
+    SlowSubscriber,
+    #[error("Invalid ACL, {0}")]
+    InvalidAcl(String),
+    #[error("NOAUTH Authentication required.")]
+    NoAuth,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("ERR AUTH called without any password configured")]
+    AuthNotConfigured,
+    #[error("NOPERM User {user} has no permissions to run the '{command}' command")]
+    NoPermCommand { user: String, command: String },
+    #[error("NOPERM User {user} has no permissions to access the '{key}' key")]
+    NoPermKey { user: String, key: String },
 }
@@ -0,0 +1,16 @@
+//! the hash of the keys shared by the shards, the bloom filters and the proxy ring
+
+/// FNV-1a, then the finalizer of MurmurHash3 to spread the similar keys,
+/// stable across the processes and the versions unlike `DefaultHasher`
+pub(crate) fn hash(key: &str) -> u64 {
+    let mut h: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in key.bytes() {
+        h ^= byte as u64;
+        h = h.wrapping_mul(0x0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
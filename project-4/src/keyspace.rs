@@ -0,0 +1,83 @@
+//! the key changes streamed to a session by `WATCH`
+
+use std::thread;
+
+use crossbeam::{Receiver, Select};
+use tokio::sync::mpsc::{self, error::TrySendError};
+
+use crate::engines::watch::KeyEvent;
+use crate::model::Msg;
+use crate::pubsub::PUSH_QUEUE_CAPACITY;
+
+/// the prefixes watched by a session, a thread forwards their events as pushes like
+/// `["keyevent", "set", "user:1", 42]`, the last is the sequence number of the event
+///
+/// the thread stops when the `KeyWatch` is dropped, the session falls `PUSH_QUEUE_CAPACITY` pushes behind,
+/// or the engine drops a receiver, then `pushes` is closed
+pub(crate) struct KeyWatch {
+    prefixes: Vec<String>,
+    added: crossbeam::Sender<Receiver<KeyEvent>>,
+    pushes: mpsc::Receiver<Msg>,
+}
+
+impl KeyWatch {
+    pub fn new() -> Self {
+        let (added, rx_added) = crossbeam::unbounded();
+        let (tx, pushes) = mpsc::channel(PUSH_QUEUE_CAPACITY);
+        thread::spawn(move || forward(rx_added, tx));
+        KeyWatch { prefixes: Vec::new(), added, pushes }
+    }
+
+    /// forward the events of `prefix` too, return the number of the watched prefixes
+    pub fn add(&mut self, prefix: String, events: Receiver<KeyEvent>) -> usize {
+        // the thread has stopped if it fails, `pushes` tells it
+        let _ = self.added.send(events);
+        self.prefixes.push(prefix);
+        self.prefixes.len()
+    }
+
+    pub fn pushes(&mut self) -> &mut mpsc::Receiver<Msg> {
+        &mut self.pushes
+    }
+}
+
+fn forward(added: Receiver<Receiver<KeyEvent>>, tx: mpsc::Sender<Msg>) {
+    let mut receivers: Vec<Receiver<KeyEvent>> = Vec::new();
+    loop {
+        let mut select = Select::new();
+        select.recv(&added);
+        for rx in &receivers {
+            select.recv(rx);
+        }
+        let operation = select.select();
+        let index = operation.index();
+        if index == 0 {
+            match operation.recv(&added) {
+                Ok(rx) => receivers.push(rx),
+                // the session unwatched or ended
+                Err(_) => return,
+            }
+            continue;
+        }
+        let event = match operation.recv(&receivers[index - 1]) {
+            Ok(event) => event,
+            Err(_) => {
+                log::warn!("key events stopped by the engine");
+                return;
+            }
+        };
+        match tx.try_send(key_event_push(event)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                log::warn!("key watcher removed, {} events queued", PUSH_QUEUE_CAPACITY);
+                return;
+            }
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+fn key_event_push(event: KeyEvent) -> Msg {
+    let bulk = |s: &str| Msg::Bulk(Some(s.to_owned()));
+    Msg::Push(vec![bulk("keyevent"), bulk(event.kind.name()), bulk(&event.key), Msg::Integer(event.seq as i64)])
+}
@@ -8,8 +8,25 @@ pub mod model;
 pub mod logger;
 pub mod engines;
 pub mod server;
+pub mod async_server;
+pub mod codec;
+pub mod net;
+pub mod tls;
+pub mod acl;
+pub mod shutdown;
 pub mod client;
+pub mod config;
+pub mod metrics;
+pub mod slowlog;
+pub mod replication;
+pub mod raft;
+pub mod proxy;
+pub mod trace;
 pub mod thread_pool;
+mod hash;
+mod keyspace;
+mod pubsub;
+mod session;
 
 /// simply type
 pub type Result<T> = std::result::Result<T, anyhow::Error>;
@@ -1,24 +1,301 @@
 //! logger config
+//!
+//! the records are written as text lines or JSON objects, to stderr or a file rotated by size or age.
+//! the fields of `with_fields` and the key-values of the record, like `log::debug!(command = "get"; "done")`,
+//! are appended to the text lines as `key=value`, or are the members of the JSON objects.
+//! every request is logged at info level with the target `REQUEST_TARGET`, with its duration in `duration_us`,
+//! e.g. the filter "info,kvs::request=warn" leaves them out
 
-#[allow(missing_docs)]
+use std::cell::RefCell;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use chrono::{Local, SecondsFormat, Utc};
+use env_logger::filter::{self, Filter};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Log, Metadata, Record};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::Result;
+
+/// the target of the record of every request, to filter them apart from the other records
+pub const REQUEST_TARGET: &str = "kvs::request";
+
+/// how a record is written
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// `2020-01-01 12:00:00 INFO [kvs::server] message key=value`
+    Text,
+    /// `{"timestamp":"2020-01-01T12:00:00.000Z","level":"INFO","module":"kvs::server","message":"message","key":"value"}`
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!("unknown log format {:?}, accept text or json", s)),
+        }
+    }
+}
+
+/// the filter, the format and the destination of the records
+#[derive(Debug, Clone)]
+pub struct LogConfig {
+    /// the filter, e.g. "info" or "kvs=debug", `None` means `RUST_LOG`, or "info" if it is not set
+    pub level: Option<String>,
+    #[allow(missing_docs)]
+    pub format: LogFormat,
+    /// write to the file instead of stderr
+    pub file: Option<PathBuf>,
+    /// rotate the file when it reaches the size in bytes
+    pub max_size: Option<u64>,
+    /// rotate the file when it is written for the duration
+    pub rotate_interval: Option<Duration>,
+    /// the number of the rotated files kept, `FILE.1` is the newest
+    pub max_files: usize,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: None,
+            format: LogFormat::Text,
+            file: None,
+            max_size: None,
+            rotate_interval: None,
+            max_files: 5,
+        }
+    }
+}
+
+/// init the logger with the filter of `RUST_LOG`, "info" if it is not set
 pub fn init_logger() {
-    use chrono::Local;
-    use std::io::Write;
-
-    let env = env_logger::Env::default()
-        .filter_or(env_logger::DEFAULT_FILTER_ENV, "info");
-    // 设置日志打印格式
-    env_logger::Builder::from_env(env)
-        .format(|buf, record| {
-            writeln!(
-                buf,
-                "{} {} [{}] {}",
-                Local::now().format("%Y-%m-%d %H:%M:%S"),
-                buf.default_styled_level(record.level()),
-                record.module_path().unwrap_or("<unnamed>"),
-                &record.args()
-            )
-        })
-        .init();
-    // info!("env_logger initialized.");
-}
\ No newline at end of file
+    let _ = init_logger_with_config(&LogConfig::default());
+}
+
+/// init the logger with the filter `level`, e.g. "info" or "kvs=debug", it overrides `RUST_LOG`
+pub fn init_logger_with_level(level: &str) {
+    let _ = init_logger_with_config(&LogConfig { level: Some(level.to_owned()), ..LogConfig::default() });
+}
+
+/// init the logger by `config`, its level overrides `RUST_LOG`, return an error if the log file can not be opened
+pub fn init_logger_with_config(config: &LogConfig) -> Result<()> {
+    let filters = match &config.level {
+        Some(level) => level.clone(),
+        None => std::env::var(env_logger::DEFAULT_FILTER_ENV).unwrap_or_else(|_| "info".to_owned()),
+    };
+    let filter = filter::Builder::new().parse(&filters).build();
+    let output = match &config.file {
+        Some(path) => Output::File(RotatingFile::open(
+            path.clone(),
+            config.max_size,
+            config.rotate_interval,
+            config.max_files,
+        )?),
+        None => Output::Stderr,
+    };
+    install(filter, config.format, output)
+}
+
+fn install(filter: Filter, format: LogFormat, output: Output) -> Result<()> {
+    let max_level = filter.filter();
+    log::set_boxed_logger(Box::new(KvsLogger { filter, format, output: Mutex::new(output) }))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(&'static str, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// run `f` with `fields` added to the records logged by the current thread, like the peer address of a request
+pub fn with_fields<R>(fields: Vec<(&'static str, String)>, f: impl FnOnce() -> R) -> R {
+    struct Restore(usize);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            FIELDS.with(|stack| stack.borrow_mut().truncate(self.0));
+        }
+    }
+
+    let _restore = FIELDS.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        let len = stack.len();
+        stack.extend(fields);
+        Restore(len)
+    });
+    f()
+}
+
+struct KvsLogger {
+    filter: Filter,
+    format: LogFormat,
+    output: Mutex<Output>,
+}
+
+impl Log for KvsLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.filter.matches(record) {
+            return;
+        }
+        let mut line = match self.format {
+            LogFormat::Text => text_line(record),
+            LogFormat::Json => json_line(record),
+        };
+        line.push('\n');
+        let mut output = self.output.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = output.write_line(&line) {
+            eprintln!("log write error, {}", e);
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(file) = &mut *self.output.lock().unwrap_or_else(|e| e.into_inner()) {
+            let _ = file.file.flush();
+        }
+    }
+}
+
+/// the fields of `with_fields`, then the key-values of `record`
+fn fields(record: &Record) -> Vec<(String, JsonValue)> {
+    struct Visitor(Vec<(String, JsonValue)>);
+
+    impl<'kvs> VisitSource<'kvs> for Visitor {
+        fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> std::result::Result<(), kv::Error> {
+            let value = if let Some(n) = value.to_u64() {
+                JsonValue::from(n)
+            } else if let Some(n) = value.to_i64() {
+                JsonValue::from(n)
+            } else if let Some(b) = value.to_bool() {
+                JsonValue::from(b)
+            } else if let Some(f) = value.to_f64() {
+                JsonValue::from(f)
+            } else {
+                JsonValue::from(value.to_string())
+            };
+            self.0.push((key.as_str().to_owned(), value));
+            Ok(())
+        }
+    }
+
+    let mut visitor = Visitor(FIELDS.with(|stack| {
+        stack.borrow().iter().map(|(key, value)| ((*key).to_owned(), JsonValue::from(value.as_str()))).collect()
+    }));
+    let _ = record.key_values().visit(&mut visitor);
+    visitor.0
+}
+
+fn text_line(record: &Record) -> String {
+    let mut line = format!(
+        "{} {} [{}] {}",
+        Local::now().format("%Y-%m-%d %H:%M:%S"),
+        record.level(),
+        record.module_path().unwrap_or("<unnamed>"),
+        record.args()
+    );
+    for (key, value) in fields(record) {
+        match value {
+            JsonValue::String(s) => line.push_str(&format!(" {}={}", key, s)),
+            other => line.push_str(&format!(" {}={}", key, other)),
+        }
+    }
+    line
+}
+
+fn json_line(record: &Record) -> String {
+    let mut object = Map::new();
+    object.insert("timestamp".to_owned(), Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true).into());
+    object.insert("level".to_owned(), record.level().as_str().into());
+    object.insert("module".to_owned(), record.module_path().unwrap_or("<unnamed>").into());
+    object.insert("message".to_owned(), record.args().to_string().into());
+    for (key, value) in fields(record) {
+        object.entry(key).or_insert(value);
+    }
+    JsonValue::Object(object).to_string()
+}
+
+enum Output {
+    Stderr,
+    File(RotatingFile),
+}
+
+impl Output {
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        match self {
+            Output::Stderr => io::stderr().write_all(line.as_bytes()),
+            Output::File(file) => file.write_line(line),
+        }
+    }
+}
+
+/// the log file, renamed to `FILE.1` when it is too large or too old, the older ones to `FILE.2` and so on
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened: Instant,
+    max_size: Option<u64>,
+    interval: Option<Duration>,
+    max_files: usize,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_size: Option<u64>, interval: Option<Duration>, max_files: usize) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, file, size, opened: Instant::now(), max_size, interval, max_files })
+    }
+
+    /// a line is never split between two files
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let too_large = self.max_size.is_some_and(|max| self.size > 0 && self.size + line.len() as u64 > max);
+        let too_old = self.interval.is_some_and(|interval| self.opened.elapsed() >= interval);
+        if too_large || too_old {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+            for n in (1..self.max_files).rev() {
+                let from = rotated_path(&self.path, n);
+                if from.exists() {
+                    fs::rename(from, rotated_path(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, rotated_path(&self.path, 1))?;
+        }
+        *self = RotatingFile::open(self.path.clone(), self.max_size, self.interval, self.max_files)?;
+        Ok(())
+    }
+}
+
+/// `FILE.n`
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
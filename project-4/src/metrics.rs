@@ -0,0 +1,188 @@
+//! Prometheus metrics of the server and the engine, served over HTTP in the text format by `serve`
+//!
+//! the metrics are process wide, the servers and engines of a process add up
+
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::OnceLock;
+use std::thread;
+use std::time::Duration;
+
+use prometheus::{
+    exponential_buckets, Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+use crate::Result;
+
+/// the max time to receive a scrape request and send the reply
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// the max size of the head of a scrape request
+const MAX_REQUEST_HEAD: usize = 8 * 1024;
+
+/// the collectors updated by the instrumented code
+pub(crate) struct Metrics {
+    registry: Registry,
+    /// `kvs_commands_total{command}`
+    pub commands: IntCounterVec,
+    /// `kvs_command_duration_seconds{command}`
+    pub command_duration: HistogramVec,
+    /// `kvs_errors_total{kind}`, the error replies by their code like `ERR` or `NOPERM`,
+    /// and the closed connections by the reason like `timeout` or `protocol`
+    pub errors: IntCounterVec,
+    /// `kvs_connections_total`
+    pub connections: IntCounter,
+    /// `kvs_connected_clients`
+    pub connected: IntGauge,
+    /// `kvs_thread_pool_queue_depth`, the connections waiting for a thread of the sync server
+    pub queue_depth: IntGauge,
+    /// `kvs_log_bytes`
+    pub log_bytes: IntGauge,
+    /// `kvs_log_live_bytes`, the log records of the current values
+    pub live_bytes: IntGauge,
+    /// `kvs_log_stale_bytes`, the log records to be dropped by the next compaction
+    pub stale_bytes: IntGauge,
+    /// `kvs_compactions_total`
+    pub compactions: IntCounter,
+    /// `kvs_compaction_duration_seconds`
+    pub compaction_duration: Histogram,
+    /// `kvs_keys`, of all namespaces
+    pub keys: IntGauge,
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new();
+        let commands = IntCounterVec::new(Opts::new("kvs_commands_total", "Commands run"), &["command"])?;
+        let command_duration = HistogramVec::new(
+            HistogramOpts::new("kvs_command_duration_seconds", "Time to run a command")
+                .buckets(exponential_buckets(0.0001, 4.0, 9)?),
+            &["command"],
+        )?;
+        let errors = IntCounterVec::new(Opts::new("kvs_errors_total", "Error replies and failed connections"), &["kind"])?;
+        let connections = IntCounter::new("kvs_connections_total", "Accepted connections")?;
+        let connected = IntGauge::new("kvs_connected_clients", "Connected clients")?;
+        let queue_depth = IntGauge::new("kvs_thread_pool_queue_depth", "Connections waiting for a thread")?;
+        let log_bytes = IntGauge::new("kvs_log_bytes", "Size of the log")?;
+        let live_bytes = IntGauge::new("kvs_log_live_bytes", "Log bytes of the current values")?;
+        let stale_bytes = IntGauge::new("kvs_log_stale_bytes", "Log bytes reclaimed by the next compaction")?;
+        let compactions = IntCounter::new("kvs_compactions_total", "Log compactions")?;
+        let compaction_duration = Histogram::with_opts(
+            HistogramOpts::new("kvs_compaction_duration_seconds", "Time to compact the log")
+                .buckets(exponential_buckets(0.001, 4.0, 8)?),
+        )?;
+        let keys = IntGauge::new("kvs_keys", "Keys of all namespaces")?;
+
+        registry.register(Box::new(commands.clone()))?;
+        registry.register(Box::new(command_duration.clone()))?;
+        registry.register(Box::new(errors.clone()))?;
+        registry.register(Box::new(connections.clone()))?;
+        registry.register(Box::new(connected.clone()))?;
+        registry.register(Box::new(queue_depth.clone()))?;
+        registry.register(Box::new(log_bytes.clone()))?;
+        registry.register(Box::new(live_bytes.clone()))?;
+        registry.register(Box::new(stale_bytes.clone()))?;
+        registry.register(Box::new(compactions.clone()))?;
+        registry.register(Box::new(compaction_duration.clone()))?;
+        registry.register(Box::new(keys.clone()))?;
+        Ok(Metrics {
+            registry,
+            commands,
+            command_duration,
+            errors,
+            connections,
+            connected,
+            queue_depth,
+            log_bytes,
+            live_bytes,
+            stale_bytes,
+            compactions,
+            compaction_duration,
+            keys,
+        })
+    }
+
+    /// count an error reply by its code, the leading upper case word like `NOPERM`, or `ERR` if there is none
+    pub fn error_reply(&self, message: &str) {
+        let code = message.split(' ').next().unwrap_or_default();
+        let kind = if !code.is_empty() && code.bytes().all(|b| b.is_ascii_uppercase()) { code } else { "ERR" };
+        self.errors.with_label_values(&[kind]).inc();
+    }
+
+    /// count a connection closed for `reason`, like "idle timeout", or rejected or failed in the TLS handshake
+    pub fn connection_closed(&self, reason: &str) {
+        self.errors.with_label_values(&[&reason.replace(' ', "_")]).inc();
+    }
+
+    /// count a connection closed by an I/O error or a malformed request
+    pub fn connection_failed(&self, e: &anyhow::Error) {
+        let kind = if e.downcast_ref::<io::Error>().is_some() { "io" } else { "protocol" };
+        self.errors.with_label_values(&[kind]).inc();
+    }
+}
+
+/// the metrics of the process
+pub(crate) fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(|| Metrics::new().expect("invalid metric definitions"))
+}
+
+/// all metrics in the Prometheus text format
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&metrics().registry.gather(), &mut buffer) {
+        log::error!("[metrics] encode error, {}", e);
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
+
+/// listen on `addr` and reply `render()` to `GET /metrics` on a background thread, for the life of the process,
+/// return the bound address
+pub fn serve(addr: &str) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr)?;
+    let local_addr = listener.local_addr()?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|mut stream| {
+                stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+                stream.set_write_timeout(Some(SCRAPE_TIMEOUT))?;
+                reply_scrape(&mut stream)
+            });
+            if let Err(e) = result {
+                log::warn!("[metrics] scrape error, {}", e);
+            }
+        }
+    });
+    log::info!("metrics served on http://{}/metrics", local_addr);
+    Ok(local_addr)
+}
+
+/// the connection is closed after one reply
+fn reply_scrape(stream: &mut TcpStream) -> io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?).take(MAX_REQUEST_HEAD as u64);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header == "\r\n" || header == "\n" {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render()),
+        (Some("GET"), Some(_)) => ("404 Not Found", "not found, try /metrics\n".to_owned()),
+        _ => ("405 Method Not Allowed", "only GET is allowed\n".to_owned()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
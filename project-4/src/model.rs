@@ -1,8 +1,7 @@
 //! struct or enum
 
 use serde::{Deserialize, Serialize};
-use std::net::TcpStream;
-use std::io::Read;
+use std::io::{self, Read};
 use crate::Result;
 use crate::error::KvsError;
 
@@ -15,10 +14,61 @@ pub enum Behavior {
     Get { key: String },
     /// The user invokes kvs rm mykey
     Remove { key: String },
+    /// Get the values of the keys, nil for the absent ones
+    MGet { keys: Vec<String> },
+    /// Set the keys to the values one by one, not atomic
+    MSet { pairs: Vec<(String, String)> },
+    /// Negotiate the protocol version, keep the current version if `None`
+    Hello { protocol: Option<Protocol> },
+    /// Switch the connection to the namespace
+    Select { namespace: String },
+    /// Count the keys of the current namespace
+    DbSize,
+    /// Remove all keys of the current namespace
+    FlushDb,
+    /// Receive the messages published to the channels
+    Subscribe { channels: Vec<String> },
+    /// Stop receiving the messages of the channels, all subscribed channels if empty
+    Unsubscribe { channels: Vec<String> },
+    /// Receive the messages published to the channels matching the glob patterns
+    PSubscribe { patterns: Vec<String> },
+    /// Stop receiving by the patterns, all subscribed patterns if empty
+    PUnsubscribe { patterns: Vec<String> },
+    /// Push the message to the subscribers of the channel
+    Publish { channel: String, message: String },
+    /// Receive the changes of the keys starting with the prefix in the current namespace
+    Watch { prefix: String },
+    /// Stop receiving the changes of the keys
+    Unwatch,
+    /// Authenticate the connection as `user`, the default user if `None`
+    Auth { user: Option<String>, password: String },
+    /// Report the statistics of the server, of a section only if it is set
+    Info { section: Option<String> },
+    /// List the connected clients
+    ClientList,
+    /// Make the span of the next request a child of the client span `traceparent`, in the W3C form, see `kvs::trace`
+    ClientTraceParent { traceparent: String },
+    /// Report the newest `count` slow commands
+    SlowLogGet { count: usize },
+    /// Count the slow commands kept
+    SlowLogLen,
+    /// Remove the slow commands kept
+    SlowLogReset,
+    /// Start the replication stream of a replica, from the write after `offset` of the history `replid`,
+    /// or from a full snapshot if either is `None`, see `kvs::replication`
+    PSync { replid: Option<String>, offset: Option<u64> },
+    /// A message from another node of the Raft cluster, in JSON, see `kvs::raft`
+    Raft { message: String },
+    /// Add the node `id` listening on `addr` to the Raft cluster, or change its address
+    ClusterAddNode { id: u64, addr: String },
+    /// Remove the node `id` from the Raft cluster
+    ClusterRemoveNode { id: u64 },
+    /// Shut down the server, or close the engine when it is sent to the engine
+    Shutdown,
 }
 
 /// A message definition like redis protocol
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Msg {
     /// start with "+", end with "\r\n"
     Line(String),
@@ -36,6 +86,47 @@ pub enum Msg {
     /// - A * character as the first byte, followed by the number of elements in the array as a decimal number, followed by CRLF.
     /// - An additional RESP type for every element of the Array.
     Array(Vec<Msg>),
+    /// RESP3, `_\r\n`
+    Null,
+    /// RESP3, `#t\r\n` or `#f\r\n`
+    Boolean(bool),
+    /// RESP3, start with ",", end with "\r\n", `inf`, `-inf` and `nan` are allowed
+    Double(f64),
+    /// RESP3, start with "(", an integer which can not be represented by `i64`
+    BigNumber(String),
+    /// RESP3, like Bulk String, but the content is prefixed by a 3 bytes format and a ":",
+    /// e.g. `=15\r\ntxt:Some string\r\n`
+    Verbatim {
+        /// `txt` or `mkd`
+        format: String,
+        /// the text without format prefix
+        text: String,
+    },
+    /// RESP3, start with "%", followed by the number of key/value pairs
+    Map(Vec<(Msg, Msg)>),
+    /// RESP3, start with "~", same as Array but the elements are unordered and unique
+    Set(Vec<Msg>),
+    /// RESP3, start with ">", out of band data pushed by the server, e.g. pub/sub messages
+    Push(Vec<Msg>),
+}
+
+/// RESP version negotiated by `HELLO`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protocol {
+    /// the default protocol of a new connection
+    Resp2,
+    /// enabled by `HELLO 3`
+    Resp3,
+}
+
+impl Protocol {
+    /// the number used by `HELLO`
+    pub fn version(&self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
 }
 
 impl Msg {
@@ -82,13 +173,79 @@ impl Msg {
                     "$-1\r\n".as_bytes().to_vec()
                 }
             }
-            Msg::Array(array) => {
-                let mut list = format!("*{}\r\n", array.len()).as_bytes().to_vec();
-                for item in array {
-                    list.append(&mut item.to_bytes());
+            Msg::Array(array) => Self::aggregate_to_bytes(b'*', array),
+            Msg::Null => b"_\r\n".to_vec(),
+            Msg::Boolean(b) => format!("#{}\r\n", if *b { 't' } else { 'f' }).as_bytes().to_vec(),
+            Msg::Double(d) => {
+                let text = if d.is_nan() {
+                    "nan".to_owned()
+                } else if d.is_infinite() {
+                    if d.is_sign_positive() { "inf" } else { "-inf" }.to_owned()
+                } else {
+                    d.to_string()
+                };
+                format!(",{}\r\n", text).as_bytes().to_vec()
+            }
+            Msg::BigNumber(n) => format!("({}\r\n", n).as_bytes().to_vec(),
+            Msg::Verbatim { format, text } => {
+                format!("={}\r\n{}:{}\r\n", format.len() + 1 + text.len(), format, text).as_bytes().to_vec()
+            }
+            Msg::Map(pairs) => {
+                let mut list = format!("%{}\r\n", pairs.len()).as_bytes().to_vec();
+                for (k, v) in pairs {
+                    list.append(&mut k.to_bytes());
+                    list.append(&mut v.to_bytes());
                 }
                 list
             }
+            Msg::Set(set) => Self::aggregate_to_bytes(b'~', set),
+            Msg::Push(push) => Self::aggregate_to_bytes(b'>', push),
+        }
+    }
+
+    fn aggregate_to_bytes(prefix: u8, items: &[Msg]) -> Vec<u8> {
+        let mut list = vec![prefix];
+        list.extend_from_slice(format!("{}\r\n", items.len()).as_bytes());
+        for item in items {
+            list.append(&mut item.to_bytes());
+        }
+        list
+    }
+
+    /// convert RESP3 types to the closest RESP2 types, used by the connections which not negotiate RESP3
+    ///
+    /// - Null => Null Bulk String
+    /// - Boolean => Integer 1 or 0
+    /// - Double, BigNumber and Verbatim => Bulk String
+    /// - Map => flat Array of key and value
+    /// - Set and Push => Array
+    pub fn into_resp2(self) -> Msg {
+        match self {
+            Msg::Null => Msg::Bulk(None),
+            Msg::Boolean(b) => Msg::Integer(b as i64),
+            Msg::Double(d) => Msg::Bulk(Some(d.to_string())),
+            Msg::BigNumber(n) => Msg::Bulk(Some(n)),
+            Msg::Verbatim { text, .. } => Msg::Bulk(Some(text)),
+            Msg::Map(pairs) => {
+                let mut list = Vec::with_capacity(pairs.len() * 2);
+                for (k, v) in pairs {
+                    list.push(k.into_resp2());
+                    list.push(v.into_resp2());
+                }
+                Msg::Array(list)
+            }
+            Msg::Array(array) | Msg::Set(array) | Msg::Push(array) => {
+                Msg::Array(array.into_iter().map(Msg::into_resp2).collect())
+            }
+            other => other,
+        }
+    }
+
+    /// convert the msg to the type supported by `protocol`
+    pub fn for_protocol(self, protocol: Protocol) -> Msg {
+        match protocol {
+            Protocol::Resp2 => self.into_resp2(),
+            Protocol::Resp3 => self,
         }
     }
 
@@ -99,7 +256,10 @@ impl Msg {
             Err(KvsError::InvalidArgumentNumber)?
         }
         let arguments = arguments_option.unwrap();
-        match arguments[0].as_str() {
+        if arguments.is_empty() {
+            Err(KvsError::InvalidArgumentNumber)?
+        }
+        match arguments[0].to_lowercase().as_str() {
             "get" => {
                 if arguments.len() < 2 {
                     Err(KvsError::InvalidArgumentNumber)?
@@ -121,6 +281,143 @@ impl Msg {
                 }
                 return Ok(Behavior::Remove { key: arguments[1].to_owned() });
             }
+            "mget" => {
+                if arguments.len() < 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::MGet { keys: arguments[1..].to_vec() });
+            }
+            "mset" => {
+                if arguments.len() < 3 || arguments.len().is_multiple_of(2) {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                let pairs = arguments[1..].chunks(2).map(|pair| (pair[0].to_owned(), pair[1].to_owned())).collect();
+                return Ok(Behavior::MSet { pairs });
+            }
+            "client" => {
+                if arguments.len() == 2 && arguments[1].eq_ignore_ascii_case("list") {
+                    return Ok(Behavior::ClientList);
+                }
+                if arguments.len() == 3 && arguments[1].eq_ignore_ascii_case("traceparent") {
+                    return Ok(Behavior::ClientTraceParent { traceparent: arguments[2].to_owned() });
+                }
+                Err(KvsError::InvalidArgumentNumber)?
+            }
+            "slowlog" => {
+                let subcommand = arguments.get(1).map(|s| s.to_lowercase());
+                match (subcommand.as_deref(), arguments.len()) {
+                    (Some("get"), 2) => return Ok(Behavior::SlowLogGet { count: 10 }),
+                    (Some("get"), 3) => {
+                        let count: i64 = arguments[2].parse().map_err(|_| KvsError::NotInteger)?;
+                        // a negative count gets all
+                        let count = if count < 0 { usize::MAX } else { count as usize };
+                        return Ok(Behavior::SlowLogGet { count });
+                    }
+                    (Some("len"), 2) => return Ok(Behavior::SlowLogLen),
+                    (Some("reset"), 2) => return Ok(Behavior::SlowLogReset),
+                    _ => Err(KvsError::InvalidArgumentNumber)?,
+                }
+            }
+            "psync" => {
+                if arguments.len() != 3 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                // `PSYNC ? -1` asks for a full snapshot
+                let replid = Some(arguments[1].to_owned()).filter(|replid| replid != "?");
+                let offset: i64 = arguments[2].parse().map_err(|_| KvsError::NotInteger)?;
+                let offset = if offset < 0 { None } else { Some(offset as u64) };
+                return Ok(Behavior::PSync { replid, offset });
+            }
+            "raft" => {
+                if arguments.len() != 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::Raft { message: arguments[1].to_owned() });
+            }
+            "cluster" => {
+                let subcommand = arguments.get(1).map(|s| s.to_lowercase());
+                let id = || -> Result<u64> { Ok(arguments[2].parse().map_err(|_| KvsError::NotInteger)?) };
+                match (subcommand.as_deref(), arguments.len()) {
+                    (Some("addnode"), 4) => return Ok(Behavior::ClusterAddNode { id: id()?, addr: arguments[3].to_owned() }),
+                    (Some("removenode"), 3) => return Ok(Behavior::ClusterRemoveNode { id: id()? }),
+                    _ => Err(KvsError::InvalidArgumentNumber)?,
+                }
+            }
+            "select" => {
+                if arguments.len() != 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::Select { namespace: arguments[1].to_owned() });
+            }
+            "dbsize" => {
+                return Ok(Behavior::DbSize);
+            }
+            "flushdb" => {
+                return Ok(Behavior::FlushDb);
+            }
+            "subscribe" | "psubscribe" => {
+                if arguments.len() < 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                let names = arguments[1..].to_vec();
+                return Ok(if arguments[0].eq_ignore_ascii_case("subscribe") {
+                    Behavior::Subscribe { channels: names }
+                } else {
+                    Behavior::PSubscribe { patterns: names }
+                });
+            }
+            "unsubscribe" => {
+                return Ok(Behavior::Unsubscribe { channels: arguments[1..].to_vec() });
+            }
+            "punsubscribe" => {
+                return Ok(Behavior::PUnsubscribe { patterns: arguments[1..].to_vec() });
+            }
+            "publish" => {
+                if arguments.len() != 3 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::Publish {
+                    channel: arguments[1].to_owned(),
+                    message: arguments[2].to_owned(),
+                });
+            }
+            "watch" => {
+                if arguments.len() != 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::Watch { prefix: arguments[1].to_owned() });
+            }
+            "unwatch" => {
+                return Ok(Behavior::Unwatch);
+            }
+            "auth" => {
+                return match arguments.len() {
+                    2 => Ok(Behavior::Auth { user: None, password: arguments[1].to_owned() }),
+                    3 => Ok(Behavior::Auth {
+                        user: Some(arguments[1].to_owned()),
+                        password: arguments[2].to_owned(),
+                    }),
+                    _ => Err(KvsError::InvalidArgumentNumber)?,
+                };
+            }
+            "info" => {
+                if arguments.len() > 2 {
+                    Err(KvsError::InvalidArgumentNumber)?
+                }
+                return Ok(Behavior::Info { section: arguments.get(1).map(|s| s.to_lowercase()) });
+            }
+            "shutdown" => {
+                return Ok(Behavior::Shutdown);
+            }
+            "hello" => {
+                let protocol = match arguments.get(1).map(|s| s.as_str()) {
+                    None => None,
+                    Some("2") => Some(Protocol::Resp2),
+                    Some("3") => Some(Protocol::Resp3),
+                    Some(other) => Err(KvsError::UnsupportedProtocol(other.to_owned()))?,
+                };
+                return Ok(Behavior::Hello { protocol });
+            }
             _ => {}
         }
 
@@ -130,7 +427,7 @@ impl Msg {
 
 /// Support Msg Struct
 pub trait MsgExtend {
-    /// blocking read some bytes from `TcpStream`
+    /// blocking read some bytes from the stream
     ///
     fn read_exact_return(&mut self, bytes_num: u32) -> Result<Vec<u8>>;
 
@@ -139,17 +436,21 @@ pub trait MsgExtend {
     /// the return vec includes `\r\n` at the end
     fn read_until_crlf(&mut self) -> Result<Vec<u8>>;
 
-    /// blocking read a `Msg` object
+    /// blocking read a `Msg` object with the default `MsgLimits`
     fn read_msg(&mut self) -> Result<Msg>;
+
+    /// blocking read a `Msg` object, return `KvsError::LimitExceeded` if the msg breaks the limits
+    fn read_msg_limited(&mut self, limits: &MsgLimits) -> Result<Msg>;
 }
 
-impl MsgExtend for TcpStream {
-    /// blocking read some bytes from `TcpStream`
+/// any blocking stream, e.g. `TcpStream`, `UnixStream` or `net::Stream`
+impl<R: Read> MsgExtend for R {
+    /// blocking read some bytes from the stream
     ///
     fn read_exact_return(&mut self, bytes_num: u32) -> Result<Vec<u8>> {
         let mut data = vec![0u8; bytes_num as usize];
         self.read_exact(&mut data)?;
-        return Ok(data);
+        Ok(data)
     }
 
     /// blocking read until `\r\n`
@@ -173,7 +474,152 @@ impl MsgExtend for TcpStream {
     }
 
     fn read_msg(&mut self) -> Result<Msg> {
+        self.read_msg_limited(&MsgLimits::default())
+    }
+
+    fn read_msg_limited(&mut self, limits: &MsgLimits) -> Result<Msg> {
+        read_msg_from(self, limits)
+    }
+}
+
+/// blocking read a `Msg` object from any reader, e.g. a `TcpStream` wrapper with a deadline
+pub(crate) fn read_msg_from<R: Read>(reader: &mut R, limits: &MsgLimits) -> Result<Msg> {
+    MsgReader::new(reader, limits).read_msg(1)
+}
+
+/// Limits of a msg read from the peer, protect the server from the huge or deeply nested msg
+#[derive(Debug, Clone)]
+pub struct MsgLimits {
+    /// max length of a Bulk String or Verbatim String
+    pub max_bulk_len: usize,
+    /// max number of elements of an Array, Set, Push or Map
+    pub max_array_len: usize,
+    /// max depth of nested aggregate types, a plain Array has depth 1
+    pub max_depth: usize,
+    /// max bytes of a whole msg, including the type bytes and CRLF
+    pub max_request_size: usize,
+}
+
+impl Default for MsgLimits {
+    fn default() -> Self {
+        MsgLimits {
+            max_bulk_len: 64 * 1024 * 1024,
+            max_array_len: 1024 * 1024,
+            max_depth: 32,
+            max_request_size: 128 * 1024 * 1024,
+        }
+    }
+}
+
+/// try to parse a `Msg` from the front of `buf`
+///
+/// return the msg and the number of bytes it takes, or `None` if `buf` does not contain a whole msg
+pub(crate) fn parse_msg(buf: &[u8], limits: &MsgLimits) -> Result<Option<(Msg, usize)>> {
+    let mut cursor = buf;
+    match MsgReader::new(&mut cursor, limits).read_msg(1) {
+        Ok(msg) => Ok(Some((msg, buf.len() - cursor.len()))),
+        Err(e) => match e.downcast_ref::<io::Error>() {
+            Some(io_error) if io_error.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        },
+    }
+}
+
+/// parse a `Msg` from the stream, count the consumed bytes and check them against `MsgLimits`
+struct MsgReader<'a, R: Read> {
+    inner: &'a mut R,
+    limits: &'a MsgLimits,
+    consumed: usize,
+}
+
+impl<'a, R: Read> MsgReader<'a, R> {
+    fn new(inner: &'a mut R, limits: &'a MsgLimits) -> Self {
+        MsgReader { inner, limits, consumed: 0 }
+    }
+
+    /// consume `n` bytes of the request size budget
+    fn consume(&mut self, n: usize) -> Result<()> {
+        self.consumed = self.consumed.saturating_add(n);
+        if self.consumed > self.limits.max_request_size {
+            Err(KvsError::LimitExceeded(format!("request size exceeds {} bytes", self.limits.max_request_size)))?
+        }
+        Ok(())
+    }
+
+    /// the buffer grows with the received bytes, a huge length can not allocate memory in advance
+    fn read_exact_return(&mut self, bytes_num: usize) -> Result<Vec<u8>> {
+        self.consume(bytes_num)?;
+        let mut data = Vec::new();
+        (&mut *self.inner).take(bytes_num as u64).read_to_end(&mut data)?;
+        if data.len() < bytes_num {
+            Err(io::Error::from(io::ErrorKind::UnexpectedEof))?
+        }
+        Ok(data)
+    }
+
+    /// the return vec not includes `\r\n` at the end
+    fn read_until_crlf(&mut self) -> Result<Vec<u8>> {
+        let mut list = Vec::new();
+        let mut cr_flag = false;
+        let mut one_byte = [0u8; 1];
+        loop {
+            self.consume(1)?;
+            self.inner.read_exact(&mut one_byte)?;
+            list.push(one_byte[0]);
+            if cr_flag && one_byte[0] == b'\n' {
+                break;
+            }
+            cr_flag = one_byte[0] == b'\r';
+        }
+        list.pop(); // remove '\n'
+        list.pop(); // remove '\r'
+        Ok(list)
+    }
+
+    /// read the length line of Bulk String or aggregate types
+    fn read_len(&mut self) -> Result<i64> {
+        let head = self.read_until_crlf()?;
+        Ok(String::from_utf8(head)?.parse()?)
+    }
+
+    /// read the length line of aggregate types, check it against `max_array_len`
+    fn read_aggregate_len(&mut self) -> Result<usize> {
+        let len = self.read_len()?;
+        if len < 0 {
+            Err(KvsError::InvalidMsg(format!("negative aggregate length {}", len)))?
+        }
+        if len as u64 > self.limits.max_array_len as u64 {
+            Err(KvsError::LimitExceeded(format!("aggregate length {} exceeds {}", len, self.limits.max_array_len)))?
+        }
+        Ok(len as usize)
+    }
+
+    /// read the content and the remain crlf of Bulk String or Verbatim String
+    fn read_bulk_content(&mut self, len: i64) -> Result<String> {
+        if len as u64 > self.limits.max_bulk_len as u64 {
+            Err(KvsError::LimitExceeded(format!("bulk length {} exceeds {}", len, self.limits.max_bulk_len)))?
+        }
+        let content = String::from_utf8(self.read_exact_return(len as usize)?)?;
+        self.read_until_crlf()?; // read remain crlf
+        Ok(content)
+    }
+
+    /// read the elements of Array, Set or Push
+    fn read_aggregate(&mut self, depth: usize) -> Result<Vec<Msg>> {
+        let len = self.read_aggregate_len()?;
+        let mut list = vec![];
+        for _ in 0..len {
+            list.push(self.read_msg(depth + 1)?);
+        }
+        Ok(list)
+    }
+
+    /// `depth` is the nesting depth of the msg to read, the top level msg has depth 1
+    fn read_msg(&mut self, depth: usize) -> Result<Msg> {
         let cmd_type = self.read_exact_return(1)?;
+        if b"*%~>".contains(&cmd_type[0]) && depth > self.limits.max_depth {
+            Err(KvsError::LimitExceeded(format!("nesting depth exceeds {}", self.limits.max_depth)))?
+        }
         let msg = match cmd_type[0] {
             // Simple String
             b'+' => {
@@ -192,36 +638,72 @@ impl MsgExtend for TcpStream {
             }
             // Bulk String
             b'$' => {
-                let head = self.read_until_crlf()?;
-                let len: i64 = String::from_utf8(head)?.parse()?;
+                let len = self.read_len()?;
                 if len < 0 {
                     Msg::Bulk(None)
                 } else {
-                    let content_bytes = self.read_exact_return(len as u32)?;
-                    let content = String::from_utf8(content_bytes)?;
-                    self.read_until_crlf()?; // read remain crlf
-                    Msg::Bulk(Some(content))
+                    Msg::Bulk(Some(self.read_bulk_content(len)?))
                 }
             }
             // Array
-            b'*' => {
-                let head = self.read_until_crlf()?;
-                let len: u32 = String::from_utf8(head)?.parse()?;
-
-                let mut list = vec![];
+            b'*' => Msg::Array(self.read_aggregate(depth)?),
+            // Null
+            b'_' => {
+                self.read_until_crlf()?;
+                Msg::Null
+            }
+            // Boolean
+            b'#' => {
+                let list = self.read_until_crlf()?;
+                match list.as_slice() {
+                    b"t" => Msg::Boolean(true),
+                    b"f" => Msg::Boolean(false),
+                    _ => Err(KvsError::InvalidMsg(format!("bad boolean {:?}", String::from_utf8_lossy(&list))))?,
+                }
+            }
+            // Double
+            b',' => {
+                let list = self.read_until_crlf()?;
+                Msg::Double(String::from_utf8(list)?.parse()?)
+            }
+            // Big Number
+            b'(' => {
+                let list = self.read_until_crlf()?;
+                Msg::BigNumber(String::from_utf8(list)?)
+            }
+            // Verbatim String
+            b'=' => {
+                let len = self.read_len()?;
+                if len < 0 {
+                    Err(KvsError::InvalidMsg(format!("negative verbatim string length {}", len)))?
+                }
+                let content = self.read_bulk_content(len)?;
+                match content.find(':') {
+                    Some(3) => Msg::Verbatim {
+                        format: content[..3].to_owned(),
+                        text: content[4..].to_owned(),
+                    },
+                    _ => Err(KvsError::InvalidMsg(format!("bad verbatim string {:?}", content)))?,
+                }
+            }
+            // Map
+            b'%' => {
+                let len = self.read_aggregate_len()?;
+                let mut pairs = vec![];
                 for _ in 0..len {
-                    list.push(self.read_msg()?);
+                    let k = self.read_msg(depth + 1)?;
+                    let v = self.read_msg(depth + 1)?;
+                    pairs.push((k, v));
                 }
-
-                Msg::Array(list)
+                Msg::Map(pairs)
             }
-            _ => unreachable!(),
+            // Set
+            b'~' => Msg::Set(self.read_aggregate(depth)?),
+            // Push
+            b'>' => Msg::Push(self.read_aggregate(depth)?),
+            other => Err(KvsError::InvalidMsg(format!("unknown type byte {:?}", other as char)))?,
         };
 
         Ok(msg)
     }
 }
-
-
-
-
@@ -0,0 +1,343 @@
+//! the addresses and connections of kvs, over TCP, TLS or a Unix domain socket
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::{Ipv4Addr, Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::ops::DerefMut;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rustls::{ClientConfig, ClientConnection, ConnectionCommon, ServerConfig, ServerConnection, SideData, StreamOwned};
+use socket2::SockRef;
+
+use crate::error::KvsError;
+use crate::Result;
+
+/// the prefix of a Unix socket address, e.g. `unix:///run/kvs.sock`
+pub const UNIX_PREFIX: &str = "unix://";
+
+/// a server address, `IP:PORT` for TCP or `unix://PATH` for a Unix domain socket
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Address {
+    /// IP:PORT
+    Tcp(String),
+    /// the path of the socket file
+    Unix(PathBuf),
+}
+
+impl Address {
+    /// parse `unix://PATH` as a Unix socket, otherwise as TCP
+    pub fn parse(address: &str) -> Self {
+        match address.strip_prefix(UNIX_PREFIX) {
+            Some(path) => Address::Unix(PathBuf::from(path)),
+            None => Address::Tcp(address.to_owned()),
+        }
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Address::Tcp(addr) => write!(f, "{}", addr),
+            Address::Unix(path) => write!(f, "{}{}", UNIX_PREFIX, path.display()),
+        }
+    }
+}
+
+/// a connection to or from a kvs server
+#[derive(Debug)]
+pub enum Stream {
+    #[allow(missing_docs)]
+    Tcp(TcpStream),
+    #[allow(missing_docs)]
+    Unix(UnixStream),
+    /// the server side of a TLS connection
+    TlsServer(Box<StreamOwned<ServerConnection, TcpStream>>),
+    /// the client side of a TLS connection
+    TlsClient(Box<StreamOwned<ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// connect to the server at `address`
+    pub fn connect(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(Stream::Tcp(TcpStream::connect(addr)?)),
+            Address::Unix(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+        }
+    }
+
+    /// connect to the server at the TCP `address` by TLS, the handshake is done by the first read or write
+    pub fn connect_tls(address: &Address, config: Arc<ClientConfig>) -> Result<Self> {
+        match address {
+            Address::Tcp(addr) => Stream::tls_client(addr, config, TcpStream::connect(addr)?),
+            Address::Unix(_) => Err(KvsError::InvalidTls("TLS over a Unix socket is not supported".to_owned()))?,
+        }
+    }
+
+    /// connect to the server at `address`, by TLS if `tls` is set.
+    /// a TCP connect fails after `timeout` for each address resolved
+    pub fn connect_with(address: &Address, tls: Option<Arc<ClientConfig>>, timeout: Option<Duration>) -> Result<Self> {
+        match (address, tls, timeout) {
+            (Address::Tcp(addr), Some(tls), Some(timeout)) => Stream::tls_client(addr, tls, connect_timeout(addr, timeout)?),
+            (Address::Tcp(addr), None, Some(timeout)) => Ok(Stream::Tcp(connect_timeout(addr, timeout)?)),
+            (_, Some(tls), _) => Stream::connect_tls(address, tls),
+            (_, None, _) => Ok(Stream::connect(address)?),
+        }
+    }
+
+    fn tls_client(addr: &str, config: Arc<ClientConfig>, sock: TcpStream) -> Result<Self> {
+        let conn = ClientConnection::new(config, crate::tls::server_name(addr)?)?;
+        Ok(Stream::TlsClient(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    /// serve the accepted TCP connection by TLS, other connections are returned as they are
+    pub(crate) fn accept_tls(self, config: Arc<ServerConfig>) -> Result<Self> {
+        match self {
+            Stream::Tcp(sock) => {
+                let conn = ServerConnection::new(config)?;
+                Ok(Stream::TlsServer(Box::new(StreamOwned::new(conn, sock))))
+            }
+            stream => Ok(stream),
+        }
+    }
+
+    /// another handle of the same connection, a TLS connection can not be cloned
+    pub fn try_clone(&self) -> io::Result<Self> {
+        match self {
+            Stream::Tcp(s) => Ok(Stream::Tcp(s.try_clone()?)),
+            Stream::Unix(s) => Ok(Stream::Unix(s.try_clone()?)),
+            Stream::TlsServer(_) | Stream::TlsClient(_) => {
+                Err(io::Error::new(io::ErrorKind::Unsupported, "a TLS connection can not be cloned"))
+            }
+        }
+    }
+
+    /// another handle of the socket under the connection, to shut it down from another thread
+    pub(crate) fn try_clone_socket(&self) -> io::Result<Self> {
+        match self {
+            Stream::TlsServer(s) => Ok(Stream::Tcp(s.sock.try_clone()?)),
+            Stream::TlsClient(s) => Ok(Stream::Tcp(s.sock.try_clone()?)),
+            stream => stream.try_clone(),
+        }
+    }
+
+    /// see `TcpStream::set_nonblocking`
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_nonblocking(nonblocking),
+            Stream::Unix(s) => s.set_nonblocking(nonblocking),
+            Stream::TlsServer(s) => s.sock.set_nonblocking(nonblocking),
+            Stream::TlsClient(s) => s.sock.set_nonblocking(nonblocking),
+        }
+    }
+
+    /// see `TcpStream::set_read_timeout`
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_read_timeout(timeout),
+            Stream::Unix(s) => s.set_read_timeout(timeout),
+            Stream::TlsServer(s) => s.sock.set_read_timeout(timeout),
+            Stream::TlsClient(s) => s.sock.set_read_timeout(timeout),
+        }
+    }
+
+    /// see `TcpStream::set_write_timeout`
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.set_write_timeout(timeout),
+            Stream::Unix(s) => s.set_write_timeout(timeout),
+            Stream::TlsServer(s) => s.sock.set_write_timeout(timeout),
+            Stream::TlsClient(s) => s.sock.set_write_timeout(timeout),
+        }
+    }
+
+    /// block until the data can be read without consuming it, return false if the peer closed the connection
+    ///
+    /// a TLS connection reads and decrypts the records, the plaintext is kept for the next read
+    pub fn wait_readable(&mut self) -> io::Result<bool> {
+        let mut probe = [MaybeUninit::<u8>::uninit()];
+        let peeked = match self {
+            Stream::Tcp(s) => SockRef::from(&*s).peek(&mut probe)?,
+            // `UnixStream::peek` is unstable yet
+            Stream::Unix(s) => SockRef::from(&*s).peek(&mut probe)?,
+            Stream::TlsServer(s) => return wait_tls_readable(&mut s.conn, &mut s.sock),
+            Stream::TlsClient(s) => return wait_tls_readable(&mut s.conn, &mut s.sock),
+        };
+        Ok(peeked > 0)
+    }
+
+    /// whether a read returns without waiting for the socket, the TLS records read before may hold the plaintext
+    pub fn has_buffered(&mut self) -> io::Result<bool> {
+        let state = match self {
+            Stream::Tcp(_) | Stream::Unix(_) => return Ok(false),
+            Stream::TlsServer(s) => s.conn.process_new_packets(),
+            Stream::TlsClient(s) => s.conn.process_new_packets(),
+        };
+        let state = state.map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(state.plaintext_bytes_to_read() > 0)
+    }
+
+    /// see `TcpStream::shutdown`
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.shutdown(how),
+            Stream::Unix(s) => s.shutdown(how),
+            Stream::TlsServer(s) => s.sock.shutdown(how),
+            Stream::TlsClient(s) => s.sock.shutdown(how),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    /// the socket, under the TLS session of a TLS stream
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Tcp(s) => s.as_raw_fd(),
+            Stream::Unix(s) => s.as_raw_fd(),
+            Stream::TlsServer(s) => s.sock.as_raw_fd(),
+            Stream::TlsClient(s) => s.sock.as_raw_fd(),
+        }
+    }
+}
+
+/// connect to the first address `addr` resolves to accepting in `timeout`
+fn connect_timeout(addr: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::InvalidInput, format!("{} resolves to no address", addr));
+    for addr in addr.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(sock) => return Ok(sock),
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// process the TLS records until there is plaintext to read, or the connection is closed
+fn wait_tls_readable<C, D>(conn: &mut C, sock: &mut TcpStream) -> io::Result<bool>
+where
+    C: DerefMut<Target = ConnectionCommon<D>>,
+    D: SideData,
+{
+    loop {
+        let state = conn.process_new_packets()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if state.plaintext_bytes_to_read() > 0 {
+            return Ok(true);
+        }
+        if state.peer_has_closed() {
+            return Ok(false);
+        }
+        if conn.wants_write() {
+            conn.write_tls(sock)?;
+            continue;
+        }
+        if conn.read_tls(sock)? == 0 {
+            return Ok(false);
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.read(buf),
+            Stream::Unix(s) => s.read(buf),
+            Stream::TlsServer(s) => s.read(buf),
+            Stream::TlsClient(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(s) => s.write(buf),
+            Stream::Unix(s) => s.write(buf),
+            Stream::TlsServer(s) => s.write(buf),
+            Stream::TlsClient(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(s) => s.flush(),
+            Stream::Unix(s) => s.flush(),
+            Stream::TlsServer(s) => s.flush(),
+            Stream::TlsClient(s) => s.flush(),
+        }
+    }
+}
+
+/// a blocking listener of `KvsServer`, the socket file of a Unix listener is removed when dropped
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// a Unix socket file left by a stopped server is replaced,
+    /// return `AddrInUse` if a server is listening on it
+    pub fn bind(address: &Address) -> io::Result<Self> {
+        match address {
+            Address::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr)?)),
+            Address::Unix(path) => {
+                remove_stale_socket(path)?;
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    /// return the connection and the peer address shown in `CLIENT LIST`
+    pub fn accept(&self) -> io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(l) => {
+                let (stream, peer_addr) = l.accept()?;
+                Ok((Stream::Tcp(stream), peer_addr.to_string()))
+            }
+            Listener::Unix(l, path) => {
+                let (stream, _) = l.accept()?;
+                Ok((Stream::Unix(stream), format!("{}:0", path.display())))
+            }
+        }
+    }
+
+    /// the address to connect to for waking up a blocking `accept`
+    pub fn local_address(&self) -> io::Result<Address> {
+        match self {
+            Listener::Tcp(l) => {
+                let mut local_addr = l.local_addr()?;
+                if local_addr.ip().is_unspecified() {
+                    local_addr.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+                Ok(Address::Tcp(local_addr.to_string()))
+            }
+            Listener::Unix(_, path) => Ok(Address::Unix(path.clone())),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// remove the socket file at `path` unless a server is listening on it
+pub(crate) fn remove_stale_socket(path: &std::path::Path) -> io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            format!("a server is listening on {}", path.display()),
+        ));
+    }
+    std::fs::remove_file(path)
+}
@@ -0,0 +1,284 @@
+//! moving the keys to a backend added to the ring
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::client::{ConnectOptions, KvsClient, ReplicationEvents};
+use crate::engines::SnapshotEntry;
+use crate::error::KvsError;
+use crate::model::{Behavior, Msg};
+use crate::proxy::{HashRing, ProxyContext};
+use crate::replication::ReplicationEvent;
+use crate::Result;
+
+/// the writes pipelined to the new backend at once
+const MIGRATION_BATCH: usize = 512;
+/// the removal of the moved keys from an old backend is tried again after `REMOVE_RETRY_DELAY`
+const REMOVE_ATTEMPTS: u32 = 3;
+const REMOVE_RETRY_DELAY: Duration = Duration::from_millis(200);
+
+/// add the backend at `addr` to the ring of the proxy, moving the keys it takes from the other backends,
+/// return their number. the migrations run one at a time
+///
+/// every old backend is followed by a full `PSYNC`, the keys moving are copied from the snapshot one by one,
+/// then from the writes of the replication stream, while the requests go on by the old ring.
+/// the ring is locked for writing only to copy the last writes before it is swapped, once the requests routed
+/// by the old ring are done, so no request sees a key half moved. a failure to copy leaves the ring as it is, every key on its old backend, the keys copied
+/// are removed from the new backend as far as it can, and the add can be retried.
+/// the ring is swapped before the keys are removed from the old backends, so the moved keys are always read
+/// from the new backend. the removal is tried `REMOVE_ATTEMPTS` times, the error tells the keys left on an old
+/// backend, they are not routed to but counted by `DBSIZE`
+pub(super) fn add_backend(context: &ProxyContext, addr: &str) -> Result<usize> {
+    let _migrating = context.migrating.lock().unwrap();
+    let options = &context.backend_connect;
+    let mut new_ring = HashRing::clone(&context.ring.read().unwrap());
+    if new_ring.contains(addr) {
+        Err(KvsError::BackendExists(addr.to_owned()))?
+    }
+    new_ring.add(addr);
+
+    let mut target = Target::connect(addr, options)?;
+    let mut sources = Vec::new();
+    if let Err(e) = copy(context, &new_ring, &mut target, &mut sources) {
+        target.remove_copied(&sources, options);
+        return Err(e);
+    }
+    let moved = sources.iter().map(Source::moving).sum();
+    log::info!("backend added, addr={}, moved_keys={}", addr, moved);
+
+    let mut left = Vec::new();
+    for source in sources {
+        let (backend, keys) = (source.addr, source.keys);
+        let count: usize = keys.values().map(HashSet::len).sum();
+        if let Err(e) = remove_moved(&backend, &keys, options) {
+            log::error!("moved keys left, addr={}, keys={}, {}", backend, count, e);
+            left.push(format!("{} on {}, {}", count, backend, e));
+        }
+    }
+    if !left.is_empty() {
+        Err(anyhow::anyhow!("backend {} added, {} moved keys are left, {}", addr, moved, left.join("; ")))?
+    }
+    Ok(moved)
+}
+
+/// copy the keys moving to the new backend and swap the ring for `new_ring`, the sources followed are put in
+/// `sources` as soon as they are, to remove their keys copied if it fails
+fn copy(context: &ProxyContext, new_ring: &HashRing, target: &mut Target, sources: &mut Vec<Source>) -> Result<()> {
+    let old_backends: Vec<String> = new_ring.backends().iter().filter(|backend| **backend != target.addr).cloned().collect();
+    for backend in &old_backends {
+        sources.push(Source::follow(backend, &context.backend_connect)?);
+        sources.last_mut().unwrap().copy_snapshot(new_ring, target)?;
+    }
+    // the writes during the snapshots, so only the last ones are copied with the ring locked
+    for source in sources.iter_mut() {
+        source.catch_up(new_ring, target)?;
+    }
+    target.flush()?;
+
+    let mut ring = context.ring.write().unwrap();
+    // the requests hold the ring they are routed by, the backend timeout bounds them
+    while Arc::strong_count(&ring) > 1 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    for source in sources.iter_mut() {
+        source.catch_up(new_ring, target)?;
+    }
+    target.flush()?;
+    *ring = Arc::new(new_ring.clone());
+    Ok(())
+}
+
+/// an old backend followed by `PSYNC`, and its keys moving to the new backend
+struct Source {
+    addr: String,
+    options: ConnectOptions,
+    events: ReplicationEvents,
+    /// the last write received
+    offset: u64,
+    /// the keys copied to the new backend by the namespace, removed from the old one after the swap
+    keys: HashMap<String, HashSet<String>>,
+}
+
+impl Source {
+    fn follow(addr: &str, options: &ConnectOptions) -> Result<Source> {
+        let mut events = connect(addr, options)?.psync(None, None).map_err(|e| backend_error(addr, e))?;
+        let offset = match events.next() {
+            Some(Ok(ReplicationEvent::FullResync { offset, .. })) => offset,
+            Some(Err(e)) => Err(e)?,
+            other => Err(KvsError::InvalidMsg(format!("unexpected reply to PSYNC {:?}", other)))?,
+        };
+        Ok(Source { addr: addr.to_owned(), options: options.clone(), events, offset, keys: HashMap::new() })
+    }
+
+    fn moving(&self) -> usize {
+        self.keys.values().map(HashSet::len).sum()
+    }
+
+    /// copy the entries of the snapshot moving to the new backend, reading one at a time
+    fn copy_snapshot(&mut self, new_ring: &HashRing, target: &mut Target) -> Result<()> {
+        loop {
+            match self.next()? {
+                ReplicationEvent::Snapshot(SnapshotEntry { namespace, key, value }) => {
+                    if new_ring.owner(&key) == Some(&target.addr) {
+                        target.send(&namespace, &["SET", &key, &value])?;
+                        self.keys.entry(namespace).or_default().insert(key);
+                    }
+                }
+                ReplicationEvent::SnapshotEnd => return Ok(()),
+                other => Err(KvsError::InvalidMsg(format!("unexpected push in the snapshot {:?}", other)))?,
+            }
+        }
+    }
+
+    /// copy the writes of the keys moving, up to the last write of the backend when it is called
+    fn catch_up(&mut self, new_ring: &HashRing, target: &mut Target) -> Result<()> {
+        let until = repl_offset(&self.addr, &self.options)?;
+        while self.offset < until {
+            let (offset, namespace, behavior) = match self.next()? {
+                ReplicationEvent::Write { offset, namespace, behavior } => (offset, namespace, behavior),
+                other => Err(KvsError::InvalidMsg(format!("unexpected push in the stream {:?}", other)))?,
+            };
+            self.offset = offset;
+            match behavior {
+                Behavior::Set { key, value } if new_ring.owner(&key) == Some(&target.addr) => {
+                    target.send(&namespace, &["SET", &key, &value])?;
+                    self.keys.entry(namespace).or_default().insert(key);
+                }
+                Behavior::Remove { key } if new_ring.owner(&key) == Some(&target.addr) => {
+                    target.send(&namespace, &["RM", &key])?;
+                    if let Some(keys) = self.keys.get_mut(&namespace) {
+                        keys.remove(&key);
+                    }
+                }
+                Behavior::FlushDb => {
+                    for key in self.keys.remove(&namespace).unwrap_or_default() {
+                        target.send(&namespace, &["RM", &key])?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<ReplicationEvent> {
+        match self.events.next() {
+            Some(Ok(event)) => Ok(event),
+            Some(Err(e)) => Err(backend_error(&self.addr, e)),
+            None => Err(backend_error(&self.addr, "the replication stream is closed")),
+        }
+    }
+}
+
+/// the new backend, the writes are pipelined by `MIGRATION_BATCH`
+struct Target {
+    addr: String,
+    client: KvsClient,
+    /// the namespace selected by the last request queued
+    namespace: Option<String>,
+    requests: Vec<Msg>,
+}
+
+impl Target {
+    fn connect(addr: &str, options: &ConnectOptions) -> Result<Target> {
+        Ok(Target { addr: addr.to_owned(), client: connect(addr, options)?, namespace: None, requests: Vec::new() })
+    }
+
+    fn send(&mut self, namespace: &str, args: &[&str]) -> Result<()> {
+        if self.namespace.as_deref() != Some(namespace) {
+            self.requests.push(command(&["SELECT", namespace]));
+            self.namespace = Some(namespace.to_owned());
+        }
+        self.requests.push(command(args));
+        if self.requests.len() >= MIGRATION_BATCH {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        if self.requests.is_empty() {
+            return Ok(());
+        }
+        let requests = std::mem::take(&mut self.requests);
+        check_replies(&self.addr, self.client.pipeline(&requests))
+    }
+
+    /// remove the keys copied from `sources` after a failure, on a new connection in case the failure broke it
+    fn remove_copied(&mut self, sources: &[Source], options: &ConnectOptions) {
+        let removed = Target::connect(&self.addr, options).and_then(|mut target| {
+            for source in sources {
+                for (namespace, keys) in &source.keys {
+                    for key in keys {
+                        target.send(namespace, &["RM", key])?;
+                    }
+                }
+            }
+            target.flush()
+        });
+        if let Err(e) = removed {
+            log::error!("copied keys left, addr={}, {}", self.addr, e);
+        }
+    }
+}
+
+/// the offset of the last write of the backend
+fn repl_offset(addr: &str, options: &ConnectOptions) -> Result<u64> {
+    let info = match connect(addr, options)?.info(Some("replication")).map_err(|e| backend_error(addr, e))? {
+        Msg::Bulk(Some(info)) => info,
+        other => Err(KvsError::InvalidMsg(format!("unexpected reply to INFO {:?}", other)))?,
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix("repl_offset:"))
+        .and_then(|offset| offset.trim().parse().ok())
+        .ok_or_else(|| KvsError::InvalidMsg("no repl_offset in INFO".to_owned()).into())
+}
+
+/// remove the keys moved away from the backend, tried `REMOVE_ATTEMPTS` times
+fn remove_moved(backend: &str, keys: &HashMap<String, HashSet<String>>, options: &ConnectOptions) -> Result<()> {
+    let mut requests = Vec::new();
+    for (namespace, keys) in keys {
+        requests.push(command(&["SELECT", namespace]));
+        requests.extend(keys.iter().map(|key| command(&["RM", key])));
+    }
+    let mut attempt = 1;
+    loop {
+        match connect(backend, options).and_then(|mut client| check_replies(backend, client.pipeline(&requests))) {
+            Err(e) if attempt < REMOVE_ATTEMPTS => {
+                log::warn!("removing moved keys failed, addr={}, attempt={}, {}", backend, attempt, e);
+                thread::sleep(REMOVE_RETRY_DELAY);
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// return the first error reply as the error, but for the `RM` of a key removed already
+fn check_replies(addr: &str, replies: Result<Vec<Msg>>) -> Result<()> {
+    let key_not_found = KvsError::KeyNotFound.to_string();
+    for reply in replies.map_err(|e| backend_error(addr, e))? {
+        match reply {
+            Msg::Error(e) if e != key_not_found => {
+                Err(anyhow::anyhow!("migration to or from {} failed, {}", addr, e))?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn connect(addr: &str, options: &ConnectOptions) -> Result<KvsClient> {
+    KvsClient::connect_with(addr.to_owned(), options).map_err(|e| backend_error(addr, e))
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|arg| (*arg).to_owned()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+pub(crate) fn backend_error(addr: &str, reason: impl std::fmt::Display) -> anyhow::Error {
+    KvsError::BackendUnreachable { addr: addr.to_owned(), reason: reason.to_string() }.into()
+}
@@ -0,0 +1,572 @@
+//! a proxy speaking the RESP protocol of `KvsServer`, sharding the keys over several kvs servers
+//!
+//! every key belongs to one backend by consistent hashing, see `HashRing`. the proxy routes
+//! - `GET`, `SET` and `RM` to the backend of the key
+//! - `MGET` and `MSET` split by backend, the parts sent to the backends at the same time.
+//!   a `MSET` failing on a backend may leave the keys of the other backends set
+//! - `DBSIZE` and `FLUSHDB` to every backend, `DBSIZE` replies the sum
+//! - `SELECT`, `HELLO`, `INFO` and `SHUTDOWN` are served by the proxy itself
+//!
+//! `PROXY BACKENDS` lists the backends, `PROXY ADDBACKEND IP:PORT` adds a backend and moves the keys it takes
+//! from the others, replying their number. the requests go on during the migration, see `add_backend`.
+//! the backends added are not kept, a restarted proxy should be given all of them.
+//! the pub/sub, `WATCH` and the admin commands of a single server are rejected, and the backends may not
+//! require `AUTH`
+//!
+//! the backends are connected with a timeout, `DEFAULT_BACKEND_TIMEOUT` unless it is set by `with_backend_timeout`,
+//! a backend not replying in time fails the request.
+//! the connections to the proxy are limited like those of `KvsServer`, by `MsgLimits`, `ConnectionTimeouts` and
+//! the max number of clients. with an `Acl` every connection must `AUTH` first, `SHUTDOWN` and `PROXY` need the
+//! `admin` category
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::acl::{command_name, Acl, Category, User, DEFAULT_USER};
+use crate::client::{ConnectOptions, KvsClient};
+use crate::engines::{check_namespace, DEFAULT_NAMESPACE};
+use crate::error::KvsError;
+use crate::model::{read_msg_from, Behavior, Msg, MsgLimits, Protocol};
+use crate::net::{Address, Listener, Stream};
+use crate::server::{is_timeout, send_msg, ConnectionTimeouts, DeadlineStream, ACCEPT_ERROR_BACKOFF, REJECT_TIMEOUT};
+use crate::session::{protocol_error_reply, ClientRegistry, Closer};
+use crate::shutdown::ShutdownHandle;
+use crate::Result;
+
+use migration::backend_error;
+pub use ring::{HashRing, DEFAULT_VIRTUAL_NODES};
+
+mod migration;
+mod ring;
+
+/// the connect, read and write timeout of a backend, unless `KvsProxy::with_backend_timeout` sets another
+pub const DEFAULT_BACKEND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// a proxy in front of the backends, every connection runs on a thread of its own
+pub struct KvsProxy {
+    address: Address,
+    backends: Vec<String>,
+    virtual_nodes: usize,
+    backend_timeout: Duration,
+    limits: MsgLimits,
+    timeouts: ConnectionTimeouts,
+    max_clients: Option<usize>,
+    acl: Option<Acl>,
+    shutdown: ShutdownHandle,
+}
+
+impl KvsProxy {
+    /// listen on `binding_address`, `IP:PORT` or `unix://PATH`, in front of the kvs servers at `backends`
+    pub fn new(binding_address: String, backends: Vec<String>) -> Self {
+        KvsProxy {
+            address: Address::parse(&binding_address),
+            backends,
+            virtual_nodes: DEFAULT_VIRTUAL_NODES,
+            backend_timeout: DEFAULT_BACKEND_TIMEOUT,
+            limits: MsgLimits::default(),
+            timeouts: ConnectionTimeouts::default(),
+            max_clients: None,
+            acl: None,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// place every backend on the ring `virtual_nodes` times, `DEFAULT_VIRTUAL_NODES` if not set.
+    /// the proxies in front of the same backends should use the same number
+    pub fn with_virtual_nodes(mut self, virtual_nodes: usize) -> Self {
+        self.virtual_nodes = virtual_nodes;
+        self
+    }
+
+    /// fail a request if a backend is not connected, or a msg is not sent to or received from it, in `timeout`.
+    /// the migration waits for the requests running, up to `timeout` each
+    pub fn with_backend_timeout(mut self, timeout: Duration) -> Self {
+        self.backend_timeout = timeout;
+        self
+    }
+
+    /// set the limits of the request msg, `MsgLimits::default()` is used if not set
+    pub fn with_limits(mut self, limits: MsgLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// set the connection timeouts, no timeout if not set
+    pub fn with_timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// reject the new connections with an error reply when `max_clients` clients are connected,
+    /// no limit if not set
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
+    }
+
+    /// require every connection to authenticate by `AUTH` as a user of `acl` before other commands
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// the handle to stop the proxy, `start` returns after the proxy is stopped
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// bind the address and proxy the connections until shutdown
+    pub fn start(&mut self) -> Result<()> {
+        if self.backends.is_empty() {
+            Err(KvsError::InvalidConfig("no backend, accept one at least".to_owned()))?
+        }
+        let mut ring = HashRing::new(self.virtual_nodes);
+        for backend in &self.backends {
+            ring.add(backend);
+        }
+        let listener = Listener::bind(&self.address)?;
+        let local_address = listener.local_address()?;
+        self.shutdown.on_shutdown(move || {
+            let _ = Stream::connect(&local_address);
+        });
+        let context = Arc::new(ProxyContext {
+            ring: RwLock::new(Arc::new(ring)),
+            migrating: Mutex::new(()),
+            backend_connect: ConnectOptions { timeout: Some(self.backend_timeout), ..Default::default() },
+            shutdown: self.shutdown.clone(),
+            started: Instant::now(),
+            clients: ClientRegistry::new(self.max_clients),
+            limits: self.limits.clone(),
+            timeouts: self.timeouts.clone(),
+            acl: self.acl.clone(),
+            commands: AtomicU64::new(0),
+            migrated: AtomicU64::new(0),
+        });
+
+        let mut connections: Vec<JoinHandle<()>> = Vec::new();
+        loop {
+            let accepted = listener.accept();
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            match accepted {
+                Ok((stream, peer_addr)) => {
+                    connections.retain(|connection| !connection.is_finished());
+                    let context = context.clone();
+                    connections.push(thread::spawn(move || serve_connection(context, stream, peer_addr)));
+                }
+                Err(e) => {
+                    // e.g. too many open files, wait for the failure to clear like `KvsServer`
+                    log::error!("accept error, {}", e);
+                    thread::sleep(ACCEPT_ERROR_BACKOFF);
+                }
+            }
+        }
+        drop(listener);
+
+        log::info!("shutting down, closing client connections");
+        context.clients.close_all();
+        for connection in connections {
+            let _ = connection.join();
+        }
+        log::info!("proxy stopped");
+        Ok(())
+    }
+}
+
+/// the state shared by all connections of a proxy
+struct ProxyContext {
+    /// cloned by every request to route by, swapped by the migration
+    ring: RwLock<Arc<HashRing>>,
+    /// held by the running migration
+    migrating: Mutex<()>,
+    /// how the backends are connected, with the backend timeout
+    backend_connect: ConnectOptions,
+    shutdown: ShutdownHandle,
+    started: Instant,
+    clients: ClientRegistry,
+    limits: MsgLimits,
+    timeouts: ConnectionTimeouts,
+    /// every connection must authenticate if it is set
+    acl: Option<Acl>,
+    commands: AtomicU64,
+    /// the keys moved by all migrations
+    migrated: AtomicU64,
+}
+
+fn serve_connection(context: Arc<ProxyContext>, mut stream: Stream, peer_addr: String) {
+    let closer: Closer = match stream.try_clone_socket() {
+        Ok(socket) => Box::new(move || {
+            let _ = socket.shutdown(Shutdown::Read);
+        }),
+        Err(e) => {
+            log::error!("connection error, peer={}, {}", peer_addr, e);
+            return;
+        }
+    };
+    let id = match context.clients.register(peer_addr.clone(), Some(closer)) {
+        Ok(id) => id,
+        Err(e) => {
+            log::warn!("connection rejected, peer={}, {}", peer_addr, e);
+            let _ = stream.set_read_timeout(Some(REJECT_TIMEOUT));
+            let _ = send_msg(&mut stream, &Msg::Error(e.to_string()), Some(REJECT_TIMEOUT));
+            return;
+        }
+    };
+    let mut session = ProxySession {
+        context: context.clone(),
+        protocol: Protocol::Resp2,
+        namespace: DEFAULT_NAMESPACE.to_owned(),
+        user: None,
+        backends: HashMap::new(),
+    };
+    match session.run(&mut stream) {
+        Ok(Some(reason)) => log::warn!("connection closed, peer={}, {}", peer_addr, reason),
+        Ok(None) => {}
+        Err(e) => log::error!("connection error, peer={}, {}", peer_addr, e),
+    }
+    context.clients.unregister(id);
+}
+
+/// a client connection and its connections to the backends
+struct ProxySession {
+    context: Arc<ProxyContext>,
+    protocol: Protocol,
+    /// the selected namespace, selected on every backend before the request
+    namespace: String,
+    /// the authenticated user, always `None` if the proxy has no ACL
+    user: Option<Arc<User>>,
+    /// connected on the first request to the backend, by the address
+    backends: HashMap<String, Backend>,
+}
+
+impl ProxySession {
+    /// serve the requests until the client closes the connection or the proxy shuts down,
+    /// return the reason if the connection is closed by a timeout
+    fn run(&mut self, stream: &mut Stream) -> Result<Option<&'static str>> {
+        let context = self.context.clone();
+        let timeouts = &context.timeouts;
+        stream.set_write_timeout(None)?;
+        while !context.shutdown.is_shutdown() {
+            stream.set_read_timeout(timeouts.idle)?;
+            match stream.wait_readable() {
+                Ok(false) => return Ok(None), // closed by peer
+                Ok(true) => {}
+                Err(e) if is_timeout(&e) => return Ok(Some("idle timeout")),
+                Err(e) => Err(e)?,
+            }
+            stream.set_read_timeout(None)?;
+
+            let mut reader = DeadlineStream::new(stream, timeouts.read);
+            let msg = match read_msg_from(&mut reader, &context.limits) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if e.downcast_ref::<io::Error>().is_some_and(is_timeout) {
+                        return Ok(Some("read timeout"));
+                    }
+                    if let Some(reply) = protocol_error_reply(&e) {
+                        let _ = send_msg(stream, &reply, timeouts.write);
+                    }
+                    return Err(e);
+                }
+            };
+            let reply = self.handle_msg(msg);
+            match send_msg(stream, &reply, timeouts.write) {
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => return Ok(Some("write timeout")),
+                Err(e) => Err(e)?,
+            }
+        }
+        Ok(None)
+    }
+
+    /// run the command in `msg`, return the reply encoded for the protocol of the session
+    fn handle_msg(&mut self, msg: Msg) -> Msg {
+        self.context.commands.fetch_add(1, Ordering::Relaxed);
+        let reply = self.dispatch(msg).unwrap_or_else(|e| Msg::Error(e.to_string()));
+        reply.for_protocol(self.protocol)
+    }
+
+    fn dispatch(&mut self, msg: Msg) -> Result<Msg> {
+        let args = msg.try_to_vec_string().unwrap_or_default();
+        if args.first().is_some_and(|cmd| cmd.eq_ignore_ascii_case("proxy")) {
+            self.check_admin("proxy")?;
+            return self.proxy_command(&args[1..]);
+        }
+        let behavior = msg.try_to_behavior()?;
+        self.check_permission(&behavior)?;
+        let ring = Arc::clone(&self.context.ring.read().unwrap());
+        let reply = match behavior {
+            Behavior::Get { ref key } | Behavior::Set { ref key, .. } | Behavior::Remove { ref key } => {
+                self.forward(owner(&ring, key), &msg)?
+            }
+            Behavior::MGet { keys } => {
+                let groups = group_by_owner(&ring, keys.iter().map(String::as_str));
+                let requests = groups.iter()
+                    .map(|(addr, indexes)| {
+                        let mut args = vec!["MGET".to_owned()];
+                        args.extend(indexes.iter().map(|i| keys[*i].clone()));
+                        (*addr, Msg::build_bulk_array(&args))
+                    })
+                    .collect();
+                let mut values = vec![Msg::Null; keys.len()];
+                for ((_, indexes), reply) in groups.iter().zip(self.fan_out(requests)?) {
+                    match reply {
+                        Msg::Array(items) if items.len() == indexes.len() => {
+                            for (i, item) in indexes.iter().zip(items) {
+                                values[*i] = item;
+                            }
+                        }
+                        Msg::Error(e) => return Ok(Msg::Error(e)),
+                        other => Err(KvsError::InvalidMsg(format!("unexpected reply to MGET {:?}", other)))?,
+                    }
+                }
+                Msg::Array(values)
+            }
+            Behavior::MSet { pairs } => {
+                let groups = group_by_owner(&ring, pairs.iter().map(|(key, _)| key.as_str()));
+                let requests = groups.iter()
+                    .map(|(addr, indexes)| {
+                        let mut args = vec!["MSET".to_owned()];
+                        for i in indexes {
+                            args.extend([pairs[*i].0.clone(), pairs[*i].1.clone()]);
+                        }
+                        (*addr, Msg::build_bulk_array(&args))
+                    })
+                    .collect();
+                first_error(self.fan_out(requests)?).unwrap_or_else(|| Msg::Line("OK".to_owned()))
+            }
+            Behavior::DbSize => {
+                let mut size = 0;
+                for reply in self.broadcast(&ring, &msg)? {
+                    match reply {
+                        Msg::Integer(n) => size += n,
+                        Msg::Error(e) => return Ok(Msg::Error(e)),
+                        other => Err(KvsError::InvalidMsg(format!("unexpected reply to DBSIZE {:?}", other)))?,
+                    }
+                }
+                Msg::Integer(size)
+            }
+            Behavior::FlushDb => {
+                first_error(self.broadcast(&ring, &msg)?).unwrap_or_else(|| Msg::Line("OK".to_owned()))
+            }
+            Behavior::Select { namespace } => {
+                check_namespace(&namespace)?;
+                self.namespace = namespace;
+                Msg::Line("OK".to_owned())
+            }
+            Behavior::Hello { protocol } => {
+                if let Some(p) = protocol {
+                    self.protocol = p;
+                }
+                self.hello_reply(&ring)
+            }
+            Behavior::Auth { user, password } => {
+                self.authenticate(user, &password)?;
+                Msg::Line("OK".to_owned())
+            }
+            Behavior::Info { section } => Msg::Bulk(Some(self.info_reply(&ring, section.as_deref()))),
+            Behavior::Shutdown => {
+                self.context.shutdown.shutdown();
+                Msg::Line("OK".to_owned())
+            }
+            other => Err(KvsError::ProxyUnsupported(command_name(&other).to_owned()))?,
+        };
+        Ok(reply)
+    }
+
+    /// return `KvsError::NoAuth` before the connection authenticates, or a `NOPERM` error if the user may not run it
+    fn check_permission(&self, behavior: &Behavior) -> Result<()> {
+        if self.context.acl.is_none() {
+            return Ok(());
+        }
+        match (&self.user, behavior) {
+            (_, Behavior::Auth { .. }) | (_, Behavior::Hello { .. }) => Ok(()),
+            (Some(user), _) => user.check(behavior),
+            (None, _) => Err(KvsError::NoAuth.into()),
+        }
+    }
+
+    /// like `check_permission` for the `command` of the proxy itself, run by the `admin` users only
+    fn check_admin(&self, command: &str) -> Result<()> {
+        if self.context.acl.is_none() {
+            return Ok(());
+        }
+        match &self.user {
+            Some(user) if user.categories.contains(&Category::Admin) => Ok(()),
+            Some(user) => Err(KvsError::NoPermCommand { user: user.name.clone(), command: command.to_owned() })?,
+            None => Err(KvsError::NoAuth)?,
+        }
+    }
+
+    /// a failed `AUTH` keeps the user authenticated before
+    fn authenticate(&mut self, user: Option<String>, password: &str) -> Result<()> {
+        let acl = match &self.context.acl {
+            Some(acl) => acl,
+            None => Err(KvsError::AuthNotConfigured)?,
+        };
+        self.user = Some(acl.authenticate(user.as_deref().unwrap_or(DEFAULT_USER), password)?);
+        Ok(())
+    }
+
+    /// `PROXY BACKENDS` or `PROXY ADDBACKEND IP:PORT`
+    fn proxy_command(&mut self, args: &[String]) -> Result<Msg> {
+        let subcommand = args.first().map(|s| s.to_lowercase());
+        match (subcommand.as_deref(), args.len()) {
+            (Some("backends"), 1) => {
+                let ring = self.context.ring.read().unwrap();
+                Ok(Msg::Array(ring.backends().iter().map(|addr| Msg::Bulk(Some(addr.clone()))).collect()))
+            }
+            (Some("addbackend"), 2) => {
+                let moved = migration::add_backend(&self.context, &args[1])?;
+                self.context.migrated.fetch_add(moved as u64, Ordering::Relaxed);
+                Ok(Msg::Integer(moved as i64))
+            }
+            _ => Err(KvsError::InvalidArgumentNumber)?,
+        }
+    }
+
+    /// send `msg` to the backend at `addr`, a broken connection is dropped and connected again by the next request
+    fn forward(&mut self, addr: &str, msg: &Msg) -> Result<Msg> {
+        if !self.backends.contains_key(addr) {
+            self.backends.insert(addr.to_owned(), Backend::connect(addr, &self.context.backend_connect)?);
+        }
+        let reply = self.backends.get_mut(addr).unwrap().request(&self.namespace, msg);
+        if reply.is_err() {
+            self.backends.remove(addr);
+        }
+        reply
+    }
+
+    /// send every request to its backend at the same time, one request for each backend at most,
+    /// return the replies in the order of the requests
+    fn fan_out(&mut self, requests: Vec<(&str, Msg)>) -> Result<Vec<Msg>> {
+        if let [(addr, msg)] = requests.as_slice() {
+            return Ok(vec![self.forward(addr, msg)?]);
+        }
+        for (addr, _) in &requests {
+            if !self.backends.contains_key(*addr) {
+                self.backends.insert((*addr).to_owned(), Backend::connect(addr, &self.context.backend_connect)?);
+            }
+        }
+        let namespace = &self.namespace;
+        let mut backends: HashMap<&str, &mut Backend> = self.backends.iter_mut()
+            .map(|(addr, backend)| (addr.as_str(), backend))
+            .collect();
+        let replies: Vec<Result<Msg>> = thread::scope(|scope| {
+            let handles: Vec<_> = requests.iter()
+                .map(|(addr, msg)| {
+                    let backend = backends.remove(addr).expect("one request for each backend");
+                    scope.spawn(move || backend.request(namespace, msg))
+                })
+                .collect();
+            handles.into_iter().map(|handle| handle.join().unwrap()).collect()
+        });
+        for ((addr, _), reply) in requests.iter().zip(&replies) {
+            if reply.is_err() {
+                self.backends.remove(*addr);
+            }
+        }
+        replies.into_iter().collect()
+    }
+
+    /// send `msg` to every backend, see `fan_out`
+    fn broadcast(&mut self, ring: &HashRing, msg: &Msg) -> Result<Vec<Msg>> {
+        self.fan_out(ring.backends().iter().map(|addr| (addr.as_str(), msg.clone())).collect())
+    }
+
+    /// the proxy properties replied to `HELLO`
+    fn hello_reply(&self, ring: &HashRing) -> Msg {
+        let bulk = |s: &str| Msg::Bulk(Some(s.to_owned()));
+        Msg::Map(vec![
+            (bulk("server"), bulk("kvs-proxy")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Msg::Integer(self.protocol.version())),
+            (bulk("mode"), bulk("proxy")),
+            (bulk("backends"), Msg::Integer(ring.backends().len() as i64)),
+        ])
+    }
+
+    /// the `INFO` text, only the `# Proxy` section, nothing if another section is asked
+    fn info_reply(&self, ring: &HashRing, section: Option<&str>) -> String {
+        let mut info = String::new();
+        if !matches!(section, None | Some("all") | Some("default") | Some("proxy")) {
+            return info;
+        }
+        let context = &self.context;
+        let fields = [
+            ("kvs_version", env!("CARGO_PKG_VERSION").to_owned()),
+            ("process_id", std::process::id().to_string()),
+            ("uptime_in_seconds", context.started.elapsed().as_secs().to_string()),
+            ("connected_clients", context.clients.connected().to_string()),
+            ("total_commands_processed", context.commands.load(Ordering::Relaxed).to_string()),
+            ("backends", ring.backends().join(",")),
+            ("virtual_nodes", ring.virtual_nodes().to_string()),
+            ("migrated_keys", context.migrated.load(Ordering::Relaxed).to_string()),
+        ];
+        info.push_str("# Proxy\r\n");
+        for (field, value) in fields {
+            let _ = write!(info, "{}:{}\r\n", field, value);
+        }
+        info
+    }
+}
+
+/// a connection from the proxy to a backend, by RESP3, the replies are converted for the client
+struct Backend {
+    addr: String,
+    client: KvsClient,
+    /// the namespace selected on the connection
+    namespace: String,
+}
+
+impl Backend {
+    fn connect(addr: &str, options: &ConnectOptions) -> Result<Backend> {
+        let mut client = KvsClient::connect_with(addr.to_owned(), options).map_err(|e| backend_error(addr, e))?;
+        if let Msg::Error(e) = client.hello(Protocol::Resp3).map_err(|e| backend_error(addr, e))? {
+            Err(backend_error(addr, e))?
+        }
+        Ok(Backend { addr: addr.to_owned(), client, namespace: DEFAULT_NAMESPACE.to_owned() })
+    }
+
+    /// send `msg` on `namespace`, the error reply of selecting the namespace is the reply.
+    /// return `KvsError::BackendUnreachable` if the connection is broken
+    fn request(&mut self, namespace: &str, msg: &Msg) -> Result<Msg> {
+        if self.namespace != namespace {
+            let select = Msg::build_bulk_array(&vec!["SELECT".to_owned(), namespace.to_owned()]);
+            match self.client.request_msg(select).map_err(|e| backend_error(&self.addr, e))? {
+                Msg::Error(e) => return Ok(Msg::Error(e)),
+                _ => self.namespace = namespace.to_owned(),
+            }
+        }
+        self.client.request_msg(msg.clone()).map_err(|e| backend_error(&self.addr, e))
+    }
+}
+
+/// the proxy starts with a backend and never removes one
+fn owner<'r>(ring: &'r HashRing, key: &str) -> &'r str {
+    ring.owner(key).expect("the ring has a backend")
+}
+
+/// the indexes of the keys of each backend, the backends in the order of their first key
+fn group_by_owner<'r, 'k>(ring: &'r HashRing, keys: impl Iterator<Item = &'k str>) -> Vec<(&'r str, Vec<usize>)> {
+    let mut groups: Vec<(&str, Vec<usize>)> = Vec::new();
+    for (i, key) in keys.enumerate() {
+        let addr = owner(ring, key);
+        match groups.iter_mut().find(|(group, _)| *group == addr) {
+            Some((_, indexes)) => indexes.push(i),
+            None => groups.push((addr, vec![i])),
+        }
+    }
+    groups
+}
+
+fn first_error(replies: Vec<Msg>) -> Option<Msg> {
+    replies.into_iter().find(|reply| matches!(reply, Msg::Error(_)))
+}
@@ -0,0 +1,63 @@
+//! consistent hashing of the keys onto the backends
+
+use crate::hash::hash;
+
+/// the points of each backend on the ring, unless `HashRing::new` is given another number
+pub const DEFAULT_VIRTUAL_NODES: usize = 160;
+
+/// the backends placed on a ring of `u64` hashes, `virtual_nodes` points each.
+/// a key belongs to the backend of the first point at or after its hash, wrapping around,
+/// so adding a backend moves only the keys the new points take, about `1 / backends` of them
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    virtual_nodes: usize,
+    backends: Vec<String>,
+    /// sorted by the hash, the index into `backends`
+    points: Vec<(u64, usize)>,
+}
+
+impl HashRing {
+    /// an empty ring, `virtual_nodes` points for every backend added
+    pub fn new(virtual_nodes: usize) -> Self {
+        HashRing { virtual_nodes: virtual_nodes.max(1), backends: Vec::new(), points: Vec::new() }
+    }
+
+    /// add the backend at `addr`, nothing if it is on the ring already
+    pub fn add(&mut self, addr: &str) {
+        if self.contains(addr) {
+            return;
+        }
+        let index = self.backends.len();
+        self.backends.push(addr.to_owned());
+        self.points.extend((0..self.virtual_nodes).map(|i| (hash(&format!("{}#{}", addr, i)), index)));
+        // the ties are broken by the address, so the owner does not depend on the order of the calls
+        let backends = &self.backends;
+        self.points.sort_unstable_by(|(a, i), (b, j)| (a, &backends[*i]).cmp(&(b, &backends[*j])));
+    }
+
+    #[allow(missing_docs)]
+    pub fn contains(&self, addr: &str) -> bool {
+        self.backends.iter().any(|backend| backend == addr)
+    }
+
+    /// the backends in the order they are added
+    pub fn backends(&self) -> &[String] {
+        &self.backends
+    }
+
+    #[allow(missing_docs)]
+    pub fn virtual_nodes(&self) -> usize {
+        self.virtual_nodes
+    }
+
+    /// the backend of `key`, `None` if the ring is empty
+    pub fn owner(&self, key: &str) -> Option<&str> {
+        if self.points.is_empty() {
+            return None;
+        }
+        let h = hash(key);
+        let i = self.points.partition_point(|(point, _)| *point < h);
+        let (_, index) = self.points[i % self.points.len()];
+        Some(&self.backends[index])
+    }
+}
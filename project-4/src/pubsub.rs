@@ -0,0 +1,238 @@
+//! the channels and patterns subscribed by the sessions of a server
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::sync::Mutex;
+
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+
+use crate::model::Msg;
+
+/// the max number of messages queued for a subscriber,
+/// a subscriber falling behind further is unsubscribed from everything
+pub(crate) const PUSH_QUEUE_CAPACITY: usize = 1024;
+
+/// a session subscribed to at least one channel or pattern
+struct Subscriber {
+    tx: Sender<Msg>,
+    channels: BTreeSet<String>,
+    patterns: BTreeSet<String>,
+}
+
+impl Subscriber {
+    fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    subscribers: HashMap<u64, Subscriber>,
+    /// channel => the ids of the sessions
+    channels: HashMap<String, HashSet<u64>>,
+    /// pattern => the ids of the sessions
+    patterns: HashMap<String, HashSet<u64>>,
+}
+
+impl Registry {
+    fn remove_subscriber(&mut self, id: u64) {
+        if let Some(subscriber) = self.subscribers.remove(&id) {
+            for channel in &subscriber.channels {
+                remove_id(&mut self.channels, channel, id);
+            }
+            for pattern in &subscriber.patterns {
+                remove_id(&mut self.patterns, pattern, id);
+            }
+        }
+    }
+}
+
+fn remove_id(index: &mut HashMap<String, HashSet<u64>>, name: &str, id: u64) {
+    if let Some(ids) = index.get_mut(name) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(name);
+        }
+    }
+}
+
+/// whether to subscribe a channel by its name or by a glob pattern
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Kind {
+    Channel,
+    Pattern,
+}
+
+/// the publish/subscribe broker shared by the sessions of a server
+#[derive(Default)]
+pub(crate) struct PubSub {
+    registry: Mutex<Registry>,
+}
+
+impl PubSub {
+    /// subscribe the session `id`, return the receiver of the pushed messages if it is the first subscription
+    /// of the session, and the number of its subscriptions after it
+    pub fn subscribe(&self, id: u64, kind: Kind, name: &str) -> (Option<Receiver<Msg>>, usize) {
+        let mut registry = self.registry.lock().unwrap();
+        let mut rx = None;
+        let subscriber = registry.subscribers.entry(id).or_insert_with(|| {
+            let (tx, new_rx) = mpsc::channel(PUSH_QUEUE_CAPACITY);
+            rx = Some(new_rx);
+            Subscriber { tx, channels: BTreeSet::new(), patterns: BTreeSet::new() }
+        });
+        let added = match kind {
+            Kind::Channel => subscriber.channels.insert(name.to_owned()),
+            Kind::Pattern => subscriber.patterns.insert(name.to_owned()),
+        };
+        let count = subscriber.count();
+        if added {
+            let index = match kind {
+                Kind::Channel => &mut registry.channels,
+                Kind::Pattern => &mut registry.patterns,
+            };
+            index.entry(name.to_owned()).or_default().insert(id);
+        }
+        (rx, count)
+    }
+
+    /// unsubscribe the session `id`, return the number of its subscriptions after it,
+    /// the session is removed when it has none
+    pub fn unsubscribe(&self, id: u64, kind: Kind, name: &str) -> usize {
+        let mut registry = self.registry.lock().unwrap();
+        let subscriber = match registry.subscribers.get_mut(&id) {
+            Some(subscriber) => subscriber,
+            None => return 0,
+        };
+        let removed = match kind {
+            Kind::Channel => subscriber.channels.remove(name),
+            Kind::Pattern => subscriber.patterns.remove(name),
+        };
+        let count = subscriber.count();
+        if removed {
+            match kind {
+                Kind::Channel => remove_id(&mut registry.channels, name, id),
+                Kind::Pattern => remove_id(&mut registry.patterns, name, id),
+            }
+        }
+        if count == 0 {
+            registry.subscribers.remove(&id);
+        }
+        count
+    }
+
+    /// the channels or patterns subscribed by the session `id`, in order
+    pub fn subscriptions(&self, id: u64, kind: Kind) -> Vec<String> {
+        let registry = self.registry.lock().unwrap();
+        match (registry.subscribers.get(&id), kind) {
+            (Some(subscriber), Kind::Channel) => subscriber.channels.iter().cloned().collect(),
+            (Some(subscriber), Kind::Pattern) => subscriber.patterns.iter().cloned().collect(),
+            (None, _) => Vec::new(),
+        }
+    }
+
+    /// remove all subscriptions of the session `id`
+    pub fn remove(&self, id: u64) {
+        self.registry.lock().unwrap().remove_subscriber(id);
+    }
+
+    /// push `message` to the subscribers of `channel` and of the patterns matching it,
+    /// return the number of the pushes
+    ///
+    /// a subscriber whose queue is full is removed, its receiver sees the channel closed
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        let bulk = |s: &str| Msg::Bulk(Some(s.to_owned()));
+        let mut registry = self.registry.lock().unwrap();
+        let mut pushes = Vec::new();
+        if let Some(ids) = registry.channels.get(channel) {
+            let push = Msg::Push(vec![bulk("message"), bulk(channel), bulk(message)]);
+            pushes.extend(ids.iter().map(|id| (*id, push.clone())));
+        }
+        for (pattern, ids) in &registry.patterns {
+            if glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                let push = Msg::Push(vec![bulk("pmessage"), bulk(pattern), bulk(channel), bulk(message)]);
+                pushes.extend(ids.iter().map(|id| (*id, push.clone())));
+            }
+        }
+
+        let mut count = 0;
+        let mut overflowed = Vec::new();
+        for (id, push) in pushes {
+            match registry.subscribers[&id].tx.try_send(push) {
+                Ok(()) => count += 1,
+                Err(TrySendError::Full(_)) => overflowed.push(id),
+                // the session is ending
+                Err(TrySendError::Closed(_)) => {}
+            }
+        }
+        for id in overflowed {
+            log::warn!("subscriber removed, id={}, {} messages queued", id, PUSH_QUEUE_CAPACITY);
+            registry.remove_subscriber(id);
+        }
+        count
+    }
+}
+
+/// match `text` against a glob `pattern`, like redis:
+/// `*` matches any bytes, `?` matches one byte, `[abc]`, `[^abc]` and `[a-z]` match one byte of the set,
+/// `\` escapes the next byte
+pub(crate) fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    // the position to resume from when the last `*` should take one more byte
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut t) = (0, 0);
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], text[t]),
+            Some(b'\\') if p + 1 < pattern.len() => Some(2).filter(|_| pattern[p + 1] == text[t]),
+            Some(c) => Some(1).filter(|_| *c == text[t]),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star, star_t))) => {
+                backtrack = Some((star, star_t + 1));
+                p = star + 1;
+                t = star_t + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+/// match `byte` against the class at the start of `pattern`, return the length of the class if it matches
+fn match_class(pattern: &[u8], byte: u8) -> Option<usize> {
+    let mut i = 1;
+    let negate = pattern.get(i) == Some(&b'^');
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    while i < pattern.len() && pattern[i] != b']' {
+        if pattern[i] == b'\\' && i + 1 < pattern.len() {
+            matched |= pattern[i + 1] == byte;
+            i += 2;
+        } else if i + 2 < pattern.len() && pattern[i + 1] == b'-' && pattern[i + 2] != b']' {
+            let (low, high) = (pattern[i].min(pattern[i + 2]), pattern[i].max(pattern[i + 2]));
+            matched |= (low..=high).contains(&byte);
+            i += 3;
+        } else {
+            matched |= pattern[i] == byte;
+            i += 1;
+        }
+    }
+    // an unclosed class matches to the end of the pattern
+    let len = (i + 1).min(pattern.len());
+    if matched != negate {
+        Some(len)
+    } else {
+        None
+    }
+}
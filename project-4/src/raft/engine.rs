@@ -0,0 +1,421 @@
+//! a node running on a thread in front of an engine
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crossbeam::{Receiver, RecvTimeoutError, Sender};
+
+use crate::engines::watch::KeyEvent;
+use crate::engines::{EngineStats, KvsEngine, SnapshotEntry, DEFAULT_NAMESPACE};
+use crate::error::KvsError;
+use crate::model::Behavior;
+use crate::raft::node::RaftNode;
+use crate::raft::storage::RaftStorage;
+use crate::raft::{Command, Entry, Members, Message, NodeId, RaftConfig, RaftStatus, Role, Snapshot, Transport};
+use crate::Result;
+
+/// how long a request waits for the node, the write may be applied later
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// the inputs of the thread of a node
+enum Input {
+    Message(Message),
+    Propose { command: Command, reply: Sender<Result<()>> },
+    ChangeMembers { change: MembersChange, reply: Sender<Result<()>> },
+    Read { reply: Sender<Result<()>> },
+    Status { reply: Sender<RaftStatus> },
+    Stop { done: Sender<()> },
+}
+
+enum MembersChange {
+    Add(NodeId, String),
+    Remove(NodeId),
+}
+
+/// run the node of `config` on a thread, applying the committed writes to `engine`
+///
+/// return the engine for the server of the node, and the handle taking the messages from `transport` of
+/// the other nodes. the node stops when the engine is closed
+pub fn start<KE: KvsEngine, T: Transport>(
+    config: RaftConfig,
+    engine: KE,
+    transport: T,
+) -> Result<(RaftEngine<KE>, RaftHandle)> {
+    let (storage, restored) = RaftStorage::open(config.dir.clone())?;
+    let node = RaftNode::new(&config, restored.state, restored.snapshot, restored.entries);
+    let (tx, inbox) = crossbeam::unbounded();
+    let handle = RaftHandle { id: config.id, inbox: tx };
+    let driver = Driver {
+        addresses: node.members().clone(),
+        node,
+        storage,
+        engine: engine.clone(),
+        transport,
+        tick: config.tick,
+        snapshot_threshold: config.snapshot_threshold.max(1),
+        inbox,
+        proposals: BTreeMap::new(),
+        reads: BTreeMap::new(),
+        confirmed: Vec::new(),
+        next_read_ctx: 0,
+    };
+    thread::Builder::new()
+        .name(format!("raft-{}", config.id))
+        .spawn(move || driver.run())?;
+    log::info!("raft node started, id={}", config.id);
+    let engine = RaftEngine { engine, namespace: DEFAULT_NAMESPACE.to_owned(), handle: handle.clone() };
+    Ok((engine, handle))
+}
+
+/// the handle of a running node
+#[derive(Clone)]
+pub struct RaftHandle {
+    id: NodeId,
+    inbox: Sender<Input>,
+}
+
+impl RaftHandle {
+    /// the id of the node
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    /// hand a message from another node to the node
+    pub fn step(&self, message: Message) {
+        let _ = self.inbox.send(Input::Message(message));
+    }
+
+    /// append `command` to the log, return the result of applying it once it is committed
+    ///
+    /// return `KvsError::NotLeader` or `KvsError::NoLeader` if the node is not the leader,
+    /// or `KvsError::TryAgain` if the leadership is lost before the command is committed
+    pub fn propose(&self, command: Command) -> Result<()> {
+        self.request(|reply| Input::Propose { command, reply })
+    }
+
+    /// return once the node is confirmed as the leader by a majority, and has applied every write
+    /// committed before the call, see `propose` for the errors
+    pub fn read(&self) -> Result<()> {
+        self.request(|reply| Input::Read { reply })
+    }
+
+    /// add the node `id` listening on `addr`, or change its address, return once the change is committed
+    pub fn add_member(&self, id: NodeId, addr: String) -> Result<()> {
+        self.request(|reply| Input::ChangeMembers { change: MembersChange::Add(id, addr), reply })
+    }
+
+    /// remove the node `id`, return once the change is committed
+    pub fn remove_member(&self, id: NodeId) -> Result<()> {
+        self.request(|reply| Input::ChangeMembers { change: MembersChange::Remove(id), reply })
+    }
+
+    #[allow(missing_docs)]
+    pub fn status(&self) -> Result<RaftStatus> {
+        let (reply, rx) = crossbeam::bounded(1);
+        self.inbox.send(Input::Status { reply }).map_err(|_| KvsError::RaftStopped)?;
+        Ok(rx.recv_timeout(REQUEST_TIMEOUT).map_err(|_| KvsError::RaftStopped)?)
+    }
+
+    /// stop the node, return after its thread ends
+    pub fn stop(&self) {
+        let (done, rx) = crossbeam::bounded(1);
+        if self.inbox.send(Input::Stop { done }).is_ok() {
+            let _ = rx.recv();
+        }
+    }
+
+    fn request(&self, input: impl FnOnce(Sender<Result<()>>) -> Input) -> Result<()> {
+        let (reply, rx) = crossbeam::bounded(1);
+        self.inbox.send(input(reply)).map_err(|_| KvsError::RaftStopped)?;
+        match rx.recv_timeout(REQUEST_TIMEOUT) {
+            Ok(result) => result,
+            Err(RecvTimeoutError::Timeout) => Err(KvsError::TryAgain.into()),
+            Err(RecvTimeoutError::Disconnected) => Err(KvsError::RaftStopped.into()),
+        }
+    }
+}
+
+/// the engine of a node, the writes go through the Raft log and the reads are served by the leader only
+///
+/// `watch`, `stats` and `snapshot` see the writes applied by this node, whether it is the leader or not
+#[derive(Clone)]
+pub struct RaftEngine<KE: KvsEngine> {
+    engine: KE,
+    namespace: String,
+    handle: RaftHandle,
+}
+
+impl<KE: KvsEngine> RaftEngine<KE> {
+    fn write(&self, behavior: Behavior) -> Result<()> {
+        self.handle.propose(Command::Write { namespace: self.namespace.clone(), behavior })
+    }
+}
+
+impl<KE: KvsEngine> KvsEngine for RaftEngine<KE> {
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.write(Behavior::Set { key, value })
+    }
+
+    fn get(&self, key: String) -> Result<Option<String>> {
+        self.handle.read()?;
+        self.engine.get(key)
+    }
+
+    fn remove(&self, key: String) -> Result<()> {
+        self.write(Behavior::Remove { key })
+    }
+
+    fn engine_name(&self) -> String {
+        self.engine.engine_name()
+    }
+
+    fn select(&self, namespace: &str) -> Result<Self> {
+        Ok(RaftEngine { engine: self.engine.select(namespace)?, namespace: namespace.to_owned(), ..self.clone() })
+    }
+
+    fn db_size(&self) -> Result<usize> {
+        self.handle.read()?;
+        self.engine.db_size()
+    }
+
+    fn flush_db(&self) -> Result<()> {
+        self.write(Behavior::FlushDb)
+    }
+
+    fn watch(&self, prefix: &str) -> Result<Receiver<KeyEvent>> {
+        self.engine.watch(prefix)
+    }
+
+    fn stats(&self) -> Result<EngineStats> {
+        self.engine.stats()
+    }
+
+    fn namespaces(&self) -> Result<Vec<String>> {
+        self.engine.namespaces()
+    }
+
+    fn snapshot(&self) -> Result<Vec<SnapshotEntry>> {
+        self.engine.snapshot()
+    }
+
+    fn close(&self) -> Result<()> {
+        self.handle.stop();
+        self.engine.close()
+    }
+}
+
+/// runs a node, see `start`
+struct Driver<KE: KvsEngine, T: Transport> {
+    node: RaftNode,
+    storage: RaftStorage,
+    engine: KE,
+    transport: T,
+    /// the address of every node known, the members removed included, to reply to them
+    addresses: Members,
+    tick: Duration,
+    snapshot_threshold: u64,
+    inbox: Receiver<Input>,
+    /// the proposals waiting to be applied, by index, with the term they were appended in
+    proposals: BTreeMap<u64, (u64, Sender<Result<()>>)>,
+    /// the reads waiting for a majority, by ctx
+    reads: BTreeMap<u64, Sender<Result<()>>>,
+    /// the reads confirmed, waiting for the entries up to the index to be applied
+    confirmed: Vec<(u64, Sender<Result<()>>)>,
+    next_read_ctx: u64,
+}
+
+impl<KE: KvsEngine, T: Transport> Driver<KE, T> {
+    fn run(mut self) {
+        let mut next_tick = Instant::now() + self.tick;
+        let mut done = None;
+        loop {
+            match self.inbox.recv_timeout(next_tick.saturating_duration_since(Instant::now())) {
+                Ok(Input::Stop { done: stopped }) => {
+                    done = Some(stopped);
+                    break;
+                }
+                Ok(input) => self.handle(input),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            let now = Instant::now();
+            if now >= next_tick {
+                self.node.tick();
+                // the ticks missed by a stalled thread are not caught up, not to start an election at once
+                next_tick = (next_tick + self.tick).max(now);
+            }
+            if let Err(e) = self.advance() {
+                log::error!("raft node failed, id={}, {}", self.node.id(), e);
+                break;
+            }
+        }
+        self.fail_waiters(|| KvsError::RaftStopped, || KvsError::RaftStopped);
+        log::info!("raft node stopped, id={}", self.node.id());
+        if let Some(done) = done {
+            let _ = done.send(());
+        }
+    }
+
+    fn handle(&mut self, input: Input) {
+        match input {
+            Input::Message(message) => self.node.step(message),
+            Input::Propose { command, reply } => self.propose(command, reply),
+            Input::ChangeMembers { change, reply } => {
+                let mut members = self.node.members().clone();
+                match change {
+                    MembersChange::Add(id, addr) => {
+                        members.insert(id, addr);
+                    }
+                    MembersChange::Remove(id) => {
+                        members.remove(&id);
+                    }
+                }
+                if members.is_empty() {
+                    let _ = reply.send(Err(KvsError::InvalidConfig("the last member can not be removed".to_owned()).into()));
+                    return;
+                }
+                self.propose(Command::Members(members), reply);
+            }
+            Input::Read { reply } => {
+                self.next_read_ctx += 1;
+                match self.node.read_index(self.next_read_ctx) {
+                    Ok(()) => {
+                        self.reads.insert(self.next_read_ctx, reply);
+                    }
+                    Err(e) => {
+                        let _ = reply.send(Err(e));
+                    }
+                }
+            }
+            Input::Status { reply } => {
+                let _ = reply.send(self.node.status());
+            }
+            Input::Stop { .. } => unreachable!(),
+        }
+    }
+
+    fn propose(&mut self, command: Command, reply: Sender<Result<()>>) {
+        if let Command::Members(members) = &command {
+            self.addresses.extend(members.iter().map(|(id, addr)| (*id, addr.clone())));
+        }
+        match self.node.propose(command) {
+            Ok(index) => {
+                self.proposals.insert(index, (self.node.term(), reply));
+            }
+            Err(e) => {
+                let _ = reply.send(Err(e));
+            }
+        }
+    }
+
+    /// keep the changes of the node, send its messages and apply its committed entries
+    fn advance(&mut self) -> Result<()> {
+        let unsynced = self.node.take_unsynced();
+        self.storage.save(&self.node, unsynced)?;
+        let ready = self.node.take_ready();
+        for (to, message) in ready.messages {
+            match self.node.members().get(&to).or_else(|| self.addresses.get(&to)) {
+                Some(addr) => self.transport.send(to, addr, message),
+                None => log::warn!("raft message dropped, the address of node {} is unknown", to),
+            }
+        }
+        if let Some(snapshot) = ready.snapshot {
+            self.restore(snapshot)?;
+        }
+        for entry in ready.committed {
+            self.apply(entry);
+        }
+        for (ctx, index) in ready.reads {
+            if let Some(reply) = self.reads.remove(&ctx) {
+                self.confirmed.push((index, reply));
+            }
+        }
+        let applied = self.node.applied();
+        self.confirmed.retain(|(index, reply)| {
+            if *index > applied {
+                return true;
+            }
+            let _ = reply.send(Ok(()));
+            false
+        });
+
+        if self.node.role() != Role::Leader {
+            let not_leader = self.node.not_leader();
+            // the proposals may still be committed by the next leader
+            self.fail_waiters(|| KvsError::TryAgain, || match &not_leader {
+                KvsError::NotLeader(addr) => KvsError::NotLeader(addr.clone()),
+                _ => KvsError::NoLeader,
+            });
+        }
+
+        if applied - self.node.snapshot().index >= self.snapshot_threshold {
+            let data = self.engine.snapshot()?;
+            self.node.compact(applied, data);
+            let unsynced = self.node.take_unsynced();
+            self.storage.save(&self.node, unsynced)?;
+        }
+        Ok(())
+    }
+
+    /// reply an error to the proposals and the reads waiting
+    fn fail_waiters(&mut self, proposal_error: impl Fn() -> KvsError, read_error: impl Fn() -> KvsError) {
+        for (_, (_, reply)) in std::mem::take(&mut self.proposals) {
+            let _ = reply.send(Err(proposal_error().into()));
+        }
+        let reads = std::mem::take(&mut self.reads).into_values().chain(self.confirmed.drain(..).map(|(_, reply)| reply));
+        for reply in reads {
+            let _ = reply.send(Err(read_error().into()));
+        }
+    }
+
+    /// apply a committed entry, and reply the proposal of it
+    fn apply(&mut self, entry: Entry) {
+        let result = match &entry.command {
+            Command::Write { namespace, behavior } => self.write(namespace, behavior),
+            Command::Members(members) => {
+                self.addresses.extend(members.iter().map(|(id, addr)| (*id, addr.clone())));
+                Ok(())
+            }
+            Command::Noop => Ok(()),
+        };
+        match self.proposals.remove(&entry.index) {
+            Some((term, reply)) if term == entry.term => {
+                let _ = reply.send(result);
+            }
+            // another leader replaced the entry of the proposal
+            Some((_, reply)) => {
+                let _ = reply.send(Err(KvsError::TryAgain.into()));
+            }
+            None => {
+                if let Err(e) = result {
+                    log::debug!("raft write failed, index={}, {}", entry.index, e);
+                }
+            }
+        }
+    }
+
+    fn write(&self, namespace: &str, behavior: &Behavior) -> Result<()> {
+        let engine = self.engine.select(namespace)?;
+        match behavior {
+            Behavior::Set { key, value } => engine.set(key.clone(), value.clone()),
+            Behavior::Remove { key } => engine.remove(key.clone()),
+            Behavior::FlushDb => engine.flush_db(),
+            other => Err(KvsError::InvalidMsg(format!("not a write, {:?}", other)).into()),
+        }
+    }
+
+    /// replace the keys of the engine by the snapshot from the leader
+    fn restore(&mut self, snapshot: Snapshot) -> Result<()> {
+        log::info!("raft snapshot restored, index={}, keys={}", snapshot.index, snapshot.data.len());
+        self.addresses.extend(snapshot.members.iter().map(|(id, addr)| (*id, addr.clone())));
+        let namespaces: BTreeSet<String> = self.engine.snapshot()?.into_iter().map(|entry| entry.namespace).collect();
+        for namespace in namespaces {
+            self.engine.select(&namespace)?.flush_db()?;
+        }
+        for SnapshotEntry { namespace, key, value } in snapshot.data {
+            self.engine.select(&namespace)?.set(key, value)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,213 @@
+//! Raft consensus in front of a `KvsEngine`, see "In Search of an Understandable Consensus Algorithm"
+//!
+//! every write is an entry of the Raft log, applied to the engine of every node once a majority of the nodes
+//! keep it, so the acknowledged writes survive the failure of a minority. the reads are served by the leader
+//! after a majority confirms it is still the leader (ReadIndex). a follower replies `KvsError::NotLeader`
+//! with the address of the leader, or `KvsError::NoLeader` during an election.
+//!
+//! - `RaftNode` is the protocol itself, leader election, log replication, snapshots and membership changes,
+//!   driven by `tick` and the messages received, without any I/O
+//! - `start` runs a node on a thread, applies the committed writes to an engine, and returns a `RaftEngine`,
+//!   the `KvsEngine` given to the server, and a `RaftHandle` receiving the messages of the other nodes
+//! - `Transport` sends the messages, `TcpTransport` by the `RAFT` command of the peer servers, by TLS and `AUTH`
+//!   if its `ConnectOptions` set them,
+//!   `sim::SimNetwork` within the process, partitioning or dropping them for the tests
+//!
+//! the log is compacted into a snapshot of the engine every `snapshot_threshold` applied entries,
+//! a follower missing the compacted entries is sent the snapshot instead.
+//! the members change one node at a time, effective as soon as the change is appended to the log.
+//! a new node starts with the members of the running cluster, itself excluded, so it never starts an election,
+//! and takes the members from the log of the leader after it is added.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::engines::SnapshotEntry;
+use crate::error::KvsError;
+use crate::model::Behavior;
+use crate::Result;
+
+pub use engine::{start, RaftEngine, RaftHandle};
+pub use node::RaftNode;
+pub use transport::{TcpTransport, Transport, PEER_TIMEOUT};
+
+mod engine;
+mod node;
+mod storage;
+mod transport;
+pub mod sim;
+
+/// the id of a node, unique in the cluster
+pub type NodeId = u64;
+
+/// the voting nodes and their `IP:PORT`
+pub type Members = BTreeMap<NodeId, String>;
+
+/// parse the members like `1=127.0.0.1:4001,2=127.0.0.1:4002`
+pub fn parse_members(text: &str) -> Result<Members> {
+    let mut members = Members::new();
+    for member in text.split(',').map(str::trim).filter(|member| !member.is_empty()) {
+        let (id, addr) = member.split_once('=')
+            .ok_or_else(|| KvsError::InvalidConfig(format!("member {:?}, expect ID=IP:PORT", member)))?;
+        let id = id.trim().parse()
+            .map_err(|_| KvsError::InvalidConfig(format!("member {:?}, the id is not an integer", member)))?;
+        if members.insert(id, addr.trim().to_owned()).is_some() {
+            Err(KvsError::InvalidConfig(format!("duplicate member id {}", id)))?
+        }
+    }
+    Ok(members)
+}
+
+/// the command of a log entry
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Command {
+    /// appended by a new leader, committing it commits the entries of the previous terms
+    Noop,
+    /// `Set`, `Remove` or `FlushDb` on the namespace
+    Write {
+        #[allow(missing_docs)]
+        namespace: String,
+        #[allow(missing_docs)]
+        behavior: Behavior,
+    },
+    /// the members from this entry on
+    Members(Members),
+}
+
+/// an entry of the Raft log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    #[allow(missing_docs)]
+    pub index: u64,
+    /// the term of the leader appending it
+    pub term: u64,
+    #[allow(missing_docs)]
+    pub command: Command,
+}
+
+/// the state of the engine after the entries up to `index` are applied, replacing those entries
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// the last entry included, 0 for the empty state before the first entry
+    pub index: u64,
+    /// the term of the last entry included
+    pub term: u64,
+    /// the members at the last entry included
+    pub members: Members,
+    /// the keys and values of the engine
+    pub data: Vec<SnapshotEntry>,
+}
+
+/// a message between two nodes of the same cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Message {
+    #[allow(missing_docs)]
+    pub from: NodeId,
+    /// the term of the sender
+    pub term: u64,
+    #[allow(missing_docs)]
+    pub body: MessageBody,
+}
+
+/// the kinds of `Message`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(missing_docs)]
+pub enum MessageBody {
+    /// a candidate asks for the vote of the receiver
+    RequestVote { last_log_index: u64, last_log_term: u64 },
+    /// the reply to `RequestVote`
+    Vote { granted: bool },
+    /// the leader replicates the entries after `prev_log_index`, a heartbeat if there are none.
+    /// `read_ctx` is echoed by the reply to confirm the leadership for the reads, 0 if no read waits for it
+    Append { prev_log_index: u64, prev_log_term: u64, entries: Vec<Entry>, commit: u64, read_ctx: u64 },
+    /// the reply to `Append` and `InstallSnapshot`. if it succeeds, the receiver keeps the log up to
+    /// `match_index` the same as the leader, otherwise its log may match the leader up to `match_index` at most
+    AppendResponse { success: bool, match_index: u64, read_ctx: u64 },
+    /// the leader replaces the log of a follower missing the compacted entries
+    InstallSnapshot { snapshot: Snapshot },
+}
+
+/// the role of a node in its term
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    #[allow(missing_docs)]
+    Follower,
+    /// asking for the votes of the members
+    Candidate,
+    #[allow(missing_docs)]
+    Leader,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        };
+        f.write_str(name)
+    }
+}
+
+/// the state of a node, shown by `INFO raft`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RaftStatus {
+    #[allow(missing_docs)]
+    pub id: NodeId,
+    #[allow(missing_docs)]
+    pub role: Role,
+    #[allow(missing_docs)]
+    pub term: u64,
+    /// the leader of the term, `None` if it is not known
+    pub leader: Option<NodeId>,
+    /// the last entry kept by a majority
+    pub commit_index: u64,
+    /// the last entry applied to the engine
+    pub applied_index: u64,
+    /// the last entry compacted into the snapshot
+    pub snapshot_index: u64,
+    /// the last entry of the log
+    pub last_index: u64,
+    #[allow(missing_docs)]
+    pub members: Members,
+}
+
+/// how a node runs
+#[derive(Debug, Clone)]
+pub struct RaftConfig {
+    #[allow(missing_docs)]
+    pub id: NodeId,
+    /// the members to start a new cluster with, this node included, or the members of the running cluster
+    /// this node is added to, this node excluded. it may be empty if the node restarts from the state in `dir`
+    pub members: Members,
+    /// the time unit of the election and the heartbeats
+    pub tick: Duration,
+    /// a follower hearing no leader for `election_ticks` to twice as many ticks starts an election,
+    /// a leader hearing no majority for `election_ticks` steps down
+    pub election_ticks: u32,
+    /// the leader sends a heartbeat every `heartbeat_ticks`, fewer than `election_ticks`
+    pub heartbeat_ticks: u32,
+    /// compact the log after as many entries are applied since the last snapshot
+    pub snapshot_threshold: u64,
+    /// keep the term, the vote, the log and the snapshot in the directory, in memory if `None`
+    pub dir: Option<PathBuf>,
+}
+
+impl RaftConfig {
+    /// the config of node `id`, a tick of 100ms, an election after 1s to 2s and a heartbeat every 200ms
+    pub fn new(id: NodeId, members: Members) -> Self {
+        RaftConfig {
+            id,
+            members,
+            tick: Duration::from_millis(100),
+            election_ticks: 10,
+            heartbeat_ticks: 2,
+            snapshot_threshold: 10000,
+            dir: None,
+        }
+    }
+}
@@ -0,0 +1,681 @@
+//! the Raft protocol of one node, without I/O
+
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+
+use crate::engines::SnapshotEntry;
+use crate::error::KvsError;
+use crate::raft::{Command, Entry, Members, Message, MessageBody, NodeId, RaftConfig, RaftStatus, Role, Snapshot};
+use crate::Result;
+
+/// the most entries sent by one `Append`
+const MAX_APPEND_ENTRIES: usize = 256;
+
+/// the term and the vote, kept before any message depending on them is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct HardState {
+    #[allow(missing_docs)]
+    pub term: u64,
+    /// the candidate voted for in `term`
+    pub vote: Option<NodeId>,
+}
+
+/// what changed in the durable state of a node since the last `take_unsynced`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unsynced {
+    /// the term or the vote changed
+    pub hard_state: bool,
+    /// the entries from the index on were replaced or appended
+    pub entries_from: Option<u64>,
+    /// the snapshot changed, the log was compacted or replaced
+    pub snapshot: bool,
+}
+
+/// the output of a node to act on, after the changes in `Unsynced` are kept
+#[derive(Debug, Default)]
+pub struct Ready {
+    /// the messages to send, to the node of the id
+    pub messages: Vec<(NodeId, Message)>,
+    /// a snapshot received from the leader, the engine should be restored from it before `committed` is applied
+    pub snapshot: Option<Snapshot>,
+    /// the entries to apply to the engine, in order
+    pub committed: Vec<Entry>,
+    /// the reads confirmed by a majority, by the ctx of `read_index`, they may be served once the entries up to
+    /// the index are applied
+    pub reads: Vec<(u64, u64)>,
+}
+
+/// where the leader is in the log of a follower
+#[derive(Debug, Clone, Copy)]
+struct Progress {
+    /// the next entry to send
+    next: u64,
+    /// the last entry known to match the leader
+    matched: u64,
+    /// whether the follower replied since the last quorum check
+    active: bool,
+    /// the greatest `read_ctx` replied
+    read_ack: u64,
+}
+
+/// the Raft protocol of one node, see the module doc of `kvs::raft`
+///
+/// the node is driven by `tick`, `step`, `propose` and `read_index`, its output is taken by `take_unsynced`
+/// and then `take_ready`
+pub struct RaftNode {
+    id: NodeId,
+    election_ticks: u32,
+    heartbeat_ticks: u32,
+    state: HardState,
+    /// the compacted entries, `data` is empty unless the log was compacted
+    snapshot: Snapshot,
+    /// the entries after the snapshot
+    entries: Vec<Entry>,
+    commit: u64,
+    /// the last entry handed out by `take_ready`
+    applied: u64,
+    role: Role,
+    leader: Option<NodeId>,
+    members: Members,
+    election_elapsed: u32,
+    /// randomized between `election_ticks` and twice as many for every election
+    election_timeout: u32,
+    heartbeat_elapsed: u32,
+    rng: u64,
+    /// the votes granted to the candidate
+    votes: BTreeSet<NodeId>,
+    /// the followers of the leader
+    progress: BTreeMap<NodeId, Progress>,
+    /// the first entry of the leader in its term
+    term_start: u64,
+    /// the reads waiting for a majority to confirm the leadership, by ctx and read index
+    reads: VecDeque<(u64, u64)>,
+    ready: Ready,
+    unsynced: Unsynced,
+}
+
+impl RaftNode {
+    /// restore a node from the kept state, `snapshot` and `entries` are empty for a new node
+    pub fn new(config: &RaftConfig, state: HardState, snapshot: Option<Snapshot>, entries: Vec<Entry>) -> Self {
+        // the members of a new cluster are kept as the empty snapshot, the node restarts without them
+        let bootstrap = snapshot.is_none() && !config.members.is_empty();
+        let snapshot = snapshot.unwrap_or_else(|| Snapshot { members: config.members.clone(), ..Snapshot::default() });
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(config.id);
+        let mut node = RaftNode {
+            id: config.id,
+            election_ticks: config.election_ticks.max(1),
+            heartbeat_ticks: config.heartbeat_ticks.max(1),
+            state,
+            commit: snapshot.index,
+            applied: snapshot.index,
+            snapshot,
+            entries,
+            role: Role::Follower,
+            leader: None,
+            members: Members::new(),
+            election_elapsed: 0,
+            election_timeout: 0,
+            heartbeat_elapsed: 0,
+            rng: hasher.finish() | 1,
+            votes: BTreeSet::new(),
+            progress: BTreeMap::new(),
+            term_start: 0,
+            reads: VecDeque::new(),
+            ready: Ready::default(),
+            unsynced: Unsynced { snapshot: bootstrap, ..Unsynced::default() },
+        };
+        node.refresh_members();
+        node.reset_election();
+        node
+    }
+
+    #[allow(missing_docs)]
+    pub fn id(&self) -> NodeId {
+        self.id
+    }
+
+    #[allow(missing_docs)]
+    pub fn role(&self) -> Role {
+        self.role
+    }
+
+    #[allow(missing_docs)]
+    pub fn term(&self) -> u64 {
+        self.state.term
+    }
+
+    /// the leader of the current term, `None` if it is not known
+    pub fn leader(&self) -> Option<NodeId> {
+        self.leader
+    }
+
+    /// the members from the last membership entry of the log
+    pub fn members(&self) -> &Members {
+        &self.members
+    }
+
+    #[allow(missing_docs)]
+    pub fn hard_state(&self) -> HardState {
+        self.state
+    }
+
+    /// the compacted entries
+    pub fn snapshot(&self) -> &Snapshot {
+        &self.snapshot
+    }
+
+    /// the entries after the snapshot
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// the last entry handed out to apply
+    pub fn applied(&self) -> u64 {
+        self.applied
+    }
+
+    /// the state shown by `INFO raft`, `applied_index` is the last entry handed out
+    pub fn status(&self) -> RaftStatus {
+        RaftStatus {
+            id: self.id,
+            role: self.role,
+            term: self.state.term,
+            leader: self.leader,
+            commit_index: self.commit,
+            applied_index: self.applied,
+            snapshot_index: self.snapshot.index,
+            last_index: self.last_index(),
+            members: self.members.clone(),
+        }
+    }
+
+    /// the error for a request only the leader serves
+    pub fn not_leader(&self) -> KvsError {
+        match self.leader.and_then(|leader| self.members.get(&leader)) {
+            Some(addr) => KvsError::NotLeader(addr.clone()),
+            None => KvsError::NoLeader,
+        }
+    }
+
+    /// advance the clock by one tick
+    pub fn tick(&mut self) {
+        self.election_elapsed += 1;
+        if self.role == Role::Leader {
+            self.heartbeat_elapsed += 1;
+            if self.heartbeat_elapsed >= self.heartbeat_ticks {
+                self.heartbeat_elapsed = 0;
+                self.broadcast_append(0);
+            }
+            if self.election_elapsed >= self.election_ticks {
+                self.election_elapsed = 0;
+                self.check_quorum();
+            }
+        } else if self.election_elapsed >= self.election_timeout && self.members.contains_key(&self.id) {
+            self.campaign();
+        }
+    }
+
+    /// append `command` to the log if the node is the leader, return the index of the entry
+    ///
+    /// return `KvsError::NotLeader` or `KvsError::NoLeader` otherwise, and `KvsError::MembershipChanging` for
+    /// a membership change before the last one is committed
+    pub fn propose(&mut self, command: Command) -> Result<u64> {
+        if self.role != Role::Leader {
+            Err(self.not_leader())?
+        }
+        if let Command::Members(_) = command {
+            let changing = self.entries.iter()
+                .any(|entry| entry.index > self.commit && matches!(entry.command, Command::Members(_)));
+            if changing {
+                Err(KvsError::MembershipChanging)?
+            }
+        }
+        let index = self.append(command);
+        self.broadcast_append(0);
+        self.maybe_commit();
+        Ok(index)
+    }
+
+    /// start a read if the node is the leader, `ctx` is greater than the ctx of every read before
+    ///
+    /// the read is in `Ready::reads` once a majority confirms the leadership, otherwise the read is dropped
+    /// if the node loses the leadership. return `KvsError::NotLeader` or `KvsError::NoLeader` if it is not the leader
+    pub fn read_index(&mut self, ctx: u64) -> Result<()> {
+        if self.role != Role::Leader {
+            Err(self.not_leader())?
+        }
+        // the entries of the previous terms are committed once the first entry of the term is
+        let index = self.commit.max(self.term_start);
+        self.reads.push_back((ctx, index));
+        self.broadcast_append(ctx);
+        self.confirm_reads();
+        Ok(())
+    }
+
+    /// handle a message from another node
+    pub fn step(&mut self, m: Message) {
+        if m.term > self.state.term {
+            if let MessageBody::RequestVote { .. } = m.body {
+                // a node removed from the cluster, or partitioned away, does not disrupt a living leader
+                if self.leader.is_some() && self.election_elapsed < self.election_ticks {
+                    log::debug!("vote ignored, the leader is alive, from={}, term={}", m.from, m.term);
+                    return;
+                }
+            }
+            let leader = match m.body {
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => Some(m.from),
+                _ => None,
+            };
+            self.become_follower(m.term, leader);
+        } else if m.term < self.state.term {
+            // tell the stale node the current term
+            match m.body {
+                MessageBody::RequestVote { .. } => self.send(m.from, MessageBody::Vote { granted: false }),
+                MessageBody::Append { .. } | MessageBody::InstallSnapshot { .. } => {
+                    self.send(m.from, MessageBody::AppendResponse { success: false, match_index: 0, read_ctx: 0 })
+                }
+                _ => {}
+            }
+            return;
+        }
+
+        match m.body {
+            MessageBody::RequestVote { last_log_index, last_log_term } => {
+                let up_to_date = (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let free = self.state.vote == Some(m.from) || (self.state.vote.is_none() && self.leader.is_none());
+                let granted = free && up_to_date;
+                if granted {
+                    self.state.vote = Some(m.from);
+                    self.unsynced.hard_state = true;
+                    self.election_elapsed = 0;
+                }
+                self.send(m.from, MessageBody::Vote { granted });
+            }
+            MessageBody::Vote { granted } => {
+                if self.role == Role::Candidate && granted {
+                    self.votes.insert(m.from);
+                    if self.has_quorum(&self.votes) {
+                        self.become_leader();
+                    }
+                }
+            }
+            MessageBody::Append { prev_log_index, prev_log_term, entries, commit, read_ctx } => {
+                if self.role != Role::Follower {
+                    self.become_follower(m.term, Some(m.from));
+                }
+                self.leader = Some(m.from);
+                self.election_elapsed = 0;
+                self.handle_append(m.from, prev_log_index, prev_log_term, entries, commit, read_ctx);
+            }
+            MessageBody::AppendResponse { success, match_index, read_ctx } => {
+                if self.role == Role::Leader {
+                    self.handle_append_response(m.from, success, match_index, read_ctx);
+                }
+            }
+            MessageBody::InstallSnapshot { snapshot } => {
+                if self.role != Role::Follower {
+                    self.become_follower(m.term, Some(m.from));
+                }
+                self.leader = Some(m.from);
+                self.election_elapsed = 0;
+                self.handle_snapshot(m.from, snapshot);
+            }
+        }
+    }
+
+    /// replace the applied entries up to `index` by `data`, the state of the engine after applying them
+    pub fn compact(&mut self, index: u64, data: Vec<SnapshotEntry>) {
+        if index <= self.snapshot.index || index > self.applied {
+            return;
+        }
+        let term = self.term_at(index).unwrap_or(0);
+        let members = self.members_at(index);
+        self.entries.drain(..(index - self.snapshot.index) as usize);
+        self.snapshot = Snapshot { index, term, members, data };
+        self.unsynced.snapshot = true;
+        log::info!("raft log compacted, index={}, entries={}", index, self.entries.len());
+    }
+
+    /// the changes of the durable state since the last call
+    pub fn take_unsynced(&mut self) -> Unsynced {
+        std::mem::take(&mut self.unsynced)
+    }
+
+    /// the output since the last call, the committed entries are counted as applied
+    pub fn take_ready(&mut self) -> Ready {
+        let mut ready = std::mem::take(&mut self.ready);
+        if self.commit > self.applied {
+            let from = (self.applied - self.snapshot.index) as usize;
+            let to = (self.commit - self.snapshot.index) as usize;
+            ready.committed = self.entries[from..to].to_vec();
+            self.applied = self.commit;
+        }
+        ready
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot.index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.entries.last().map_or(self.snapshot.term, |entry| entry.term)
+    }
+
+    /// the term of the entry, `None` if it is compacted or beyond the log
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot.index {
+            return Some(self.snapshot.term);
+        }
+        if index < self.snapshot.index {
+            return None;
+        }
+        self.entries.get((index - self.snapshot.index - 1) as usize).map(|entry| entry.term)
+    }
+
+    /// the members of the last membership entry up to `index`
+    fn members_at(&self, index: u64) -> Members {
+        self.entries.iter()
+            .rev()
+            .filter(|entry| entry.index <= index)
+            .find_map(|entry| match &entry.command {
+                Command::Members(members) => Some(members.clone()),
+                _ => None,
+            })
+            .unwrap_or_else(|| self.snapshot.members.clone())
+    }
+
+    /// take the members of the last membership entry, and track the new followers if the node is the leader
+    fn refresh_members(&mut self) {
+        self.members = self.members_at(self.last_index());
+        if self.role == Role::Leader {
+            let next = self.last_index() + 1;
+            let (id, members) = (self.id, &self.members);
+            self.progress.retain(|peer, _| members.contains_key(peer));
+            for peer in members.keys().filter(|peer| **peer != id) {
+                self.progress.entry(*peer).or_insert(Progress { next, matched: 0, active: true, read_ack: 0 });
+            }
+        }
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// whether the nodes are a majority of the members
+    fn has_quorum(&self, nodes: &BTreeSet<NodeId>) -> bool {
+        nodes.iter().filter(|node| self.members.contains_key(node)).count() >= self.quorum()
+    }
+
+    fn reset_election(&mut self) {
+        self.election_elapsed = 0;
+        // xorshift
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.election_timeout = self.election_ticks + (self.rng % self.election_ticks as u64) as u32;
+    }
+
+    fn send(&mut self, to: NodeId, body: MessageBody) {
+        let message = Message { from: self.id, term: self.state.term, body };
+        self.ready.messages.push((to, message));
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<NodeId>) {
+        if term > self.state.term {
+            self.state = HardState { term, vote: None };
+            self.unsynced.hard_state = true;
+        }
+        if self.role != Role::Follower || leader != self.leader {
+            log::info!("raft follower, term={}, leader={:?}", term, leader);
+        }
+        self.role = Role::Follower;
+        self.leader = leader;
+        self.progress.clear();
+        self.reads.clear();
+        self.reset_election();
+    }
+
+    fn campaign(&mut self) {
+        self.state = HardState { term: self.state.term + 1, vote: Some(self.id) };
+        self.unsynced.hard_state = true;
+        self.role = Role::Candidate;
+        self.leader = None;
+        self.votes = BTreeSet::from([self.id]);
+        self.reset_election();
+        log::info!("raft election, term={}", self.state.term);
+        if self.has_quorum(&self.votes) {
+            self.become_leader();
+            return;
+        }
+        let (last_log_index, last_log_term) = (self.last_index(), self.last_term());
+        let peers: Vec<NodeId> = self.members.keys().copied().filter(|peer| *peer != self.id).collect();
+        for peer in peers {
+            self.send(peer, MessageBody::RequestVote { last_log_index, last_log_term });
+        }
+    }
+
+    fn become_leader(&mut self) {
+        log::info!("raft leader, term={}", self.state.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.election_elapsed = 0;
+        self.heartbeat_elapsed = 0;
+        self.progress.clear();
+        self.refresh_members();
+        self.term_start = self.append(Command::Noop);
+        self.broadcast_append(0);
+        self.maybe_commit();
+    }
+
+    /// append an entry of the current term, return its index
+    fn append(&mut self, command: Command) -> u64 {
+        let index = self.last_index() + 1;
+        let members = matches!(command, Command::Members(_));
+        self.entries.push(Entry { index, term: self.state.term, command });
+        self.mark_entries(index);
+        if members {
+            self.refresh_members();
+        }
+        index
+    }
+
+    fn mark_entries(&mut self, from: u64) {
+        self.unsynced.entries_from = Some(self.unsynced.entries_from.map_or(from, |unsynced| unsynced.min(from)));
+    }
+
+    fn broadcast_append(&mut self, read_ctx: u64) {
+        let peers: Vec<NodeId> = self.progress.keys().copied().collect();
+        for peer in peers {
+            self.send_append(peer, read_ctx);
+        }
+    }
+
+    /// send the entries from the next of the follower, or the snapshot if they are compacted
+    fn send_append(&mut self, to: NodeId, read_ctx: u64) {
+        let progress = match self.progress.get_mut(&to) {
+            Some(progress) => progress,
+            None => return,
+        };
+        if progress.next <= self.snapshot.index {
+            progress.next = self.snapshot.index + 1;
+            let snapshot = self.snapshot.clone();
+            log::info!("raft snapshot sent, to={}, index={}", to, snapshot.index);
+            self.send(to, MessageBody::InstallSnapshot { snapshot });
+            return;
+        }
+        let prev_log_index = progress.next - 1;
+        let from = (progress.next - self.snapshot.index - 1) as usize;
+        let entries: Vec<Entry> = self.entries[from.min(self.entries.len())..].iter()
+            .take(MAX_APPEND_ENTRIES)
+            .cloned()
+            .collect();
+        // sent optimistically, a rejection moves it back
+        progress.next += entries.len() as u64;
+        let prev_log_term = self.term_at(prev_log_index).unwrap_or(0);
+        let commit = self.commit;
+        self.send(to, MessageBody::Append { prev_log_index, prev_log_term, entries, commit, read_ctx });
+    }
+
+    fn handle_append(
+        &mut self,
+        from: NodeId,
+        mut prev_log_index: u64,
+        mut prev_log_term: u64,
+        mut entries: Vec<Entry>,
+        commit: u64,
+        read_ctx: u64,
+    ) {
+        if prev_log_index < self.commit {
+            // the committed entries match the leader, they may be compacted here
+            entries.retain(|entry| entry.index > self.commit);
+            prev_log_index = self.commit;
+            prev_log_term = self.term_at(self.commit).unwrap_or(0);
+        }
+        match self.term_at(prev_log_index) {
+            Some(term) if term == prev_log_term => {}
+            found => {
+                let match_index = match found {
+                    None => self.last_index(),
+                    Some(_) => prev_log_index - 1,
+                };
+                self.send(from, MessageBody::AppendResponse { success: false, match_index, read_ctx });
+                return;
+            }
+        }
+        let last_new = prev_log_index + entries.len() as u64;
+        let mut members_changed = false;
+        for entry in entries {
+            match self.term_at(entry.index) {
+                Some(term) if term == entry.term => continue,
+                Some(_) => {
+                    log::info!("raft log truncated, from={}", entry.index);
+                    let removed = self.entries.split_off((entry.index - self.snapshot.index - 1) as usize);
+                    members_changed |= removed.iter().any(|removed| matches!(removed.command, Command::Members(_)));
+                }
+                None => {}
+            }
+            members_changed |= matches!(entry.command, Command::Members(_));
+            self.mark_entries(entry.index);
+            self.entries.push(entry);
+        }
+        if members_changed {
+            self.refresh_members();
+        }
+        if commit > self.commit {
+            self.commit = commit.min(last_new);
+        }
+        self.send(from, MessageBody::AppendResponse { success: true, match_index: last_new, read_ctx });
+    }
+
+    fn handle_append_response(&mut self, from: NodeId, success: bool, match_index: u64, read_ctx: u64) {
+        let last_index = self.last_index();
+        let progress = match self.progress.get_mut(&from) {
+            Some(progress) => progress,
+            None => return,
+        };
+        progress.active = true;
+        progress.read_ack = progress.read_ack.max(read_ctx);
+        if success {
+            progress.matched = progress.matched.max(match_index);
+            progress.next = progress.next.max(progress.matched + 1);
+            let behind = progress.next <= last_index;
+            self.maybe_commit();
+            if behind {
+                self.send_append(from, 0);
+            }
+        } else {
+            progress.next = (match_index + 1).max(progress.matched + 1);
+            self.send_append(from, 0);
+        }
+        self.confirm_reads();
+    }
+
+    fn handle_snapshot(&mut self, from: NodeId, snapshot: Snapshot) {
+        if snapshot.index <= self.commit {
+            let match_index = self.commit;
+            self.send(from, MessageBody::AppendResponse { success: true, match_index, read_ctx: 0 });
+            return;
+        }
+        log::info!("raft snapshot received, from={}, index={}", from, snapshot.index);
+        if self.term_at(snapshot.index) == Some(snapshot.term) {
+            let compacted = (snapshot.index - self.snapshot.index) as usize;
+            self.entries.drain(..compacted);
+        } else {
+            self.entries.clear();
+        }
+        self.commit = snapshot.index;
+        self.applied = snapshot.index;
+        self.snapshot = snapshot.clone();
+        self.unsynced.snapshot = true;
+        self.refresh_members();
+        let match_index = snapshot.index;
+        self.ready.snapshot = Some(snapshot);
+        self.send(from, MessageBody::AppendResponse { success: true, match_index, read_ctx: 0 });
+    }
+
+    /// commit the last entry of the current term kept by a majority
+    fn maybe_commit(&mut self) {
+        let mut matched: Vec<u64> = self.members.keys()
+            .map(|member| match self.progress.get(member) {
+                Some(progress) => progress.matched,
+                None if *member == self.id => self.last_index(),
+                None => 0,
+            })
+            .collect();
+        if matched.is_empty() {
+            return;
+        }
+        matched.sort_unstable_by(|a, b| b.cmp(a));
+        let index = matched[self.quorum() - 1];
+        if index <= self.commit || self.term_at(index) != Some(self.state.term) {
+            return;
+        }
+        self.commit = index;
+        self.confirm_reads();
+        // a leader removed from the members steps down once the removal is committed
+        if !self.members.contains_key(&self.id) {
+            let removal = self.entries.iter()
+                .rev()
+                .find(|entry| matches!(entry.command, Command::Members(_)))
+                .map_or(0, |entry| entry.index);
+            if removal <= self.commit {
+                log::info!("raft leader removed from the members, term={}", self.state.term);
+                self.become_follower(self.state.term, None);
+            }
+        }
+    }
+
+    /// step down if no majority replied since the last check
+    fn check_quorum(&mut self) {
+        let mut active: BTreeSet<NodeId> = self.progress.iter()
+            .filter(|(_, progress)| progress.active)
+            .map(|(peer, _)| *peer)
+            .collect();
+        active.insert(self.id);
+        for progress in self.progress.values_mut() {
+            progress.active = false;
+        }
+        if !self.has_quorum(&active) {
+            log::warn!("raft leader lost the majority, term={}", self.state.term);
+            self.become_follower(self.state.term, None);
+        }
+    }
+
+    /// hand out the reads whose ctx a majority replied
+    fn confirm_reads(&mut self) {
+        while let Some((ctx, index)) = self.reads.front().copied() {
+            let mut acked: BTreeSet<NodeId> = self.progress.iter()
+                .filter(|(_, progress)| progress.read_ack >= ctx)
+                .map(|(peer, _)| *peer)
+                .collect();
+            acked.insert(self.id);
+            if !self.has_quorum(&acked) {
+                break;
+            }
+            self.reads.pop_front();
+            self.ready.reads.push((ctx, index));
+        }
+    }
+}
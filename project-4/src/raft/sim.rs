@@ -0,0 +1,147 @@
+//! an in-process network of nodes for the tests, which partitions the nodes or drops the messages on demand
+//!
+//! ```no_run
+//! # use kvs::raft::{self, sim::SimNetwork, RaftConfig};
+//! # use kvs::KvStore;
+//! # fn main() -> kvs::Result<()> {
+//! let network = SimNetwork::new();
+//! let members = SimNetwork::members(&[1, 2, 3]);
+//! for id in 1..=3 {
+//!     let engine = KvStore::open(format!("/tmp/node{}", id))?;
+//!     let (engine, handle) = raft::start(RaftConfig::new(id, members.clone()), engine, network.transport(id))?;
+//!     network.register(handle);
+//! }
+//! network.isolate(1);
+//! network.heal();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+
+use crate::raft::{Members, Message, NodeId, RaftHandle, Transport};
+
+/// the nodes of a test and the links between them, cheap to clone
+#[derive(Clone, Default)]
+pub struct SimNetwork {
+    inner: Arc<Mutex<Links>>,
+}
+
+#[derive(Default)]
+struct Links {
+    nodes: HashMap<NodeId, RaftHandle>,
+    /// the directed links dropping every message, by sender and receiver
+    cut: HashSet<(NodeId, NodeId)>,
+    /// the share of the messages dropped on the other links, 0 to 1
+    drop_rate: f64,
+    rng: u64,
+    delivered: u64,
+    dropped: u64,
+}
+
+impl SimNetwork {
+    #[allow(missing_docs)]
+    pub fn new() -> Self {
+        let network = SimNetwork::default();
+        network.inner.lock().unwrap().rng = RandomState::new().build_hasher().finish() | 1;
+        network
+    }
+
+    /// the members with placeholder addresses, the simulated links ignore the addresses
+    pub fn members(ids: &[NodeId]) -> Members {
+        ids.iter().map(|id| (*id, format!("sim-{}", id))).collect()
+    }
+
+    /// the transport of the node `id`
+    pub fn transport(&self, id: NodeId) -> SimTransport {
+        SimTransport { id, network: self.clone() }
+    }
+
+    /// deliver the messages to the node of `handle`, replacing the node of the same id
+    pub fn register(&self, handle: RaftHandle) {
+        self.inner.lock().unwrap().nodes.insert(handle.id(), handle);
+    }
+
+    /// stop delivering to the node `id`, as if it crashed
+    pub fn unregister(&self, id: NodeId) {
+        self.inner.lock().unwrap().nodes.remove(&id);
+    }
+
+    /// cut the links between the groups, the links within a group are kept
+    pub fn partition(&self, groups: &[&[NodeId]]) {
+        let mut links = self.inner.lock().unwrap();
+        for (i, group) in groups.iter().enumerate() {
+            for other in groups.iter().skip(i + 1) {
+                for a in group.iter() {
+                    for b in other.iter() {
+                        links.cut.insert((*a, *b));
+                        links.cut.insert((*b, *a));
+                    }
+                }
+            }
+        }
+    }
+
+    /// cut every link of the node `id`, the nodes registered now and later included
+    pub fn isolate(&self, id: NodeId) {
+        // the wildcard 0 is never a node id of the tests
+        self.inner.lock().unwrap().cut.insert((id, 0));
+    }
+
+    /// restore every link cut
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().cut.clear();
+    }
+
+    /// drop `rate` of the messages at random on the links not cut, 0 to 1
+    pub fn set_drop_rate(&self, rate: f64) {
+        self.inner.lock().unwrap().drop_rate = rate.clamp(0.0, 1.0);
+    }
+
+    /// the messages delivered and dropped so far
+    pub fn counts(&self) -> (u64, u64) {
+        let links = self.inner.lock().unwrap();
+        (links.delivered, links.dropped)
+    }
+}
+
+impl Links {
+    fn is_cut(&self, from: NodeId, to: NodeId) -> bool {
+        self.cut.contains(&(from, to)) || self.cut.contains(&(from, 0)) || self.cut.contains(&(to, 0))
+    }
+
+    /// xorshift, in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        (self.rng >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// the transport of a node of `SimNetwork`
+pub struct SimTransport {
+    id: NodeId,
+    network: SimNetwork,
+}
+
+impl Transport for SimTransport {
+    fn send(&self, to: NodeId, _addr: &str, message: Message) {
+        let mut links = self.network.inner.lock().unwrap();
+        let drop_rate = links.drop_rate;
+        if links.is_cut(self.id, to) || (drop_rate > 0.0 && links.random() < drop_rate) {
+            links.dropped += 1;
+            return;
+        }
+        match links.nodes.get(&to) {
+            Some(node) => {
+                node.step(message);
+                links.delivered += 1;
+            }
+            None => links.dropped += 1,
+        }
+    }
+}
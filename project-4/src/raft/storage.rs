@@ -0,0 +1,160 @@
+//! the durable state of a node: `state.json` keeps the term and the vote, `snapshot.json` the snapshot,
+//! and `log` the entries after the snapshot, one JSON line each
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::raft::node::{HardState, RaftNode, Unsynced};
+use crate::raft::{Entry, Snapshot};
+use crate::Result;
+
+/// the files of a node, nothing is kept if there is no directory
+pub(crate) struct RaftStorage {
+    dir: Option<PathBuf>,
+    /// the log file, `None` if there is no directory
+    log: Option<File>,
+    /// the index and the offset in the log file of every entry kept
+    offsets: Vec<(u64, u64)>,
+}
+
+/// the state read back by `RaftStorage::open`
+pub(crate) struct Restored {
+    pub state: HardState,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<Entry>,
+}
+
+impl RaftStorage {
+    /// open the files in `dir`, creating it if needed, and read the state kept before
+    pub fn open(dir: Option<PathBuf>) -> Result<(RaftStorage, Restored)> {
+        let mut restored = Restored { state: HardState::default(), snapshot: None, entries: Vec::new() };
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok((RaftStorage { dir: None, log: None, offsets: Vec::new() }, restored)),
+        };
+        fs::create_dir_all(&dir)?;
+        let state_path = dir.join("state.json");
+        if state_path.exists() {
+            restored.state = serde_json::from_slice(&fs::read(&state_path)?)?;
+        }
+        let snapshot_path = dir.join("snapshot.json");
+        if snapshot_path.exists() {
+            restored.snapshot = Some(serde_json::from_slice(&fs::read(&snapshot_path)?)?);
+        }
+        let snapshot_index = restored.snapshot.as_ref().map_or(0, |snapshot| snapshot.index);
+
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(dir.join("log"))?;
+        let mut offsets = Vec::new();
+        let mut offset = 0;
+        let mut reader = BufReader::new(&mut log);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let len = reader.read_line(&mut line)? as u64;
+            // a line torn by a crash is dropped with the lines after it
+            let entry: Entry = match serde_json::from_str(line.trim_end()) {
+                Ok(entry) if line.ends_with('\n') => entry,
+                _ => break,
+            };
+            // the entries compacted before a crash are still in the log
+            if entry.index > snapshot_index {
+                offsets.push((entry.index, offset));
+                restored.entries.push(entry);
+            }
+            offset += len;
+        }
+        drop(reader);
+        log.set_len(offset)?;
+        log.seek(SeekFrom::End(0))?;
+        log::info!(
+            "raft state restored, term={}, snapshot_index={}, entries={}",
+            restored.state.term,
+            snapshot_index,
+            restored.entries.len(),
+        );
+        Ok((RaftStorage { dir: Some(dir), log: Some(log), offsets }, restored))
+    }
+
+    /// keep the changes of the node, before its messages are sent
+    pub fn save(&mut self, node: &RaftNode, unsynced: Unsynced) -> Result<()> {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return Ok(()),
+        };
+        if unsynced.hard_state {
+            write_atomic(&dir.join("state.json"), &node.hard_state())?;
+        }
+        if unsynced.snapshot {
+            write_atomic(&dir.join("snapshot.json"), node.snapshot())?;
+            self.rewrite_log(node.entries())?;
+        } else if let Some(from) = unsynced.entries_from {
+            self.append_log(node.entries(), from)?;
+        }
+        Ok(())
+    }
+
+    /// replace the log file by the entries, written to `log.tmp` and renamed over it,
+    /// so a crash leaves either the old or the new log whole
+    fn rewrite_log(&mut self, entries: &[Entry]) -> Result<()> {
+        let dir = self.dir.as_ref().expect("the log file of a directory");
+        let tmp = dir.join("log.tmp");
+        let mut log = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&tmp)?;
+        let mut offsets = Vec::new();
+        log.write_all(&encode(entries, 0, &mut offsets)?)?;
+        log.sync_all()?;
+        fs::rename(&tmp, dir.join("log"))?;
+        sync_dir(dir)?;
+        self.log = Some(log);
+        self.offsets = offsets;
+        Ok(())
+    }
+
+    /// drop the entries from `from` on in the log file, then write the entries of the node from it
+    fn append_log(&mut self, entries: &[Entry], from: u64) -> Result<()> {
+        let log = self.log.as_mut().expect("the log file of a directory");
+        let kept = self.offsets.partition_point(|(index, _)| *index < from);
+        if let Some((_, offset)) = self.offsets.get(kept) {
+            log.set_len(*offset)?;
+            log.seek(SeekFrom::Start(*offset))?;
+            self.offsets.truncate(kept);
+        }
+        let from_entry = entries.partition_point(|entry| entry.index < from);
+        let buf = encode(&entries[from_entry..], log.stream_position()?, &mut self.offsets)?;
+        log.write_all(&buf)?;
+        log.sync_data()?;
+        Ok(())
+    }
+}
+
+/// the JSON lines of the entries written at `offset` of the log file, the index and the offset of each
+/// pushed to `offsets`
+fn encode(entries: &[Entry], mut offset: u64, offsets: &mut Vec<(u64, u64)>) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    for entry in entries {
+        let start = buf.len();
+        serde_json::to_writer(&mut buf, entry)?;
+        buf.push(b'\n');
+        offsets.push((entry.index, offset));
+        offset += (buf.len() - start) as u64;
+    }
+    Ok(buf)
+}
+
+/// replace the file by the JSON of `value`, a crash leaves either the old or the new file
+fn write_atomic(path: &Path, value: &impl Serialize) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let mut file = File::create(&tmp)?;
+    serde_json::to_writer(&mut file, value)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path.parent().expect("a file in the directory of the node"))
+}
+
+/// keep the renames in `dir` through a crash
+fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
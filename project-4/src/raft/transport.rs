@@ -0,0 +1,98 @@
+//! sending the messages between the nodes
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crossbeam::{Receiver, Sender, TrySendError};
+
+use crate::client::{ConnectOptions, KvsClient};
+use crate::model::Msg;
+use crate::raft::{Message, NodeId};
+
+/// the messages queued for a peer before the new ones are dropped
+const PEER_QUEUE_CAPACITY: usize = 1024;
+
+/// the timeout of connecting to a peer and of sending a message, unless `TcpTransport::new` is given another
+pub const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// sends the messages of a node to the other nodes
+///
+/// the messages may be lost, duplicated or reordered, Raft tolerates it, but `send` should not block
+pub trait Transport: Send + 'static {
+    /// send `message` to the node `to` listening on `addr`
+    fn send(&self, to: NodeId, addr: &str, message: Message);
+}
+
+/// sends the messages by the `RAFT` command of the kvs server of each node, on a connection per peer
+pub struct TcpTransport {
+    /// how the peers are connected
+    options: ConnectOptions,
+    /// the address and the queue of each peer, served by a thread each
+    peers: Mutex<HashMap<NodeId, (String, Sender<Message>)>>,
+}
+
+impl TcpTransport {
+    /// connect to the peers by `options`, e.g. by TLS and `AUTH`, with `PEER_TIMEOUT` if it sets no timeout
+    pub fn new(mut options: ConnectOptions) -> Self {
+        options.timeout = options.timeout.or(Some(PEER_TIMEOUT));
+        TcpTransport { options, peers: Mutex::default() }
+    }
+}
+
+impl Default for TcpTransport {
+    fn default() -> Self {
+        TcpTransport::new(ConnectOptions::default())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&self, to: NodeId, addr: &str, message: Message) {
+        let mut peers = self.peers.lock().unwrap();
+        let stale = peers.get(&to).is_none_or(|(known, _)| known != addr);
+        if stale {
+            // the thread of the old address ends with its queue
+            let (tx, rx) = crossbeam::bounded(PEER_QUEUE_CAPACITY);
+            let (peer, options) = (addr.to_owned(), self.options.clone());
+            thread::spawn(move || send_loop(peer, options, rx));
+            peers.insert(to, (addr.to_owned(), tx));
+        }
+        let (_, tx) = &peers[&to];
+        if let Err(TrySendError::Full(_)) = tx.try_send(message) {
+            log::debug!("raft message dropped, to={}, the queue is full", to);
+        }
+    }
+}
+
+/// send the queued messages to `addr`, reconnecting after an error, until the queue is dropped
+fn send_loop(addr: String, options: ConnectOptions, messages: Receiver<Message>) {
+    let mut client: Option<KvsClient> = None;
+    for message in messages {
+        if client.is_none() {
+            client = match KvsClient::connect_with(addr.clone(), &options) {
+                Ok(client) => Some(client),
+                Err(e) => {
+                    log::debug!("raft peer unreachable, addr={}, {}", addr, e);
+                    continue;
+                }
+            };
+        }
+        let json = match serde_json::to_string(&message) {
+            Ok(json) => json,
+            Err(e) => {
+                log::error!("raft message not serialized, {}", e);
+                continue;
+            }
+        };
+        let req = Msg::build_bulk_array(&vec!["RAFT".to_owned(), json]);
+        match client.as_mut().map(|client| client.request_msg(req)) {
+            Some(Ok(Msg::Error(e))) => log::warn!("raft message rejected, addr={}, {}", addr, e),
+            Some(Err(e)) => {
+                log::debug!("raft peer connection lost, addr={}, {}", addr, e);
+                client = None;
+            }
+            _ => {}
+        }
+    }
+}
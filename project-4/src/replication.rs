@@ -0,0 +1,535 @@
+//! master–replica replication
+//!
+//! a replica connects to its primary and sends `PSYNC replid offset`, or `PSYNC ? -1` the first time.
+//! the primary replies
+//! - `+CONTINUE` if `replid` is its own and the writes after `offset` are still kept, then pushes those writes
+//! - `+FULLRESYNC replid offset` otherwise, then pushes its live records as `["snapshot", namespace, key, value]`,
+//!   followed by `["snapshot-end"]`
+//!
+//! from then on every write of the primary is pushed, like `["write", 42, "0", "set", "key", "value"]`,
+//! `["write", 43, "0", "rm", "key"]` or `["write", 44, "orders", "flushdb"]`.
+//! the offset counts the writes since the primary started, `replid` is random for every start of the primary,
+//! so the offsets of a restarted primary are not mistaken for the old ones.
+//!
+//! the snapshot is read after the reply, while the writes go on, so it may hold some writes pushed after it,
+//! applying them again gives the same keys. A replica reads the writes from the backlog of its primary, it is
+//! removed if it falls `MAX_REPLICA_LAG` bytes of writes behind. The backlog is kept from the first `PSYNC` on,
+//! a server never followed only counts its writes.
+//!
+//! the replica clears its keys before the snapshot, then applies the pushes to its engine.
+//! after the connection is lost it reconnects, and continues from the last write applied if the primary still
+//! keeps the writes after it. The clients of a replica get `KvsError::ReadOnly` for the writes,
+//! and the replicas of a replica follow the writes it applies.
+
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crossbeam::RecvTimeoutError;
+use tokio::sync::watch;
+
+use crate::client::{ConnectOptions, KvsClient};
+use crate::engines::{KvsEngine, SnapshotEntry};
+use crate::error::KvsError;
+use crate::model::{Behavior, Msg};
+use crate::net::Stream;
+use crate::session::ServerContext;
+use crate::Result;
+
+/// the bytes of the last writes kept for the replicas to continue from, the last write is kept even if larger
+pub const REPLICATION_BACKLOG_SIZE: u64 = 1 << 20;
+
+/// the most bytes of writes kept for a connected replica which has not sent them, a replica further behind is removed
+pub const MAX_REPLICA_LAG: u64 = 64 << 20;
+
+/// the number of the locks ordering the writes of the keys, see `Replication::write`
+const WRITE_STRIPES: usize = 64;
+
+/// how long a replica waits to reconnect after the connection to the primary is lost
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// a msg of the replication stream, see the module doc
+#[derive(Debug, Clone)]
+pub enum ReplicationEvent {
+    /// the snapshot follows, `offset` is the last write of `replid` included in it
+    FullResync {
+        #[allow(missing_docs)]
+        replid: String,
+        #[allow(missing_docs)]
+        offset: u64,
+    },
+    /// the writes after the offset of the `PSYNC` follow
+    Continue,
+    /// a live record of the primary
+    Snapshot(SnapshotEntry),
+    /// the whole snapshot is pushed
+    SnapshotEnd,
+    /// a write of the primary, `behavior` is `Set`, `Remove` or `FlushDb`
+    Write {
+        #[allow(missing_docs)]
+        offset: u64,
+        #[allow(missing_docs)]
+        namespace: String,
+        #[allow(missing_docs)]
+        behavior: Behavior,
+    },
+}
+
+impl ReplicationEvent {
+    /// parse the reply to `PSYNC` or a push after it, an error reply is returned as the error
+    pub fn parse(msg: Msg) -> Result<ReplicationEvent> {
+        let items = match msg {
+            Msg::Line(line) => {
+                let words: Vec<&str> = line.split(' ').collect();
+                let event = match words.as_slice() {
+                    ["CONTINUE"] => ReplicationEvent::Continue,
+                    ["FULLRESYNC", replid, offset] => {
+                        ReplicationEvent::FullResync { replid: (*replid).to_owned(), offset: offset.parse()? }
+                    }
+                    _ => Err(KvsError::InvalidMsg(format!("unexpected reply {:?}", line)))?,
+                };
+                return Ok(event);
+            }
+            Msg::Array(items) | Msg::Push(items) => items,
+            Msg::Error(e) => Err(anyhow::anyhow!(e))?,
+            other => Err(KvsError::InvalidMsg(format!("unexpected push {:?}", other)))?,
+        };
+        let malformed = || KvsError::InvalidMsg("malformed replication push".to_owned());
+        let offset = match items.get(1) {
+            Some(Msg::Integer(offset)) if *offset >= 0 => Some(*offset as u64),
+            _ => None,
+        };
+        let strings: Vec<Option<&str>> = items.iter()
+            .map(|item| match item {
+                Msg::Bulk(Some(s)) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect();
+        let event = match strings.as_slice() {
+            [Some("snapshot"), Some(namespace), Some(key), Some(value)] => ReplicationEvent::Snapshot(SnapshotEntry {
+                namespace: (*namespace).to_owned(),
+                key: (*key).to_owned(),
+                value: (*value).to_owned(),
+            }),
+            [Some("snapshot-end")] => ReplicationEvent::SnapshotEnd,
+            [Some("write"), _, Some(namespace), command @ ..] => {
+                let behavior = match command {
+                    [Some("set"), Some(key), Some(value)] => {
+                        Behavior::Set { key: (*key).to_owned(), value: (*value).to_owned() }
+                    }
+                    [Some("rm"), Some(key)] => Behavior::Remove { key: (*key).to_owned() },
+                    [Some("flushdb")] => Behavior::FlushDb,
+                    _ => Err(malformed())?,
+                };
+                let offset = offset.ok_or_else(malformed)?;
+                ReplicationEvent::Write { offset, namespace: (*namespace).to_owned(), behavior }
+            }
+            _ => Err(malformed())?,
+        };
+        Ok(event)
+    }
+}
+
+fn bulk(s: &str) -> Msg {
+    Msg::Bulk(Some(s.to_owned()))
+}
+
+fn snapshot_push(entry: SnapshotEntry) -> Msg {
+    Msg::Push(vec![bulk("snapshot"), bulk(&entry.namespace), bulk(&entry.key), bulk(&entry.value)])
+}
+
+fn write_push(offset: u64, namespace: &str, behavior: &Behavior) -> Msg {
+    let mut items = vec![bulk("write"), Msg::Integer(offset as i64), bulk(namespace)];
+    match behavior {
+        Behavior::Set { key, value } => items.extend([bulk("set"), bulk(key), bulk(value)]),
+        Behavior::Remove { key } => items.extend([bulk("rm"), bulk(key)]),
+        Behavior::FlushDb => items.push(bulk("flushdb")),
+        other => unreachable!("not a write, {:?}", other),
+    }
+    Msg::Push(items)
+}
+
+/// about the bytes of the push of a write, its strings and the framing
+fn push_len(namespace: &str, behavior: &Behavior) -> u64 {
+    let strings = match behavior {
+        Behavior::Set { key, value } => key.len() + value.len(),
+        Behavior::Remove { key } => key.len(),
+        _ => 0,
+    };
+    (namespace.len() + strings + 64) as u64
+}
+
+/// the replication role and state of a server
+pub(crate) struct Replication {
+    /// random for every start of the server, see the module doc
+    replid: String,
+    /// the primary followed, `None` if the server is a primary
+    primary: Option<String>,
+    /// how the replica connects to the primary
+    primary_connect: ConnectOptions,
+    /// a write holds the stripe of its key, or all stripes for `FLUSHDB`, over the engine write and the offset,
+    /// so the writes of a key get the offsets in the order the engine runs them
+    stripes: Vec<Mutex<()>>,
+    /// the number of the writes since the server started
+    offset: AtomicU64,
+    /// whether the writes are kept in the backlog, turned on by the first `PSYNC` holding all stripes,
+    /// so it does not change while a write holds its stripe
+    backlog_on: AtomicBool,
+    log: Mutex<WriteLog>,
+    /// the offset of the last write, the replicas wait for its change
+    written: watch::Sender<u64>,
+    link: Mutex<Link>,
+}
+
+/// the writes of a server, kept for its replicas
+struct WriteLog {
+    /// the back is the last write, at least the last `REPLICATION_BACKLOG_SIZE` bytes of writes are kept,
+    /// and the writes not yet sent to a connected replica, up to `MAX_REPLICA_LAG` bytes
+    backlog: VecDeque<KeptWrite>,
+    /// the bytes of the writes since the backlog is on, where the next write starts
+    bytes: u64,
+    /// the next write of every connected replica
+    replicas: Vec<Weak<AtomicU64>>,
+}
+
+struct KeptWrite {
+    push: Msg,
+    /// where the write starts in `WriteLog::bytes`
+    start: u64,
+}
+
+impl WriteLog {
+    /// the offset of the front of the backlog, `offset` is the offset of the last write
+    fn first_kept(&self, offset: u64) -> u64 {
+        offset + 1 - self.backlog.len() as u64
+    }
+
+    /// the bytes of the writes in the backlog
+    fn kept_bytes(&self) -> u64 {
+        self.backlog.front().map_or(0, |front| self.bytes - front.start)
+    }
+}
+
+/// the pushes to a replica after the reply to its `PSYNC`, the snapshot first if it is fully resynced,
+/// then the writes read from the backlog
+pub(crate) struct ReplicaFeed {
+    /// the entries not pushed yet, followed by `snapshot-end`
+    snapshot: Option<std::vec::IntoIter<SnapshotEntry>>,
+    /// the offset of the next write pushed
+    next: Arc<AtomicU64>,
+    written: watch::Receiver<u64>,
+}
+
+impl ReplicaFeed {
+    /// the next push if it is ready, an error if the replica falls out of the backlog
+    pub fn try_next(&mut self, replication: &Replication) -> Result<Option<Msg>> {
+        if let Some(snapshot) = &mut self.snapshot {
+            return Ok(Some(match snapshot.next() {
+                Some(entry) => snapshot_push(entry),
+                None => {
+                    self.snapshot = None;
+                    Msg::Push(vec![bulk("snapshot-end")])
+                }
+            }));
+        }
+        let log = replication.log.lock().unwrap();
+        let offset = replication.offset.load(Ordering::Relaxed);
+        let next = self.next.load(Ordering::Relaxed);
+        if next > offset {
+            return Ok(None);
+        }
+        if next < log.first_kept(offset) {
+            log::warn!("replica removed, {} writes behind", offset + 1 - next);
+            Err(KvsError::SlowSubscriber)?
+        }
+        let push = log.backlog[(next - log.first_kept(offset)) as usize].push.clone();
+        self.next.store(next + 1, Ordering::Relaxed);
+        Ok(Some(push))
+    }
+
+    /// wait for the next push, see `try_next`
+    pub async fn next(&mut self, replication: &Replication) -> Result<Msg> {
+        loop {
+            if let Some(push) = self.try_next(replication)? {
+                return Ok(push);
+            }
+            // a write after the last wait wakes it up at once, none is missed
+            self.written.changed().await?;
+        }
+    }
+}
+
+/// where a replica is in the writes of its primary
+#[derive(Default)]
+struct Link {
+    /// whether the replica is connected to its primary
+    up: bool,
+    /// the replid of the primary and the offset of the last write applied,
+    /// `None` until a snapshot is applied completely
+    synced: Option<(String, u64)>,
+}
+
+impl Replication {
+    /// `replica_of` is the `IP:PORT` of the primary and how to connect to it if the server is a replica
+    pub fn new(replica_of: Option<(String, ConnectOptions)>) -> Self {
+        let (primary, primary_connect) = match replica_of {
+            Some((primary, options)) => (Some(primary), options),
+            None => (None, ConnectOptions::default()),
+        };
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        let high = hasher.finish();
+        hasher.write_u128(SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos()));
+        Replication {
+            replid: format!("{:016x}{:016x}", high, hasher.finish()),
+            primary,
+            primary_connect,
+            stripes: (0..WRITE_STRIPES).map(|_| Mutex::new(())).collect(),
+            offset: AtomicU64::new(0),
+            backlog_on: AtomicBool::new(false),
+            log: Mutex::new(WriteLog { backlog: VecDeque::new(), bytes: 0, replicas: Vec::new() }),
+            written: watch::channel(0).0,
+            link: Mutex::default(),
+        }
+    }
+
+    /// a replica rejects the writes of its clients
+    pub fn is_replica(&self) -> bool {
+        self.primary.is_some()
+    }
+
+    /// run the write `f` of `behavior` on `namespace`, then keep it for the replicas if it succeeds
+    ///
+    /// the writes of different keys run in parallel, the writes of a key and `FLUSHDB` run one at a time,
+    /// so the replicas applying the writes by the offsets end with the same values
+    pub fn write(&self, namespace: &str, behavior: &Behavior, f: impl FnOnce() -> Result<()>) -> Result<()> {
+        let _ordered: Vec<MutexGuard<()>> = match behavior {
+            Behavior::Set { key, .. } | Behavior::Remove { key } => {
+                let mut hasher = DefaultHasher::new();
+                (namespace, key).hash(&mut hasher);
+                vec![self.stripes[(hasher.finish() % WRITE_STRIPES as u64) as usize].lock().unwrap()]
+            }
+            _ => self.lock_stripes(),
+        };
+        f()?;
+        if !self.backlog_on.load(Ordering::Relaxed) {
+            self.offset.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+        let mut log = self.log.lock().unwrap();
+        let offset = self.offset.fetch_add(1, Ordering::Relaxed) + 1;
+        let start = log.bytes;
+        log.backlog.push_back(KeptWrite { push: write_push(offset, namespace, behavior), start });
+        log.bytes += push_len(namespace, behavior);
+        log.replicas.retain(|next| next.strong_count() > 0);
+        // keep the writes the slowest replica has not sent, unless it is too far behind
+        let first_kept = log.first_kept(offset);
+        let slowest = log.replicas.iter()
+            .filter_map(Weak::upgrade)
+            .map(|next| next.load(Ordering::Relaxed))
+            .filter(|next| *next >= first_kept)
+            .min();
+        while log.backlog.len() > 1 {
+            let kept = log.kept_bytes();
+            let needed = slowest == Some(log.first_kept(offset));
+            if kept <= REPLICATION_BACKLOG_SIZE || (needed && kept <= MAX_REPLICA_LAG) {
+                break;
+            }
+            log.backlog.pop_front();
+        }
+        self.written.send_replace(offset);
+        Ok(())
+    }
+
+    fn lock_stripes(&self) -> Vec<MutexGuard<'_, ()>> {
+        self.stripes.iter().map(|stripe| stripe.lock().unwrap()).collect()
+    }
+
+    /// start streaming the writes to a replica after the write `offset` of `replid`, see the module doc
+    ///
+    /// return the reply to `PSYNC` and the feed of the pushes after it
+    pub fn sync<KE: KvsEngine>(&self, engine: &KE, replid: Option<&str>, offset: Option<u64>) -> Result<(Msg, ReplicaFeed)> {
+        let (reply, next, full) = {
+            let mut log = if self.backlog_on.load(Ordering::Relaxed) {
+                self.log.lock().unwrap()
+            } else {
+                // the writes in progress are counted before, the later ones are kept
+                let _stripes = self.lock_stripes();
+                self.backlog_on.store(true, Ordering::Relaxed);
+                log::info!("replication backlog on");
+                self.log.lock().unwrap()
+            };
+            let last = self.offset.load(Ordering::Relaxed);
+            let (reply, next, full) = match (replid, offset) {
+                (Some(replid), Some(offset))
+                    if replid == self.replid && offset + 1 >= log.first_kept(last) && offset <= last =>
+                {
+                    log::info!("partial resync of a replica, offset={}", offset);
+                    (Msg::Line("CONTINUE".to_owned()), offset + 1, false)
+                }
+                _ => (Msg::Line(format!("FULLRESYNC {} {}", self.replid, last)), last + 1, true),
+            };
+            let next = Arc::new(AtomicU64::new(next));
+            log.replicas.push(Arc::downgrade(&next));
+            (reply, next, full)
+        };
+        // the snapshot is read without the lock, so it holds every write up to the offset of the reply,
+        // and maybe some writes after it, which the replica applies again to the same result
+        let snapshot = if full {
+            let entries = engine.snapshot()?;
+            log::info!("full resync of a replica, offset={}, keys={}", next.load(Ordering::Relaxed) - 1, entries.len());
+            Some(entries.into_iter())
+        } else {
+            None
+        };
+        Ok((reply, ReplicaFeed { snapshot, next, written: self.written.subscribe() }))
+    }
+
+    /// the fields of `INFO replication`
+    pub fn info(&self) -> Vec<(&'static str, String)> {
+        let mut log = self.log.lock().unwrap();
+        log.replicas.retain(|next| next.strong_count() > 0);
+        let role = if self.is_replica() { "replica" } else { "master" };
+        let mut fields = vec![
+            ("role", role.to_owned()),
+            ("replid", self.replid.clone()),
+            ("repl_offset", self.offset.load(Ordering::Relaxed).to_string()),
+            ("repl_backlog_active", (self.backlog_on.load(Ordering::Relaxed) as u8).to_string()),
+            ("repl_backlog_len", log.backlog.len().to_string()),
+            ("repl_backlog_bytes", log.kept_bytes().to_string()),
+            ("connected_replicas", log.replicas.len().to_string()),
+        ];
+        if let Some(primary) = &self.primary {
+            let link = self.link.lock().unwrap();
+            let (replid, offset) = match &link.synced {
+                Some((replid, offset)) => (replid.clone(), offset.to_string()),
+                None => (String::new(), "-1".to_owned()),
+            };
+            fields.extend([
+                ("master_addr", primary.clone()),
+                ("master_link_status", if link.up { "up" } else { "down" }.to_owned()),
+                ("master_replid", replid),
+                ("master_repl_offset", offset),
+            ]);
+        }
+        fields
+    }
+}
+
+/// follow the primary of a replica on a thread until the server shuts down, `None` if the server is a primary
+///
+/// the thread should be joined before the engine is closed
+pub(crate) fn follow<KE: KvsEngine>(engine: KE, context: Arc<ServerContext>) -> Option<JoinHandle<()>> {
+    let primary = context.replication.primary.clone()?;
+    Some(thread::spawn(move || {
+        // unblock the read of the connection and the wait to reconnect on shutdown
+        let socket: Arc<Mutex<Option<Stream>>> = Arc::default();
+        let (tx_stop, stop) = crossbeam::bounded::<()>(0);
+        let shutdown_socket = socket.clone();
+        context.shutdown.on_shutdown(move || {
+            if let Some(socket) = shutdown_socket.lock().unwrap().take() {
+                let _ = socket.shutdown(Shutdown::Both);
+            }
+            drop(tx_stop);
+        });
+        loop {
+            match follow_once(&primary, &engine, &context, &socket) {
+                Ok(()) => log::warn!("replication link closed, primary={}", primary),
+                Err(e) => log::warn!("replication link error, primary={}, {}", primary, e),
+            }
+            context.replication.link.lock().unwrap().up = false;
+            match stop.recv_timeout(RECONNECT_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        }
+        log::info!("replication stopped, primary={}", primary);
+    }))
+}
+
+/// apply the replication stream of one connection to the primary, return when it is closed
+fn follow_once<KE: KvsEngine>(
+    primary: &str,
+    engine: &KE,
+    context: &ServerContext,
+    socket: &Mutex<Option<Stream>>,
+) -> Result<()> {
+    let replication = &context.replication;
+    let synced = replication.link.lock().unwrap().synced.clone();
+    let client = KvsClient::connect_with(primary.to_owned(), &replication.primary_connect)?;
+    {
+        let mut socket = socket.lock().unwrap();
+        if context.shutdown.is_shutdown() {
+            return Ok(());
+        }
+        *socket = Some(client.try_clone_socket()?);
+    }
+    let (replid, offset) = match &synced {
+        Some((replid, offset)) => (Some(replid.as_str()), Some(*offset)),
+        None => (None, None),
+    };
+    let events = client.psync(replid, offset)?;
+    replication.link.lock().unwrap().up = true;
+
+    // the replid and the offset of the snapshot being applied
+    let mut snapshot = None;
+    for event in events {
+        match event? {
+            ReplicationEvent::FullResync { replid, offset } => {
+                log::info!("full resync, primary={}, replid={}, offset={}", primary, replid, offset);
+                replication.link.lock().unwrap().synced = None;
+                clear(engine, replication)?;
+                snapshot = Some((replid, offset));
+            }
+            ReplicationEvent::Continue => {
+                log::info!("partial resync, primary={}, offset={:?}", primary, offset);
+            }
+            ReplicationEvent::Snapshot(SnapshotEntry { namespace, key, value }) => {
+                apply(engine, replication, &namespace, Behavior::Set { key, value })?;
+            }
+            ReplicationEvent::SnapshotEnd => {
+                let synced = snapshot.take().ok_or_else(|| KvsError::InvalidMsg("snapshot-end without snapshot".to_owned()))?;
+                log::info!("snapshot applied, primary={}, offset={}", primary, synced.1);
+                replication.link.lock().unwrap().synced = Some(synced);
+            }
+            ReplicationEvent::Write { offset, namespace, behavior } => {
+                let expected = replication.link.lock().unwrap().synced.as_ref().map(|(_, last)| last + 1);
+                if expected != Some(offset) {
+                    Err(KvsError::InvalidMsg(format!("write {} out of order, expect {:?}", offset, expected)))?
+                }
+                apply(engine, replication, &namespace, behavior)?;
+                if let Some((_, last)) = &mut replication.link.lock().unwrap().synced {
+                    *last = offset;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// flush every namespace having keys before a full snapshot
+fn clear<KE: KvsEngine>(engine: &KE, replication: &Replication) -> Result<()> {
+    for namespace in engine.namespaces()? {
+        apply(engine, replication, &namespace, Behavior::FlushDb)?;
+    }
+    Ok(())
+}
+
+/// run a write of the primary, a removed key which is already absent is fine
+fn apply<KE: KvsEngine>(engine: &KE, replication: &Replication, namespace: &str, behavior: Behavior) -> Result<()> {
+    let engine = engine.select(namespace)?;
+    replication.write(namespace, &behavior, || match &behavior {
+        Behavior::Set { key, value } => engine.set(key.clone(), value.clone()),
+        Behavior::Remove { key } => match engine.remove(key.clone()) {
+            Err(e) if matches!(e.downcast_ref(), Some(KvsError::KeyNotFound)) => Ok(()),
+            removed => removed,
+        },
+        Behavior::FlushDb => engine.flush_db(),
+        other => Err(KvsError::InvalidMsg(format!("not a write, {:?}", other)).into()),
+    })
+}
@@ -1,71 +1,610 @@
 //! kvs server
 
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use std::future::Future;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use crate::acl::Acl;
+use crate::client::ConnectOptions;
 use crate::engines::KvsEngine;
-use crate::model::{Behavior, Msg, MsgExtend};
+use crate::logger;
+use crate::metrics::metrics;
+use crate::model::{read_msg_from, Msg, MsgLimits};
+use crate::net::{Address, Listener, Stream};
 use crate::Result;
+use crate::session::{protocol_error_reply, ClientRegistry, Closer, PoolInfo, ServerContext, Session};
+use crate::shutdown::ShutdownHandle;
+use crate::raft::RaftHandle;
+use crate::replication;
+use crate::slowlog::SlowLogConfig;
 use crate::thread_pool::ThreadPool;
 
+/// per connection timeouts, `None` means waiting forever
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTimeouts {
+    /// max time to wait for the first byte of the next request
+    pub idle: Option<Duration>,
+    /// max time to receive the whole request after its first byte arrived
+    pub read: Option<Duration>,
+    /// max time to send the whole reply
+    pub write: Option<Duration>,
+}
+
 #[allow(missing_docs)]
 pub struct KvsServer<KE: KvsEngine,TP: ThreadPool> {
-    binding_address: String,
+    addresses: Vec<Address>,
     engine: KE,
     thread_pool: TP,
+    limits: MsgLimits,
+    timeouts: ConnectionTimeouts,
+    max_clients: Option<usize>,
+    slowlog: SlowLogConfig,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    acl: Option<Acl>,
+    replica_of: Option<String>,
+    primary_connect: ConnectOptions,
+    raft: Option<RaftHandle>,
+    shutdown: ShutdownHandle,
 }
 
+/// how long an accept loop waits after an accept error before accepting again
+pub(crate) const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// the max time to send the error reply to a rejected connection
+pub(crate) const REJECT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// the rejected connections waiting for their error reply, those beyond are closed without one
+const REJECT_QUEUE_CAPACITY: usize = 64;
+
+/// how often the error replies not sent yet are tried again
+const REJECT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 impl<KE: KvsEngine, TP: ThreadPool> KvsServer<KE, TP> {
-    /// create with address and engine
+    /// create with address and engine, the address is `IP:PORT` or `unix://PATH`
     pub fn new(binding_address: String, engine: KE, thread_pool: TP) -> Self {
-        KvsServer { binding_address, engine ,thread_pool}
+        KvsServer {
+            addresses: vec![Address::parse(&binding_address)],
+            engine,
+            thread_pool,
+            limits: MsgLimits::default(),
+            timeouts: ConnectionTimeouts::default(),
+            max_clients: None,
+            slowlog: SlowLogConfig::default(),
+            tls: None,
+            acl: None,
+            replica_of: None,
+            primary_connect: ConnectOptions::default(),
+            raft: None,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// set the limits of the request msg, `MsgLimits::default()` is used if not set
+    pub fn with_limits(mut self, limits: MsgLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// set the connection timeouts, no timeout if not set
+    pub fn with_timeouts(mut self, timeouts: ConnectionTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// record the commands slower than `slowlog.threshold` for `SLOWLOG GET`,
+    /// slower than 10 ms if not set
+    pub fn with_slowlog(mut self, slowlog: SlowLogConfig) -> Self {
+        self.slowlog = slowlog;
+        self
+    }
+
+    /// reject the new connections with an error reply when `max_clients` clients are connected,
+    /// no limit if not set. a connection is counted from the time a thread of the pool takes it,
+    /// not while it waits in the queue of the pool
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = Some(max_clients);
+        self
     }
 
-    /// bind tcp port and handle connection
+    /// serve the TCP connections by TLS, see `ServerTlsConfig::load`, the Unix socket is not affected
+    pub fn with_tls(mut self, tls: Arc<rustls::ServerConfig>) -> Self {
+        self.tls = Some(tls);
+        self
+    }
+
+    /// require every connection to authenticate by `AUTH` as a user of `acl` before other commands
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    /// run as a replica of the primary at `primary`, `IP:PORT` or `unix://PATH`, see `kvs::replication`.
+    /// the data of the engine is replaced by the data of the primary, the writes of the clients are rejected
+    pub fn with_replica_of(mut self, primary: String) -> Self {
+        self.replica_of = Some(primary);
+        self
+    }
+
+    /// connect to the primary by `options`, e.g. by TLS or authenticated by `AUTH`, see `with_replica_of`
+    pub fn with_primary_connect(mut self, options: ConnectOptions) -> Self {
+        self.primary_connect = options;
+        self
+    }
+
+    /// serve as the node of `raft` in a Raft cluster, the engine should be the `RaftEngine` of the node,
+    /// see `kvs::raft`. the messages of the other nodes are taken by the `RAFT` command
+    pub fn with_raft(mut self, raft: RaftHandle) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// listen on the Unix socket at `path` too, the socket file is removed after the server stops
+    pub fn with_unix_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.addresses.push(Address::Unix(path.into()));
+        self
+    }
+
+    /// the handle to stop the server, `start` returns after the server is stopped
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// bind the addresses and handle connection until shutdown
+    ///
+    /// every connection holds a thread of the pool while it is served, waiting for its requests too.
+    /// a subscriber, by `SUBSCRIBE`, `WATCH` or as a replica, keeps its thread until it disconnects,
+    /// so the pool needs a thread for every subscriber besides the other clients
     pub fn start(&mut self) -> Result<()> {
-        let listener = TcpListener::bind(&self.binding_address)?;
-
-        // accept connections and process them serially
-        for stream in listener.incoming() {
-            let mut stream = stream?;
-            let peer_addr = stream.peer_addr()?.clone();
-            let engine_clone = self.engine.clone();
-            self.thread_pool.spawn(move || {
-                if let Err(e) = Self::handle_client(engine_clone, &mut stream) {
-                    log::error!("connection error, peer={}, {}", peer_addr,  e);
-                };
+        let listeners = self.addresses.iter()
+            .map(Listener::bind)
+            .collect::<io::Result<Vec<_>>>()?;
+        let pool = PoolInfo {
+            mode: "sync",
+            thread_pool: std::any::type_name::<TP>().rsplit("::").next().unwrap_or_default().to_owned(),
+            threads: self.thread_pool.threads(),
+        };
+        let clients = ClientRegistry::new(self.max_clients);
+        let context = Arc::new(ServerContext::new(
+            clients,
+            self.shutdown.clone(),
+            self.acl.clone(),
+            pool,
+            self.slowlog.clone(),
+            self.replica_of.clone().map(|primary| (primary, self.primary_connect.clone())),
+            self.raft.clone(),
+        ));
+        let follower = replication::follow(self.engine.clone(), context.clone());
+
+        // close the clients right away, the workers blocked on idle clients would never take
+        // the connections queued in a full thread pool, and the hand-off to the pool would block forever
+        let weak_context = Arc::downgrade(&context);
+        self.shutdown.on_shutdown(move || {
+            if let Some(context) = weak_context.upgrade() {
+                context.clients.close_all();
+            }
+        });
+
+        // wake up the blocking accepts by a connection
+        for listener in &listeners {
+            let local_address = listener.local_address()?;
+            self.shutdown.on_shutdown(move || {
+                let _ = Stream::connect(&local_address);
             });
         }
+
+        let rejector = Rejector::spawn();
+        // every listener accepts on its own thread, the connections are handed over one by one,
+        // so the accept loops are blocked when the queue of a bounded thread pool is full
+        let (tx, rx) = crossbeam::bounded::<(Stream, String)>(0);
+        thread::scope(|scope| {
+            let mut accept_threads = Vec::new();
+            for listener in &listeners {
+                let tx = tx.clone();
+                let shutdown = &self.shutdown;
+                accept_threads.push(scope.spawn(move || loop {
+                    let accepted = listener.accept();
+                    if shutdown.is_shutdown() {
+                        return;
+                    }
+                    match accepted {
+                        Ok(accepted) => if tx.send(accepted).is_err() {
+                            return;
+                        },
+                        Err(e) => {
+                            // e.g. too many open files, the listener is still usable,
+                            // wait a little for the failure to clear instead of spinning on it
+                            log::error!("accept error, {}", e);
+                            thread::sleep(ACCEPT_ERROR_BACKOFF);
+                        }
+                    }
+                }));
+            }
+            drop(tx);
+
+            while let Ok((stream, peer_addr)) = rx.recv() {
+                if self.shutdown.is_shutdown() {
+                    continue;
+                }
+                let peer = peer_addr.clone();
+                if let Err(e) = self.serve_connection(&context, &rejector, stream, peer_addr) {
+                    log::error!("connection error, peer={}, {}", peer, e);
+                }
+            }
+            accept_threads.into_iter().for_each(|t| t.join().unwrap());
+        });
+        drop(listeners);
+
+        // a closed connection finishes its in-flight request, then the session ends,
+        // the connections registered after the callback are closed here
+        log::info!("shutting down, closing client connections");
+        context.clients.close_all();
+        context.clients.wait_empty();
+        if let Some(follower) = follower {
+            let _ = follower.join();
+        }
+        self.engine.close()?;
+        log::info!("server stopped");
+        Ok(())
+    }
+
+    /// handle the connection on the thread pool, its session is registered when a worker takes it,
+    /// so the connections waiting in the queue of the pool are not counted by `max_clients`
+    fn serve_connection(
+        &self,
+        context: &Arc<ServerContext>,
+        rejector: &Rejector,
+        mut stream: Stream,
+        peer_addr: String,
+    ) -> Result<()> {
+        if let Some(tls) = &self.tls {
+            stream = stream.accept_tls(tls.clone())?;
+        }
+        let closer_stream = stream.try_clone_socket()?;
+        let closer: Closer = Box::new(move || {
+            let _ = closer_stream.shutdown(Shutdown::Read);
+        });
+        let engine = self.engine.clone();
+        let context = context.clone();
+        let limits = self.limits.clone();
+        let timeouts = self.timeouts.clone();
+        let rejector = rejector.clone();
+        metrics().queue_depth.inc();
+        self.thread_pool.spawn(move || {
+            metrics().queue_depth.dec();
+            // a connection taken after the shutdown is registered too late to be closed by it,
+            // `handle_client` checks the shutdown before the first request
+            let session = match Session::new(engine, context, peer_addr.clone(), Some(closer)) {
+                Ok(session) => session,
+                Err(e) => {
+                    log::warn!("connection rejected, peer={}, {}", peer_addr, e);
+                    metrics().connection_closed("rejected");
+                    // the reply, including the TLS handshake, is sent by the rejector,
+                    // so a slow or silent client does not hold up the worker
+                    rejector.reject(stream, Msg::Error(e.to_string()));
+                    return;
+                }
+            };
+            match Self::handle_client(session, &limits, &timeouts, &mut stream) {
+                Ok(Some(reason)) => {
+                    metrics().connection_closed(reason);
+                    log::warn!("connection closed, peer={}, {}", peer_addr, reason);
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    metrics().connection_failed(&e);
+                    log::error!("connection error, peer={}, {}", peer_addr, e);
+                }
+            }
+        });
         Ok(())
     }
 
-    fn handle_client(engine: KE, stream: &mut TcpStream) -> Result<()> {
+    /// return the reason if the connection is closed by a timeout
+    fn handle_client(
+        mut session: Session<KE>,
+        limits: &MsgLimits,
+        timeouts: &ConnectionTimeouts,
+        stream: &mut Stream,
+    ) -> Result<Option<&'static str>> {
+        stream.set_write_timeout(None)?;
+        // created when the session subscribes
+        let mut signal = None;
         loop {
-            let msg = stream.read_msg()?;
-            let behavior = msg.try_to_behavior()?;
-
-            let resp_msg = match behavior {
-                Behavior::Set { key, value } => {
-                    match engine.set(key, value) {
-                        Ok(_) => Msg::Bulk(None),
-                        Err(e) => Msg::Error(e.to_string()),
-                    }
+            if session.is_shutdown() {
+                return Ok(None);
+            }
+            loop {
+                let push = match session.try_push() {
+                    Ok(Some(push)) => push,
+                    Ok(None) => break,
+                    Err(_) => return Ok(Some("slow subscriber")),
+                };
+                match send_msg(stream, &push, timeouts.write) {
+                    Ok(_) => {}
+                    Err(e) if is_timeout(&e) => return Ok(Some("write timeout")),
+                    Err(e) => Err(e)?,
                 }
-                Behavior::Get { key } => {
-                    match engine.get(key) {
-                        Ok(value) => Msg::Bulk(value),
-                        Err(e) => Msg::Error(e.to_string()),
+            }
+            // wait for the next request without consuming it,
+            // a subscribed connection is not idle, it is woken up by the msgs to push meanwhile
+            let subscribed = session.is_subscribed();
+            if subscribed {
+                if signal.is_none() {
+                    signal = Some(Arc::new(PushSignal::new()?));
+                }
+                let signal = signal.as_ref().expect("created above");
+                match wait_subscribed(&mut session, stream, signal)? {
+                    Wake::Request => {}
+                    Wake::Push(Ok(push)) => {
+                        match send_msg(stream, &push, timeouts.write) {
+                            Ok(_) => {}
+                            Err(e) if is_timeout(&e) => return Ok(Some("write timeout")),
+                            Err(e) => Err(e)?,
+                        }
+                        continue;
                     }
+                    Wake::Push(Err(_)) => return Ok(Some("slow subscriber")),
                 }
-                Behavior::Remove { key } => {
-                    match engine.remove(key) {
-                        Ok(_) => Msg::Bulk(None),
-                        Err(e) => Msg::Error(e.to_string()),
+            }
+            stream.set_read_timeout(if subscribed { None } else { timeouts.idle })?;
+            match stream.wait_readable() {
+                Ok(false) => return Ok(None), // closed by peer
+                Ok(true) => {}
+                Err(e) if is_timeout(&e) => return Ok(Some("idle timeout")),
+                Err(e) => Err(e)?,
+            }
+            stream.set_read_timeout(None)?;
+
+            let mut reader = DeadlineStream::new(stream, timeouts.read);
+            let msg = match read_msg_from(&mut reader, limits) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    if let Some(io_error) = e.downcast_ref::<io::Error>() {
+                        if is_timeout(io_error) {
+                            return Ok(Some("read timeout"));
+                        }
                     }
+                    if let Some(reply) = protocol_error_reply(&e) {
+                        let _ = send_msg(stream, &reply, timeouts.write);
+                    }
+                    return Err(e);
                 }
             };
-            stream.write_all(&resp_msg.to_bytes())?;
+            let peer = session.peer_addr().to_owned();
+            let resp_msg = logger::with_fields(vec![("peer", peer)], || session.handle_msg(msg));
+            match send_msg(stream, &resp_msg, timeouts.write) {
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => return Ok(Some("write timeout")),
+                Err(e) => Err(e)?,
+            }
+        }
+    }
+}
+
+/// what a subscribed connection waiting for the next request is woken up by
+enum Wake {
+    /// the request, or the end of the connection, is ready to read
+    Request,
+    /// a msg to push to the client
+    Push(Result<Msg>),
+}
+
+/// wait for the next request or the next msg to push, whichever comes first.
+/// the thread sleeps in `poll` on the socket and the pipe of `signal`, written when a push is ready
+fn wait_subscribed<KE: KvsEngine>(session: &mut Session<KE>, stream: &mut Stream, signal: &Arc<PushSignal>) -> Result<Wake> {
+    if stream.has_buffered()? {
+        return Ok(Wake::Request);
+    }
+    let waker = Waker::from(signal.clone());
+    let mut cx = Context::from_waker(&waker);
+    let mut push = Box::pin(session.recv_push());
+    loop {
+        if let Poll::Ready(pushed) = push.as_mut().poll(&mut cx) {
+            return Ok(Wake::Push(pushed));
+        }
+        if signal.wait(stream)? {
+            return Ok(Wake::Request);
         }
-        // Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// the waker of the pushes of a subscribed connection, a byte written to a pipe wakes up the `poll` of the worker
+struct PushSignal {
+    rx: UnixStream,
+    tx: UnixStream,
+}
+
+impl PushSignal {
+    fn new() -> io::Result<Self> {
+        let (rx, tx) = UnixStream::pair()?;
+        rx.set_nonblocking(true)?;
+        tx.set_nonblocking(true)?;
+        Ok(PushSignal { rx, tx })
+    }
+
+    /// wait until `stream` is readable, return true, or a push is woken up, return false
+    fn wait(&self, stream: &Stream) -> io::Result<bool> {
+        let mut fds = [
+            libc::pollfd { fd: stream.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+            libc::pollfd { fd: self.rx.as_raw_fd(), events: libc::POLLIN, revents: 0 },
+        ];
+        loop {
+            // SAFETY: `fds` is a valid array of `fds.len()` pollfd for the duration of the call
+            if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, -1) } >= 0 {
+                break;
+            }
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        if fds[0].revents != 0 {
+            return Ok(true);
+        }
+        // drain the pipe, every wake before this poll of the pushes is seen by it
+        let mut buf = [0; 64];
+        while matches!((&self.rx).read(&mut buf), Ok(n) if n > 0) {}
+        Ok(false)
+    }
+}
+
+impl std::task::Wake for PushSignal {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        // a full pipe is woken up already
+        let _ = (&self.tx).write(&[0]);
+    }
+}
+
+/// write and flush the whole `msg` before the timeout
+pub(crate) fn send_msg(stream: &mut Stream, msg: &Msg, timeout: Option<Duration>) -> io::Result<()> {
+    let mut writer = DeadlineStream::new(stream, timeout);
+    writer.write_all(&msg.to_bytes())?;
+    writer.flush()
+}
+
+/// sends the error replies of the rejected connections on one thread by non-blocking writes,
+/// so a slow or silent client holds up neither a worker nor the other rejected clients
+#[derive(Clone)]
+struct Rejector {
+    tx: crossbeam::Sender<(Stream, Msg)>,
+}
+
+impl Rejector {
+    /// the thread ends once every `Rejector` is dropped and the replies queued are done
+    fn spawn() -> Self {
+        let (tx, rx) = crossbeam::bounded(REJECT_QUEUE_CAPACITY);
+        thread::spawn(move || reject_loop(rx));
+        Rejector { tx }
+    }
+
+    /// queue the reply to the connection, or close it without a reply if the queue is full
+    fn reject(&self, stream: Stream, reply: Msg) {
+        if let Err(crossbeam::TrySendError::Full(_)) = self.tx.try_send((stream, reply)) {
+            log::debug!("the rejection queue is full, the connection is closed without a reply");
+        }
+    }
+}
+
+fn reject_loop(rx: crossbeam::Receiver<(Stream, Msg)>) {
+    let mut pending: Vec<PendingReply> = Vec::new();
+    loop {
+        let received = if pending.is_empty() {
+            rx.recv().map_err(|_| crossbeam::RecvTimeoutError::Disconnected)
+        } else {
+            rx.recv_timeout(REJECT_POLL_INTERVAL)
+        };
+        match received {
+            Ok((stream, reply)) => pending.push(PendingReply::new(stream, &reply)),
+            Err(crossbeam::RecvTimeoutError::Disconnected) if pending.is_empty() => return,
+            Err(crossbeam::RecvTimeoutError::Disconnected) => thread::sleep(REJECT_POLL_INTERVAL),
+            Err(crossbeam::RecvTimeoutError::Timeout) => {}
+        }
+        pending.retain_mut(|reply| !reply.try_send());
+    }
+}
+
+/// an error reply written without blocking, given up after `REJECT_TIMEOUT`
+struct PendingReply {
+    stream: Stream,
+    bytes: Vec<u8>,
+    written: usize,
+    deadline: Instant,
+}
+
+impl PendingReply {
+    fn new(stream: Stream, reply: &Msg) -> Self {
+        let _ = stream.set_nonblocking(true);
+        PendingReply { stream, bytes: reply.to_bytes(), written: 0, deadline: Instant::now() + REJECT_TIMEOUT }
+    }
+
+    /// write what the socket takes, a TLS stream goes on with its handshake first,
+    /// return true once the reply is sent, failed or timed out
+    fn try_send(&mut self) -> bool {
+        let sent = (|| {
+            while self.written < self.bytes.len() {
+                match self.stream.write(&self.bytes[self.written..])? {
+                    0 => return Err(io::ErrorKind::WriteZero.into()),
+                    n => self.written += n,
+                }
+            }
+            self.stream.flush()
+        })();
+        match sent {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Instant::now() >= self.deadline,
+            _ => true,
+        }
+    }
+}
+
+/// a blocking read or write on the socket with timeout returns `WouldBlock` or `TimedOut`
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+}
+
+/// read or write the `Stream` until the deadline, instead of a timeout for each system call
+///
+/// the timeout of the stream is not touched if there is no deadline
+pub(crate) struct DeadlineStream<'a> {
+    stream: &'a mut Stream,
+    deadline: Option<Instant>,
+}
+
+impl<'a> DeadlineStream<'a> {
+    pub fn new(stream: &'a mut Stream, timeout: Option<Duration>) -> Self {
+        DeadlineStream { stream, deadline: timeout.map(|t| Instant::now() + t) }
+    }
+
+    /// the time left before the deadline, `TimedOut` if it is passed
+    fn remaining(&self) -> io::Result<Option<Duration>> {
+        match self.deadline {
+            None => Ok(None),
+            Some(deadline) => {
+                let now = Instant::now();
+                if now >= deadline {
+                    return Err(io::ErrorKind::TimedOut.into());
+                }
+                Ok(Some(deadline - now))
+            }
+        }
+    }
+}
+
+impl Read for DeadlineStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.deadline.is_some() {
+            let remaining = self.remaining()?;
+            self.stream.set_read_timeout(remaining)?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for DeadlineStream<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.deadline.is_some() {
+            let remaining = self.remaining()?;
+            self.stream.set_write_timeout(remaining)?;
+        }
+        self.stream.write(buf)
+    }
+
+    /// a TLS stream sends the buffered records here
+    fn flush(&mut self) -> io::Result<()> {
+        if self.deadline.is_some() {
+            let remaining = self.remaining()?;
+            self.stream.set_write_timeout(remaining)?;
+        }
+        self.stream.flush()
+    }
+}
@@ -0,0 +1,792 @@
+//! per connection state and command dispatch, shared by `KvsServer` and `AsyncKvsServer`
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::fmt::Write;
+use std::time::{Instant, UNIX_EPOCH};
+
+use crate::acl::{command_name, Acl, User, DEFAULT_USER};
+use crate::client::ConnectOptions;
+use crate::engines::{KvsEngine, DEFAULT_NAMESPACE};
+use crate::error::KvsError;
+use tokio::sync::mpsc::error::TryRecvError;
+use tokio::sync::mpsc::Receiver;
+
+use crate::keyspace::KeyWatch;
+use crate::logger;
+use crate::metrics::metrics;
+use crate::model::{Behavior, Msg, Protocol};
+use crate::pubsub::{Kind, PubSub};
+use crate::raft::RaftHandle;
+use crate::replication::{ReplicaFeed, Replication};
+use crate::Result;
+use crate::shutdown::ShutdownHandle;
+use crate::slowlog::{SlowLog, SlowLogConfig};
+
+/// the state shared by all sessions of a server
+pub(crate) struct ServerContext {
+    pub clients: ClientRegistry,
+    pub shutdown: ShutdownHandle,
+    /// every connection must authenticate if it is set
+    pub acl: Option<Acl>,
+    pub pubsub: PubSub,
+    pub started: Instant,
+    /// the commands run by all sessions
+    pub commands: AtomicU64,
+    /// the id of the last request, in the log fields and the spans of the requests
+    pub last_request_id: AtomicU64,
+    pub pool: PoolInfo,
+    pub slowlog: SlowLog,
+    pub replication: Replication,
+    /// the node of the server, `None` if it is not in a Raft cluster
+    pub raft: Option<RaftHandle>,
+}
+
+impl ServerContext {
+    pub fn new(
+        clients: ClientRegistry,
+        shutdown: ShutdownHandle,
+        acl: Option<Acl>,
+        pool: PoolInfo,
+        slowlog: SlowLogConfig,
+        replica_of: Option<(String, ConnectOptions)>,
+        raft: Option<RaftHandle>,
+    ) -> Self {
+        ServerContext {
+            clients,
+            shutdown,
+            acl,
+            pubsub: PubSub::default(),
+            started: Instant::now(),
+            commands: AtomicU64::new(0),
+            last_request_id: AtomicU64::new(0),
+            pool,
+            slowlog: SlowLog::new(slowlog),
+            replication: Replication::new(replica_of),
+            raft,
+        }
+    }
+}
+
+/// how a server runs the connections, shown by `INFO threadpool`
+pub(crate) struct PoolInfo {
+    /// "sync" or "async"
+    pub mode: &'static str,
+    /// the name of the thread pool type, or "tokio"
+    pub thread_pool: String,
+    /// the threads running the connections, 0 if every connection runs on a new thread
+    pub threads: usize,
+}
+
+/// the state of a client connection
+pub(crate) struct Session<KE: KvsEngine> {
+    engine: KE,
+    protocol: Protocol,
+    context: Arc<ServerContext>,
+    id: u64,
+    peer_addr: String,
+    /// the selected namespace
+    namespace: String,
+    /// the authenticated user, always `None` if the server has no ACL
+    user: Option<Arc<User>>,
+    /// the published messages, `None` if the session subscribes nothing
+    pushes: Option<Receiver<Msg>>,
+    /// the msgs to push before the published messages, e.g. the confirmations of a `SUBSCRIBE` of many channels
+    pending: VecDeque<Msg>,
+    /// the key changes, `None` if the session watches nothing
+    watch: Option<KeyWatch>,
+    /// the writes streamed to a replica, `None` if the connection is not a replica
+    writes: Option<ReplicaFeed>,
+    /// the client span of the next request, set by `CLIENT TRACEPARENT`
+    trace_parent: Option<String>,
+}
+
+impl<KE: KvsEngine> Session<KE> {
+    /// create and register the session in the clients of `context`, it is unregistered when dropped.
+    /// `closer` is called to close the connection when the server shuts down
+    ///
+    /// return `KvsError::MaxClientsReached` if the clients are full
+    pub fn new(engine: KE, context: Arc<ServerContext>, peer_addr: String, closer: Option<Closer>) -> Result<Self> {
+        let id = context.clients.register(peer_addr.clone(), closer)?;
+        metrics().connections.inc();
+        metrics().connected.inc();
+        Ok(Session {
+            engine,
+            protocol: Protocol::Resp2,
+            context,
+            id,
+            peer_addr,
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            user: None,
+            pushes: None,
+            pending: VecDeque::new(),
+            watch: None,
+            writes: None,
+            trace_parent: None,
+        })
+    }
+
+    /// whether the server is shutting down, the connection should be closed after the in-flight request
+    pub fn is_shutdown(&self) -> bool {
+        self.context.shutdown.is_shutdown()
+    }
+
+    /// the address of the client
+    pub fn peer_addr(&self) -> &str {
+        &self.peer_addr
+    }
+
+    /// the handle to watch or request the server shutdown
+    pub fn shutdown_handle(&self) -> &ShutdownHandle {
+        &self.context.shutdown
+    }
+
+    /// whether the published messages, the key changes or the writes of the replication may be pushed,
+    /// the connection should wait for them besides the requests
+    pub fn is_subscribed(&self) -> bool {
+        self.pushes.is_some() || self.watch.is_some() || self.writes.is_some()
+    }
+
+    /// the next msg to push to the client besides the replies, `None` if there is none for now,
+    /// return `KvsError::SlowSubscriber` if the session fell behind the published messages
+    pub fn try_push(&mut self) -> Result<Option<Msg>> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(Some(msg.for_protocol(self.protocol)));
+        }
+        let queues = self.pushes.iter_mut().chain(self.watch.iter_mut().map(KeyWatch::pushes));
+        for pushes in queues {
+            match pushes.try_recv() {
+                Ok(msg) => return Ok(Some(msg.for_protocol(self.protocol))),
+                Err(TryRecvError::Empty) => {}
+                Err(TryRecvError::Disconnected) => Err(KvsError::SlowSubscriber)?,
+            }
+        }
+        match &mut self.writes {
+            Some(writes) => Ok(writes.try_next(&self.context.replication)?.map(|msg| msg.for_protocol(self.protocol))),
+            None => Ok(None),
+        }
+    }
+
+    /// wait for the next msg to push to the client, never ready if the session subscribes nothing,
+    /// see `try_push`
+    pub async fn recv_push(&mut self) -> Result<Msg> {
+        if let Some(msg) = self.pending.pop_front() {
+            return Ok(msg.for_protocol(self.protocol));
+        }
+        let (published, watch, writes) = (&mut self.pushes, &mut self.watch, &mut self.writes);
+        let replication = &self.context.replication;
+        let published = async move {
+            match published {
+                Some(pushes) => pushes.recv().await.ok_or_else(|| KvsError::SlowSubscriber.into()),
+                None => futures::future::pending().await,
+            }
+        };
+        let changed = async move {
+            match watch {
+                Some(watch) => watch.pushes().recv().await.ok_or_else(|| KvsError::SlowSubscriber.into()),
+                None => futures::future::pending().await,
+            }
+        };
+        let written = async move {
+            match writes {
+                Some(writes) => writes.next(replication).await,
+                None => futures::future::pending().await,
+            }
+        };
+        let pushed: Result<Msg> = tokio::select! {
+            msg = published => msg,
+            msg = changed => msg,
+            msg = written => msg,
+        };
+        Ok(pushed?.for_protocol(self.protocol))
+    }
+
+    /// run the command in `msg`, return the reply encoded for the protocol of the session
+    ///
+    /// it may block on the engine
+    pub fn handle_msg(&mut self, msg: Msg) -> Msg {
+        let args = msg.try_to_vec_string().unwrap_or_default();
+        let cmd = args.first().map(|s| s.to_lowercase()).unwrap_or_default();
+        self.context.clients.touch(self.id, cmd.clone());
+
+        let request_id = self.context.last_request_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let started = Instant::now();
+        let behavior = msg.try_to_behavior();
+        // the client span carried by the command itself, or sent by the command before
+        let traceparent = match &behavior {
+            Ok(Behavior::ClientTraceParent { traceparent }) => Some(traceparent.clone()),
+            _ => self.trace_parent.take(),
+        };
+        let span = tracing::info_span!(
+            "request",
+            otel.kind = "server",
+            request_id,
+            peer = %self.peer_addr,
+            traceparent,
+            command = tracing::field::Empty,
+            error = tracing::field::Empty,
+        );
+        let _entered = span.enter();
+        // sent by the client before its requests when the spans are exported, not a request of its own
+        let trace_only = matches!(behavior, Ok(Behavior::ClientTraceParent { .. }));
+        // the known names only, not to label the metrics by arbitrary input
+        let (command, reply) = match behavior {
+            Ok(behavior) => {
+                let command = command_name(&behavior);
+                span.record("command", command);
+                let fields = vec![("request_id", request_id.to_string()), ("command", command.to_owned())];
+                (command, logger::with_fields(fields, || self.dispatch(behavior)))
+            }
+            Err(e) => ("unknown", Msg::Error(e.to_string())),
+        };
+        if let Msg::Error(e) = &reply {
+            span.record("error", e.as_str());
+        }
+        let metrics = metrics();
+        if !trace_only {
+            self.context.commands.fetch_add(1, Ordering::Relaxed);
+            let elapsed = started.elapsed();
+            metrics.commands.with_label_values(&[command]).inc();
+            metrics.command_duration.with_label_values(&[command]).observe(elapsed.as_secs_f64());
+            let duration_us = elapsed.as_micros() as u64;
+            log::info!(target: logger::REQUEST_TARGET, command = command, duration_us = duration_us; "command done");
+            if cmd == "auth" {
+                // never keep the passwords
+                self.context.slowlog.record(elapsed, &self.peer_addr, &args[..1]);
+            } else {
+                self.context.slowlog.record(elapsed, &self.peer_addr, &args);
+            }
+        }
+        if let Msg::Error(e) = &reply {
+            metrics.error_reply(e);
+        }
+        reply.for_protocol(self.protocol)
+    }
+
+    fn dispatch(&mut self, behavior: Behavior) -> Msg {
+        if let Err(e) = self.check_permission(&behavior) {
+            return Msg::Error(e.to_string());
+        }
+        if let Err(e) = self.check_subscribed_context(&behavior) {
+            return Msg::Error(e.to_string());
+        }
+        if let Err(e) = self.check_writable(&behavior) {
+            return Msg::Error(e.to_string());
+        }
+
+        let (engine, replication) = (&self.engine, &self.context.replication);
+        match behavior {
+            Behavior::Set { ref key, ref value } => {
+                match replication.write(&self.namespace, &behavior, || engine.set(key.clone(), value.clone())) {
+                    Ok(_) => Msg::Bulk(None),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::Get { key } => {
+                match self.engine.get(key) {
+                    Ok(value) => Msg::Bulk(value),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::Remove { ref key } => {
+                match replication.write(&self.namespace, &behavior, || engine.remove(key.clone())) {
+                    Ok(_) => Msg::Bulk(None),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::MGet { keys } => {
+                let values: Result<Vec<Msg>> = keys.into_iter()
+                    .map(|key| Ok(Msg::Bulk(self.engine.get(key)?)))
+                    .collect();
+                match values {
+                    Ok(values) => Msg::Array(values),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::MSet { pairs } => {
+                // each key is a write of its own for the replicas, a failure leaves the keys before it set
+                let written = pairs.into_iter().try_for_each(|(key, value)| {
+                    let set = Behavior::Set { key: key.clone(), value: value.clone() };
+                    replication.write(&self.namespace, &set, || engine.set(key, value))
+                });
+                match written {
+                    Ok(_) => Msg::Line("OK".to_owned()),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::Select { namespace } => {
+                match self.engine.select(&namespace) {
+                    Ok(engine) => {
+                        self.engine = engine;
+                        self.namespace = namespace.clone();
+                        self.context.clients.set_namespace(self.id, namespace);
+                        Msg::Line("OK".to_owned())
+                    }
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::DbSize => {
+                match self.engine.db_size() {
+                    Ok(size) => Msg::Integer(size as i64),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::FlushDb => {
+                match replication.write(&self.namespace, &behavior, || engine.flush_db()) {
+                    Ok(_) => Msg::Line("OK".to_owned()),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::Subscribe { channels } => self.subscribe(Kind::Channel, channels),
+            Behavior::PSubscribe { patterns } => self.subscribe(Kind::Pattern, patterns),
+            Behavior::Unsubscribe { channels } => self.unsubscribe(Kind::Channel, channels),
+            Behavior::PUnsubscribe { patterns } => self.unsubscribe(Kind::Pattern, patterns),
+            Behavior::Publish { channel, message } => {
+                Msg::Integer(self.context.pubsub.publish(&channel, &message) as i64)
+            }
+            Behavior::Watch { prefix } => match self.engine.watch(&prefix) {
+                Ok(events) => {
+                    let count = self.watch.get_or_insert_with(KeyWatch::new).add(prefix, events);
+                    Msg::Integer(count as i64)
+                }
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::Unwatch => {
+                // the changes forwarded before are dropped
+                self.watch = None;
+                Msg::Line("OK".to_owned())
+            }
+            Behavior::Hello { protocol } => {
+                if let Some(p) = protocol {
+                    self.protocol = p;
+                }
+                self.hello_reply()
+            }
+            Behavior::Auth { user, password } => match self.authenticate(user, &password) {
+                Ok(()) => Msg::Line("OK".to_owned()),
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::ClientList => Msg::Bulk(Some(self.context.clients.list())),
+            Behavior::ClientTraceParent { traceparent } => {
+                self.trace_parent = Some(traceparent);
+                Msg::Line("OK".to_owned())
+            }
+            Behavior::SlowLogGet { count } => {
+                Msg::Array(self.context.slowlog.get(count).iter().map(|entry| entry.to_msg()).collect())
+            }
+            Behavior::SlowLogLen => Msg::Integer(self.context.slowlog.len() as i64),
+            Behavior::SlowLogReset => {
+                self.context.slowlog.reset();
+                Msg::Line("OK".to_owned())
+            }
+            Behavior::PSync { replid, offset } => match replication.sync(engine, replid.as_deref(), offset) {
+                Ok((reply, writes)) => {
+                    self.writes = Some(writes);
+                    reply
+                }
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::Raft { message } => {
+                let stepped = self.raft().and_then(|raft| {
+                    let message = serde_json::from_str(&message).map_err(|e| KvsError::InvalidMsg(e.to_string()))?;
+                    raft.step(message);
+                    Ok(())
+                });
+                match stepped {
+                    Ok(()) => Msg::Line("OK".to_owned()),
+                    Err(e) => Msg::Error(e.to_string()),
+                }
+            }
+            Behavior::ClusterAddNode { id, addr } => match self.raft().and_then(|raft| raft.add_member(id, addr)) {
+                Ok(()) => Msg::Line("OK".to_owned()),
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::ClusterRemoveNode { id } => match self.raft().and_then(|raft| raft.remove_member(id)) {
+                Ok(()) => Msg::Line("OK".to_owned()),
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::Info { section } => match self.info_reply(section.as_deref()) {
+                Ok(info) => Msg::Bulk(Some(info)),
+                Err(e) => Msg::Error(e.to_string()),
+            },
+            Behavior::Shutdown => {
+                self.context.shutdown.shutdown();
+                Msg::Line("OK".to_owned())
+            }
+        }
+    }
+
+    /// return `KvsError::NoAuth` before the connection authenticates, or a `NOPERM` error if the user may not run it
+    fn check_permission(&self, behavior: &Behavior) -> Result<()> {
+        if self.context.acl.is_none() {
+            return Ok(());
+        }
+        match (&self.user, behavior) {
+            (_, Behavior::Auth { .. }) | (_, Behavior::Hello { .. }) | (_, Behavior::ClientTraceParent { .. }) => Ok(()),
+            (Some(user), _) => user.check(behavior),
+            (None, _) => Err(KvsError::NoAuth.into()),
+        }
+    }
+
+    /// RESP2 can not tell the replies from the pushed messages, a subscribed session runs the subscriptions only
+    fn check_subscribed_context(&self, behavior: &Behavior) -> Result<()> {
+        if self.protocol != Protocol::Resp2 || !self.is_subscribed() {
+            return Ok(());
+        }
+        match behavior {
+            Behavior::Subscribe { .. }
+            | Behavior::Unsubscribe { .. }
+            | Behavior::PSubscribe { .. }
+            | Behavior::PUnsubscribe { .. }
+            | Behavior::Watch { .. }
+            | Behavior::Unwatch
+            | Behavior::ClientTraceParent { .. } => Ok(()),
+            _ => Err(KvsError::SubscribedContext(command_name(behavior).to_owned()).into()),
+        }
+    }
+
+    /// a replica takes the writes from its primary only
+    fn check_writable(&self, behavior: &Behavior) -> Result<()> {
+        let write = matches!(
+            behavior,
+            Behavior::Set { .. } | Behavior::MSet { .. } | Behavior::Remove { .. } | Behavior::FlushDb
+        );
+        if write && self.context.replication.is_replica() {
+            Err(KvsError::ReadOnly)?
+        }
+        Ok(())
+    }
+
+    /// the Raft node of the server, `KvsError::ClusterDisabled` if it is not in a cluster
+    fn raft(&self) -> Result<&RaftHandle> {
+        Ok(self.context.raft.as_ref().ok_or(KvsError::ClusterDisabled)?)
+    }
+
+    /// reply the confirmation of the first name, the others are pushed after it
+    fn subscribe(&mut self, kind: Kind, names: Vec<String>) -> Msg {
+        let mut confirmations = Vec::new();
+        for name in names {
+            let (pushes, count) = self.context.pubsub.subscribe(self.id, kind, &name);
+            if pushes.is_some() {
+                self.pushes = pushes;
+            }
+            confirmations.push(confirmation(kind, "subscribe", Some(name), count));
+        }
+        self.reply_confirmations(confirmations)
+    }
+
+    /// unsubscribe all if `names` is empty, see `subscribe`
+    fn unsubscribe(&mut self, kind: Kind, mut names: Vec<String>) -> Msg {
+        if names.is_empty() {
+            names = self.context.pubsub.subscriptions(self.id, kind);
+        }
+        let mut confirmations = Vec::new();
+        let mut count = self.subscription_count();
+        for name in names {
+            count = self.context.pubsub.unsubscribe(self.id, kind, &name);
+            confirmations.push(confirmation(kind, "unsubscribe", Some(name), count));
+        }
+        if confirmations.is_empty() {
+            confirmations.push(confirmation(kind, "unsubscribe", None, count));
+        }
+        if count == 0 {
+            // the messages published before are still pushed
+            if let Some(mut pushes) = self.pushes.take() {
+                while let Ok(msg) = pushes.try_recv() {
+                    self.pending.push_back(msg);
+                }
+            }
+        }
+        self.reply_confirmations(confirmations)
+    }
+
+    fn subscription_count(&self) -> usize {
+        let pubsub = &self.context.pubsub;
+        pubsub.subscriptions(self.id, Kind::Channel).len() + pubsub.subscriptions(self.id, Kind::Pattern).len()
+    }
+
+    fn reply_confirmations(&mut self, confirmations: Vec<Msg>) -> Msg {
+        let mut confirmations = confirmations.into_iter();
+        let reply = confirmations.next().unwrap_or(Msg::Bulk(None));
+        self.pending.extend(confirmations);
+        reply
+    }
+
+    /// a failed `AUTH` keeps the user authenticated before
+    fn authenticate(&mut self, user: Option<String>, password: &str) -> Result<()> {
+        let acl = match &self.context.acl {
+            Some(acl) => acl,
+            None => Err(KvsError::AuthNotConfigured)?,
+        };
+        let user = acl.authenticate(user.as_deref().unwrap_or(DEFAULT_USER), password)?;
+        self.context.clients.set_user(self.id, user.name.clone());
+        self.user = Some(user);
+        Ok(())
+    }
+
+    /// the server properties replied to `HELLO`
+    fn hello_reply(&self) -> Msg {
+        let bulk = |s: &str| Msg::Bulk(Some(s.to_owned()));
+        Msg::Map(vec![
+            (bulk("server"), bulk("kvs")),
+            (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+            (bulk("proto"), Msg::Integer(self.protocol.version())),
+            (bulk("id"), Msg::Integer(self.id as i64)),
+            (bulk("mode"), bulk(if self.context.raft.is_some() { "cluster" } else { "standalone" })),
+            (bulk("role"), bulk(if self.context.replication.is_replica() { "replica" } else { "master" })),
+            (bulk("engine"), bulk(&self.engine.engine_name())),
+        ])
+    }
+
+    /// the `INFO` text, a `# Section` line followed by `field:value` lines for each section,
+    /// all sections if `section` is `None`, "all" or "default", none if it is unknown
+    fn info_reply(&self, section: Option<&str>) -> Result<String> {
+        let all = matches!(section, None | Some("all") | Some("default"));
+        let wanted = |name: &str| all || section == Some(name);
+        let context = &self.context;
+        let mut sections: Vec<(&str, Vec<(&str, String)>)> = Vec::new();
+
+        if wanted("server") {
+            sections.push(("Server", vec![
+                ("kvs_version", env!("CARGO_PKG_VERSION").to_owned()),
+                ("process_id", std::process::id().to_string()),
+                ("uptime_in_seconds", context.started.elapsed().as_secs().to_string()),
+                ("server_mode", context.pool.mode.to_owned()),
+            ]));
+        }
+        if wanted("clients") {
+            let max = context.clients.max_clients.unwrap_or(0);
+            sections.push(("Clients", vec![
+                ("connected_clients", context.clients.connected().to_string()),
+                ("maxclients", max.to_string()),
+            ]));
+        }
+        if wanted("stats") {
+            sections.push(("Stats", vec![
+                ("total_connections_received", context.clients.total_connections().to_string()),
+                ("total_commands_processed", context.commands.load(Ordering::Relaxed).to_string()),
+            ]));
+        }
+        if wanted("engine") {
+            let stats = self.engine.stats()?;
+            let mut fields = vec![
+                ("engine", self.engine.engine_name()),
+                ("keys", stats.keys.to_string()),
+                ("namespaces", stats.namespaces.to_string()),
+                ("data_dir_bytes", stats.disk_bytes.to_string()),
+            ];
+            if let Some(log) = stats.log {
+                let last_compaction = log.last_compaction
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |since| since.as_secs());
+                fields.extend([
+                    ("log_segments", log.segments.to_string()),
+                    ("log_bytes", log.bytes.to_string()),
+                    ("log_live_bytes", log.live_bytes.to_string()),
+                    ("log_stale_bytes", log.bytes.saturating_sub(log.live_bytes).to_string()),
+                    ("compactions", log.compactions.to_string()),
+                    ("last_compaction_time", last_compaction.to_string()),
+                ]);
+            }
+            sections.push(("Engine", fields));
+        }
+        if wanted("replication") {
+            sections.push(("Replication", context.replication.info()));
+        }
+        if let (true, Some(raft)) = (wanted("raft"), &context.raft) {
+            let status = raft.status()?;
+            let leader_addr = status.leader.and_then(|leader| status.members.get(&leader)).cloned();
+            let members: Vec<String> = status.members.iter().map(|(id, addr)| format!("{}={}", id, addr)).collect();
+            sections.push(("Raft", vec![
+                ("raft_id", status.id.to_string()),
+                ("raft_role", status.role.to_string()),
+                ("raft_term", status.term.to_string()),
+                ("raft_leader_id", status.leader.map_or(String::new(), |leader| leader.to_string())),
+                ("raft_leader_addr", leader_addr.unwrap_or_default()),
+                ("raft_members", members.join(",")),
+                ("commit_index", status.commit_index.to_string()),
+                ("applied_index", status.applied_index.to_string()),
+                ("snapshot_index", status.snapshot_index.to_string()),
+                ("last_index", status.last_index.to_string()),
+            ]));
+        }
+        if wanted("threadpool") {
+            sections.push(("Threadpool", vec![
+                ("thread_pool", context.pool.thread_pool.clone()),
+                ("threads", context.pool.threads.to_string()),
+                ("queued_connections", metrics().queue_depth.get().to_string()),
+            ]));
+        }
+
+        let mut info = String::new();
+        for (i, (name, fields)) in sections.iter().enumerate() {
+            if i > 0 {
+                info.push_str("\r\n");
+            }
+            let _ = write!(info, "# {}\r\n", name);
+            for (field, value) in fields {
+                let _ = write!(info, "{}:{}\r\n", field, value);
+            }
+        }
+        Ok(info)
+    }
+}
+
+impl<KE: KvsEngine> Drop for Session<KE> {
+    fn drop(&mut self) {
+        self.context.pubsub.remove(self.id);
+        self.context.clients.unregister(self.id);
+        metrics().connected.dec();
+    }
+}
+
+/// like `["subscribe", "news", 1]`, the count is the number of the subscriptions of the session after it
+fn confirmation(kind: Kind, action: &str, name: Option<String>, count: usize) -> Msg {
+    let action = match kind {
+        Kind::Channel => action.to_owned(),
+        Kind::Pattern => format!("p{}", action),
+    };
+    Msg::Push(vec![Msg::Bulk(Some(action)), Msg::Bulk(name), Msg::Integer(count as i64)])
+}
+
+/// the reply to a malformed or oversized msg, `None` if the error comes from the connection itself
+///
+/// such a msg leaves the stream in an unknown state, the connection should be closed after the reply
+pub(crate) fn protocol_error_reply(e: &anyhow::Error) -> Option<Msg> {
+    if e.downcast_ref::<std::io::Error>().is_some() {
+        return None;
+    }
+    Some(Msg::Error(format!("ERR Protocol error: {}", e)))
+}
+
+/// close a client connection from another thread
+pub(crate) type Closer = Box<dyn Fn() + Send>;
+
+/// a connected client shown by `CLIENT LIST`
+struct ClientInfo {
+    addr: String,
+    connected_at: Instant,
+    last_active: Instant,
+    last_cmd: String,
+    user: String,
+    namespace: String,
+    closer: Option<Closer>,
+}
+
+/// the connected clients of a server
+#[derive(Default)]
+pub(crate) struct ClientRegistry {
+    next_id: AtomicU64,
+    clients: Mutex<HashMap<u64, ClientInfo>>,
+    /// notified when a client is unregistered
+    unregistered: Condvar,
+    max_clients: Option<usize>,
+}
+
+impl ClientRegistry {
+    /// `max_clients` is the max number of clients registered at the same time, no limit if `None`
+    pub fn new(max_clients: Option<usize>) -> Self {
+        ClientRegistry { max_clients, ..Default::default() }
+    }
+
+    /// return the id of the new client
+    pub fn register(&self, addr: String, closer: Option<Closer>) -> Result<u64> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some(max) = self.max_clients {
+            if clients.len() >= max {
+                Err(KvsError::MaxClientsReached)?
+            }
+        }
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst) + 1;
+        let now = Instant::now();
+        let info = ClientInfo {
+            addr,
+            connected_at: now,
+            last_active: now,
+            last_cmd: "NULL".to_owned(),
+            user: DEFAULT_USER.to_owned(),
+            namespace: DEFAULT_NAMESPACE.to_owned(),
+            closer,
+        };
+        clients.insert(id, info);
+        Ok(id)
+    }
+
+    pub fn unregister(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+        self.unregistered.notify_all();
+    }
+
+    /// call the closer of every client
+    pub fn close_all(&self) {
+        for info in self.clients.lock().unwrap().values() {
+            if let Some(closer) = &info.closer {
+                closer();
+            }
+        }
+    }
+
+    /// the number of the clients registered now
+    pub fn connected(&self) -> usize {
+        self.clients.lock().unwrap().len()
+    }
+
+    /// the number of the clients ever registered
+    fn total_connections(&self) -> u64 {
+        self.next_id.load(Ordering::SeqCst)
+    }
+
+    /// block until all clients are unregistered
+    pub fn wait_empty(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        while !clients.is_empty() {
+            clients = self.unregistered.wait(clients).unwrap();
+        }
+    }
+
+    /// record the command just received from the client
+    fn touch(&self, id: u64, cmd: String) {
+        if let Some(info) = self.clients.lock().unwrap().get_mut(&id) {
+            info.last_active = Instant::now();
+            info.last_cmd = cmd;
+        }
+    }
+
+    /// record the user the client authenticated as
+    fn set_user(&self, id: u64, user: String) {
+        if let Some(info) = self.clients.lock().unwrap().get_mut(&id) {
+            info.user = user;
+        }
+    }
+
+    /// record the namespace the client selected
+    fn set_namespace(&self, id: u64, namespace: String) {
+        if let Some(info) = self.clients.lock().unwrap().get_mut(&id) {
+            info.namespace = namespace;
+        }
+    }
+
+    /// one line for each client, like `id=1 addr=127.0.0.1:50000 age=10 idle=2 cmd=get user=default db=0`,
+    /// `age` and `idle` are in seconds
+    fn list(&self) -> String {
+        let clients = self.clients.lock().unwrap();
+        let mut ids: Vec<&u64> = clients.keys().collect();
+        ids.sort();
+        let mut text = String::new();
+        for id in ids {
+            let info = &clients[id];
+            text += &format!(
+                "id={} addr={} age={} idle={} cmd={} user={} db={}\n",
+                id,
+                info.addr,
+                info.connected_at.elapsed().as_secs(),
+                info.last_active.elapsed().as_secs(),
+                info.last_cmd,
+                info.user,
+                info.namespace,
+            );
+        }
+        text
+    }
+}
@@ -0,0 +1,65 @@
+//! stop a running server
+
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+
+/// request a server to shut down, it can be cloned and sent to other threads, e.g. a signal handler
+///
+/// the server stops accepting, lets in-flight requests finish, closes client connections,
+/// closes the engine, then returns from `start`
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    inner: Arc<ShutdownInner>,
+}
+
+struct ShutdownInner {
+    tx: watch::Sender<bool>,
+    callbacks: Mutex<Vec<Box<dyn FnOnce() + Send>>>,
+}
+
+impl Default for ShutdownHandle {
+    fn default() -> Self {
+        ShutdownHandle {
+            inner: Arc::new(ShutdownInner {
+                tx: watch::channel(false).0,
+                callbacks: Mutex::new(vec![]),
+            }),
+        }
+    }
+}
+
+impl ShutdownHandle {
+    /// request the server to shut down, return without waiting for it
+    pub fn shutdown(&self) {
+        if self.inner.tx.send_replace(true) {
+            return; // already requested
+        }
+        log::info!("shutdown requested");
+        let callbacks = std::mem::take(&mut *self.inner.callbacks.lock().unwrap());
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// whether the shutdown is requested
+    pub fn is_shutdown(&self) -> bool {
+        *self.inner.tx.borrow()
+    }
+
+    /// the receiver changes to `true` when the shutdown is requested
+    pub(crate) fn subscribe(&self) -> watch::Receiver<bool> {
+        self.inner.tx.subscribe()
+    }
+
+    /// run `callback` when the shutdown is requested, or right now if it was requested
+    pub(crate) fn on_shutdown<F: FnOnce() + Send + 'static>(&self, callback: F) {
+        let mut callbacks = self.inner.callbacks.lock().unwrap();
+        if self.is_shutdown() {
+            drop(callbacks);
+            callback();
+        } else {
+            callbacks.push(Box::new(callback));
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! the commands running longer than a threshold, kept in a ring buffer read by `SLOWLOG GET`
+
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::model::Msg;
+
+/// the max number of arguments kept for an entry, the rest are counted in the last one
+pub const SLOWLOG_MAX_ARGS: usize = 32;
+
+/// the max bytes kept for an argument, the rest are counted after it
+pub const SLOWLOG_MAX_ARG_LEN: usize = 128;
+
+/// which commands are recorded and how many are kept
+#[derive(Debug, Clone)]
+pub struct SlowLogConfig {
+    /// the commands running at least this long are recorded, none if `None`
+    pub threshold: Option<Duration>,
+    /// the max number of entries, the oldest are dropped
+    pub max_len: usize,
+    /// write the entries to the log too
+    pub log: bool,
+}
+
+impl Default for SlowLogConfig {
+    fn default() -> Self {
+        SlowLogConfig { threshold: Some(Duration::from_millis(10)), max_len: 128, log: false }
+    }
+}
+
+/// a slow command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowLogEntry {
+    /// increases by one with every entry since the server started, kept by `SLOWLOG RESET`
+    pub id: u64,
+    /// when the command finished
+    pub timestamp: SystemTime,
+    #[allow(missing_docs)]
+    pub duration: Duration,
+    /// the address of the client
+    pub addr: String,
+    /// the command and its arguments, truncated by `SLOWLOG_MAX_ARGS` and `SLOWLOG_MAX_ARG_LEN`
+    pub args: Vec<String>,
+}
+
+impl SlowLogEntry {
+    /// `[id, unix seconds, microseconds, [args..], addr]`
+    pub fn to_msg(&self) -> Msg {
+        let timestamp = self.timestamp.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs());
+        Msg::Array(vec![
+            Msg::Integer(self.id as i64),
+            Msg::Integer(timestamp as i64),
+            Msg::Integer(self.duration.as_micros() as i64),
+            Msg::Array(self.args.iter().map(|arg| Msg::Bulk(Some(arg.clone()))).collect()),
+            Msg::Bulk(Some(self.addr.clone())),
+        ])
+    }
+}
+
+#[derive(Default)]
+struct Entries {
+    next_id: u64,
+    /// the newest first
+    entries: VecDeque<SlowLogEntry>,
+}
+
+/// the slow commands of a server
+pub(crate) struct SlowLog {
+    config: SlowLogConfig,
+    inner: Mutex<Entries>,
+}
+
+impl SlowLog {
+    pub fn new(config: SlowLogConfig) -> Self {
+        SlowLog { config, inner: Mutex::new(Entries::default()) }
+    }
+
+    /// record the command if it ran at least the threshold
+    pub fn record(&self, duration: Duration, addr: &str, args: &[String]) {
+        match self.config.threshold {
+            Some(threshold) if duration >= threshold => {}
+            _ => return,
+        }
+        let args = truncate_args(args);
+        if self.config.log {
+            log::warn!("slow command, {}us, client={}, {}", duration.as_micros(), addr, args.join(" "));
+        }
+        if self.config.max_len == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = SlowLogEntry { id: inner.next_id, timestamp: SystemTime::now(), duration, addr: addr.to_owned(), args };
+        inner.next_id += 1;
+        inner.entries.push_front(entry);
+        inner.entries.truncate(self.config.max_len);
+    }
+
+    /// the newest `count` entries, the newest first
+    pub fn get(&self, count: usize) -> Vec<SlowLogEntry> {
+        self.inner.lock().unwrap().entries.iter().take(count).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().entries.len()
+    }
+
+    /// remove all entries
+    pub fn reset(&self) {
+        self.inner.lock().unwrap().entries.clear();
+    }
+}
+
+/// like `["set", "key", "aaaa... (872 more bytes)"]`
+fn truncate_args(args: &[String]) -> Vec<String> {
+    let mut truncated: Vec<String> = args.iter()
+        .take(if args.len() > SLOWLOG_MAX_ARGS { SLOWLOG_MAX_ARGS - 1 } else { SLOWLOG_MAX_ARGS })
+        .map(|arg| {
+            if arg.len() <= SLOWLOG_MAX_ARG_LEN {
+                return arg.clone();
+            }
+            let mut end = SLOWLOG_MAX_ARG_LEN;
+            while !arg.is_char_boundary(end) {
+                end -= 1;
+            }
+            format!("{}... ({} more bytes)", &arg[..end], arg.len() - end)
+        })
+        .collect();
+    if args.len() > SLOWLOG_MAX_ARGS {
+        truncated.push(format!("... ({} more arguments)", args.len() - truncated.len()));
+    }
+    truncated
+}
@@ -10,6 +10,7 @@ mod shared_queue_thread_pool;
 mod rayon_thread_pool;
 
 ///
+#[allow(clippy::empty_docs, clippy::new_ret_no_self)]
 pub trait ThreadPool<RET=Self> {
     /// Creates a new thread pool, immediately spawning the specified number of threads.
     /// Returns an error if any thread fails to spawn. All previously-spawned threads are terminated.
@@ -19,5 +20,7 @@ pub trait ThreadPool<RET=Self> {
     /// operate with the same number of threads — the thread count is not reduced nor is
     /// the thread pool destroyed, corrupted or invalidated.
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static;
+    /// The number of threads running the jobs, 0 if every job runs on a new thread.
+    fn threads(&self) -> usize;
 }
 
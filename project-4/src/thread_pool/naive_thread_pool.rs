@@ -15,4 +15,8 @@ impl ThreadPool for NaiveThreadPool {
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
         std::thread::spawn(job);
     }
+
+    fn threads(&self) -> usize {
+        0
+    }
 }
\ No newline at end of file
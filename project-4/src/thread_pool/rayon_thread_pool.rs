@@ -15,4 +15,8 @@ impl ThreadPool for RayonThreadPool{
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
         self.thread_pool.spawn(job);
     }
+
+    fn threads(&self) -> usize {
+        self.thread_pool.current_num_threads()
+    }
 }
\ No newline at end of file
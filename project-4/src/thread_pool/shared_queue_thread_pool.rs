@@ -8,13 +8,26 @@ use crate::*;
 use crate::thread_pool::ThreadPool;
 
 ///
-#[allow(dead_code)]
+#[allow(dead_code, clippy::empty_docs)]
 pub struct SharedQueueThreadPool {
     threads: u32,
     tx: Sender<ThreadPoolMessage>,
 }
 
 impl SharedQueueThreadPool {
+    /// 创建任务队列有界的线程池，队列满时`spawn`会阻塞，直到有线程取走任务
+    pub fn with_queue_capacity(threads: u32, capacity: usize) -> Result<Self> {
+        Ok(Self::with_channel(threads, crossbeam::bounded(capacity)))
+    }
+
+    fn with_channel(threads: u32, (tx, rx): (Sender<ThreadPoolMessage>, Receiver<ThreadPoolMessage>)) -> Self {
+        for _ in 0..threads {
+            let receiver = rx.clone();
+            SharedQueueThreadPool::add_thread(receiver);
+        }
+        Self { threads, tx }
+    }
+
     /// 向线程池里面补充新的线程
     fn add_thread(rx: Receiver<ThreadPoolMessage>) {
         std::thread::spawn(move || {
@@ -38,12 +51,7 @@ impl SharedQueueThreadPool {
 
 impl ThreadPool for SharedQueueThreadPool {
     fn new(threads: u32) -> Result<Self> {
-        let (tx, rx) = crossbeam::unbounded();
-        for _ in 0..threads {
-            let receiver = rx.clone();
-            SharedQueueThreadPool::add_thread(receiver);
-        }
-        Ok(Self { threads, tx })
+        Ok(Self::with_channel(threads, crossbeam::unbounded()))
     }
 
     fn spawn<F>(&self, job: F) where F: FnOnce() + Send + 'static {
@@ -51,6 +59,10 @@ impl ThreadPool for SharedQueueThreadPool {
             log::error!("[SharedQueueThreadPool] spawn error, {}", e);
         }
     }
+
+    fn threads(&self) -> usize {
+        self.threads as usize
+    }
 }
 
 impl Drop for SharedQueueThreadPool {
@@ -0,0 +1,113 @@
+//! TLS of the TCP connections, by rustls
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{ClientConfig, RootCertStore, ServerConfig};
+
+use crate::error::KvsError;
+use crate::Result;
+
+/// the PEM files of the server
+#[derive(Debug, Clone)]
+pub struct ServerTlsConfig {
+    /// the certificate chain, the server certificate first
+    pub cert: PathBuf,
+    /// the private key of the server certificate
+    pub key: PathBuf,
+    /// the CA certificates to verify the client certificates, every client must present one if it is set
+    pub client_ca: Option<PathBuf>,
+}
+
+impl ServerTlsConfig {
+    /// read the files, return `KvsError::InvalidTls` if any of them is missing or invalid
+    pub fn load(&self) -> Result<Arc<ServerConfig>> {
+        let certs = read_certs(&self.cert)?;
+        let key = read_key(&self.key)?;
+        let builder = match &self.client_ca {
+            Some(client_ca) => {
+                let roots = read_roots(client_ca)?;
+                let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                    .build()
+                    .map_err(|e| KvsError::InvalidTls(e.to_string()))?;
+                ServerConfig::builder().with_client_cert_verifier(verifier)
+            }
+            None => ServerConfig::builder().with_no_client_auth(),
+        };
+        let config = builder
+            .with_single_cert(certs, key)
+            .map_err(|e| KvsError::InvalidTls(e.to_string()))?;
+        Ok(Arc::new(config))
+    }
+}
+
+/// the PEM files of the client
+#[derive(Debug, Clone)]
+pub struct ClientTlsConfig {
+    /// the CA certificates to verify the server certificate, the only trusted roots
+    pub ca: PathBuf,
+    /// the client certificate chain for mutual TLS
+    pub cert: Option<PathBuf>,
+    /// the private key of the client certificate
+    pub key: Option<PathBuf>,
+}
+
+impl ClientTlsConfig {
+    /// read the files, return `KvsError::InvalidTls` if any of them is missing or invalid
+    pub fn load(&self) -> Result<Arc<ClientConfig>> {
+        let builder = ClientConfig::builder().with_root_certificates(read_roots(&self.ca)?);
+        let config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(read_certs(cert)?, read_key(key)?)
+                .map_err(|e| KvsError::InvalidTls(e.to_string()))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => Err(KvsError::InvalidTls("the client certificate and key must be set together".to_owned()))?,
+        };
+        Ok(Arc::new(config))
+    }
+}
+
+/// the name to verify the server certificate with, the host of `IP:PORT` or `HOST:PORT`
+pub(crate) fn server_name(address: &str) -> Result<ServerName<'static>> {
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_owned())
+        .map_err(|e| KvsError::InvalidTls(format!("invalid server name {:?}, {}", host, e)).into())
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path)
+        .map_err(|e| KvsError::InvalidTls(format!("can not read {:?}, {}", path, e)))?;
+    Ok(BufReader::new(file))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?).collect::<std::io::Result<Vec<_>>>()?;
+    if certs.is_empty() {
+        Err(KvsError::InvalidTls(format!("no certificate in {:?}", path)))?
+    }
+    Ok(certs)
+}
+
+fn read_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    match rustls_pemfile::private_key(&mut open(path)?)? {
+        Some(key) => Ok(key),
+        None => Err(KvsError::InvalidTls(format!("no private key in {:?}", path)).into()),
+    }
+}
+
+fn read_roots(path: &Path) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certs(path)? {
+        roots.add(cert).map_err(|e| KvsError::InvalidTls(e.to_string()))?;
+    }
+    Ok(roots)
+}
@@ -0,0 +1,260 @@
+//! export the `tracing` spans to a file in the OTLP JSON form, to inspect them offline
+//!
+//! a line of the file is an `ExportTraceServiceRequest` of one finished span, like
+//! ```json
+//! {"resourceSpans":[{"resource":{"attributes":[..]},"scopeSpans":[{"scope":{"name":"kvs"},"spans":[{
+//!   "traceId":"..","spanId":"..","parentSpanId":"..","name":"request","kind":2,
+//!   "startTimeUnixNano":"..","endTimeUnixNano":"..","attributes":[{"key":"request_id","value":{"intValue":"1"}}],
+//!   "status":{}}]}]}]}
+//! ```
+//! a span without a parent starts a trace, the field `otel.kind` sets the kind, e.g. "server" or "client",
+//! and the field `error` sets the error status
+//!
+//! the field `traceparent`, a W3C `00-TRACE_ID-SPAN_ID-01`, makes a span without a local parent the child
+//! of a span in another process. `KvsClient` sends the context of its span by `CLIENT TRACEPARENT`
+//! before the request, so the `request` span of the server joins the trace of the client.
+//! `KvsProxy` does not pass the context on to the backends
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Span, Subscriber};
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Registry;
+
+use crate::Result;
+
+/// OTLP `SPAN_KIND_INTERNAL`
+const KIND_INTERNAL: u8 = 1;
+
+/// OTLP `STATUS_CODE_ERROR`
+const STATUS_ERROR: u8 = 2;
+
+/// export the spans of the process to `path`, appended to the spans there
+///
+/// return an error if the file can not be opened or a global subscriber is set
+pub fn init_otlp_file(path: impl AsRef<Path>) -> Result<()> {
+    let subscriber = Registry::default().with(OtlpFileLayer::open(path.as_ref())?);
+    tracing::subscriber::set_global_default(subscriber)?;
+    Ok(())
+}
+
+/// the W3C `traceparent` of the current span, to send to the server, `None` if it is not exported
+pub fn traceparent() -> Option<String> {
+    Span::current()
+        .with_subscriber(|(id, dispatch)| {
+            let registry = dispatch.downcast_ref::<Registry>()?;
+            let span = registry.span(id)?;
+            let extensions = span.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some(format!("00-{:032x}-{:016x}-01", data.trace_id, data.span_id))
+        })
+        .flatten()
+}
+
+/// the trace id and the span id of a W3C `traceparent`, `None` if it is malformed or the ids are 0
+fn parse_traceparent(traceparent: &str) -> Option<(u128, u64)> {
+    let parts: Vec<&str> = traceparent.split('-').collect();
+    match parts[..] {
+        ["00", trace_id, span_id, _] if trace_id.len() == 32 && span_id.len() == 16 => {
+            let trace_id = u128::from_str_radix(trace_id, 16).ok().filter(|id| *id != 0)?;
+            let span_id = u64::from_str_radix(span_id, 16).ok().filter(|id| *id != 0)?;
+            Some((trace_id, span_id))
+        }
+        _ => None,
+    }
+}
+
+/// the layer writing a line for each closed span
+pub struct OtlpFileLayer {
+    file: Mutex<File>,
+    /// random, the ids are unique among the processes writing the same file
+    id_base: u64,
+    next_id: AtomicU64,
+}
+
+impl OtlpFileLayer {
+    /// append the spans to the file at `path`
+    pub fn open(path: &Path) -> Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        Ok(OtlpFileLayer { file: Mutex::new(file), id_base: hasher.finish(), next_id: AtomicU64::new(1) })
+    }
+
+    /// never 0, which means no id in OTLP
+    fn new_id(&self) -> u64 {
+        match self.id_base.wrapping_add(self.next_id.fetch_add(1, Ordering::Relaxed)) {
+            0 => self.new_id(),
+            id => id,
+        }
+    }
+}
+
+/// the span recorded until it is closed
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_span_id: Option<u64>,
+    start: SystemTime,
+    kind: u8,
+    error: Option<String>,
+    /// the span of another process given by the field `traceparent`
+    remote_parent: Option<(u128, u64)>,
+    attributes: Vec<Value>,
+}
+
+impl<S> Layer<S> for OtlpFileLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut data = SpanData {
+            trace_id: 0,
+            span_id: self.new_id(),
+            parent_span_id: None,
+            start: SystemTime::now(),
+            kind: KIND_INTERNAL,
+            error: None,
+            remote_parent: None,
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut data);
+        let parent = span.parent().and_then(|parent| {
+            parent.extensions().get::<SpanData>().map(|data| (data.trace_id, data.span_id))
+        });
+        match parent.or(data.remote_parent) {
+            Some((trace_id, parent_span_id)) => {
+                data.trace_id = trace_id;
+                data.parent_span_id = Some(parent_span_id);
+            }
+            None => data.trace_id = (self.new_id() as u128) << 64 | self.new_id() as u128,
+        }
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(data) = span.extensions_mut().get_mut::<SpanData>() {
+                values.record(data);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(&id) {
+            Some(span) => span,
+            None => return,
+        };
+        let data = match span.extensions_mut().remove::<SpanData>() {
+            Some(data) => data,
+            None => return,
+        };
+        let mut line = export_request(span.name(), &data).to_string();
+        line.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::warn!("[trace] write span error, {}", e);
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.attributes.push(attribute(field.name(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.attributes.push(attribute(field.name(), json!({ "intValue": value.to_string() })));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.attributes.push(attribute(field.name(), json!({ "doubleValue": value })));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.attributes.push(attribute(field.name(), json!({ "boolValue": value })));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "otel.kind" => self.kind = span_kind(value),
+            "error" => self.error = Some(value.to_owned()),
+            "traceparent" => self.remote_parent = parse_traceparent(value),
+            name => self.attributes.push(attribute(name, json!({ "stringValue": value }))),
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value));
+    }
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+    json!({ "key": key, "value": value })
+}
+
+/// OTLP `SpanKind`
+fn span_kind(kind: &str) -> u8 {
+    match kind {
+        "server" => 2,
+        "client" => 3,
+        "producer" => 4,
+        "consumer" => 5,
+        _ => KIND_INTERNAL,
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_nanos()).to_string()
+}
+
+fn export_request(name: &str, data: &SpanData) -> Value {
+    let status = match &data.error {
+        Some(message) => json!({ "code": STATUS_ERROR, "message": message }),
+        None => json!({}),
+    };
+    let span = json!({
+        "traceId": format!("{:032x}", data.trace_id),
+        "spanId": format!("{:016x}", data.span_id),
+        "parentSpanId": data.parent_span_id.map(|id| format!("{:016x}", id)).unwrap_or_default(),
+        "name": name,
+        "kind": data.kind,
+        "startTimeUnixNano": unix_nanos(data.start),
+        "endTimeUnixNano": unix_nanos(SystemTime::now()),
+        "attributes": data.attributes,
+        "status": status,
+    });
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [
+                    attribute("service.name", json!({ "stringValue": "kvs" })),
+                    attribute("process.pid", json!({ "intValue": std::process::id().to_string() })),
+                ],
+            },
+            "scopeSpans": [{
+                "scope": { "name": "kvs", "version": env!("CARGO_PKG_VERSION") },
+                "spans": [span],
+            }],
+        }],
+    })
+}
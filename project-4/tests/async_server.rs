@@ -0,0 +1,83 @@
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::model::{Msg, MsgExtend, MsgLimits};
+use kvs::{KvStore, Result};
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, limits: MsgLimits) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let mut server = AsyncKvsServer::new(addr.to_owned(), engine).with_limits(limits);
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+// Idle connections should not stop other clients from being served
+#[test]
+fn serve_with_idle_connections() -> Result<()> {
+    let addr = "127.0.0.1:4020";
+    let _temp_dir = start_server(addr, MsgLimits::default());
+
+    let mut idle = Vec::new();
+    for _ in 0..2000 {
+        idle.push(TcpStream::connect(addr)?);
+    }
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    assert_eq!(
+        client.request_msg(command(&["get", "key1"]))?,
+        Msg::Bulk(Some("value1".to_owned()))
+    );
+    drop(idle);
+    Ok(())
+}
+
+// Pipelined and fragmented requests should be decoded one by one
+#[test]
+fn pipelined_and_fragmented_requests() -> Result<()> {
+    let addr = "127.0.0.1:4021";
+    let _temp_dir = start_server(addr, MsgLimits::default());
+
+    let mut stream = TcpStream::connect(addr)?;
+    let mut bytes = command(&["set", "key1", "value1"]).to_bytes();
+    bytes.extend(command(&["get", "key1"]).to_bytes());
+    let (head, tail) = bytes.split_at(7);
+    stream.write_all(head)?;
+    thread::sleep(Duration::from_millis(100));
+    stream.write_all(tail)?;
+
+    assert_eq!(stream.read_msg()?, Msg::Bulk(None));
+    assert_eq!(stream.read_msg()?, Msg::Bulk(Some("value1".to_owned())));
+    Ok(())
+}
+
+// A request breaking the limits should get an error reply, then the connection is closed
+#[test]
+fn request_limits() -> Result<()> {
+    let addr = "127.0.0.1:4022";
+    let limits = MsgLimits {
+        max_bulk_len: 16,
+        ..MsgLimits::default()
+    };
+    let _temp_dir = start_server(addr, limits);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"*2\r\n$3\r\nget\r\n$4294967296\r\n")?;
+    match stream.read_msg()? {
+        Msg::Error(e) => assert!(e.starts_with("ERR Protocol error"), "{}", e),
+        other => panic!("expect Error, got {:?}", other),
+    }
+    assert!(stream.read_msg().is_err(), "connection should be closed");
+    Ok(())
+}
@@ -0,0 +1,229 @@
+use assert_cmd::prelude::*;
+use kvs::acl::{Acl, Category, User};
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use predicates::str::contains;
+use std::process::{Child, Command};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+const ACL: &str = r#"
+[[user]]
+name = "admin"
+password = "admin-pass"
+categories = ["read", "write", "admin"]
+
+[[user]]
+name = "alice"
+password = "alice-pass"
+categories = ["read", "write"]
+keys = ["alice:*", "shared"]
+
+[[user]]
+name = "reader"
+password = "reader-pass"
+categories = ["read"]
+"#;
+
+fn start_server(addr: &str, acl: Acl, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine).with_acl(acl);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool).with_acl(acl);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn assert_error(reply: Msg, prefix: &str) {
+    match reply {
+        Msg::Error(e) => assert!(e.starts_with(prefix), "expect {}, got {}", prefix, e),
+        other => panic!("expect Error, got {:?}", other),
+    }
+}
+
+fn check_password(addr: &str, is_async: bool) -> Result<()> {
+    let (handle, join) = start_server(addr, Acl::with_password("secret"), is_async);
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    assert_error(client.request_msg(command(&["set", "key1", "value1"]))?, "NOAUTH");
+    assert_error(client.request_msg(command(&["get", "key1"]))?, "NOAUTH");
+    // HELLO is allowed before AUTH
+    assert!(matches!(client.request_msg(command(&["hello"]))?, Msg::Array(_)));
+    assert_error(client.auth(None, "wrong")?, "WRONGPASS");
+    assert_error(client.request_msg(command(&["get", "key1"]))?, "NOAUTH");
+
+    assert_eq!(client.auth(None, "secret")?, Msg::Line("OK".to_owned()));
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    assert_eq!(client.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("value1".to_owned())));
+    match client.request_msg(command(&["client", "list"]))? {
+        Msg::Bulk(Some(list)) => assert!(list.contains("user=default"), "{}", list),
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+    // a failed AUTH keeps the connection authenticated
+    assert_error(client.auth(None, "wrong")?, "WRONGPASS");
+    assert_eq!(client.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("value1".to_owned())));
+
+    // every connection authenticates on its own
+    let mut other = KvsClient::connect(addr.to_owned())?;
+    assert_error(other.request_msg(command(&["get", "key1"]))?, "NOAUTH");
+    assert_error(other.request_msg(command(&["shutdown"]))?, "NOAUTH");
+
+    drop(client);
+    drop(other);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// Only the connections authenticated by the password should run the commands
+#[test]
+fn password() -> Result<()> {
+    check_password("127.0.0.1:4070", false)
+}
+
+#[test]
+fn password_async() -> Result<()> {
+    check_password("127.0.0.1:4071", true)
+}
+
+// The users should run only the commands of their categories on the keys matching their patterns
+#[test]
+fn acl_users() -> Result<()> {
+    let addr = "127.0.0.1:4072";
+    let (handle, join) = start_server(addr, Acl::from_toml(ACL)?, false);
+
+    let mut admin = KvsClient::connect(addr.to_owned())?;
+    assert_error(admin.auth(None, "admin-pass")?, "WRONGPASS");
+    assert_error(admin.auth(Some("admin"), "alice-pass")?, "WRONGPASS");
+    assert_eq!(admin.auth(Some("admin"), "admin-pass")?, Msg::Line("OK".to_owned()));
+    assert_eq!(admin.request_msg(command(&["set", "bob:1", "b"]))?, Msg::Bulk(None));
+
+    let mut alice = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(alice.auth(Some("alice"), "alice-pass")?, Msg::Line("OK".to_owned()));
+    assert_eq!(alice.request_msg(command(&["set", "alice:1", "a"]))?, Msg::Bulk(None));
+    assert_eq!(alice.request_msg(command(&["set", "shared", "s"]))?, Msg::Bulk(None));
+    assert_eq!(alice.request_msg(command(&["get", "alice:1"]))?, Msg::Bulk(Some("a".to_owned())));
+    assert_error(alice.request_msg(command(&["get", "bob:1"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["rm", "bob:1"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["set", "shared2", "s"]))?, "NOPERM");
+    // every key of a multi-key command must be allowed
+    assert_error(alice.request_msg(command(&["mget", "alice:1", "bob:1"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["mset", "alice:2", "a", "bob:2", "b"]))?, "NOPERM");
+    assert_eq!(alice.request_msg(command(&["get", "alice:2"]))?, Msg::Bulk(None));
+    assert_error(alice.request_msg(command(&["client", "list"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["info"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["slowlog", "get"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["flushdb"]))?, "NOPERM");
+    assert_eq!(alice.request_msg(command(&["dbsize"]))?, Msg::Integer(3));
+    // a watch must be within the allowed keys
+    assert_error(alice.request_msg(command(&["watch", "bob:"]))?, "NOPERM");
+    assert_error(alice.request_msg(command(&["watch", ""]))?, "NOPERM");
+    assert_eq!(alice.request_msg(command(&["watch", "alice:1"]))?, Msg::Integer(1));
+    assert_eq!(alice.request_msg(command(&["unwatch"]))?, Msg::Line("OK".to_owned()));
+    assert_error(alice.request_msg(command(&["shutdown"]))?, "NOPERM");
+
+    let mut reader = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(reader.auth(Some("reader"), "reader-pass")?, Msg::Line("OK".to_owned()));
+    assert_eq!(reader.request_msg(command(&["get", "bob:1"]))?, Msg::Bulk(Some("b".to_owned())));
+    assert_error(reader.request_msg(command(&["set", "bob:1", "r"]))?, "NOPERM");
+    assert_error(reader.request_msg(command(&["rm", "bob:1"]))?, "NOPERM");
+
+    match admin.request_msg(command(&["client", "list"]))? {
+        Msg::Bulk(Some(list)) => {
+            assert!(list.contains("user=admin"), "{}", list);
+            assert!(list.contains("user=alice"), "{}", list);
+            assert!(list.contains("user=reader"), "{}", list);
+        }
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+    assert_eq!(admin.request_msg(command(&["shutdown"]))?, Msg::Line("OK".to_owned()));
+    drop(admin);
+    drop(alice);
+    drop(reader);
+    join.join().unwrap();
+    assert!(handle.is_shutdown());
+    Ok(())
+}
+
+// An invalid ACL file should be rejected
+#[test]
+fn invalid_acl() {
+    assert!(Acl::from_toml("[[user]]\nname = \"a\"\npassword = \"p\"\ncategories = [\"all\"]").is_err());
+    assert!(Acl::from_toml("[[user]]\nname = \"a\"\ncategories = []").is_err());
+    let duplicate = "[[user]]\nname = \"a\"\npassword = \"p\"\ncategories = []\n".repeat(2);
+    assert!(Acl::from_toml(&duplicate).is_err());
+
+    let user = User { name: "a".to_owned(), password: "p".to_owned(), categories: vec![Category::Read], keys: vec![] };
+    let mut acl = Acl::default();
+    acl.add_user(user);
+    assert!(acl.authenticate("a", "p").is_ok());
+    assert!(acl.authenticate("a", "").is_err());
+    assert!(acl.authenticate("b", "p").is_err());
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-client --user --password` should authenticate to `kvs-server --acl-file --requirepass`
+#[test]
+fn cli_auth() {
+    let addr = "127.0.0.1:4073";
+    let temp_dir = TempDir::new().unwrap();
+    let acl_file = temp_dir.path().join("acl.toml");
+    std::fs::write(&acl_file, ACL).unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--requirepass", "secret", "--acl-file", acl_file.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"]).assert().failure().stderr(contains("NOAUTH"));
+    client(&["set", "key1", "value1", "--password", "wrong"]).assert().failure().stderr(contains("WRONGPASS"));
+    client(&["set", "key1", "value1", "--password", "secret"]).assert().success();
+    client(&["get", "key1", "--user", "reader", "--password", "reader-pass"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["rm", "key1", "--user", "reader", "--password", "reader-pass"])
+        .assert()
+        .failure()
+        .stderr(contains("NOPERM"));
+    client(&["get", "key1", "--user", "reader"]).assert().failure();
+    terminate(&mut child);
+}
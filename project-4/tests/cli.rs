@@ -1,12 +1,21 @@
 use assert_cmd::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::process::{Child, Command};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+// Stop the server by SIGTERM, it should exit successfully after a graceful shutdown
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    let status = child.wait().expect("unable to wait for the server");
+    assert!(status.success(), "server exited with {}", status);
+}
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -16,6 +25,7 @@ fn client_cli_no_args() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -48,6 +58,7 @@ fn client_cli_invalid_get() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -87,6 +98,7 @@ fn client_cli_invalid_set() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -119,6 +131,7 @@ fn client_cli_invalid_rm() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
@@ -131,6 +144,7 @@ fn client_cli_invalid_subcommand() {
 
 // `kvs-client -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
@@ -142,6 +156,7 @@ fn client_cli_version() {
 
 // `kvs-server -V` should print the version
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
@@ -152,6 +167,7 @@ fn server_cli_version() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
     let stderr_path = temp_dir.path().join("stderr");
@@ -163,7 +179,7 @@ fn cli_log_configuration() {
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
-    child.kill().expect("server exited before killed");
+    terminate(&mut child);
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -172,6 +188,7 @@ fn cli_log_configuration() {
 }
 
 #[test]
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_wrong_engine() {
     // sled first, kvs second
     {
@@ -183,7 +200,7 @@ fn cli_wrong_engine() {
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
-        child.kill().expect("server exited before killed");
+        terminate(&mut child);
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -202,7 +219,7 @@ fn cli_wrong_engine() {
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
-        child.kill().expect("server exited before killed");
+        terminate(&mut child);
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,6 +229,7 @@ fn cli_wrong_engine() {
     }
 }
 
+#[allow(clippy::needless_borrows_for_generic_args)]
 fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
@@ -223,7 +241,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
-        child.kill().expect("server exited before killed");
+        terminate(&mut child);
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -304,7 +322,7 @@ fn cli_access_server(engine: &str, addr: &str) {
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
-        child.kill().expect("server exited before killed");
+        terminate(&mut child);
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +353,212 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_sharded_engine() {
+    cli_access_server("sharded", "127.0.0.1:4200");
+}
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4201");
+}
+
+// `kvs-client shutdown` should stop the server, and the data should be kept
+#[test]
+fn cli_shutdown_command() {
+    let addr = "127.0.0.1:4006";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["shutdown", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    let status = child.wait().expect("unable to wait for the server");
+    assert!(status.success(), "server exited with {}", status);
+
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    terminate(&mut child);
+}
+
+// The config file, the environment and the flags should be merged in order, and the data kept in --data-dir
+#[test]
+fn cli_config_file_and_data_dir() {
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let config_path = temp_dir.path().join("kvs.toml");
+    fs::write(
+        &config_path,
+        "addr = \"127.0.0.1:4009\"\nengine = \"sled\"\nmax-clients = 1\nlog-level = \"warn\"\n",
+    )
+    .unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    // the environment overrides the file, the flags override the environment
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", config_path.to_str().unwrap(), "--data-dir", data_dir.to_str().unwrap()])
+        .args(["--log-level", "info"])
+        .env("KVS_ADDR", "127.0.0.1:4007")
+        .env("KVS_LOG_LEVEL", "error")
+        .current_dir(&temp_dir)
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    terminate(&mut child);
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("max_clients: 1"), "{}", content);
+    assert_eq!(fs::read_to_string(data_dir.join("engine.lock")).unwrap(), "sled");
+    assert!(data_dir.join("db").exists());
+    assert!(!temp_dir.path().join("engine.lock").exists());
+
+    // the engine in use is the default
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--data-dir", data_dir.to_str().unwrap(), "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    terminate(&mut child);
+}
+
+// A config file with an unknown key should be rejected
+#[test]
+fn cli_invalid_config() {
+    let temp_dir = TempDir::new().unwrap();
+    fs::write(temp_dir.path().join("kvs.toml"), "no-such-key = 1\n").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--config", "kvs.toml"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid config"));
+}
+
+// Every thread pool should serve the clients
+#[test]
+fn cli_thread_pools() {
+    let addr = "127.0.0.1:4040";
+    for pool in ["naive", "shared", "rayon"] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--addr", addr, "--thread-pool", pool, "--threads", "2"])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", pool, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(format!("{}\n", pool));
+        terminate(&mut child);
+    }
+
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--thread-pool", "pooled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "0"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Invalid config"));
+}
+
+// `kvs-client --addr unix://PATH` should access the server listening on `--unix PATH`
+#[test]
+fn cli_unix_socket() {
+    let temp_dir = TempDir::new().unwrap();
+    let socket = temp_dir.path().join("kvs.sock");
+    let unix_addr = format!("unix://{}", socket.display());
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--unix", socket.to_str().unwrap()])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", &unix_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", &unix_addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+    // only the Unix socket is listened without --addr
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4000"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    terminate(&mut child);
+    assert!(!socket.exists());
+}
@@ -0,0 +1,136 @@
+use kvs::config::{ServerConfig, ThreadPoolKind, CONFIG_KEYS};
+use kvs::engines::Durability;
+use kvs::logger::LogFormat;
+use kvs::Result;
+use std::path::Path;
+use std::time::Duration;
+
+// The absent keys should take the default value
+#[test]
+fn parse_toml() -> Result<()> {
+    let config = ServerConfig::from_toml(
+        r#"
+        addr = "0.0.0.0:5000"
+        data-dir = "/var/lib/kvs"
+        async = true
+        thread-pool = "rayon"
+        threads = 4
+        durability = "sync"
+        idle-timeout = 1.5
+        max-nesting-depth = 8
+        "#,
+    )?;
+    let default = ServerConfig::default();
+    assert_eq!(config.tcp_addr().as_deref(), Some("0.0.0.0:5000"));
+    assert_eq!(config.data_dir.to_str(), Some("/var/lib/kvs"));
+    assert!(config.is_async);
+    assert_eq!(config.thread_pool, ThreadPoolKind::Rayon);
+    assert_eq!(config.threads, Some(4));
+    assert_eq!(config.durability, Some(Durability::Sync));
+    assert_eq!(config.timeouts()?.idle, Some(Duration::from_millis(1500)));
+    assert_eq!(config.timeouts()?.read, None);
+    assert_eq!(config.limits().max_depth, 8);
+    assert_eq!(config.max_clients, default.max_clients);
+    assert_eq!(config.engine, None);
+
+    assert_eq!(ServerConfig::from_toml("")?, default);
+    assert_eq!(default.tcp_addr().as_deref(), Some("127.0.0.1:4000"));
+    let unix_only = ServerConfig::from_toml("unix = \"/run/kvs.sock\"")?;
+    assert_eq!(unix_only.tcp_addr(), None);
+    assert!(ServerConfig::from_toml("unknown = 1").is_err());
+    assert!(ServerConfig::from_toml("thread-pool = \"pooled\"").is_err());
+    Ok(())
+}
+
+// Every key should be settable from text, as the environment and the flags do
+#[test]
+fn set_by_key() -> Result<()> {
+    let mut config = ServerConfig::from_toml("threads = 4\ndurability = \"sync\"")?;
+    config.set("max-clients", "3")?;
+    config.set("engine", "sled")?;
+    config.set("thread-pool", "naive")?;
+    config.set("threads", "")?;
+    config.set("durability", "")?;
+    config.set("shards", "8")?;
+    assert_eq!(config.max_clients, 3);
+    assert_eq!(config.shards, Some(8));
+    assert_eq!(config.engine.as_deref(), Some("sled"));
+    assert_eq!(config.thread_pool, ThreadPoolKind::Naive);
+    assert_eq!(config.threads, None);
+    assert_eq!(config.durability, None);
+
+    assert!(config.set("max-clients", "many").is_err());
+    assert!(config.set("idle-timeout", "-1").is_ok());
+    assert!(config.timeouts().is_err());
+    assert!(config.set("idle-timeout", "0").is_ok());
+    assert!(config.timeouts().is_err());
+    assert!(config.set("read-timeout", "0.0").is_ok());
+    assert!(config.set("idle-timeout", "").is_ok());
+    assert!(config.timeouts().is_err());
+    assert!(config.set("no-such-key", "1").is_err());
+
+    assert!(config.tls()?.is_none());
+    config.set("tls-cert", "server.pem")?;
+    assert!(config.tls().is_err(), "tls-key is missing");
+    config.set("tls-key", "server-key.pem")?;
+    let tls = config.tls()?.expect("TLS should be enabled");
+    assert_eq!(tls.key.to_str(), Some("server-key.pem"));
+    assert_eq!(tls.client_ca, None);
+
+    assert!(config.acl()?.is_none());
+    config.set("requirepass", "secret")?;
+    let acl = config.acl()?.expect("AUTH should be required");
+    assert!(acl.authenticate("default", "secret").is_ok());
+    assert!(config.redacted().requirepass.as_deref() != Some("secret"));
+    config.set("acl-file", "no-such-acl.toml")?;
+    assert!(config.acl().is_err(), "the ACL file is missing");
+    config.set("metrics-addr", "0.0.0.0:9100")?;
+    assert_eq!(config.metrics_addr.as_deref(), Some("0.0.0.0:9100"));
+    assert_eq!(config.slowlog()?.threshold, Some(Duration::from_millis(10)));
+    config.set("slowlog-threshold", "-1")?;
+    config.set("slowlog-log", "true")?;
+    assert_eq!(config.slowlog()?.threshold, None);
+    assert!(config.slowlog()?.log);
+    config.set("log-format", "json")?;
+    config.set("log-file", "/var/log/kvs.log")?;
+    config.set("log-rotate-interval", "3600")?;
+    let log = config.log()?;
+    assert_eq!(log.format, LogFormat::Json);
+    assert_eq!(log.file.as_deref(), Some(Path::new("/var/log/kvs.log")));
+    assert_eq!(log.rotate_interval, Some(Duration::from_secs(3600)));
+    assert_eq!(log.max_size, None);
+    assert!(config.set("log-format", "xml").is_err());
+    config.set("trace-file", "spans.json")?;
+    assert_eq!(config.trace_file.as_deref(), Some(Path::new("spans.json")));
+    assert_eq!(config.replica_of, None);
+    config.set("replica-of", "10.0.0.1:4000")?;
+    assert_eq!(config.replica_of.as_deref(), Some("10.0.0.1:4000"));
+    config.set("peer-user", "replica")?;
+    config.set("peer-password", "replica-pass")?;
+    let peer = config.peer_connect()?;
+    assert_eq!((peer.user.as_deref(), peer.password.as_deref()), (Some("replica"), Some("replica-pass")));
+    assert!(peer.tls.is_none());
+    assert!(config.redacted().peer_password.as_deref() != Some("replica-pass"));
+    config.set("peer-tls-cert", "client.pem")?;
+    assert!(config.peer_connect().is_err(), "peer-tls-ca is missing");
+    config.set("peer-tls-cert", "")?;
+    assert!(config.raft()?.is_none());
+    config.set("raft-id", "1")?;
+    config.set("raft-peers", "1=127.0.0.1:4001, 2=127.0.0.1:4002")?;
+    // a replica can not be a Raft node
+    assert!(config.raft().is_err());
+    config.set("replica-of", "")?;
+    let raft = config.raft()?.unwrap();
+    assert_eq!((raft.id, raft.members.len(), raft.members[&2].as_str()), (1, 2, "127.0.0.1:4002"));
+    assert_eq!(raft.dir, Some(config.data_dir.join("raft")));
+    config.set("raft-peers", "1=127.0.0.1:4001,1=127.0.0.1:4002")?;
+    assert!(config.raft().is_err());
+    config.set("raft-id", "")?;
+    assert!(config.raft().is_err());
+
+    for key in CONFIG_KEYS {
+        assert!(ServerConfig::env_name(key).starts_with("KVS_"));
+    }
+    assert_eq!(ServerConfig::env_name("max-clients"), "KVS_MAX_CLIENTS");
+    Ok(())
+}
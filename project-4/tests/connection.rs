@@ -0,0 +1,281 @@
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::model::{Msg, MsgExtend};
+use kvs::server::{ConnectionTimeouts, KvsServer};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result};
+use std::io::Write;
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, timeouts: ConnectionTimeouts, max_clients: usize, is_async: bool) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let addr = addr.to_owned();
+    if is_async {
+        let mut server = AsyncKvsServer::new(addr, engine)
+            .with_timeouts(timeouts)
+            .with_max_clients(max_clients);
+        thread::spawn(move || server.start().unwrap());
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr, engine, thread_pool)
+            .with_timeouts(timeouts)
+            .with_max_clients(max_clients);
+        thread::spawn(move || server.start().unwrap());
+    }
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn timeouts() -> ConnectionTimeouts {
+    ConnectionTimeouts {
+        idle: Some(Duration::from_millis(500)),
+        read: Some(Duration::from_millis(300)),
+        write: Some(Duration::from_secs(1)),
+    }
+}
+
+fn check_timeouts(addr: &str, is_async: bool) -> Result<()> {
+    let _temp_dir = start_server(addr, timeouts(), 100, is_async);
+
+    // an active connection is kept
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for _ in 0..4 {
+        thread::sleep(Duration::from_millis(200));
+        client.request_msg(command(&["get", "key1"]))?;
+    }
+
+    // an idle connection is closed
+    let mut idle = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(800));
+    let _ = idle.write_all(&command(&["get", "key1"]).to_bytes());
+    assert!(idle.read_msg().is_err(), "idle connection should be closed");
+
+    // a connection sending a part of the request is closed
+    let mut slow = TcpStream::connect(addr)?;
+    slow.write_all(b"*2\r\n$3\r\nget\r\n")?;
+    thread::sleep(Duration::from_millis(600));
+    let _ = slow.write_all(b"$4\r\nkey1\r\n");
+    assert!(slow.read_msg().is_err(), "slow connection should be closed");
+    Ok(())
+}
+
+// Connections exceeding the idle or read timeout should be closed
+#[test]
+fn connection_timeouts() -> Result<()> {
+    check_timeouts("127.0.0.1:4030", false)
+}
+
+#[test]
+fn connection_timeouts_async() -> Result<()> {
+    check_timeouts("127.0.0.1:4031", true)
+}
+
+fn check_client_list(addr: &str, is_async: bool) -> Result<()> {
+    let _temp_dir = start_server(addr, ConnectionTimeouts::default(), 100, is_async);
+
+    let mut first = KvsClient::connect(addr.to_owned())?;
+    first.request_msg(command(&["set", "key1", "value1"]))?;
+    let mut second = KvsClient::connect(addr.to_owned())?;
+    thread::sleep(Duration::from_millis(1100));
+
+    let list = match second.request_msg(command(&["CLIENT", "LIST"]))? {
+        Msg::Bulk(Some(list)) => list,
+        other => panic!("expect Bulk String, got {:?}", other),
+    };
+    let lines: Vec<&str> = list.lines().collect();
+    assert_eq!(lines.len(), 2, "{}", list);
+    assert!(lines[0].starts_with("id=1 "), "{}", list);
+    assert!(lines[0].contains("idle=1 cmd=set"), "{}", list);
+    assert!(lines[1].contains("idle=0 cmd=client"), "{}", list);
+
+    // a closed connection is removed
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    match second.request_msg(command(&["client", "list"]))? {
+        Msg::Bulk(Some(list)) => assert_eq!(list.lines().count(), 1, "{}", list),
+        other => panic!("expect Bulk String, got {:?}", other),
+    }
+    Ok(())
+}
+
+// CLIENT LIST should show how long each connection has been idle
+#[test]
+fn client_list() -> Result<()> {
+    check_client_list("127.0.0.1:4032", false)
+}
+
+#[test]
+fn client_list_async() -> Result<()> {
+    check_client_list("127.0.0.1:4033", true)
+}
+
+fn check_max_clients(addr: &str, is_async: bool) -> Result<()> {
+    let _temp_dir = start_server(addr, ConnectionTimeouts::default(), 2, is_async);
+
+    let mut first = KvsClient::connect(addr.to_owned())?;
+    let mut second = KvsClient::connect(addr.to_owned())?;
+    first.request_msg(command(&["set", "key1", "value1"]))?;
+    second.request_msg(command(&["get", "key1"]))?;
+
+    // the third connection is rejected
+    let mut third = TcpStream::connect(addr)?;
+    match third.read_msg()? {
+        Msg::Error(e) => assert_eq!(e, "ERR max number of clients reached"),
+        other => panic!("expect Error, got {:?}", other),
+    }
+    assert!(third.read_msg().is_err(), "rejected connection should be closed");
+
+    // a new connection is accepted after a client left
+    drop(first);
+    thread::sleep(Duration::from_millis(200));
+    let mut fourth = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(
+        fourth.request_msg(command(&["get", "key1"]))?,
+        Msg::Bulk(Some("value1".to_owned()))
+    );
+    Ok(())
+}
+
+// Connections beyond the max number of clients should be rejected
+#[test]
+fn max_clients() -> Result<()> {
+    check_max_clients("127.0.0.1:4034", false)
+}
+
+#[test]
+fn max_clients_async() -> Result<()> {
+    check_max_clients("127.0.0.1:4035", true)
+}
+
+fn check_shutdown_handle(addr: &str, is_async: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let (shutdown, server_thread) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        (server.shutdown_handle(), thread::spawn(move || server.start()))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4)?;
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+        (server.shutdown_handle(), thread::spawn(move || server.start()))
+    };
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    let mut idle = TcpStream::connect(addr)?;
+    thread::sleep(Duration::from_millis(100));
+
+    shutdown.shutdown();
+    server_thread.join().unwrap()?;
+
+    // client connections are closed, and new connections are refused
+    assert!(idle.read_msg().is_err(), "idle connection should be closed");
+    assert!(client.request_msg(command(&["get", "key1"])).is_err(), "connection should be closed");
+    assert!(TcpStream::connect(addr).is_err(), "server should stop accepting");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// The server should stop after the shutdown is requested by the handle
+#[test]
+fn shutdown_handle() -> Result<()> {
+    check_shutdown_handle("127.0.0.1:4036", false)
+}
+
+#[test]
+fn shutdown_handle_async() -> Result<()> {
+    check_shutdown_handle("127.0.0.1:4037", true)
+}
+
+fn check_shutdown_half_sent(addr: &str, is_async: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let (shutdown, server_thread) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        (server.shutdown_handle(), thread::spawn(move || server.start()))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4)?;
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+        (server.shutdown_handle(), thread::spawn(move || server.start()))
+    };
+    thread::sleep(Duration::from_millis(500));
+
+    let mut half_sent = TcpStream::connect(addr)?;
+    half_sent.write_all(b"*2\r\n$3\r\nGET")?;
+    thread::sleep(Duration::from_millis(200));
+
+    shutdown.shutdown();
+    let (tx, rx) = crossbeam::bounded(1);
+    thread::spawn(move || tx.send(server_thread.join().unwrap()).unwrap());
+    rx.recv_timeout(Duration::from_secs(5)).expect("the server should stop")?;
+    drop(half_sent);
+    Ok(())
+}
+
+// The shutdown should close a connection stopped in the middle of a request, with no timeouts set
+#[test]
+fn shutdown_half_sent_request() -> Result<()> {
+    check_shutdown_half_sent("127.0.0.1:4210", false)
+}
+
+#[test]
+fn shutdown_half_sent_request_async() -> Result<()> {
+    check_shutdown_half_sent("127.0.0.1:4211", true)
+}
+
+// The shutdown should close the idle connections holding the workers while the pool queue is full
+#[test]
+fn shutdown_with_full_queue() -> Result<()> {
+    let addr = "127.0.0.1:4202";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let thread_pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+    let shutdown = server.shutdown_handle();
+    let server_thread = thread::spawn(move || server.start());
+    thread::sleep(Duration::from_millis(500));
+
+    // one connection holds the worker, one waits in the queue, one blocks the hand-off to the pool
+    let idle: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr)).collect::<std::io::Result<_>>()?;
+    thread::sleep(Duration::from_millis(300));
+
+    shutdown.shutdown();
+    let (tx, rx) = crossbeam::bounded(1);
+    thread::spawn(move || tx.send(server_thread.join().unwrap()).unwrap());
+    rx.recv_timeout(Duration::from_secs(5)).expect("the server should stop")?;
+    drop(idle);
+    Ok(())
+}
+
+// The connections waiting in the queue of the pool should not take the places of the clients
+#[test]
+fn max_clients_queued() -> Result<()> {
+    let addr = "127.0.0.1:4209";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let thread_pool = SharedQueueThreadPool::new(1)?;
+    let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool).with_max_clients(1);
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut first = KvsClient::connect(addr.to_owned())?;
+    first.request_msg(command(&["set", "key1", "value1"]))?;
+    // waits for the worker held by the first client, it is served after the first client left
+    let mut second = TcpStream::connect(addr)?;
+    second.write_all(&command(&["get", "key1"]).to_bytes())?;
+    thread::sleep(Duration::from_millis(200));
+    drop(first);
+    assert_eq!(second.read_msg()?, Msg::Bulk(Some("value1".to_owned())));
+    Ok(())
+}
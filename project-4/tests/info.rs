@@ -0,0 +1,156 @@
+use assert_cmd::prelude::*;
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::prelude::*;
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool).with_max_clients(100);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+/// the section names and the fields of all sections
+fn parse_info(msg: Msg) -> (Vec<String>, HashMap<String, String>) {
+    let text = match msg {
+        Msg::Bulk(Some(text)) => text,
+        other => panic!("expect Bulk, got {:?}", other),
+    };
+    let mut sections = Vec::new();
+    let mut fields = HashMap::new();
+    for line in text.split("\r\n").filter(|line| !line.is_empty()) {
+        if let Some(section) = line.strip_prefix("# ") {
+            sections.push(section.to_owned());
+        } else {
+            let (field, value) = line.split_once(':').unwrap();
+            fields.insert(field.to_owned(), value.to_owned());
+        }
+    }
+    (sections, fields)
+}
+
+fn check_info<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> Result<()> {
+    let (handle, join) = start_server(addr, engine, is_async);
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..5 {
+        client.request_msg(command(&["set", &format!("key{}", i), "value"]))?;
+    }
+    client.request_msg(command(&["set", "key0", "value0"]))?;
+    client.request_msg(command(&["select", "orders"]))?;
+    client.request_msg(command(&["set", "order1", "value"]))?;
+    let _other = KvsClient::connect(addr.to_owned())?;
+    // the other connection is registered by the server thread
+    thread::sleep(Duration::from_millis(200));
+
+    let (sections, fields) = parse_info(client.info(None)?);
+    assert_eq!(sections, vec!["Server", "Clients", "Stats", "Engine", "Replication", "Threadpool"]);
+    assert_eq!(fields["kvs_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(fields["process_id"], std::process::id().to_string());
+    assert!(fields["uptime_in_seconds"].parse::<u64>().is_ok());
+    assert_eq!(fields["server_mode"], if is_async { "async" } else { "sync" });
+    assert_eq!(fields["connected_clients"], "2", "{:?}", fields);
+    assert_eq!(fields["total_connections_received"], "2");
+    // the commands before this one
+    assert_eq!(fields["total_commands_processed"], "8");
+    assert_eq!(fields["keys"], "6");
+    assert_eq!(fields["namespaces"], "2");
+    assert_eq!(fields["role"], "master");
+    assert_eq!(fields["repl_offset"], "7");
+    assert_eq!(fields["connected_replicas"], "0");
+    assert!(fields["data_dir_bytes"].parse::<u64>()? > 0);
+    assert!(fields["threads"].parse::<usize>()? > 0);
+    if is_async {
+        assert_eq!(fields["engine"], "sled");
+        assert_eq!(fields["thread_pool"], "tokio");
+        assert!(!fields.contains_key("log_bytes"));
+    } else {
+        assert_eq!(fields["engine"], "kvs");
+        assert_eq!(fields["maxclients"], "100");
+        assert_eq!(fields["thread_pool"], "SharedQueueThreadPool");
+        assert_eq!(fields["threads"], "4");
+        let log_bytes: u64 = fields["log_bytes"].parse()?;
+        let live_bytes: u64 = fields["log_live_bytes"].parse()?;
+        assert!(live_bytes > 0 && live_bytes < log_bytes, "{:?}", fields);
+        assert_eq!(fields["log_stale_bytes"], (log_bytes - live_bytes).to_string());
+        assert_eq!(fields["compactions"], "0");
+        assert_eq!(fields["last_compaction_time"], "0");
+    }
+
+    let (sections, fields) = parse_info(client.info(Some("CLIENTS"))?);
+    assert_eq!(sections, vec!["Clients"]);
+    assert_eq!(fields.len(), 2);
+    let (sections, _) = parse_info(client.info(Some("nosuchsection"))?);
+    assert!(sections.is_empty());
+    assert!(matches!(client.request_msg(command(&["info", "a", "b"]))?, Msg::Error(_)));
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// INFO should report the server, the clients, the engine and the thread pool
+#[test]
+fn info() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_info("127.0.0.1:4120", KvStore::open(temp_dir.path())?, false)
+}
+
+#[test]
+fn info_async_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_info("127.0.0.1:4121", SledKvsEngine::open(temp_dir.path())?, true)
+}
+
+// `kvs-client info` should print the sections
+#[test]
+fn cli_info() {
+    let addr = "127.0.0.1:4122";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("# Server\n").and(contains("\n# Engine\n")).and(contains("engine:kvs\n")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["info", "engine", "--addr", addr])
+        .assert()
+        .success()
+        .stdout(contains("# Server").not().and(contains("keys:0\n")));
+    server.kill().unwrap();
+    server.wait().unwrap();
+}
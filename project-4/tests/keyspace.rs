@@ -0,0 +1,161 @@
+use assert_cmd::prelude::*;
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::engines::watch::{EventKind, KeyEvent};
+use kvs::model::{Msg, MsgExtend};
+use kvs::server::KvsServer;
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn request(stream: &mut TcpStream, args: &[&str]) -> Result<()> {
+    stream.write_all(&command(args).to_bytes())?;
+    Ok(())
+}
+
+fn kinds(events: &[KeyEvent]) -> Vec<(EventKind, &str)> {
+    events.iter().map(|e| (e.kind, e.key.as_str())).collect()
+}
+
+fn check_watch<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> Result<()> {
+    let (handle, join) = start_server(addr, engine, is_async);
+
+    let mut users = KvsClient::connect(addr.to_owned())?.watch("user:")?;
+    let mut writer = KvsClient::connect(addr.to_owned())?;
+    writer.request_msg(command(&["set", "user:1", "a"]))?;
+    writer.request_msg(command(&["set", "item:1", "b"]))?;
+    writer.request_msg(command(&["rm", "user:1"]))?;
+    writer.request_msg(command(&["set", "user:2", "c"]))?;
+    writer.request_msg(command(&["flushdb"]))?;
+    // another namespace is not watched
+    writer.request_msg(command(&["select", "orders"]))?;
+    writer.request_msg(command(&["set", "user:3", "d"]))?;
+    writer.request_msg(command(&["select", "0"]))?;
+    writer.request_msg(command(&["set", "user:4", "e"]))?;
+
+    let events = users.by_ref().take(5).collect::<Result<Vec<KeyEvent>>>()?;
+    assert_eq!(
+        kinds(&events),
+        vec![
+            (EventKind::Set, "user:1"),
+            (EventKind::Remove, "user:1"),
+            (EventKind::Set, "user:2"),
+            (EventKind::Remove, "user:2"),
+            (EventKind::Set, "user:4"),
+        ]
+    );
+    assert!(events.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    // a RESP2 connection runs only the watches while watching
+    let mut stream = TcpStream::connect(addr)?;
+    request(&mut stream, &["watch", "a"])?;
+    assert_eq!(stream.read_msg()?, Msg::Integer(1));
+    request(&mut stream, &["watch", "b"])?;
+    assert_eq!(stream.read_msg()?, Msg::Integer(2));
+    request(&mut stream, &["get", "a"])?;
+    match stream.read_msg()? {
+        Msg::Error(e) => assert!(e.contains("WATCH / UNWATCH"), "{}", e),
+        other => panic!("expect Error, got {:?}", other),
+    }
+    writer.request_msg(command(&["set", "b1", "x"]))?;
+    let bulk = |s: &str| Msg::Bulk(Some(s.to_owned()));
+    match stream.read_msg()? {
+        Msg::Array(items) => assert_eq!(items[..3], [bulk("keyevent"), bulk("set"), bulk("b1")]),
+        other => panic!("expect Array, got {:?}", other),
+    }
+    request(&mut stream, &["unwatch"])?;
+    assert_eq!(stream.read_msg()?, Msg::Line("OK".to_owned()));
+    request(&mut stream, &["get", "b1"])?;
+    assert_eq!(stream.read_msg()?, bulk("x"));
+
+    drop(stream);
+    drop(users);
+    drop(writer);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// The watching connections should receive the changes of their prefixes
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch("127.0.0.1:4100", KvStore::open(temp_dir.path())?, false)
+}
+
+#[test]
+fn watch_async_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_watch("127.0.0.1:4101", SledKvsEngine::open(temp_dir.path())?, true)
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-client watch` should print the changes made by other clients
+#[test]
+fn cli_watch() {
+    let addr = "127.0.0.1:4102";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watcher = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["watch", "user:", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client").unwrap().args(args).args(["--addr", addr]).assert().success();
+    };
+    client(&["set", "item:1", "a"]);
+    client(&["set", "user:1", "a"]);
+    client(&["rm", "user:1"]);
+
+    let mut lines = BufReader::new(watcher.stdout.take().unwrap()).lines();
+    let set = lines.next().unwrap().unwrap();
+    let remove = lines.next().unwrap().unwrap();
+    assert!(set.ends_with(" set user:1"), "{}", set);
+    assert!(remove.ends_with(" remove user:1"), "{}", remove);
+
+    // the stream ends when the server stops
+    terminate(&mut server);
+    assert!(lines.next().is_none());
+    assert!(watcher.wait().unwrap().success());
+}
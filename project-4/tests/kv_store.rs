@@ -1,85 +1,84 @@
+use kvs::engines::kvs_sharded::ShardedKvStore;
+use kvs::engines::lsm::{LsmKvStore, LsmOptions};
+use kvs::engines::sled::SledKvsEngine;
+use kvs::engines::{Durability, SnapshotEntry, DEFAULT_NAMESPACE};
+use kvs::engines::watch::{EventKind, KeyEvent};
 use kvs::{KvStore, KvsEngine, Result};
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
-// Should get previously stored value
-#[test]
-fn get_stored_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+// Run the tests shared by all engines against `$engine`
+macro_rules! engine_tests {
+    ($module:ident, $engine:ty) => {
+        mod $module {
+            use super::*;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
-
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+            #[test]
+            fn get_stored_value() -> Result<()> {
+                super::get_stored_value(|path| <$engine>::open(path))
+            }
 
-    Ok(())
-}
+            #[test]
+            fn overwrite_value() -> Result<()> {
+                super::overwrite_value(|path| <$engine>::open(path))
+            }
 
-// Should overwrite existent value
-#[test]
-fn overwrite_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+            #[test]
+            fn get_non_existent_value() -> Result<()> {
+                super::get_non_existent_value(|path| <$engine>::open(path))
+            }
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+            #[test]
+            fn remove_non_existent_key() -> Result<()> {
+                super::remove_non_existent_key(|path| <$engine>::open(path))
+            }
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+            #[test]
+            fn remove_key() -> Result<()> {
+                super::remove_key(|path| <$engine>::open(path))
+            }
 
-    Ok(())
-}
+            #[test]
+            fn concurrent_set() -> Result<()> {
+                super::concurrent_set(|path| <$engine>::open(path))
+            }
 
-// Should get `None` when getting a non-existent key
-#[test]
-fn get_non_existent_value() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+            #[test]
+            fn concurrent_get() -> Result<()> {
+                super::concurrent_get(|path| <$engine>::open(path))
+            }
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+            #[test]
+            fn namespaces() -> Result<()> {
+                super::namespaces(|path| <$engine>::open(path))
+            }
 
-    // Open from disk again and check persistent data
-    drop(store);
-    let store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+            #[test]
+            fn watch() -> Result<()> {
+                super::watch(|path| <$engine>::open(path))
+            }
 
-    Ok(())
-}
+            #[test]
+            fn stats() -> Result<()> {
+                super::stats(|path| <$engine>::open(path))
+            }
 
-#[test]
-fn remove_non_existent_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
-    Ok(())
+            #[test]
+            fn snapshot() -> Result<()> {
+                super::snapshot(|path| <$engine>::open(path))
+            }
+        }
+    };
 }
 
-#[test]
-fn remove_key() -> Result<()> {
-    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
-    Ok(())
-}
+engine_tests!(kvs_engine, KvStore);
+engine_tests!(sled_engine, SledKvsEngine);
+engine_tests!(sharded_engine, ShardedKvStore);
+engine_tests!(lsm_engine, LsmKvStore);
 
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
@@ -87,6 +86,7 @@ fn remove_key() -> Result<()> {
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    store.select("1")?.set("key0".to_owned(), "one".to_owned())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -121,26 +121,223 @@ fn compaction() -> Result<()> {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
         }
+        assert_eq!(store.select("1")?.get("key0".to_owned())?, Some("one".to_owned()));
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
 
+// The log written before namespaces existed should be read into the default namespace
+#[test]
+fn log_without_namespaces() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let log = "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+               {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+               {\"Remove\":{\"key\":\"key2\"}}\n";
+    std::fs::write(temp_dir.path().join("x.log"), log)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.db_size()?, 1);
+    assert_eq!(store.select("1")?.db_size()?, 0);
+    Ok(())
+}
+
+// A write should reach the log before it returns, so a failed write is reported instead of acknowledged
 #[test]
-fn concurrent_set() -> Result<()> {
+fn write_before_reply() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(std::fs::read_to_string(temp_dir.path().join("x.log"))?.contains("key1"));
+
+    // the log can no longer be appended to
+    std::fs::remove_file(temp_dir.path().join("x.log"))?;
+    std::fs::create_dir(temp_dir.path().join("x.log"))?;
+    assert!(store.set("key2".to_owned(), "value2".to_owned()).is_err());
+    Ok(())
+}
+
+// The keys should be spread over the shard logs, and the store should keep its number of shards
+#[test]
+fn sharded_layout() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = ShardedKvStore::open_with_shards(temp_dir.path(), Durability::Flush, 4)?;
+    assert_eq!(store.shards(), 4);
+    for i in 0..200 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    for i in 0..4 {
+        let log = temp_dir.path().join(format!("shard-{}", i)).join("x.log");
+        assert!(std::fs::metadata(&log)?.len() > 0, "shard {} has no keys", i);
+    }
+    assert_eq!(store.db_size()?, 200);
+    store.close()?;
+
+    assert!(ShardedKvStore::open_with_shards(temp_dir.path(), Durability::Flush, 2).is_err());
+    let store = ShardedKvStore::open(temp_dir.path())?;
+    assert_eq!(store.shards(), 4);
+    for i in 0..200 {
+        assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
+    }
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 200);
+    assert_eq!(stats.log.map(|log| log.segments), Some(4));
+    Ok(())
+}
+
+// The memtable should be flushed to SSTables and compacted down the levels, keeping the latest values
+#[test]
+fn lsm_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || LsmOptions {
+        memtable_size: 2048,
+        block_size: 256,
+        level0_tables: 2,
+        level1_size: 8 * 1024,
+        table_size: 4 * 1024,
+        ..LsmOptions::default()
+    };
+    let store = LsmKvStore::open_with_options(temp_dir.path(), Durability::Flush, options())?;
+    let orders = store.select("orders")?;
+    for round in 0..5 {
+        for i in 0..400 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        orders.set(format!("order{}", round), "order".to_owned())?;
+    }
+    for i in (0..400).step_by(2) {
+        store.remove(format!("key{}", i))?;
+    }
+
+    let check = |store: &LsmKvStore| -> Result<()> {
+        for i in 0..400 {
+            let expected = if i % 2 == 0 { None } else { Some(format!("value{}-4", i)) };
+            assert_eq!(store.get(format!("key{}", i))?, expected);
+        }
+        assert_eq!(store.get("key400".to_owned())?, None);
+        assert_eq!(store.db_size()?, 200);
+        assert_eq!(store.select("orders")?.db_size()?, 5);
+        let snapshot = store.snapshot()?;
+        assert_eq!(snapshot.len(), 205);
+        // counted by the writes, an entry is `key_len u32 | namespace \0 key | value_len u32 | value`
+        let stats = store.stats()?;
+        assert_eq!((stats.keys, stats.namespaces), (205, 2));
+        let live_bytes: usize =
+            snapshot.iter().map(|entry| 8 + entry.namespace.len() + 1 + entry.key.len() + entry.value.len()).sum();
+        assert_eq!(stats.log.expect("the lsm engine has a log").live_bytes, live_bytes as u64);
+        Ok(())
+    };
+    check(&store)?;
+    // the compactions run in the background, close waits for them
+    store.close()?;
+    let log = store.stats()?.log.expect("the lsm engine has a log");
+    assert!(log.compactions > 0, "{:?}", log);
+    assert!(log.last_compaction.is_some());
+    assert!(log.segments > 1, "{:?}", log);
+    let tables = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter(|entry| entry.as_ref().is_ok_and(|entry| entry.path().extension().is_some_and(|e| e == "sst")))
+        .count();
+    assert_eq!(tables + 1, log.segments);
+
+    // Open from disk again, the unflushed writes are replayed from the write-ahead log
+    drop((store, orders));
+    let store = LsmKvStore::open_with_options(temp_dir.path(), Durability::Flush, options())?;
+    check(&store)?;
+    assert!(store.remove("key0".to_owned()).is_err());
+    Ok(())
+}
+
+// Should get previously stored value
+fn get_stored_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Should overwrite existent value
+fn overwrite_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should get `None` when getting a non-existent key
+fn get_non_existent_value<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+fn remove_non_existent_key<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    assert!(store.remove("key1".to_owned()).is_err());
+    Ok(())
+}
+
+fn remove_key<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove("key1".to_owned()).is_ok());
+    assert_eq!(store.get("key1".to_owned())?, None);
+    Ok(())
+}
+
+fn concurrent_set<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        let handle = thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
         });
+        handles.push(handle);
     }
     barrier.wait();
 
@@ -148,9 +345,12 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data, after every clone is dropped
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..1000 {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
@@ -158,10 +358,9 @@ fn concurrent_set() -> Result<()> {
     Ok(())
 }
 
-#[test]
-fn concurrent_get() -> Result<()> {
+fn concurrent_get<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     for i in 0..100 {
         store
             .set(format!("key{}", i), format!("value{}", i))
@@ -188,7 +387,7 @@ fn concurrent_get() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let store = KvStore::open(temp_dir.path())?;
+    let store = open(temp_dir.path())?;
     let mut handles = Vec::new();
     for thread_id in 0..100 {
         let store = store.clone();
@@ -209,3 +408,152 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Every namespace should keep its own keys, counted and flushed apart
+fn namespaces<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let one = store.select("1")?;
+    let orders = store.select("orders")?;
+
+    store.set("key1".to_owned(), "default".to_owned())?;
+    one.set("key1".to_owned(), "one".to_owned())?;
+    one.set("key2".to_owned(), "one".to_owned())?;
+    orders.set("key1".to_owned(), "orders".to_owned())?;
+    orders.set("key3".to_owned(), "orders".to_owned())?;
+    orders.remove("key3".to_owned())?;
+    assert!(store.remove("key2".to_owned()).is_err());
+
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(one.get("key1".to_owned())?, Some("one".to_owned()));
+    assert_eq!(orders.get("key1".to_owned())?, Some("orders".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!((store.db_size()?, one.db_size()?, orders.db_size()?), (1, 2, 1));
+    // selecting from another namespace is the same as from the default one
+    assert_eq!(one.select("0")?.get("key1".to_owned())?, Some("default".to_owned()));
+
+    one.flush_db()?;
+    assert_eq!((store.db_size()?, one.db_size()?, orders.db_size()?), (1, 0, 1));
+    assert_eq!(one.get("key2".to_owned())?, None);
+
+    for name in ["", "a b", "ns/1", &"n".repeat(65)] {
+        assert!(store.select(name).is_err(), "{:?} should be invalid", name);
+    }
+
+    // Open from disk again and check persistent data
+    drop((store, one, orders));
+    let store = open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("default".to_owned()));
+    assert_eq!(store.select("1")?.db_size()?, 0);
+    let orders = store.select("orders")?;
+    assert_eq!(orders.get("key1".to_owned())?, Some("orders".to_owned()));
+    assert_eq!(orders.get("key3".to_owned())?, None);
+    Ok(())
+}
+
+// The watchers should receive the changes of their prefixes and namespaces in order
+fn watch<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    store.set("user:0".to_owned(), "before".to_owned())?;
+    let users = store.watch("user:")?;
+    let all = store.watch("")?;
+    let orders = store.select("orders")?;
+    let order_events = orders.watch("")?;
+
+    store.set("user:1".to_owned(), "a".to_owned())?;
+    store.set("item:1".to_owned(), "b".to_owned())?;
+    orders.set("user:1".to_owned(), "c".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    assert!(store.remove("user:2".to_owned()).is_err());
+    store.flush_db()?;
+
+    let timeout = Duration::from_secs(5);
+    let mut received = Vec::new();
+    for _ in 0..3 {
+        received.push(users.recv_timeout(timeout)?);
+    }
+    let kinds: Vec<(EventKind, &str)> = received.iter().map(|e| (e.kind, e.key.as_str())).collect();
+    assert_eq!(kinds, vec![(EventKind::Set, "user:1"), (EventKind::Remove, "user:1"), (EventKind::Remove, "user:0")]);
+    assert!(received.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+
+    let mut received: Vec<KeyEvent> = Vec::new();
+    for _ in 0..5 {
+        received.push(all.recv_timeout(timeout)?);
+    }
+    assert!(received.windows(2).all(|pair| pair[0].seq < pair[1].seq));
+    let mut removed: Vec<&str> = received[3..].iter().map(|e| e.key.as_str()).collect();
+    removed.sort_unstable();
+    assert_eq!(removed, vec!["item:1", "user:0"]);
+    assert_eq!(order_events.recv_timeout(timeout)?.key, "user:1");
+    assert!(users.recv_timeout(Duration::from_millis(200)).is_err());
+
+    // a dropped receiver stops watching, the engine goes on
+    drop(all);
+    store.set("user:3".to_owned(), "d".to_owned())?;
+    assert_eq!(users.recv_timeout(timeout)?.key, "user:3");
+
+    // the receivers are disconnected when the engine closes
+    store.close()?;
+    assert!(users.recv_timeout(timeout).is_err());
+    Ok(())
+}
+
+// Should count the keys and namespaces of the whole engine
+fn stats<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    let orders = store.select("orders")?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    orders.set("key0".to_owned(), "value".to_owned())?;
+    store.remove("key9".to_owned())?;
+    store.select("empty")?.set("key".to_owned(), "value".to_owned())?;
+    store.select("empty")?.remove("key".to_owned())?;
+
+    let stats = orders.stats()?;
+    assert_eq!(stats.keys, 10);
+    assert_eq!(stats.namespaces, 2);
+    assert!(stats.disk_bytes > 0);
+    if let Some(log) = stats.log {
+        assert!(log.live_bytes > 0 && log.live_bytes < log.bytes, "{:?}", log);
+        assert_eq!(log.compactions, 0);
+        assert_eq!(log.last_compaction, None);
+    }
+    Ok(())
+}
+
+// A snapshot should hold the live keys of every namespace, and still be right after reopening the engine
+fn snapshot<E: KvsEngine>(open: fn(&Path) -> Result<E>) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = open(temp_dir.path())?;
+    assert!(store.snapshot()?.is_empty());
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let orders = store.select("orders")?;
+    orders.set("key1".to_owned(), "order1".to_owned())?;
+    store.select("flushed")?.set("key1".to_owned(), "value".to_owned())?;
+    store.select("flushed")?.flush_db()?;
+
+    let sorted = |mut entries: Vec<SnapshotEntry>| {
+        entries.sort_by(|a, b| (&a.namespace, &a.key).cmp(&(&b.namespace, &b.key)));
+        entries
+    };
+    let entry = |namespace: &str, key: &str, value: &str| SnapshotEntry {
+        namespace: namespace.to_owned(),
+        key: key.to_owned(),
+        value: value.to_owned(),
+    };
+    let expected = vec![entry(DEFAULT_NAMESPACE, "key1", "value3"), entry("orders", "key1", "order1")];
+    assert_eq!(sorted(orders.snapshot()?), expected);
+
+    store.close()?;
+    drop(orders);
+    drop(store);
+    let store = open(temp_dir.path())?;
+    assert_eq!(sorted(store.snapshot()?), expected);
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use assert_cmd::prelude::*;
+use serde_json::Value;
+use std::fs;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `--log-format json` should write a JSON object for every record, and a record at info level for every request
+#[test]
+fn cli_json_log_file() {
+    let addr = "127.0.0.1:4140";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("logs").join("kvs.log");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--log-format", "json"])
+        .arg("--log-file")
+        .arg(&log_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", addr]).assert().success();
+    terminate(&mut server);
+
+    let text = fs::read_to_string(&log_file).unwrap();
+    let records: Vec<Value> = text.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    for record in &records {
+        for field in ["timestamp", "level", "module", "message"] {
+            assert!(record[field].is_string(), "{}", record);
+        }
+    }
+    assert!(records.iter().any(|r| r["level"] == "INFO" && r["message"] == "version=0.1.0"), "{}", text);
+    let done = records.iter().find(|r| r["message"] == "command done").expect("no command record");
+    assert_eq!(done["level"], "INFO");
+    assert_eq!(done["command"], "set");
+    assert!(done["peer"].as_str().unwrap().starts_with("127.0.0.1:"), "{}", done);
+    assert!(done["duration_us"].is_u64(), "{}", done);
+}
+
+// The log file should be renamed to FILE.1 when it is full, the older ones shifted up to --log-max-files
+#[test]
+fn cli_log_rotation() {
+    let addr = "127.0.0.1:4141";
+    let temp_dir = TempDir::new().unwrap();
+    let log_file = temp_dir.path().join("kvs.log");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--log-level", "debug", "--log-max-size", "1000", "--log-max-files", "2"])
+        .arg("--log-file")
+        .arg(&log_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    for i in 0..20 {
+        let key = format!("key{}", i);
+        Command::cargo_bin("kvs-client").unwrap().args(["set", &key, "value", "--addr", addr]).assert().success();
+    }
+    terminate(&mut server);
+
+    let rotated = |n: usize| temp_dir.path().join(format!("kvs.log.{}", n));
+    for path in [log_file.clone(), rotated(1), rotated(2)] {
+        let text = fs::read_to_string(&path).unwrap();
+        assert!(text.len() <= 1000, "{:?} has {} bytes", path, text.len());
+        assert!(text.ends_with('\n'));
+    }
+    assert!(!rotated(3).exists());
+    // the text lines carry the fields too
+    let newest = fs::read_to_string(&log_file).unwrap() + &fs::read_to_string(rotated(1)).unwrap();
+    assert!(newest.contains("command done peer=127.0.0.1:"), "{}", newest);
+}
+
+// `RUST_LOG` should filter the log when --log-level is not set, and --log-level should override it
+#[test]
+fn cli_rust_log() {
+    for (addr, level, shown) in [("127.0.0.1:4207", None, false), ("127.0.0.1:4208", Some("info"), true)] {
+        let temp_dir = TempDir::new().unwrap();
+        let log_file = temp_dir.path().join("kvs.log");
+        let mut cmd = Command::cargo_bin("kvs-server").unwrap();
+        cmd.args(["--addr", addr]).arg("--log-file").arg(&log_file).env("RUST_LOG", "warn").current_dir(&temp_dir);
+        if let Some(level) = level {
+            cmd.args(["--log-level", level]);
+        }
+        let mut server = cmd.spawn().unwrap();
+        thread::sleep(Duration::from_secs(1));
+        terminate(&mut server);
+
+        let text = fs::read_to_string(&log_file).unwrap();
+        assert_eq!(text.contains("version=0.1.0"), shown, "{:?}: {}", level, text);
+    }
+}
@@ -0,0 +1,108 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{metrics, KvStore, Result};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+/// the HTTP status line and the body
+fn http_get(addr: &str, path: &str) -> Result<(String, String)> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: {}\r\nAccept: */*\r\n\r\n", path, addr)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((&response, ""));
+    Ok((head.lines().next().unwrap_or_default().to_owned(), body.to_owned()))
+}
+
+/// the value of the sample named `name` with the labels, like `kvs_commands_total{command="set"}`
+fn sample(text: &str, name: &str) -> Option<f64> {
+    text.lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .and_then(|value| value.parse().ok())
+}
+
+// The metrics endpoint should count the commands, the errors and the log of the engine
+#[test]
+fn scrape() -> Result<()> {
+    let addr = "127.0.0.1:4110";
+    let metrics_addr = metrics::serve("127.0.0.1:0")?.to_string();
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path())?;
+    let mut server = KvsServer::new(addr.to_owned(), engine, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..10 {
+        client.request_msg(command(&["set", "key", &format!("value{}", i)]))?;
+    }
+    client.request_msg(command(&["get", "key"]))?;
+    assert!(matches!(client.request_msg(command(&["rm", "missing"]))?, Msg::Error(_)));
+    assert!(matches!(client.request_msg(command(&["nosuchcommand"]))?, Msg::Error(_)));
+
+    let (status, text) = http_get(&metrics_addr, "/metrics")?;
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert!(sample(&text, r#"kvs_commands_total{command="set"}"#) >= Some(10.0), "{}", text);
+    assert!(sample(&text, r#"kvs_command_duration_seconds_count{command="get"}"#) >= Some(1.0), "{}", text);
+    assert!(sample(&text, r#"kvs_commands_total{command="unknown"}"#) >= Some(1.0), "{}", text);
+    assert!(sample(&text, r#"kvs_errors_total{kind="ERR"}"#) >= Some(2.0), "{}", text);
+    assert!(sample(&text, "kvs_connected_clients") >= Some(1.0), "{}", text);
+    assert!(sample(&text, "kvs_connections_total") >= Some(1.0), "{}", text);
+    // one of the ten records of the key is live
+    let log_bytes = sample(&text, "kvs_log_bytes").unwrap();
+    let live_bytes = sample(&text, "kvs_log_live_bytes").unwrap();
+    assert!(live_bytes > 0.0 && live_bytes < log_bytes, "{}", text);
+    assert_eq!(sample(&text, "kvs_log_stale_bytes"), Some(log_bytes - live_bytes));
+    assert!(text.contains("kvs_compactions_total"), "{}", text);
+    assert!(text.contains("kvs_thread_pool_queue_depth"), "{}", text);
+
+    let (status, _) = http_get(&metrics_addr, "/")?;
+    assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-server --metrics-addr` should serve the metrics
+#[test]
+fn cli_metrics_addr() {
+    let addr = "127.0.0.1:4111";
+    let metrics_addr = "127.0.0.1:4112";
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--metrics-addr", metrics_addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", addr]).assert().success();
+    let (status, text) = http_get(metrics_addr, "/metrics").unwrap();
+    assert_eq!(status, "HTTP/1.1 200 OK");
+    assert_eq!(sample(&text, r#"kvs_commands_total{command="set"}"#), Some(1.0), "{}", text);
+    assert_eq!(sample(&text, "kvs_keys"), Some(1.0), "{}", text);
+    terminate(&mut child);
+}
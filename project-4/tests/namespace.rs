@@ -0,0 +1,90 @@
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::engines::DEFAULT_NAMESPACE;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+        (server.shutdown_handle(), thread::spawn(move || server.start().unwrap()))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn ok() -> Msg {
+    Msg::Line("OK".to_owned())
+}
+
+fn check_select<KE: KvsEngine>(addr: &str, engine: KE, is_async: bool) -> Result<()> {
+    let (handle, join) = start_server(addr, engine.clone(), is_async);
+
+    let mut default = KvsClient::connect(addr.to_owned())?;
+    let mut orders = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(orders.request_msg(command(&["select", "orders"]))?, ok());
+    assert_eq!(default.request_msg(command(&["set", "key1", "default"]))?, Msg::Bulk(None));
+    assert_eq!(orders.request_msg(command(&["set", "key1", "orders"]))?, Msg::Bulk(None));
+    assert_eq!(orders.request_msg(command(&["set", "key2", "orders"]))?, Msg::Bulk(None));
+
+    assert_eq!(default.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("default".to_owned())));
+    assert_eq!(orders.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("orders".to_owned())));
+    assert_eq!(default.request_msg(command(&["dbsize"]))?, Msg::Integer(1));
+    assert_eq!(orders.request_msg(command(&["dbsize"]))?, Msg::Integer(2));
+    let mut names = engine.namespaces()?;
+    names.sort();
+    assert_eq!(names, [DEFAULT_NAMESPACE, "orders"]);
+    match default.request_msg(command(&["client", "list"]))? {
+        Msg::Bulk(Some(list)) => {
+            assert!(list.contains("db=0"), "{}", list);
+            assert!(list.contains("db=orders"), "{}", list);
+        }
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+
+    // an invalid name keeps the current namespace
+    assert!(matches!(orders.request_msg(command(&["select", "a/b"]))?, Msg::Error(_)));
+    assert!(matches!(orders.request_msg(command(&["select"]))?, Msg::Error(_)));
+    assert_eq!(orders.request_msg(command(&["flushdb"]))?, ok());
+    assert_eq!(orders.request_msg(command(&["dbsize"]))?, Msg::Integer(0));
+    assert_eq!(default.request_msg(command(&["dbsize"]))?, Msg::Integer(1));
+    assert_eq!(engine.namespaces()?, [DEFAULT_NAMESPACE]);
+
+    assert_eq!(orders.request_msg(command(&["select", "0"]))?, ok());
+    assert_eq!(orders.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("default".to_owned())));
+
+    drop(default);
+    drop(orders);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// Every connection should read and write the namespace it selected
+#[test]
+fn select() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_select("127.0.0.1:4080", KvStore::open(temp_dir.path())?, false)
+}
+
+#[test]
+fn select_async_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_select("127.0.0.1:4081", SledKvsEngine::open(temp_dir.path())?, true)
+}
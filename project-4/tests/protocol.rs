@@ -0,0 +1,185 @@
+use bytes::BytesMut;
+use kvs::client::KvsClient;
+use kvs::codec::MsgCodec;
+use kvs::model::{Msg, MsgExtend, MsgLimits, Protocol};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+use tokio_util::codec::Decoder;
+
+fn start_server(addr: &str, limits: MsgLimits) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let thread_pool = SharedQueueThreadPool::new(2).unwrap();
+    let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool).with_limits(limits);
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn bulk(s: &str) -> Msg {
+    Msg::Bulk(Some(s.to_owned()))
+}
+
+// Every RESP3 type should be read back as it was written
+#[test]
+fn resp3_round_trip() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:4010")?;
+    let mut writer = TcpStream::connect("127.0.0.1:4010")?;
+    let (mut reader, _) = listener.accept()?;
+
+    let messages = vec![
+        Msg::Null,
+        Msg::Boolean(true),
+        Msg::Boolean(false),
+        Msg::Double(3.25),
+        Msg::Double(f64::INFINITY),
+        Msg::Double(f64::NEG_INFINITY),
+        Msg::BigNumber("3492890328409238509324850943850943825024385".to_owned()),
+        Msg::Verbatim { format: "txt".to_owned(), text: "Some string".to_owned() },
+        Msg::Map(vec![(bulk("first"), Msg::Integer(1)), (bulk("second"), Msg::Null)]),
+        Msg::Set(vec![bulk("a"), bulk("b")]),
+        Msg::Push(vec![bulk("message"), bulk("channel"), bulk("payload")]),
+        Msg::Array(vec![Msg::Map(vec![]), Msg::Set(vec![Msg::Boolean(true)])]),
+    ];
+    for msg in &messages {
+        writer.write_all(&msg.to_bytes())?;
+    }
+    for msg in messages {
+        assert_eq!(reader.read_msg()?, msg);
+    }
+
+    writer.write_all(b",nan\r\n")?;
+    match reader.read_msg()? {
+        Msg::Double(d) => assert!(d.is_nan()),
+        other => panic!("expect Double, got {:?}", other),
+    }
+    Ok(())
+}
+
+// RESP3 types should be downgraded for RESP2 connections
+#[test]
+fn resp3_into_resp2() {
+    assert_eq!(Msg::Null.into_resp2(), Msg::Bulk(None));
+    assert_eq!(Msg::Boolean(true).into_resp2(), Msg::Integer(1));
+    assert_eq!(Msg::Double(1.5).into_resp2(), bulk("1.5"));
+    assert_eq!(
+        Msg::Verbatim { format: "txt".to_owned(), text: "hi".to_owned() }.into_resp2(),
+        bulk("hi")
+    );
+    assert_eq!(
+        Msg::Map(vec![(bulk("k"), Msg::Set(vec![Msg::Null]))]).into_resp2(),
+        Msg::Array(vec![bulk("k"), Msg::Array(vec![Msg::Bulk(None)])])
+    );
+    assert_eq!(Msg::Push(vec![bulk("p")]).into_resp2(), Msg::Array(vec![bulk("p")]));
+}
+
+// HELLO should switch the protocol of the connection
+#[test]
+fn hello_negotiation() -> Result<()> {
+    let addr = "127.0.0.1:4011";
+    let _temp_dir = start_server(addr, MsgLimits::default());
+    let mut client = KvsClient::connect(addr.to_owned())?;
+
+    match client.hello(Protocol::Resp3)? {
+        Msg::Map(pairs) => {
+            assert!(pairs.contains(&(bulk("proto"), Msg::Integer(3))));
+            assert!(pairs.contains(&(bulk("server"), bulk("kvs"))));
+        }
+        other => panic!("expect Map, got {:?}", other),
+    }
+
+    match client.hello(Protocol::Resp2)? {
+        Msg::Array(list) => {
+            let pos = list.iter().position(|m| m == &bulk("proto")).unwrap();
+            assert_eq!(list[pos + 1], Msg::Integer(2));
+        }
+        other => panic!("expect Array, got {:?}", other),
+    }
+
+    let req = Msg::build_bulk_array(&vec!["HELLO".to_owned(), "4".to_owned()]);
+    match client.request_msg(req)? {
+        Msg::Error(e) => assert!(e.starts_with("NOPROTO")),
+        other => panic!("expect Error, got {:?}", other),
+    }
+
+    // the connection is still usable after an error reply
+    let req = Msg::build_bulk_array(&vec!["set".to_owned(), "key1".to_owned(), "value1".to_owned()]);
+    client.request_msg(req)?;
+    let req = Msg::build_bulk_array(&vec!["get".to_owned(), "key1".to_owned()]);
+    assert_eq!(client.request_msg(req)?, bulk("value1"));
+    Ok(())
+}
+
+// A request breaking the limits should get an error reply, then the connection is closed
+#[test]
+fn request_limits() -> Result<()> {
+    let addr = "127.0.0.1:4012";
+    let limits = MsgLimits {
+        max_bulk_len: 16,
+        max_array_len: 4,
+        max_depth: 2,
+        max_request_size: 128,
+    };
+    let _temp_dir = start_server(addr, limits);
+
+    let requests: Vec<Vec<u8>> = vec![
+        // bulk string too long
+        b"*2\r\n$3\r\nget\r\n$4294967296\r\n".to_vec(),
+        // array too long
+        b"*5\r\n".to_vec(),
+        // nested too deep
+        b"*1\r\n*1\r\n*1\r\n".to_vec(),
+        // request too large, without any CRLF
+        [b"+".to_vec(), vec![b'x'; 256]].concat(),
+    ];
+    for request in requests {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&request)?;
+        match stream.read_msg()? {
+            Msg::Error(e) => assert!(e.starts_with("ERR Protocol error"), "{}", e),
+            other => panic!("expect Error, got {:?}", other),
+        }
+        assert!(stream.read_msg().is_err(), "connection should be closed");
+    }
+
+    // requests within the limits still work
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    let req = Msg::build_bulk_array(&vec!["set".to_owned(), "key1".to_owned(), "value1".to_owned()]);
+    assert_eq!(client.request_msg(req)?, Msg::Bulk(None));
+    Ok(())
+}
+
+// The codec should decode the msgs split across many reads, and report a broken limit before the msg is whole
+#[test]
+fn codec_partial_reads() -> Result<()> {
+    let large = "v".repeat(100 * 1024);
+    let msgs = vec![
+        Msg::build_bulk_array(&vec!["set".to_owned(), "key1".to_owned(), large]),
+        Msg::Map(vec![(bulk("key"), Msg::Array(vec![Msg::Integer(1), Msg::Bulk(None), Msg::Array(vec![])]))]),
+        Msg::Line("OK".to_owned()),
+    ];
+    let bytes: Vec<u8> = msgs.iter().flat_map(|msg| msg.to_bytes()).collect();
+
+    let mut codec = MsgCodec::default();
+    let mut buf = BytesMut::new();
+    let mut decoded = Vec::new();
+    for chunk in bytes.chunks(7) {
+        buf.extend_from_slice(chunk);
+        while let Some(msg) = codec.decode(&mut buf)? {
+            decoded.push(msg);
+        }
+    }
+    assert_eq!(decoded, msgs);
+    assert!(buf.is_empty());
+
+    let limits = MsgLimits { max_bulk_len: 16, ..MsgLimits::default() };
+    let mut buf = BytesMut::from(&b"*2\r\n$3\r\nget\r\n$17\r\n"[..]);
+    assert!(MsgCodec::new(limits).decode(&mut buf).is_err());
+    Ok(())
+}
@@ -0,0 +1,633 @@
+use assert_cmd::prelude::*;
+use kvs::acl::Acl;
+use kvs::client::KvsClient;
+use kvs::model::{Msg, MsgExtend, Protocol};
+use kvs::proxy::{HashRing, KvsProxy, DEFAULT_VIRTUAL_NODES};
+use kvs::server::{ConnectionTimeouts, KvsServer};
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::process::{Child, Command};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn bulk(s: &str) -> Msg {
+    Msg::Bulk(Some(s.to_owned()))
+}
+
+fn ok() -> Msg {
+    Msg::Line("OK".to_owned())
+}
+
+/// a backend serving every connection on a thread of its own, the proxy keeps a connection to it per client
+struct Backend {
+    addr: String,
+    handle: ShutdownHandle,
+    join: JoinHandle<()>,
+    _dir: TempDir,
+}
+
+impl Backend {
+    fn start(addr: &str) -> Backend {
+        let dir = TempDir::new().unwrap();
+        let engine = KvStore::open(dir.path()).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, NaiveThreadPool::new(0).unwrap());
+        let handle = server.shutdown_handle();
+        let join = thread::spawn(move || server.start().unwrap());
+        wait_until("the backend to listen", || KvsClient::connect(addr.to_owned()).is_ok());
+        Backend { addr: addr.to_owned(), handle, join, _dir: dir }
+    }
+
+    fn client(&self) -> KvsClient {
+        KvsClient::connect(self.addr.clone()).unwrap()
+    }
+
+    fn stop(self) {
+        self.handle.shutdown();
+        self.join.join().unwrap();
+    }
+}
+
+fn start_proxy(addr: &str, backends: &[&Backend]) -> (ShutdownHandle, JoinHandle<()>) {
+    let backends = backends.iter().map(|backend| backend.addr.clone()).collect();
+    let mut proxy = KvsProxy::new(addr.to_owned(), backends);
+    let handle = proxy.shutdown_handle();
+    let join = thread::spawn(move || proxy.start().unwrap());
+    wait_until("the proxy to listen", || KvsClient::connect(addr.to_owned()).is_ok());
+    (handle, join)
+}
+
+/// panic if `condition` is still false after 10 seconds
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timeout waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn assert_error(reply: Msg, contains: &str) {
+    match reply {
+        Msg::Error(e) => assert!(e.contains(contains), "expect {:?} in {:?}", contains, e),
+        other => panic!("expect an error, got {:?}", other),
+    }
+}
+
+fn db_size(client: &mut KvsClient) -> i64 {
+    match client.request_msg(command(&["dbsize"])).unwrap() {
+        Msg::Integer(size) => size,
+        other => panic!("expect Integer, got {:?}", other),
+    }
+}
+
+// The keys should spread over the backends, and a new backend should take keys from the others only
+#[test]
+fn hash_ring() {
+    let backends = ["127.0.0.1:5001", "127.0.0.1:5002", "127.0.0.1:5003"];
+    let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    assert_eq!(ring.owner("key"), None);
+    for backend in &backends {
+        ring.add(backend);
+    }
+    ring.add(backends[0]);
+    assert_eq!(ring.backends(), &backends[..]);
+
+    let keys: Vec<String> = (0..3000).map(|i| format!("key{}", i)).collect();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for key in &keys {
+        *counts.entry(ring.owner(key).unwrap()).or_default() += 1;
+    }
+    for backend in &backends {
+        let count = counts[backend];
+        assert!((600..=1400).contains(&count), "{} keys on {}", count, backend);
+    }
+
+    // the same placement in any order of the calls
+    let mut reversed = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    for backend in backends.iter().rev() {
+        reversed.add(backend);
+    }
+    assert!(keys.iter().all(|key| ring.owner(key) == reversed.owner(key)));
+
+    let mut grown = ring.clone();
+    grown.add("127.0.0.1:5004");
+    let moved: Vec<&String> = keys.iter().filter(|key| ring.owner(key) != grown.owner(key)).collect();
+    assert!(moved.iter().all(|key| grown.owner(key) == Some("127.0.0.1:5004")));
+    assert!((450..=1050).contains(&moved.len()), "{} keys moved", moved.len());
+}
+
+// A server should get and set several keys by MGET and MSET
+#[test]
+fn multi_key_commands() -> Result<()> {
+    let backend = Backend::start("127.0.0.1:4180");
+    let mut client = backend.client();
+    assert_eq!(client.request_msg(command(&["mset", "a", "1", "b", "2"]))?, ok());
+    assert_eq!(
+        client.request_msg(command(&["mget", "a", "missing", "b"]))?,
+        Msg::Array(vec![bulk("1"), Msg::Bulk(None), bulk("2")])
+    );
+    assert_error(client.request_msg(command(&["mset", "a", "1", "b"]))?, "argument");
+    assert_error(client.request_msg(command(&["mget"]))?, "argument");
+    assert_eq!(db_size(&mut client), 2);
+    drop(client);
+    backend.stop();
+    Ok(())
+}
+
+// The proxy should route every key to its backend and fan out the commands of several keys
+#[test]
+fn route_and_fan_out() -> Result<()> {
+    let backends = [Backend::start("127.0.0.1:4181"), Backend::start("127.0.0.1:4182"), Backend::start("127.0.0.1:4183")];
+    let addr = "127.0.0.1:4184";
+    let (handle, join) = start_proxy(addr, &[&backends[0], &backends[1], &backends[2]]);
+    let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    for backend in &backends {
+        ring.add(&backend.addr);
+    }
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..100 {
+        let (key, value) = (format!("key{}", i), format!("value{}", i));
+        assert_eq!(client.request_msg(command(&["set", &key, &value]))?, Msg::Bulk(None));
+    }
+    assert_eq!(db_size(&mut client), 100);
+    // every key is on its own backend only
+    for backend in &backends {
+        let mut direct = backend.client();
+        let mine: Vec<String> = (0..100)
+            .map(|i| format!("key{}", i))
+            .filter(|key| ring.owner(key) == Some(backend.addr.as_str()))
+            .collect();
+        assert!(!mine.is_empty());
+        assert_eq!(db_size(&mut direct), mine.len() as i64);
+        let value = direct.request_msg(command(&["get", &mine[0]]))?;
+        assert_eq!(value, bulk(&mine[0].replace("key", "value")));
+    }
+
+    let mut args = vec!["mget", "missing"];
+    let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+    args.extend(keys.iter().map(String::as_str));
+    match client.request_msg(command(&args))? {
+        Msg::Array(values) => {
+            assert_eq!(values.len(), 101);
+            assert_eq!(values[0], Msg::Bulk(None));
+            for (i, value) in values[1..].iter().enumerate() {
+                assert_eq!(*value, bulk(&format!("value{}", i)));
+            }
+        }
+        other => panic!("expect Array, got {:?}", other),
+    }
+    assert_eq!(client.request_msg(command(&["mset", "key1", "new1", "key2", "new2", "key3", "new3"]))?, ok());
+    assert_eq!(
+        client.request_msg(command(&["mget", "key3", "key1", "key2"]))?,
+        Msg::Array(vec![bulk("new3"), bulk("new1"), bulk("new2")])
+    );
+    assert_eq!(client.request_msg(command(&["rm", "key1"]))?, Msg::Bulk(None));
+    assert_eq!(client.request_msg(command(&["get", "key1"]))?, Msg::Bulk(None));
+
+    // the namespace is selected on the backends
+    assert_eq!(client.request_msg(command(&["select", "orders"]))?, ok());
+    assert_eq!(client.request_msg(command(&["set", "order1", "apple"]))?, Msg::Bulk(None));
+    assert_eq!(db_size(&mut client), 1);
+    let owner = backends.iter().find(|backend| ring.owner("order1") == Some(backend.addr.as_str())).unwrap();
+    let mut direct = owner.client();
+    assert_eq!(direct.request_msg(command(&["select", "orders"]))?, ok());
+    assert_eq!(direct.request_msg(command(&["get", "order1"]))?, bulk("apple"));
+    assert_eq!(client.request_msg(command(&["flushdb"]))?, ok());
+    assert_eq!(db_size(&mut client), 0);
+    assert_eq!(client.request_msg(command(&["select", "0"]))?, ok());
+    assert_eq!(db_size(&mut client), 99);
+
+    assert_error(client.request_msg(command(&["subscribe", "news"]))?, "does not support 'subscribe'");
+    assert_error(client.request_msg(command(&["select", "bad name"]))?, "invalid namespace");
+    match client.request_msg(command(&["info"]))? {
+        Msg::Bulk(Some(info)) => {
+            assert!(info.contains("# Proxy"), "{}", info);
+            assert!(info.contains("backends:127.0.0.1:4181,127.0.0.1:4182,127.0.0.1:4183"), "{}", info);
+        }
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+    // a RESP3 client gets the replies a backend gives to RESP3
+    match client.hello(Protocol::Resp3)? {
+        Msg::Map(pairs) => assert!(pairs.contains(&(bulk("server"), bulk("kvs-proxy")))),
+        other => panic!("expect Map, got {:?}", other),
+    }
+    let mut direct = backends[1].client();
+    direct.hello(Protocol::Resp3)?;
+    assert_eq!(client.request_msg(command(&["get", "key1"]))?, direct.request_msg(command(&["get", "key1"]))?);
+    assert_eq!(client.request_msg(command(&["mget", "key2"]))?, Msg::Array(vec![bulk("new2")]));
+
+    // a stopped backend fails its keys only
+    let (stopped, key_of_stopped) = {
+        let backend = &backends[0];
+        let key = keys.iter().find(|key| ring.owner(key) == Some(backend.addr.as_str())).unwrap();
+        (backend.addr.clone(), key.clone())
+    };
+    let [first, second, third] = backends;
+    first.stop();
+    assert_error(client.request_msg(command(&["get", &key_of_stopped]))?, &format!("backend {} is unreachable", stopped));
+    let key_of_running = keys.iter().find(|key| ring.owner(key) == Some(second.addr.as_str())).unwrap();
+    assert!(matches!(client.request_msg(command(&["get", key_of_running]))?, Msg::Bulk(Some(_))));
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    second.stop();
+    third.stop();
+    Ok(())
+}
+
+// A backend added to the proxy should take its keys from the other backends
+#[test]
+fn add_backend() -> Result<()> {
+    let first = Backend::start("127.0.0.1:4185");
+    let second = Backend::start("127.0.0.1:4186");
+    let addr = "127.0.0.1:4187";
+    let (handle, join) = start_proxy(addr, &[&first, &second]);
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..200 {
+        let (key, value) = (format!("key{}", i), format!("value{}", i));
+        assert_eq!(client.request_msg(command(&["set", &key, &value]))?, Msg::Bulk(None));
+    }
+    assert_eq!(client.request_msg(command(&["select", "orders"]))?, ok());
+    for i in 0..20 {
+        assert_eq!(client.request_msg(command(&["set", &format!("order{}", i), "apple"]))?, Msg::Bulk(None));
+    }
+
+    let third = Backend::start("127.0.0.1:4188");
+    let mut admin = KvsClient::connect(addr.to_owned())?;
+    let moved = match admin.request_msg(command(&["proxy", "addbackend", &third.addr]))? {
+        Msg::Integer(moved) => moved,
+        other => panic!("expect Integer, got {:?}", other),
+    };
+    assert!(moved > 0 && moved < 220, "{} keys moved", moved);
+    assert_error(admin.request_msg(command(&["proxy", "addbackend", &third.addr]))?, "already in the ring");
+    assert_eq!(
+        admin.request_msg(command(&["proxy", "backends"]))?,
+        Msg::Array(vec![bulk(&first.addr), bulk(&second.addr), bulk(&third.addr)])
+    );
+
+    // the keys are moved, not copied
+    let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    for backend in [&first, &second, &third] {
+        ring.add(&backend.addr);
+    }
+    let mut total = 0;
+    for backend in [&first, &second, &third] {
+        let mut direct = backend.client();
+        let size = db_size(&mut direct);
+        assert_eq!(direct.request_msg(command(&["select", "orders"]))?, ok());
+        let orders = db_size(&mut direct);
+        let expected = (0..200).filter(|i| ring.owner(&format!("key{}", i)) == Some(backend.addr.as_str())).count()
+            + (0..20).filter(|i| ring.owner(&format!("order{}", i)) == Some(backend.addr.as_str())).count();
+        assert_eq!((size + orders) as usize, expected, "keys on {}", backend.addr);
+        if backend.addr == third.addr {
+            assert_eq!(size + orders, moved);
+        }
+        total += size + orders;
+    }
+    assert_eq!(total, 220);
+
+    // the sessions open before the migration read the moved keys from the new backend
+    assert_eq!(db_size(&mut client), 20);
+    assert_eq!(client.request_msg(command(&["get", "order7"]))?, bulk("apple"));
+    assert_eq!(client.request_msg(command(&["select", "0"]))?, ok());
+    for i in 0..200 {
+        assert_eq!(client.request_msg(command(&["get", &format!("key{}", i)]))?, bulk(&format!("value{}", i)));
+    }
+    match admin.request_msg(command(&["info", "proxy"]))? {
+        Msg::Bulk(Some(info)) => assert!(info.contains(&format!("migrated_keys:{}", moved)), "{}", info),
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+
+    drop(client);
+    drop(admin);
+    handle.shutdown();
+    join.join().unwrap();
+    for backend in [first, second, third] {
+        backend.stop();
+    }
+    Ok(())
+}
+
+// The requests should go on while the keys are copied to a new backend, and their writes should be moved too
+#[test]
+fn add_backend_under_writes() -> Result<()> {
+    let first = Backend::start("127.0.0.1:4220");
+    let second = Backend::start("127.0.0.1:4221");
+    let addr = "127.0.0.1:4222";
+    let (handle, join) = start_proxy(addr, &[&first, &second]);
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    let value = "v".repeat(4096);
+    for batch in 0..10 {
+        let mut args = vec!["mset"];
+        let keys: Vec<String> = (0..300).map(|i| format!("key{}", batch * 300 + i)).collect();
+        for key in &keys {
+            args.extend([key.as_str(), value.as_str()]);
+        }
+        assert_eq!(client.request_msg(command(&args))?, ok());
+    }
+
+    let done = Arc::new(AtomicBool::new(false));
+    let slowest = Arc::new(Mutex::new(Duration::ZERO));
+    let writer = {
+        let (done, slowest) = (done.clone(), slowest.clone());
+        let mut client = KvsClient::connect(addr.to_owned())?;
+        thread::spawn(move || {
+            let mut written = 0;
+            while !done.load(Ordering::SeqCst) {
+                written += 1;
+                let started = Instant::now();
+                let reply = client.request_msg(command(&["set", &format!("live{}", written % 100), &written.to_string()]));
+                assert_eq!(reply.unwrap(), Msg::Bulk(None));
+                let mut slowest = slowest.lock().unwrap();
+                *slowest = (*slowest).max(started.elapsed());
+                if written % 7 == 0 && written < 3000 {
+                    assert_eq!(client.request_msg(command(&["rm", &format!("key{}", written)])).unwrap(), Msg::Bulk(None));
+                }
+            }
+            written
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    let third = Backend::start("127.0.0.1:4223");
+    let mut admin = KvsClient::connect(addr.to_owned())?;
+    let started = Instant::now();
+    let moved = match admin.request_msg(command(&["proxy", "addbackend", &third.addr]))? {
+        Msg::Integer(moved) => moved,
+        other => panic!("expect Integer, got {:?}", other),
+    };
+    let migration = started.elapsed();
+    assert!(moved > 0, "{} keys moved", moved);
+    // no write waits for the whole copy
+    let slowest = *slowest.lock().unwrap();
+    assert!(slowest < migration / 2, "a write took {:?}, the migration {:?}", slowest, migration);
+    thread::sleep(Duration::from_millis(100));
+    done.store(true, Ordering::SeqCst);
+    let written: usize = writer.join().unwrap();
+
+    let mut total = 0;
+    for backend in [&first, &second, &third] {
+        total += db_size(&mut backend.client());
+    }
+    let removed = (1..=written).filter(|i| i % 7 == 0 && *i < 3000).count();
+    assert_eq!(total as usize, 3000 - removed + 100.min(written));
+    for i in (written.saturating_sub(100) + 1)..=written {
+        assert_eq!(client.request_msg(command(&["get", &format!("live{}", i % 100)]))?, bulk(&i.to_string()));
+    }
+    for i in 0..3000 {
+        let expected = if i % 7 == 0 && i > 0 && i <= written { Msg::Bulk(None) } else { bulk(&value) };
+        assert_eq!(client.request_msg(command(&["get", &format!("key{}", i)]))?, expected, "key{}", i);
+    }
+
+    drop(client);
+    drop(admin);
+    handle.shutdown();
+    join.join().unwrap();
+    for backend in [first, second, third] {
+        backend.stop();
+    }
+    Ok(())
+}
+
+// A backend accepting the connections but never replying should fail the requests by the backend timeout
+#[test]
+fn backend_timeout() -> Result<()> {
+    let hung = "127.0.0.1:4224";
+    let listener = TcpListener::bind(hung)?;
+    thread::spawn(move || {
+        let mut accepted = Vec::new();
+        for stream in listener.incoming() {
+            accepted.push(stream.unwrap());
+        }
+    });
+    let backend = Backend::start("127.0.0.1:4225");
+    let addr = "127.0.0.1:4226";
+    let mut proxy = KvsProxy::new(addr.to_owned(), vec![backend.addr.clone(), hung.to_owned()])
+        .with_backend_timeout(Duration::from_millis(300));
+    let handle = proxy.shutdown_handle();
+    let join = thread::spawn(move || proxy.start().unwrap());
+    wait_until("the proxy to listen", || KvsClient::connect(addr.to_owned()).is_ok());
+
+    let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    ring.add(&backend.addr);
+    ring.add(hung);
+    let key_of = |owner: &str| (0..).map(|i| format!("key{}", i)).find(|key| ring.owner(key) == Some(owner)).unwrap();
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    let started = Instant::now();
+    assert_error(client.request_msg(command(&["get", &key_of(hung)]))?, "unreachable");
+    assert!(started.elapsed() < Duration::from_secs(2), "failed after {:?}", started.elapsed());
+    let key = key_of(&backend.addr);
+    assert_eq!(client.request_msg(command(&["set", &key, "value1"]))?, Msg::Bulk(None));
+    assert_eq!(client.request_msg(command(&["get", &key]))?, bulk("value1"));
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    backend.stop();
+    Ok(())
+}
+
+/// a relay to `backend`, closing every connection and stopping at the first `RM`, as if the backend is killed
+fn start_killed_on_rm(addr: &str, backend: &str) {
+    let listener = TcpListener::bind(addr).unwrap();
+    let backend = backend.to_owned();
+    let killed = Arc::new(AtomicBool::new(false));
+    let streams: Arc<Mutex<Vec<TcpStream>>> = Arc::default();
+    thread::spawn(move || {
+        for client in listener.incoming() {
+            let client = client.unwrap();
+            if killed.load(Ordering::SeqCst) {
+                continue;
+            }
+            let server = TcpStream::connect(&backend).unwrap();
+            streams.lock().unwrap().extend([client.try_clone().unwrap(), server.try_clone().unwrap()]);
+            for (mut from, mut to, is_request) in [
+                (client.try_clone().unwrap(), server.try_clone().unwrap(), true),
+                (server, client, false),
+            ] {
+                let (killed, streams) = (killed.clone(), streams.clone());
+                thread::spawn(move || {
+                    let mut buf = [0; 4096];
+                    while let Ok(n @ 1..) = from.read(&mut buf) {
+                        if is_request && buf[..n].windows(6).any(|w| w == b"\r\nRM\r\n") {
+                            killed.store(true, Ordering::SeqCst);
+                            for stream in streams.lock().unwrap().iter() {
+                                let _ = stream.shutdown(Shutdown::Both);
+                            }
+                            return;
+                        }
+                        if to.write_all(&buf[..n]).is_err() {
+                            return;
+                        }
+                    }
+                });
+            }
+        }
+    });
+}
+
+// A source backend killed while the moved keys are removed should lose none of them
+#[test]
+fn add_backend_source_killed() -> Result<()> {
+    let first = Backend::start("127.0.0.1:4215");
+    let second = Backend::start("127.0.0.1:4216");
+    let relay = "127.0.0.1:4217";
+    start_killed_on_rm(relay, &second.addr);
+    let addr = "127.0.0.1:4218";
+    let mut proxy = KvsProxy::new(addr.to_owned(), vec![first.addr.clone(), relay.to_owned()]);
+    let handle = proxy.shutdown_handle();
+    let join = thread::spawn(move || proxy.start().unwrap());
+    wait_until("the proxy to listen", || KvsClient::connect(addr.to_owned()).is_ok());
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..200 {
+        assert_eq!(client.request_msg(command(&["set", &format!("key{}", i), "value"]))?, Msg::Bulk(None));
+    }
+    let third = Backend::start("127.0.0.1:4219");
+    assert_error(client.request_msg(command(&["proxy", "addbackend", &third.addr]))?, "left");
+    assert_eq!(
+        client.request_msg(command(&["proxy", "backends"]))?,
+        Msg::Array(vec![bulk(&first.addr), bulk(relay), bulk(&third.addr)])
+    );
+
+    // the keys moved from both backends are read from the new one, only the keys kept on the killed one are lost
+    let mut ring = HashRing::new(DEFAULT_VIRTUAL_NODES);
+    for backend in [first.addr.as_str(), relay, third.addr.as_str()] {
+        ring.add(backend);
+    }
+    for i in 0..200 {
+        let key = format!("key{}", i);
+        let reply = client.request_msg(command(&["get", &key]))?;
+        if ring.owner(&key) == Some(relay) {
+            assert!(matches!(reply, Msg::Error(_)), "{:?}", reply);
+        } else {
+            assert_eq!(reply, bulk("value"), "{}", key);
+        }
+    }
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    for backend in [first, second, third] {
+        backend.stop();
+    }
+    Ok(())
+}
+
+// The proxy should limit its clients like the server, and run SHUTDOWN and PROXY for the admin users only
+#[test]
+fn proxy_limits() -> Result<()> {
+    let backend = Backend::start("127.0.0.1:4204");
+    let addr = "127.0.0.1:4205";
+    let acl = Acl::from_toml(
+        r#"
+        [[user]]
+        name = "admin"
+        password = "secret"
+        categories = ["read", "write", "admin"]
+
+        [[user]]
+        name = "reader"
+        password = "secret"
+        categories = ["read"]
+        "#,
+    )?;
+    let timeouts = ConnectionTimeouts { idle: Some(Duration::from_secs(1)), ..ConnectionTimeouts::default() };
+    let mut proxy = KvsProxy::new(addr.to_owned(), vec![backend.addr.clone()])
+        .with_acl(acl)
+        .with_timeouts(timeouts)
+        .with_max_clients(2);
+    let handle = proxy.shutdown_handle();
+    let join = thread::spawn(move || proxy.start().unwrap());
+    wait_until("the proxy to listen", || TcpStream::connect(addr).is_ok());
+    thread::sleep(Duration::from_millis(200));
+
+    let mut reader = KvsClient::connect(addr.to_owned())?;
+    for args in [&["get", "key1"][..], &["proxy", "backends"], &["shutdown"]] {
+        assert_error(reader.request_msg(command(args))?, "NOAUTH");
+    }
+    assert_eq!(reader.request_msg(command(&["auth", "reader", "secret"]))?, ok());
+    assert_eq!(reader.request_msg(command(&["get", "key1"]))?, Msg::Bulk(None));
+    for args in [&["set", "key1", "value1"][..], &["proxy", "addbackend", "127.0.0.1:4206"], &["shutdown"]] {
+        assert_error(reader.request_msg(command(args))?, "NOPERM");
+    }
+    let mut admin = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(admin.request_msg(command(&["auth", "admin", "secret"]))?, ok());
+    assert_eq!(admin.request_msg(command(&["proxy", "backends"]))?, Msg::Array(vec![bulk(&backend.addr)]));
+
+    // the third connection is rejected
+    let mut third = TcpStream::connect(addr)?;
+    assert_error(third.read_msg()?, "max number of clients reached");
+    // the idle connections are closed
+    thread::sleep(Duration::from_millis(1500));
+    assert!(reader.request_msg(command(&["get", "key1"])).is_err(), "idle connection should be closed");
+
+    handle.shutdown();
+    join.join().unwrap();
+    backend.stop();
+    Ok(())
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-client` should set and get through `kvs-proxy` in front of `kvs-server` backends
+#[test]
+fn cli_proxy() {
+    let backends = ["127.0.0.1:4190", "127.0.0.1:4191"];
+    let addr = "127.0.0.1:4192";
+    let dirs: Vec<TempDir> = backends.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut children: Vec<Child> = backends.iter()
+        .zip(&dirs)
+        .map(|(backend, dir)| {
+            Command::cargo_bin("kvs-server").unwrap().args(["--addr", backend]).current_dir(dir).spawn().unwrap()
+        })
+        .collect();
+    children.push(
+        Command::cargo_bin("kvs-proxy")
+            .unwrap()
+            .args(["--addr", addr, "--backends", &backends.join(",")])
+            .spawn()
+            .unwrap(),
+    );
+    wait_until("the proxy and the backends to listen", || {
+        backends.iter().chain([&addr]).all(|addr| KvsClient::connect(addr.to_string()).is_ok())
+    });
+
+    for i in 0..10 {
+        let (key, value) = (format!("key{}", i), format!("value{}", i));
+        Command::cargo_bin("kvs-client").unwrap().args(["set", &key, &value, "--addr", addr]).assert().success();
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key3", "--addr", addr])
+        .assert()
+        .success()
+        .stdout("value3\n");
+    let sizes: Vec<i64> = backends.iter().map(|backend| db_size(&mut KvsClient::connect(backend.to_string()).unwrap())).collect();
+    assert_eq!(sizes.iter().sum::<i64>(), 10);
+    assert!(sizes.iter().all(|size| *size > 0), "{:?}", sizes);
+
+    Command::cargo_bin("kvs-proxy").unwrap().assert().failure();
+    for child in &mut children {
+        terminate(child);
+    }
+}
@@ -0,0 +1,241 @@
+use assert_cmd::prelude::*;
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::{KvsClient, Message};
+use kvs::model::{Msg, MsgExtend};
+use kvs::server::KvsServer;
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::process::{Child, Command, Stdio};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn start_server(addr: &str, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(8).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool);
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn strings(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+fn bulk(s: &str) -> Msg {
+    Msg::Bulk(Some(s.to_owned()))
+}
+
+fn message(pattern: Option<&str>, channel: &str, payload: &str) -> Message {
+    Message { pattern: pattern.map(str::to_owned), channel: channel.to_owned(), payload: payload.to_owned() }
+}
+
+fn request(stream: &mut TcpStream, args: &[&str]) -> Result<()> {
+    stream.write_all(&command(args).to_bytes())?;
+    Ok(())
+}
+
+fn check_pubsub(addr: &str, is_async: bool) -> Result<()> {
+    let (handle, join) = start_server(addr, is_async);
+
+    let mut subscription = KvsClient::connect(addr.to_owned())?.subscribe(&strings(&["news", "weather"]))?;
+    let mut psubscription = KvsClient::connect(addr.to_owned())?.psubscribe(&strings(&["news.*", "h?llo", "[a-c]x"]))?;
+    let mut publisher = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(publisher.publish("news", "n1")?, Msg::Integer(1));
+    assert_eq!(publisher.publish("news.tech", "t1")?, Msg::Integer(1));
+    assert_eq!(publisher.publish("hello", "h1")?, Msg::Integer(1));
+    assert_eq!(publisher.publish("bx", "b1")?, Msg::Integer(1));
+    assert_eq!(publisher.publish("dx", "d1")?, Msg::Integer(0));
+    assert_eq!(publisher.publish("weather", "w1")?, Msg::Integer(1));
+    assert_eq!(publisher.publish("nobody", "x")?, Msg::Integer(0));
+
+    assert_eq!(subscription.next().unwrap()?, message(None, "news", "n1"));
+    assert_eq!(subscription.next().unwrap()?, message(None, "weather", "w1"));
+    assert_eq!(psubscription.next().unwrap()?, message(Some("news.*"), "news.tech", "t1"));
+    assert_eq!(psubscription.next().unwrap()?, message(Some("h?llo"), "hello", "h1"));
+    assert_eq!(psubscription.next().unwrap()?, message(Some("[a-c]x"), "bx", "b1"));
+
+    // the subscription grows on the same connection
+    subscription.subscribe(&strings(&["sports"]))?;
+    assert_eq!(publisher.publish("sports", "s1")?, Msg::Integer(1));
+    assert_eq!(subscription.next().unwrap()?, message(None, "sports", "s1"));
+
+    // the subscriptions are removed with the connection
+    drop(subscription);
+    drop(psubscription);
+    let mut receivers = Msg::Integer(1);
+    for _ in 0..50 {
+        receivers = publisher.publish("news", "n2")?;
+        if receivers == Msg::Integer(0) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(receivers, Msg::Integer(0));
+
+    drop(publisher);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// The subscribers should receive the messages of their channels and patterns
+#[test]
+fn pubsub() -> Result<()> {
+    check_pubsub("127.0.0.1:4090", false)
+}
+
+#[test]
+fn pubsub_async() -> Result<()> {
+    check_pubsub("127.0.0.1:4091", true)
+}
+
+// Every SUBSCRIBE and UNSUBSCRIBE should be confirmed for each channel,
+// a RESP2 connection should run only the subscriptions while it subscribes
+#[test]
+fn subscribed_context() -> Result<()> {
+    let addr = "127.0.0.1:4092";
+    let (handle, join) = start_server(addr, false);
+
+    let mut stream = TcpStream::connect(addr)?;
+    request(&mut stream, &["subscribe", "a", "b"])?;
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("subscribe"), bulk("a"), Msg::Integer(1)]));
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("subscribe"), bulk("b"), Msg::Integer(2)]));
+    request(&mut stream, &["psubscribe", "c*"])?;
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("psubscribe"), bulk("c*"), Msg::Integer(3)]));
+    request(&mut stream, &["get", "key1"])?;
+    match stream.read_msg()? {
+        Msg::Error(e) => assert!(e.contains("only (P)SUBSCRIBE / (P)UNSUBSCRIBE"), "{}", e),
+        other => panic!("expect Error, got {:?}", other),
+    }
+
+    request(&mut stream, &["unsubscribe"])?;
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("unsubscribe"), bulk("a"), Msg::Integer(2)]));
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("unsubscribe"), bulk("b"), Msg::Integer(1)]));
+    request(&mut stream, &["punsubscribe", "c*"])?;
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("punsubscribe"), bulk("c*"), Msg::Integer(0)]));
+    request(&mut stream, &["unsubscribe"])?;
+    assert_eq!(stream.read_msg()?, Msg::Array(vec![bulk("unsubscribe"), Msg::Bulk(None), Msg::Integer(0)]));
+    request(&mut stream, &["get", "key1"])?;
+    assert_eq!(stream.read_msg()?, Msg::Bulk(None));
+
+    // RESP3 tells the pushes from the replies, any command runs while subscribing
+    request(&mut stream, &["hello", "3"])?;
+    assert!(matches!(stream.read_msg()?, Msg::Map(_)));
+    request(&mut stream, &["subscribe", "a"])?;
+    assert_eq!(stream.read_msg()?, Msg::Push(vec![bulk("subscribe"), bulk("a"), Msg::Integer(1)]));
+    request(&mut stream, &["publish", "a", "hi"])?;
+    let mut replies = vec![stream.read_msg()?, stream.read_msg()?];
+    replies.sort_by_key(|msg| matches!(msg, Msg::Push(_)));
+    assert_eq!(replies, vec![Msg::Integer(1), Msg::Push(vec![bulk("message"), bulk("a"), bulk("hi")])]);
+
+    drop(stream);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// A subscriber not reading the pushed messages should be removed, then disconnected
+#[test]
+fn slow_subscriber() -> Result<()> {
+    let addr = "127.0.0.1:4093";
+    let (handle, join) = start_server(addr, false);
+
+    let mut stream = TcpStream::connect(addr)?;
+    request(&mut stream, &["subscribe", "a"])?;
+    stream.read_msg()?;
+    let mut publisher = KvsClient::connect(addr.to_owned())?;
+    let payload = "x".repeat(16 * 1024);
+    let mut published = 0;
+    while publisher.publish("a", &payload)? == Msg::Integer(1) {
+        published += 1;
+        assert!(published < 10000, "the subscriber should be removed");
+    }
+
+    // the queued messages are still pushed before the connection is closed
+    let mut received = 0;
+    while let Ok(msg) = stream.read_msg() {
+        assert_eq!(msg, Msg::Array(vec![bulk("message"), bulk("a"), bulk(&payload)]));
+        received += 1;
+    }
+    assert!(received > 0 && received <= published, "received {} of {}", received, published);
+
+    drop(publisher);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-client subscribe` should print the messages published by `kvs-client publish`
+#[test]
+fn cli_subscribe() {
+    let addr = "127.0.0.1:4094";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--threads", "4"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    // the subscriber takes a thread of the server all along
+    let mut subscriber = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["subscribe", "news.*", "--pattern", "--addr", addr])
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut lines = BufReader::new(subscriber.stdout.take().unwrap()).lines();
+    let mut receivers = String::new();
+    for _ in 0..50 {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["publish", "news.tech", "hello world", "--addr", addr])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        receivers = String::from_utf8(output.stdout).unwrap();
+        if receivers == "1\n" {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(receivers, "1\n");
+    assert_eq!(lines.next().unwrap().unwrap(), "news.tech hello world");
+
+    // the stream ends when the server stops
+    terminate(&mut server);
+    assert!(lines.next().is_none());
+    assert!(subscriber.wait().unwrap().success());
+}
@@ -0,0 +1,400 @@
+use assert_cmd::prelude::*;
+use kvs::client::{ConnectOptions, KvsClient};
+use kvs::error::KvsError;
+use kvs::model::Msg;
+use kvs::raft::sim::SimNetwork;
+use kvs::raft::{self, Members, NodeId, RaftConfig, RaftEngine, RaftHandle, Role};
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::collections::{BTreeMap, HashMap};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// a node of a simulated cluster
+struct Node {
+    engine: RaftEngine<KvStore>,
+    handle: RaftHandle,
+    /// the engine behind the node, to see the writes applied by a follower
+    store: KvStore,
+    _dir: TempDir,
+}
+
+/// a quick election for the tests, after 150ms to 300ms without a leader
+fn config(id: NodeId, members: Members, snapshot_threshold: u64) -> RaftConfig {
+    let mut config = RaftConfig::new(id, members);
+    config.tick = Duration::from_millis(10);
+    config.election_ticks = 15;
+    config.heartbeat_ticks = 3;
+    config.snapshot_threshold = snapshot_threshold;
+    config
+}
+
+fn start_node(network: &SimNetwork, id: NodeId, members: Members, snapshot_threshold: u64) -> Node {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(dir.path()).unwrap();
+    let config = config(id, members, snapshot_threshold);
+    let (engine, handle) = raft::start(config, store.clone(), network.transport(id)).unwrap();
+    network.register(handle.clone());
+    Node { engine, handle, store, _dir: dir }
+}
+
+fn start_cluster(network: &SimNetwork, ids: &[NodeId], snapshot_threshold: u64) -> BTreeMap<NodeId, Node> {
+    let members = SimNetwork::members(ids);
+    ids.iter().map(|id| (*id, start_node(network, *id, members.clone(), snapshot_threshold))).collect()
+}
+
+fn stop_cluster(nodes: BTreeMap<NodeId, Node>) {
+    for node in nodes.values() {
+        node.engine.close().unwrap();
+    }
+}
+
+/// panic if `condition` is still false after 10 seconds
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timeout waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// wait until one of `among` is the leader, return its id
+fn wait_leader(nodes: &BTreeMap<NodeId, Node>, among: &[NodeId]) -> NodeId {
+    let mut leader = None;
+    wait_until("a leader", || {
+        leader = among.iter().copied().find(|id| nodes[id].handle.status().unwrap().role == Role::Leader);
+        leader.is_some()
+    });
+    leader.unwrap()
+}
+
+/// set the key on the leader among `among`, retrying after a lost leadership or a dropped message
+fn set_on_leader(nodes: &BTreeMap<NodeId, Node>, among: &[NodeId], key: &str, value: &str) {
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        let leader = wait_leader(nodes, among);
+        match nodes[&leader].engine.set(key.to_owned(), value.to_owned()) {
+            Ok(()) => return,
+            Err(e) => assert!(Instant::now() < deadline, "set {} failed, {}", key, e),
+        }
+    }
+}
+
+fn wait_applied(node: &Node, key: &str, value: Option<&str>) {
+    wait_until(&format!("{} applied", key), || node.store.get(key.to_owned()).unwrap().as_deref() == value);
+}
+
+fn error_of<T: std::fmt::Debug>(result: Result<T>) -> String {
+    result.unwrap_err().to_string()
+}
+
+// One node should be elected, replicate the writes to every node, and the followers should redirect to it
+#[test]
+fn elect_and_replicate() -> Result<()> {
+    let network = SimNetwork::new();
+    let nodes = start_cluster(&network, &[1, 2, 3], 1000);
+    let leader = wait_leader(&nodes, &[1, 2, 3]);
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+
+    nodes[&leader].engine.set("key1".to_owned(), "value1".to_owned())?;
+    nodes[&leader].engine.select("orders")?.set("order1".to_owned(), "apple".to_owned())?;
+    assert_eq!(nodes[&leader].engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(nodes[&leader].engine.db_size()?, 1);
+    let removed = nodes[&leader].engine.remove("missing".to_owned());
+    assert!(matches!(removed.unwrap_err().downcast_ref(), Some(KvsError::KeyNotFound)));
+
+    wait_until("the same leader on every node", || {
+        nodes.values().all(|node| node.handle.status().unwrap().leader == Some(leader))
+    });
+    let redirect = format!("NOTLEADER the leader is sim-{}", leader);
+    assert_eq!(error_of(nodes[&follower].engine.set("key2".to_owned(), "value2".to_owned())), redirect);
+    assert_eq!(error_of(nodes[&follower].engine.get("key1".to_owned())), redirect);
+
+    for node in nodes.values() {
+        wait_applied(node, "key1", Some("value1"));
+        let orders = node.store.select("orders")?;
+        wait_until("order1 applied", || orders.get("order1".to_owned()).unwrap().is_some());
+    }
+    let status = nodes[&leader].handle.status()?;
+    assert_eq!(status.members, SimNetwork::members(&[1, 2, 3]));
+    assert_eq!(status.commit_index, status.last_index);
+    stop_cluster(nodes);
+    Ok(())
+}
+
+// A leader cut off from the majority should lose the writes it can not commit, and follow the new leader
+// after the partition heals
+#[test]
+fn partitioned_leader() -> Result<()> {
+    let network = SimNetwork::new();
+    let nodes = start_cluster(&network, &[1, 2, 3], 1000);
+    let old_leader = wait_leader(&nodes, &[1, 2, 3]);
+    set_on_leader(&nodes, &[old_leader], "before", "1");
+    let majority: Vec<NodeId> = (1..=3).filter(|id| *id != old_leader).collect();
+
+    network.partition(&[&[old_leader], &majority]);
+    let lost = nodes[&old_leader].engine.set("lost".to_owned(), "1".to_owned());
+    assert!(lost.is_err(), "a write committed by a minority");
+    let new_leader = wait_leader(&nodes, &majority);
+    nodes[&new_leader].engine.set("after".to_owned(), "2".to_owned())?;
+    assert_ne!(nodes[&old_leader].handle.status()?.role, Role::Leader);
+
+    network.heal();
+    wait_applied(&nodes[&old_leader], "after", Some("2"));
+    assert_eq!(nodes[&old_leader].store.get("lost".to_owned())?, None);
+    assert_eq!(nodes[&old_leader].handle.status()?.leader, Some(new_leader));
+    for node in nodes.values() {
+        wait_applied(node, "before", Some("1"));
+    }
+    stop_cluster(nodes);
+    Ok(())
+}
+
+// The writes should be committed on every node although messages are dropped
+#[test]
+fn lossy_network() -> Result<()> {
+    let network = SimNetwork::new();
+    let nodes = start_cluster(&network, &[1, 2, 3], 1000);
+    network.set_drop_rate(0.2);
+    for i in 0..30 {
+        set_on_leader(&nodes, &[1, 2, 3], &format!("key{}", i), &i.to_string());
+    }
+    let (_, dropped) = network.counts();
+    assert!(dropped > 0);
+
+    network.set_drop_rate(0.0);
+    for node in nodes.values() {
+        for i in 0..30 {
+            wait_applied(node, &format!("key{}", i), Some(&i.to_string()));
+        }
+    }
+    stop_cluster(nodes);
+    Ok(())
+}
+
+// A follower missing the compacted entries should be sent the snapshot, replacing its keys
+#[test]
+fn snapshot_catch_up() -> Result<()> {
+    let network = SimNetwork::new();
+    let nodes = start_cluster(&network, &[1, 2, 3], 20);
+    let leader = wait_leader(&nodes, &[1, 2, 3]);
+    let lagging = (1..=3).find(|id| *id != leader).unwrap();
+    nodes[&leader].engine.select("orders")?.set("order1".to_owned(), "apple".to_owned())?;
+    let lagging_orders = nodes[&lagging].store.select("orders")?;
+    wait_until("order1 applied", || lagging_orders.get("order1".to_owned()).unwrap().is_some());
+
+    network.isolate(lagging);
+    let others: Vec<NodeId> = (1..=3).filter(|id| *id != lagging).collect();
+    set_on_leader(&nodes, &others, "key", "old");
+    nodes[&leader].engine.select("orders")?.flush_db()?;
+    for i in 0..50 {
+        set_on_leader(&nodes, &others, &format!("key{}", i), &i.to_string());
+    }
+    set_on_leader(&nodes, &others, "key", "new");
+    let leader = wait_leader(&nodes, &others);
+    assert!(nodes[&leader].handle.status()?.snapshot_index > 0);
+
+    network.heal();
+    let lagging = &nodes[&lagging];
+    wait_applied(lagging, "key", Some("new"));
+    for i in 0..50 {
+        wait_applied(lagging, &format!("key{}", i), Some(&i.to_string()));
+    }
+    assert_eq!(lagging_orders.get("order1".to_owned())?, None);
+    assert!(lagging.handle.status()?.snapshot_index > 0);
+    stop_cluster(nodes);
+    Ok(())
+}
+
+// A node should join with the members of the cluster and catch up, and a removed leader should hand over
+// to the others
+#[test]
+fn membership_change() -> Result<()> {
+    let network = SimNetwork::new();
+    let mut nodes = start_cluster(&network, &[1, 2, 3], 1000);
+    set_on_leader(&nodes, &[1, 2, 3], "key1", "value1");
+    let joining = start_node(&network, 4, SimNetwork::members(&[1, 2, 3]), 1000);
+    nodes.insert(4, joining);
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(nodes[&4].handle.status()?.role, Role::Follower);
+
+    let leader = wait_leader(&nodes, &[1, 2, 3]);
+    nodes[&leader].handle.add_member(4, "sim-4".to_owned())?;
+    wait_applied(&nodes[&4], "key1", Some("value1"));
+    assert_eq!(nodes[&4].handle.status()?.members, SimNetwork::members(&[1, 2, 3, 4]));
+    let follower = (1..=3).find(|id| *id != leader).unwrap();
+    let add = nodes[&follower].handle.add_member(5, "sim-5".to_owned());
+    assert!(error_of(add).starts_with("NOTLEADER"));
+
+    nodes[&leader].handle.remove_member(leader)?;
+    let remaining: Vec<NodeId> = (1..=4).filter(|id| *id != leader).collect();
+    let new_leader = wait_leader(&nodes, &remaining);
+    nodes[&new_leader].engine.set("key2".to_owned(), "value2".to_owned())?;
+    for id in &remaining {
+        wait_applied(&nodes[id], "key2", Some("value2"));
+    }
+    assert_eq!(nodes[&new_leader].handle.status()?.members, SimNetwork::members(&remaining));
+    assert_ne!(nodes[&leader].handle.status()?.role, Role::Leader);
+    stop_cluster(nodes);
+    Ok(())
+}
+
+// A node should restart from its kept log and members, without them in the config
+#[test]
+fn restart_from_kept_log() -> Result<()> {
+    let network = SimNetwork::new();
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let raft_dir = dir.path().join("raft");
+    let start = |members: Members| -> Result<(RaftEngine<KvStore>, RaftHandle)> {
+        let mut config = config(1, members, 5);
+        config.dir = Some(raft_dir.clone());
+        let (engine, handle) = raft::start(config, KvStore::open(dir.path())?, network.transport(1))?;
+        network.register(handle.clone());
+        Ok((engine, handle))
+    };
+
+    let (engine, handle) = start(SimNetwork::members(&[1]))?;
+    wait_until("the leader", || handle.status().unwrap().role == Role::Leader);
+    for i in 0..8 {
+        engine.set(format!("key{}", i), i.to_string())?;
+    }
+    let before = handle.status()?;
+    assert!(before.snapshot_index > 0);
+    engine.close()?;
+    // the log is rewritten by a rename at the compaction, the file of a rewrite torn by a crash is ignored
+    assert!(raft_dir.join("log").exists());
+    std::fs::write(raft_dir.join("log.tmp"), "{\"torn")?;
+
+    let (engine, handle) = start(Members::new())?;
+    let mut after = handle.status()?;
+    wait_until("the restarted leader", || {
+        after = handle.status().unwrap();
+        after.role == Role::Leader
+    });
+    assert!(after.term > before.term);
+    assert_eq!(after.members, SimNetwork::members(&[1]));
+    assert!(after.last_index > before.last_index);
+    assert_eq!(engine.get("key7".to_owned())?, Some("7".to_owned()));
+    engine.close()?;
+    Ok(())
+}
+
+/// the fields of `INFO raft`, authenticated by `password` if it is set
+fn raft_info(addr: &str, password: Option<&str>) -> Option<HashMap<String, String>> {
+    let options = ConnectOptions { password: password.map(str::to_owned), ..Default::default() };
+    let mut client = KvsClient::connect_with(addr.to_owned(), &options).ok()?;
+    let text = match client.info(Some("raft")).ok()? {
+        Msg::Bulk(Some(text)) => text,
+        _ => return None,
+    };
+    let info = text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(field, value)| (field.to_owned(), value.to_owned()))
+        .collect();
+    Some(info)
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-server --raft-id --raft-peers` should run a cluster, the followers redirecting the clients to the leader
+#[test]
+fn cli_cluster() {
+    let addrs = ["127.0.0.1:4170", "127.0.0.1:4171", "127.0.0.1:4172"];
+    let peers = "1=127.0.0.1:4170,2=127.0.0.1:4171,3=127.0.0.1:4172";
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = addrs.iter()
+        .zip(&dirs)
+        .enumerate()
+        .map(|(i, (addr, dir))| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr, "--async", "--raft-id", &(i + 1).to_string(), "--raft-peers", peers])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let mut leader = None;
+    wait_until("a leader", || {
+        leader = addrs.iter().find(|addr| raft_info(addr, None).is_some_and(|info| info["raft_role"] == "leader"));
+        leader.is_some()
+    });
+    let leader = *leader.unwrap();
+    let follower = addrs.iter().find(|addr| **addr != leader).unwrap();
+    wait_until("the leader known by the follower", || {
+        raft_info(follower, None).is_some_and(|info| info["raft_leader_addr"] == leader)
+    });
+
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", leader]).assert().success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", leader])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", follower])
+        .assert()
+        .failure()
+        .stderr(contains(format!("NOTLEADER the leader is {}", leader)));
+    let commit = raft_info(leader, None).unwrap()["commit_index"].clone();
+    wait_until("the write applied by the follower", || {
+        raft_info(follower, None).is_some_and(|info| info["applied_index"] == commit)
+    });
+    for server in &mut servers {
+        terminate(server);
+    }
+}
+
+// The nodes should authenticate to their peers requiring a password, by --peer-password
+#[test]
+fn cli_cluster_auth() {
+    let addrs = ["127.0.0.1:4227", "127.0.0.1:4228", "127.0.0.1:4229"];
+    let peers = "1=127.0.0.1:4227,2=127.0.0.1:4228,3=127.0.0.1:4229";
+    let dirs: Vec<TempDir> = addrs.iter().map(|_| TempDir::new().unwrap()).collect();
+    let mut servers: Vec<Child> = addrs.iter()
+        .zip(&dirs)
+        .enumerate()
+        .map(|(i, (addr, dir))| {
+            Command::cargo_bin("kvs-server")
+                .unwrap()
+                .args(["--addr", addr, "--async", "--raft-id", &(i + 1).to_string(), "--raft-peers", peers])
+                .args(["--requirepass", "secret", "--peer-password", "secret"])
+                .current_dir(dir)
+                .spawn()
+                .unwrap()
+        })
+        .collect();
+
+    let mut leader = None;
+    wait_until("a leader", || {
+        leader = addrs.iter().find(|addr| {
+            raft_info(addr, Some("secret")).is_some_and(|info| info["raft_role"] == "leader")
+        });
+        leader.is_some()
+    });
+    let leader = *leader.unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", leader, "--password", "secret"])
+        .assert()
+        .success();
+    let commit = raft_info(leader, Some("secret")).unwrap()["commit_index"].clone();
+    for follower in addrs.iter().filter(|addr| **addr != leader) {
+        wait_until("the write applied by the follower", || {
+            raft_info(follower, Some("secret")).is_some_and(|info| info["applied_index"] == commit)
+        });
+    }
+    for server in &mut servers {
+        terminate(server);
+    }
+}
@@ -0,0 +1,408 @@
+use assert_cmd::prelude::*;
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::model::{Msg, Protocol};
+use kvs::replication::{ReplicationEvent, MAX_REPLICA_LAG, REPLICATION_BACKLOG_SIZE};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsEngine, Result};
+use predicates::str::contains;
+use std::collections::HashMap;
+use std::io;
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+/// the fields of `INFO replication`
+fn replication_info(addr: &str) -> HashMap<String, String> {
+    let mut client = KvsClient::connect(addr.to_owned()).unwrap();
+    let text = match client.info(Some("replication")).unwrap() {
+        Msg::Bulk(Some(text)) => text,
+        other => panic!("expect Bulk, got {:?}", other),
+    };
+    text.lines()
+        .filter_map(|line| line.split_once(':'))
+        .map(|(field, value)| (field.to_owned(), value.to_owned()))
+        .collect()
+}
+
+/// panic if `condition` is still false after 10 seconds
+fn wait_until(what: &str, mut condition: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !condition() {
+        assert!(Instant::now() < deadline, "timeout waiting for {}", what);
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+/// the replica has applied every write of the primary
+fn wait_synced(primary: &str, replica: &str) {
+    wait_until("the replica to catch up", || {
+        let info = replication_info(replica);
+        info["master_link_status"] == "up" && info["master_repl_offset"] == replication_info(primary)["repl_offset"]
+    });
+}
+
+// The replica should load the snapshot of the primary, then apply its writes, and reject the writes of its clients
+#[test]
+fn full_sync_and_stream() -> Result<()> {
+    let (primary_addr, replica_addr) = ("127.0.0.1:4160", "127.0.0.1:4161");
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let primary_engine = KvStore::open(primary_dir.path())?;
+    primary_engine.set("key1".to_owned(), "value1".to_owned())?;
+    primary_engine.select("orders")?.set("order1".to_owned(), "apple".to_owned())?;
+    // replaced by the snapshot
+    let replica_engine = KvStore::open(replica_dir.path())?;
+    replica_engine.set("stale".to_owned(), "value".to_owned())?;
+    replica_engine.select("old")?.set("stale".to_owned(), "value".to_owned())?;
+
+    let mut primary = KvsServer::new(primary_addr.to_owned(), primary_engine, SharedQueueThreadPool::new(4)?);
+    let primary_handle = primary.shutdown_handle();
+    let primary_join = thread::spawn(move || primary.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let mut replica = KvsServer::new(replica_addr.to_owned(), replica_engine.clone(), SharedQueueThreadPool::new(4)?)
+        .with_replica_of(primary_addr.to_owned());
+    let replica_handle = replica.shutdown_handle();
+    let replica_join = thread::spawn(move || replica.start().unwrap());
+
+    wait_until("the snapshot", || replica_engine.get("key1".to_owned()).unwrap().is_some());
+    wait_synced(primary_addr, replica_addr);
+    assert_eq!(replica_engine.get("stale".to_owned())?, None);
+    assert_eq!(replica_engine.select("old")?.db_size()?, 0);
+    assert_eq!(replica_engine.select("orders")?.get("order1".to_owned())?, Some("apple".to_owned()));
+
+    // the writes after the snapshot
+    let mut client = KvsClient::connect(primary_addr.to_owned())?;
+    client.request_msg(command(&["set", "key2", "value2"]))?;
+    client.request_msg(command(&["rm", "key1"]))?;
+    client.request_msg(command(&["select", "orders"]))?;
+    client.request_msg(command(&["flushdb"]))?;
+    client.request_msg(command(&["set", "order2", "pear"]))?;
+    // a failed write is not replicated
+    assert!(matches!(client.request_msg(command(&["rm", "missing"]))?, Msg::Error(_)));
+    wait_synced(primary_addr, replica_addr);
+    assert_eq!(replica_engine.get("key1".to_owned())?, None);
+    assert_eq!(replica_engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    let orders = replica_engine.select("orders")?;
+    assert_eq!(orders.get("order1".to_owned())?, None);
+    assert_eq!(orders.get("order2".to_owned())?, Some("pear".to_owned()));
+
+    let primary_info = replication_info(primary_addr);
+    assert_eq!(primary_info["role"], "master");
+    assert_eq!(primary_info["repl_offset"], "4");
+    assert_eq!(primary_info["connected_replicas"], "1");
+    let replica_info = replication_info(replica_addr);
+    assert_eq!(replica_info["role"], "replica");
+    assert_eq!(replica_info["master_addr"], primary_addr);
+    assert_eq!(replica_info["master_replid"], primary_info["replid"]);
+
+    // the clients of the replica may read only
+    let mut replica_client = KvsClient::connect(replica_addr.to_owned())?;
+    for write in [&["set", "key3", "value3"][..], &["rm", "key2"], &["flushdb"]] {
+        match replica_client.request_msg(command(write))? {
+            Msg::Error(e) => assert!(e.starts_with("READONLY"), "{}", e),
+            other => panic!("expect READONLY, got {:?}", other),
+        }
+    }
+    assert_eq!(replica_client.request_msg(command(&["get", "key2"]))?, Msg::Bulk(Some("value2".to_owned())));
+    match replica_client.hello(Protocol::Resp3)? {
+        Msg::Map(fields) => {
+            let role = fields.iter().find(|(field, _)| *field == Msg::Bulk(Some("role".to_owned())));
+            assert_eq!(role.unwrap().1, Msg::Bulk(Some("replica".to_owned())));
+        }
+        other => panic!("expect Map, got {:?}", other),
+    }
+
+    drop(client);
+    drop(replica_client);
+    replica_handle.shutdown();
+    replica_join.join().unwrap();
+    primary_handle.shutdown();
+    primary_join.join().unwrap();
+    Ok(())
+}
+
+/// forward the connections to `target`, `cut` closes the forwarded ones
+struct Proxy {
+    sockets: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Proxy {
+    fn start(addr: &str, target: &'static str) -> Proxy {
+        let listener = TcpListener::bind(addr).unwrap();
+        let sockets: Arc<Mutex<Vec<TcpStream>>> = Arc::default();
+        let accepted = sockets.clone();
+        thread::spawn(move || {
+            for client in listener.incoming() {
+                let client = client.unwrap();
+                let server = match TcpStream::connect(target) {
+                    Ok(server) => server,
+                    Err(_) => continue,
+                };
+                accepted.lock().unwrap().extend([client.try_clone().unwrap(), server.try_clone().unwrap()]);
+                Proxy::pipe(client.try_clone().unwrap(), server.try_clone().unwrap());
+                Proxy::pipe(server, client);
+            }
+        });
+        Proxy { sockets }
+    }
+
+    fn pipe(mut from: TcpStream, mut to: TcpStream) {
+        thread::spawn(move || {
+            let _ = io::copy(&mut from, &mut to);
+            let _ = to.shutdown(Shutdown::Both);
+        });
+    }
+
+    fn cut(&self) {
+        for socket in self.sockets.lock().unwrap().drain(..) {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+// After the connection is lost, the replica should reconnect and apply only the writes it missed
+#[test]
+fn reconnect_and_resume() -> Result<()> {
+    let (primary_addr, proxy_addr, replica_addr) = ("127.0.0.1:4162", "127.0.0.1:4163", "127.0.0.1:4164");
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut primary =
+        KvsServer::new(primary_addr.to_owned(), KvStore::open(primary_dir.path())?, SharedQueueThreadPool::new(4)?);
+    let primary_handle = primary.shutdown_handle();
+    let primary_join = thread::spawn(move || primary.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+    let proxy = Proxy::start(proxy_addr, primary_addr);
+    // an async replica on sled, the replication does not depend on the engine
+    let replica_engine = SledKvsEngine::open(replica_dir.path())?;
+    let mut replica =
+        AsyncKvsServer::new(replica_addr.to_owned(), replica_engine.clone()).with_replica_of(proxy_addr.to_owned());
+    let replica_handle = replica.shutdown_handle();
+    let replica_join = thread::spawn(move || replica.start().unwrap());
+
+    let mut client = KvsClient::connect(primary_addr.to_owned())?;
+    for i in 0..10 {
+        client.request_msg(command(&["set", &format!("key{}", i), "value"]))?;
+    }
+    wait_synced(primary_addr, replica_addr);
+    let applied = |addr: &str| -> u64 { replication_info(addr)["repl_offset"].parse().unwrap() };
+    let applied_before = applied(replica_addr);
+
+    proxy.cut();
+    for i in 0..5 {
+        client.request_msg(command(&["set", &format!("key{}", i), "changed"]))?;
+    }
+    client.request_msg(command(&["rm", "key9"]))?;
+    wait_synced(primary_addr, replica_addr);
+    // a full resync would flush and set all keys again
+    assert_eq!(applied(replica_addr), applied_before + 6);
+    assert_eq!(replica_engine.get("key4".to_owned())?, Some("changed".to_owned()));
+    assert_eq!(replica_engine.get("key5".to_owned())?, Some("value".to_owned()));
+    assert_eq!(replica_engine.get("key9".to_owned())?, None);
+
+    drop(client);
+    replica_handle.shutdown();
+    replica_join.join().unwrap();
+    primary_handle.shutdown();
+    primary_join.join().unwrap();
+    Ok(())
+}
+
+// `PSYNC` should continue from a kept write of the same history, and send a snapshot otherwise
+#[test]
+fn psync() -> Result<()> {
+    let addr = "127.0.0.1:4165";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(addr.to_owned(), KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    client.request_msg(command(&["set", "key2", "value2"]))?;
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(None, None)?;
+    let replid = match events.next().unwrap()? {
+        ReplicationEvent::FullResync { replid, offset } => {
+            assert_eq!(offset, 2);
+            replid
+        }
+        other => panic!("expect FullResync, got {:?}", other),
+    };
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+        match events.next().unwrap()? {
+            ReplicationEvent::Snapshot(entry) => keys.push(entry.key),
+            other => panic!("expect Snapshot, got {:?}", other),
+        }
+    }
+    keys.sort();
+    assert_eq!(keys, vec!["key1", "key2"]);
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::SnapshotEnd));
+    client.request_msg(command(&["rm", "key1"]))?;
+    match events.next().unwrap()? {
+        ReplicationEvent::Write { offset: 3, namespace, behavior } => {
+            assert_eq!(namespace, "0");
+            assert_eq!(format!("{:?}", behavior), r#"Remove { key: "key1" }"#);
+        }
+        other => panic!("expect Write, got {:?}", other),
+    }
+    // every replica connection takes a thread of the pool
+    drop(events);
+
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(Some(&replid), Some(2))?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::Continue));
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::Write { offset: 3, .. }));
+    drop(events);
+    // the writes before the first `PSYNC`, a future offset or another history need a snapshot
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(Some(&replid), Some(1))?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::FullResync { offset: 3, .. }));
+    drop(events);
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(Some(&replid), Some(4))?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::FullResync { offset: 3, .. }));
+    drop(events);
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(Some("other"), Some(1))?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::FullResync { .. }));
+    assert!(matches!(client.request_msg(command(&["psync", "?", "x"]))?, Msg::Error(_)));
+
+    drop(events);
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// A replica reading slower than the writes should get them all from the backlog, not be removed
+#[test]
+fn slow_replica() -> Result<()> {
+    let addr = "127.0.0.1:4203";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(addr.to_owned(), KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(None, None)?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::FullResync { offset: 0, .. }));
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::SnapshotEnd));
+    // more than the sockets buffer, and than the pushes queued for a subscriber
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    let value = "v".repeat(16 * 1024);
+    for i in 0..2000 {
+        client.request_msg(command(&["set", &format!("key{}", i), &value]))?;
+    }
+    for offset in 1..=2000 {
+        match events.next().unwrap()? {
+            ReplicationEvent::Write { offset: written, .. } => assert_eq!(written, offset),
+            other => panic!("expect Write, got {:?}", other),
+        }
+    }
+
+    drop(events);
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// The backlog should be on from the first replica, and a replica too many bytes behind should be removed
+#[test]
+fn backlog_size() -> Result<()> {
+    let addr = "127.0.0.1:4212";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(addr.to_owned(), KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    let info = replication_info(addr);
+    assert_eq!(info["repl_offset"], "1");
+    assert_eq!(info["repl_backlog_active"], "0");
+    assert_eq!(info["repl_backlog_len"], "0");
+
+    let mut events = KvsClient::connect(addr.to_owned())?.psync(None, None)?;
+    assert!(matches!(events.next().unwrap()?, ReplicationEvent::FullResync { offset: 1, .. }));
+    let value = "v".repeat(1 << 20);
+    let writes = (MAX_REPLICA_LAG >> 20) + 8;
+    for i in 0..writes {
+        client.request_msg(command(&["set", &format!("key{}", i), &value]))?;
+    }
+    let info = replication_info(addr);
+    assert_eq!(info["repl_backlog_active"], "1");
+    assert!(info["repl_backlog_bytes"].parse::<u64>()? <= MAX_REPLICA_LAG + (1 << 20), "{:?}", info);
+
+    // the replica is removed once it reads past the writes still buffered for it
+    let mut received = 0;
+    for event in events.by_ref() {
+        match event {
+            Ok(ReplicationEvent::Write { .. }) => received += 1,
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    assert!(received < writes, "received {} writes", received);
+    client.request_msg(command(&["set", "key1", &value]))?;
+    let info = replication_info(addr);
+    assert_eq!(info["connected_replicas"], "0");
+    assert!(info["repl_backlog_bytes"].parse::<u64>()? <= REPLICATION_BACKLOG_SIZE + (1 << 20), "{:?}", info);
+
+    drop(events);
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-server --replica-of` should follow the primary
+#[test]
+fn cli_replica_of() {
+    let (primary_addr, replica_addr) = ("127.0.0.1:4166", "127.0.0.1:4167");
+    let primary_dir = TempDir::new().unwrap();
+    let replica_dir = TempDir::new().unwrap();
+    let mut primary = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", primary_addr, "--threads", "4"])
+        .current_dir(&primary_dir)
+        .spawn()
+        .unwrap();
+    let mut replica = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", replica_addr, "--replica-of", primary_addr, "--threads", "4"])
+        .current_dir(&replica_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", primary_addr]).assert().success();
+    wait_until("the write on the replica", || {
+        let output = Command::cargo_bin("kvs-client").unwrap().args(["get", "key1", "--addr", replica_addr]).output();
+        output.unwrap().stdout == b"value1\n"
+    });
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", replica_addr])
+        .assert()
+        .failure()
+        .stderr(contains("READONLY"));
+    terminate(&mut replica);
+    terminate(&mut primary);
+}
@@ -0,0 +1,141 @@
+use assert_cmd::prelude::*;
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::engines::sled::SledKvsEngine;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::slowlog::{SlowLogConfig, SLOWLOG_MAX_ARGS, SLOWLOG_MAX_ARG_LEN};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+/// the arguments and the client address of the entries of `SLOWLOG GET`
+fn entries(reply: Msg) -> Vec<(Vec<String>, String)> {
+    let entries = match reply {
+        Msg::Array(entries) => entries,
+        other => panic!("expect Array, got {:?}", other),
+    };
+    entries.into_iter()
+        .map(|entry| match entry {
+            Msg::Array(fields) => {
+                assert!(matches!(fields[..3], [Msg::Integer(_), Msg::Integer(_), Msg::Integer(_)]), "{:?}", fields);
+                let addr = match &fields[4] {
+                    Msg::Bulk(Some(addr)) => addr.clone(),
+                    other => panic!("expect Bulk, got {:?}", other),
+                };
+                (fields[3].try_to_vec_string().unwrap(), addr)
+            }
+            other => panic!("expect Array, got {:?}", other),
+        })
+        .collect()
+}
+
+fn slowlog_get(client: &mut KvsClient, count: Option<&str>) -> Result<Vec<(Vec<String>, String)>> {
+    let mut args = vec!["slowlog", "get"];
+    args.extend(count);
+    Ok(entries(client.request_msg(command(&args))?))
+}
+
+// Every command slower than the threshold should be kept, the newest first
+#[test]
+fn slowlog() -> Result<()> {
+    let addr = "127.0.0.1:4130";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let slowlog = SlowLogConfig { threshold: Some(Duration::ZERO), max_len: 3, log: true };
+    let mut server = KvsServer::new(addr.to_owned(), KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .with_slowlog(slowlog);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    client.request_msg(command(&["get", "key1"]))?;
+    let entries = slowlog_get(&mut client, None)?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries[0].0, vec!["get", "key1"]);
+    assert_eq!(entries[1].0, vec!["set", "key1", "value1"]);
+    assert!(entries[0].1.starts_with("127.0.0.1:"), "{}", entries[0].1);
+
+    // the oldest are dropped
+    assert_eq!(client.request_msg(command(&["slowlog", "len"]))?, Msg::Integer(3));
+    assert_eq!(slowlog_get(&mut client, Some("-1"))?.len(), 3);
+    assert_eq!(slowlog_get(&mut client, Some("1"))?.len(), 1);
+
+    // the passwords are not kept, the long commands are truncated
+    client.request_msg(command(&["auth", "secret"]))?;
+    assert_eq!(slowlog_get(&mut client, Some("1"))?[0].0, vec!["auth"]);
+    let value = "v".repeat(SLOWLOG_MAX_ARG_LEN + 10);
+    client.request_msg(command(&["set", "key2", &value]))?;
+    let args = &slowlog_get(&mut client, Some("1"))?[0].0;
+    assert_eq!(args[2], format!("{}... (10 more bytes)", &value[..SLOWLOG_MAX_ARG_LEN]));
+    let mut many = vec!["publish"; SLOWLOG_MAX_ARGS + 5];
+    many[1] = "channel";
+    client.request_msg(command(&many))?;
+    let args = &slowlog_get(&mut client, Some("1"))?[0].0;
+    assert_eq!(args.len(), SLOWLOG_MAX_ARGS);
+    assert_eq!(args[SLOWLOG_MAX_ARGS - 1], "... (6 more arguments)");
+
+    assert_eq!(client.request_msg(command(&["slowlog", "reset"]))?, Msg::Line("OK".to_owned()));
+    // the reset itself
+    assert_eq!(client.request_msg(command(&["slowlog", "len"]))?, Msg::Integer(1));
+    assert!(matches!(client.request_msg(command(&["slowlog", "get", "x"]))?, Msg::Error(_)));
+    assert!(matches!(client.request_msg(command(&["slowlog"]))?, Msg::Error(_)));
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// No command should be kept without a threshold
+#[test]
+fn slowlog_disabled_async_sled() -> Result<()> {
+    let addr = "127.0.0.1:4131";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let slowlog = SlowLogConfig { threshold: None, ..SlowLogConfig::default() };
+    let mut server = AsyncKvsServer::new(addr.to_owned(), SledKvsEngine::open(temp_dir.path())?).with_slowlog(slowlog);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    assert_eq!(client.request_msg(command(&["slowlog", "len"]))?, Msg::Integer(0));
+    assert!(slowlog_get(&mut client, None)?.is_empty());
+
+    drop(client);
+    handle.shutdown();
+    join.join().unwrap();
+    Ok(())
+}
+
+// `kvs-server --slowlog-threshold` should set the threshold
+#[test]
+fn cli_slowlog_threshold() {
+    let addr = "127.0.0.1:4132";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--slowlog-threshold", "0", "--slowlog-max-len", "1"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", addr]).assert().success();
+    let mut client = KvsClient::connect(addr.to_owned()).unwrap();
+    let entries = slowlog_get(&mut client, None).unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, vec!["set", "key1", "value1"]);
+}
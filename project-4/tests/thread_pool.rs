@@ -68,3 +68,29 @@ fn rayon_thread_pool_spawn_counter() -> Result<()> {
 fn shared_queue_thread_pool_panic_task() -> Result<()> {
     spawn_panic_task::<SharedQueueThreadPool>()
 }
+
+#[test]
+fn shared_queue_thread_pool_bounded_queue() -> Result<()> {
+    let pool = SharedQueueThreadPool::with_queue_capacity(1, 1)?;
+    let (release_tx, release_rx) = crossbeam::bounded::<()>(0);
+    let spawned = Arc::new(AtomicUsize::new(0));
+
+    // the only thread is busy and the queue has one job
+    pool.spawn(move || release_rx.recv().unwrap());
+    pool.spawn(|| {});
+
+    let handle = {
+        let spawned = Arc::clone(&spawned);
+        std::thread::spawn(move || {
+            pool.spawn(|| {});
+            spawned.fetch_add(1, Ordering::SeqCst);
+        })
+    };
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    assert_eq!(spawned.load(Ordering::SeqCst), 0, "spawn should block when the queue is full");
+
+    release_tx.send(()).unwrap();
+    handle.join().unwrap();
+    assert_eq!(spawned.load(Ordering::SeqCst), 1);
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use assert_cmd::prelude::*;
+use kvs::acl::{Acl, User};
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::{ConnectOptions, KvsClient};
+use kvs::model::{Msg, MsgExtend};
+use kvs::net::{Address, Stream};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::tls::{ClientTlsConfig, ServerTlsConfig};
+use kvs::{KvStore, Result};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, IsCa, KeyPair};
+use std::fs;
+use std::io::Write;
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+/// the PEM files generated for a test
+struct TestCerts {
+    dir: TempDir,
+}
+
+impl TestCerts {
+    /// a CA, a server certificate for 127.0.0.1 and a client certificate signed by the CA,
+    /// and another CA signing nothing
+    fn generate() -> Result<Self> {
+        let dir = TempDir::new()?;
+        let (ca, ca_key) = generate_ca("kvs test ca")?;
+        let (other_ca, _) = generate_ca("kvs other ca")?;
+        fs::write(dir.path().join("ca.pem"), ca.pem())?;
+        fs::write(dir.path().join("other-ca.pem"), other_ca.pem())?;
+        for (name, subject_alt_names) in [("server", vec!["127.0.0.1", "localhost"]), ("client", vec!["client"])] {
+            let key = KeyPair::generate()?;
+            let params = CertificateParams::new(subject_alt_names.into_iter().map(String::from).collect::<Vec<_>>())?;
+            let cert = params.signed_by(&key, &ca, &ca_key)?;
+            fs::write(dir.path().join(format!("{}.pem", name)), cert.pem())?;
+            fs::write(dir.path().join(format!("{}-key.pem", name)), key.serialize_pem())?;
+        }
+        Ok(TestCerts { dir })
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    fn server(&self, require_client_cert: bool) -> ServerTlsConfig {
+        ServerTlsConfig {
+            cert: self.path("server.pem"),
+            key: self.path("server-key.pem"),
+            client_ca: if require_client_cert { Some(self.path("ca.pem")) } else { None },
+        }
+    }
+
+    fn client(&self, ca: &str, with_cert: bool) -> ClientTlsConfig {
+        ClientTlsConfig {
+            ca: self.path(ca),
+            cert: if with_cert { Some(self.path("client.pem")) } else { None },
+            key: if with_cert { Some(self.path("client-key.pem")) } else { None },
+        }
+    }
+}
+
+fn generate_ca(name: &str) -> Result<(Certificate, KeyPair)> {
+    let key = KeyPair::generate()?;
+    let mut params = CertificateParams::new(Vec::<String>::new())?;
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params.distinguished_name.push(DnType::CommonName, name);
+    Ok((params.self_signed(&key)?, key))
+}
+
+fn start_server(addr: &str, tls: &ServerTlsConfig, is_async: bool) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let tls = tls.load().unwrap();
+    if is_async {
+        let mut server = AsyncKvsServer::new(addr.to_owned(), engine).with_tls(tls);
+        thread::spawn(move || server.start().unwrap());
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(addr.to_owned(), engine, thread_pool).with_tls(tls);
+        thread::spawn(move || server.start().unwrap());
+    }
+    thread::sleep(Duration::from_millis(500));
+    temp_dir
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn check_tls(addr: &str, is_async: bool) -> Result<()> {
+    let certs = TestCerts::generate()?;
+    let _temp_dir = start_server(addr, &certs.server(false), is_async);
+
+    let mut client = KvsClient::connect_tls(addr.to_owned(), certs.client("ca.pem", false).load()?)?;
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    assert_eq!(
+        client.request_msg(command(&["get", "key1"]))?,
+        Msg::Bulk(Some("value1".to_owned()))
+    );
+
+    // pipelined requests in one TLS record are all served
+    let mut stream = Stream::connect_tls(&Address::parse(addr), certs.client("ca.pem", false).load()?)?;
+    let mut bytes = command(&["set", "key2", "value2"]).to_bytes();
+    bytes.extend(command(&["get", "key2"]).to_bytes());
+    stream.write_all(&bytes)?;
+    stream.flush()?;
+    assert_eq!(stream.read_msg()?, Msg::Bulk(None));
+    assert_eq!(stream.read_msg()?, Msg::Bulk(Some("value2".to_owned())));
+
+    // a client not trusting the server certificate fails
+    let mut untrusted = KvsClient::connect_tls(addr.to_owned(), certs.client("other-ca.pem", false).load()?)?;
+    assert!(untrusted.request_msg(command(&["get", "key1"])).is_err());
+
+    // a plaintext client gets no reply
+    let mut plaintext = KvsClient::connect(addr.to_owned())?;
+    assert!(plaintext.request_msg(command(&["get", "key1"])).is_err());
+    Ok(())
+}
+
+// The server should serve TLS connections of the clients trusting its certificate
+#[test]
+fn tls() -> Result<()> {
+    check_tls("127.0.0.1:4060", false)
+}
+
+#[test]
+fn tls_async() -> Result<()> {
+    check_tls("127.0.0.1:4061", true)
+}
+
+fn check_mutual_tls(addr: &str, is_async: bool) -> Result<()> {
+    let certs = TestCerts::generate()?;
+    let _temp_dir = start_server(addr, &certs.server(true), is_async);
+
+    let mut client = KvsClient::connect_tls(addr.to_owned(), certs.client("ca.pem", true).load()?)?;
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+
+    let mut anonymous = KvsClient::connect_tls(addr.to_owned(), certs.client("ca.pem", false).load()?)?;
+    assert!(anonymous.request_msg(command(&["get", "key1"])).is_err());
+    Ok(())
+}
+
+// The server requiring client certificates should reject the clients without one
+#[test]
+fn mutual_tls() -> Result<()> {
+    check_mutual_tls("127.0.0.1:4062", false)
+}
+
+#[test]
+fn mutual_tls_async() -> Result<()> {
+    check_mutual_tls("127.0.0.1:4063", true)
+}
+
+// The clients rejected at max-clients should not hold up the accepts by their TLS handshake
+#[test]
+fn tls_rejection() -> Result<()> {
+    let addr = "127.0.0.1:4206";
+    let certs = TestCerts::generate()?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(addr.to_owned(), KvStore::open(temp_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .with_tls(certs.server(false).load()?)
+        .with_max_clients(1);
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut first = KvsClient::connect_tls(addr.to_owned(), certs.client("ca.pem", false).load()?)?;
+    assert_eq!(first.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    // rejected, and never start the handshake
+    let _silent: Vec<TcpStream> = (0..3).map(|_| TcpStream::connect(addr)).collect::<std::io::Result<_>>()?;
+    thread::sleep(Duration::from_millis(100));
+
+    let started = Instant::now();
+    let mut rejected = KvsClient::connect_tls(addr.to_owned(), certs.client("ca.pem", false).load()?)?;
+    assert_eq!(
+        rejected.request_msg(command(&["get", "key1"]))?,
+        Msg::Error("ERR max number of clients reached".to_owned())
+    );
+    assert_eq!(first.request_msg(command(&["get", "key1"]))?, Msg::Bulk(Some("value1".to_owned())));
+    assert!(started.elapsed() < Duration::from_secs(1), "served after {:?}", started.elapsed());
+    Ok(())
+}
+
+// A replica should authenticate to a primary requiring TLS and a password
+#[test]
+fn replica_of_tls_primary() -> Result<()> {
+    let (primary, replica) = ("127.0.0.1:4213", "127.0.0.1:4214");
+    let certs = TestCerts::generate()?;
+    let mut acl = Acl::default();
+    acl.add_user(User::unrestricted("replica", "replica-pass"));
+    let primary_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut server = KvsServer::new(primary.to_owned(), KvStore::open(primary_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .with_tls(certs.server(false).load()?)
+        .with_acl(acl);
+    thread::spawn(move || server.start().unwrap());
+    let replica_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = ConnectOptions {
+        tls: Some(certs.client("ca.pem", false).load()?),
+        user: Some("replica".to_owned()),
+        password: Some("replica-pass".to_owned()),
+        ..Default::default()
+    };
+    let mut server = KvsServer::new(replica.to_owned(), KvStore::open(replica_dir.path())?, SharedQueueThreadPool::new(4)?)
+        .with_replica_of(primary.to_owned())
+        .with_primary_connect(options.clone());
+    thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect_with(primary.to_owned(), &options)?;
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    let mut client = KvsClient::connect(replica.to_owned())?;
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.request_msg(command(&["get", "key1"]))? != Msg::Bulk(Some("value1".to_owned())) {
+        assert!(Instant::now() < deadline, "timeout waiting for the replica");
+        thread::sleep(Duration::from_millis(50));
+    }
+    Ok(())
+}
+
+// Missing or invalid files should be reported
+#[test]
+fn invalid_tls_files() -> Result<()> {
+    let certs = TestCerts::generate()?;
+    let mut server = certs.server(false);
+    server.key = certs.path("server.pem");
+    assert!(server.load().is_err());
+    server.key = certs.path("missing.pem");
+    assert!(server.load().is_err());
+
+    let mut client = certs.client("ca.pem", true);
+    client.key = None;
+    assert!(client.load().is_err());
+    Ok(())
+}
+
+// kvs-client should access kvs-server by mutual TLS
+#[test]
+fn cli_mutual_tls() -> Result<()> {
+    let addr = "127.0.0.1:4064";
+    let certs = TestCerts::generate()?;
+    let temp_dir = TempDir::new()?;
+    let arg = |name: &str| certs.path(name).to_str().unwrap().to_owned();
+    let mut child = Command::cargo_bin("kvs-server")?
+        .args(["--addr", addr])
+        .args(["--tls-cert", &arg("server.pem"), "--tls-key", &arg("server-key.pem")])
+        .args(["--tls-client-ca", &arg("ca.pem")])
+        .current_dir(&temp_dir)
+        .spawn()?;
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str], dir: &Path| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args)
+            .args(["--addr", addr, "--tls-ca", &arg("ca.pem")])
+            .current_dir(dir);
+        cmd
+    };
+    client(&["set", "key1", "value1"], temp_dir.path())
+        .args(["--tls-cert", &arg("client.pem"), "--tls-key", &arg("client-key.pem")])
+        .assert()
+        .success();
+    client(&["get", "key1"], temp_dir.path())
+        .args(["--tls-cert", &arg("client.pem"), "--tls-key", &arg("client-key.pem")])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    client(&["get", "key1"], temp_dir.path()).assert().failure();
+
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    assert!(child.wait()?.success());
+    Ok(())
+}
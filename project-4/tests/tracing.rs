@@ -0,0 +1,146 @@
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::model::Msg;
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{trace, KvStore, KvsEngine, Result};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+/// the spans of the OTLP JSON lines
+fn read_spans(path: &Path) -> Vec<Value> {
+    let text = fs::read_to_string(path).unwrap();
+    text.lines()
+        .map(|line| {
+            let request: Value = serde_json::from_str(line).unwrap();
+            request["resourceSpans"][0]["scopeSpans"][0]["spans"][0].clone()
+        })
+        .collect()
+}
+
+fn attribute<'a>(span: &'a Value, key: &str) -> Option<&'a Value> {
+    span["attributes"].as_array()?.iter().find(|a| a["key"] == key).map(|a| &a["value"])
+}
+
+fn children<'a>(spans: &'a [Value], parent: &Value, name: &str) -> Vec<&'a Value> {
+    spans.iter().filter(|s| s["parentSpanId"] == parent["spanId"] && s["name"] == name).collect()
+}
+
+// A request should be traced from the client, through the session and the channel, to the writer of KvStore
+#[test]
+fn otlp_file() -> Result<()> {
+    let addr = "127.0.0.1:4150";
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let trace_file = temp_dir.path().join("trace").join("spans.json");
+    trace::init_otlp_file(&trace_file)?;
+    assert!(trace::init_otlp_file(&trace_file).is_err(), "the global subscriber is set");
+
+    let engine = KvStore::open(temp_dir.path())?;
+    let mut server = KvsServer::new(addr.to_owned(), engine.clone(), SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let join = thread::spawn(move || server.start().unwrap());
+    thread::sleep(Duration::from_millis(500));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.request_msg(command(&["set", "key1", "value1"]))?;
+    client.request_msg(command(&["get", "key1"]))?;
+    client.request_msg(command(&["rm", "missing"]))?;
+    // `CLIENT TRACEPARENT` is not counted as a command
+    let info = match client.info(Some("stats"))? {
+        Msg::Bulk(Some(info)) => info,
+        other => panic!("unexpected reply to INFO {:?}", other),
+    };
+    assert!(info.contains("total_commands_processed:3\r\n"), "{}", info);
+    drop(client);
+    // the writer compacts the log every 2048 writes
+    for i in 0..2048 {
+        engine.set(format!("key{}", i), "value".to_owned())?;
+    }
+    handle.shutdown();
+    join.join().unwrap();
+
+    let spans = read_spans(&trace_file);
+    let traceparent = serde_json::json!({"stringValue": "client|traceparent"});
+    let (traceparents, requests): (Vec<&Value>, Vec<&Value>) = spans.iter()
+        .filter(|s| s["name"] == "request")
+        .partition(|s| attribute(s, "command") == Some(&traceparent));
+    assert_eq!(requests.len(), 4);
+    assert_eq!(traceparents.len(), 4);
+    let set = requests.iter().find(|s| attribute(s, "command") == Some(&serde_json::json!({"stringValue": "set"})));
+    let set = set.expect("no set request");
+    assert_eq!(set["kind"], 2);
+    assert_eq!(set["traceId"].as_str().unwrap().len(), 32);
+    // the client span is the parent, sent by `CLIENT TRACEPARENT` before the request
+    let clients: Vec<&Value> = spans.iter().filter(|s| s["name"] == "kvs_client.request" && s["kind"] == 3).collect();
+    assert_eq!(clients.len(), 4);
+    let client = clients.iter().find(|s| s["spanId"] == set["parentSpanId"]).expect("no client span of set");
+    assert_eq!(client["parentSpanId"], "");
+    assert_eq!(client["traceId"], set["traceId"]);
+    assert_eq!(children(&spans, client, "request").len(), 2);
+    assert!(attribute(set, "request_id").unwrap()["intValue"].is_string());
+    assert!(attribute(set, "peer").unwrap()["stringValue"].as_str().unwrap().starts_with("127.0.0.1:"));
+    assert!(set["startTimeUnixNano"].as_str().unwrap() <= set["endTimeUnixNano"].as_str().unwrap());
+
+    // request > engine > channel wait, and the writer thread under the engine call
+    let engine_calls = children(&spans, set, "kvs.engine");
+    assert_eq!(engine_calls.len(), 1);
+    let engine_call = engine_calls[0];
+    assert_eq!(engine_call["traceId"], set["traceId"]);
+    assert_eq!(children(&spans, engine_call, "kvs.channel_wait").len(), 1);
+    let writes = children(&spans, engine_call, "kvs_core.write");
+    assert_eq!(writes.len(), 1);
+    assert_eq!(children(&spans, writes[0], "kvs_core.flush").len(), 1);
+
+    let get = requests.iter().find(|s| attribute(s, "command") == Some(&serde_json::json!({"stringValue": "get"})));
+    let get_engine = children(&spans, get.unwrap(), "kvs.engine");
+    assert_eq!(children(&spans, get_engine[0], "kvs_core.read").len(), 1);
+
+    let rm = requests.iter().find(|s| attribute(s, "command") == Some(&serde_json::json!({"stringValue": "rm"})));
+    assert_eq!(rm.unwrap()["status"]["code"], 2);
+    assert!(spans.iter().any(|s| s["name"] == "kvs_core.compact"));
+    // every request has a trace of its own
+    let mut trace_ids: Vec<&str> = requests.iter().map(|s| s["traceId"].as_str().unwrap()).collect();
+    trace_ids.sort_unstable();
+    trace_ids.dedup();
+    assert_eq!(trace_ids.len(), 4);
+    Ok(())
+}
+
+fn terminate(child: &mut Child) {
+    unsafe {
+        libc::kill(child.id() as libc::pid_t, libc::SIGTERM);
+    }
+    child.wait().unwrap();
+}
+
+// `kvs-server --trace-file` should export the spans of the requests
+#[test]
+fn cli_trace_file() {
+    let addr = "127.0.0.1:4151";
+    let temp_dir = TempDir::new().unwrap();
+    let trace_file = temp_dir.path().join("spans.json");
+    let mut server = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--addr", addr, "--trace-file"])
+        .arg(&trace_file)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client").unwrap().args(["set", "key1", "value1", "--addr", addr]).assert().success();
+    terminate(&mut server);
+
+    let spans = read_spans(&trace_file);
+    assert!(spans.iter().any(|s| s["name"] == "request"), "{:?}", spans);
+    assert!(spans.iter().any(|s| s["name"] == "kvs_core.flush"), "{:?}", spans);
+}
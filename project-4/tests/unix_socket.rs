@@ -0,0 +1,126 @@
+use kvs::async_server::AsyncKvsServer;
+use kvs::client::KvsClient;
+use kvs::model::{Msg, MsgExtend};
+use kvs::server::{ConnectionTimeouts, KvsServer};
+use kvs::shutdown::ShutdownHandle;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, Result};
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// `unix_only` listens on the socket alone, otherwise alongside `addr`
+fn start_server(addr: &str, socket: &Path, unix_only: bool, is_async: bool) -> (ShutdownHandle, JoinHandle<()>) {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = KvStore::open(temp_dir.path()).unwrap();
+    let timeouts = ConnectionTimeouts { idle: Some(Duration::from_millis(500)), ..Default::default() };
+    let address = if unix_only { format!("unix://{}", socket.display()) } else { addr.to_owned() };
+    let (handle, join) = if is_async {
+        let mut server = AsyncKvsServer::new(address, engine).with_timeouts(timeouts);
+        if !unix_only {
+            server = server.with_unix_socket(socket);
+        }
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    } else {
+        let thread_pool = SharedQueueThreadPool::new(4).unwrap();
+        let mut server = KvsServer::new(address, engine, thread_pool).with_timeouts(timeouts);
+        if !unix_only {
+            server = server.with_unix_socket(socket);
+        }
+        let handle = server.shutdown_handle();
+        (handle, thread::spawn(move || {
+            server.start().unwrap();
+            drop(temp_dir);
+        }))
+    };
+    thread::sleep(Duration::from_millis(500));
+    (handle, join)
+}
+
+fn command(args: &[&str]) -> Msg {
+    let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+    Msg::build_bulk_array(&args)
+}
+
+fn check_unix_socket(addr: &str, is_async: bool) -> Result<()> {
+    let socket_dir = TempDir::new()?;
+    let socket = socket_dir.path().join("kvs.sock");
+    // a socket file left by a stopped server is replaced
+    drop(std::os::unix::net::UnixListener::bind(&socket)?);
+    let (handle, join) = start_server(addr, &socket, false, is_async);
+
+    let unix_addr = format!("unix://{}", socket.display());
+    let mut unix_client = KvsClient::connect(unix_addr)?;
+    assert_eq!(unix_client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    let mut tcp_client = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(
+        tcp_client.request_msg(command(&["get", "key1"]))?,
+        Msg::Bulk(Some("value1".to_owned()))
+    );
+    match unix_client.request_msg(command(&["client", "list"]))? {
+        Msg::Bulk(Some(list)) => assert!(list.contains(&format!("addr={}:0", socket.display())), "{}", list),
+        other => panic!("expect Bulk, got {:?}", other),
+    }
+
+    // the idle timeout applies to the Unix socket too
+    let mut idle = UnixStream::connect(&socket)?;
+    thread::sleep(Duration::from_millis(800));
+    let _ = idle.write_all(&command(&["get", "key1"]).to_bytes());
+    assert!(idle.read_msg().is_err(), "idle connection should be closed");
+
+    // the socket file is removed after the server stops
+    drop(unix_client);
+    drop(tcp_client);
+    handle.shutdown();
+    join.join().unwrap();
+    assert!(!socket.exists());
+    Ok(())
+}
+
+// The server should serve a Unix socket alongside TCP
+#[test]
+fn unix_socket() -> Result<()> {
+    check_unix_socket("127.0.0.1:4050", false)
+}
+
+#[test]
+fn unix_socket_async() -> Result<()> {
+    check_unix_socket("127.0.0.1:4051", true)
+}
+
+fn check_unix_socket_only(is_async: bool) -> Result<()> {
+    let socket_dir = TempDir::new()?;
+    let socket = socket_dir.path().join("kvs.sock");
+    let (handle, join) = start_server("", &socket, true, is_async);
+
+    let mut client = KvsClient::connect(format!("unix://{}", socket.display()))?;
+    assert_eq!(client.request_msg(command(&["set", "key1", "value1"]))?, Msg::Bulk(None));
+    drop(client);
+
+    // a second server can not take the socket in use
+    let (_, second) = start_server("", &socket, true, is_async);
+    assert!(second.join().is_err(), "the socket in use should not be replaced");
+
+    handle.shutdown();
+    join.join().unwrap();
+    assert!(!socket.exists());
+    Ok(())
+}
+
+// The server should listen on a Unix socket alone
+#[test]
+fn unix_socket_only() -> Result<()> {
+    check_unix_socket_only(false)
+}
+
+#[test]
+fn unix_socket_only_async() -> Result<()> {
+    check_unix_socket_only(true)
+}